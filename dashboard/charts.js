@@ -44,6 +44,50 @@ var weightedKhopsChart = {
   }
 };
 
+var cpChart = {
+  "width": 300,
+  "autosize": { "resize": true },
+  "mark": "bar",
+  "data": { "name": "table" },
+  "encoding": {
+    "x": {
+      "field": "ca",
+      "type": "nominal",
+      "title": "activity type"
+    },
+    "y": {
+      "field": "cp",
+      "type": "quantitative",
+      "title": "critical participation"
+    }
+  }
+};
+
+var skewChart = {
+  "width": 300,
+  "autosize": { "resize": true },
+  "mark": "bar",
+  "data": { "name": "table" },
+  "encoding": {
+    "x": {
+      "field": "ol",
+      "type": "nominal",
+      "title": "operator"
+    },
+    "y": {
+      "field": "mm",
+      "type": "quantitative",
+      "title": "max/mean across workers"
+    },
+    "color": {
+      "field": "s",
+      "type": "nominal",
+      "title": "skewed"
+    },
+    "tooltip": [{ "field": "ol", "type": "nominal", "title": "operator" }, { "field": "wc", "type": "quantitative", "title": "workers" }, { "field": "mm", "type": "quantitative", "title": "max/mean", "format": ".2f" }, { "field": "g", "type": "quantitative", "title": "Gini", "format": ".2f" }]
+  }
+};
+
 var activityCountChart = {
   "width": 300,
   "autosize": { "resize": true },
@@ -184,7 +228,7 @@ var recordProcessedChart = {
   }
 };
 
-var types = (_types = {}, _defineProperty(_types, "Processing", "#0b6623"), _defineProperty(_types, "Spinning", "#e48282"), _defineProperty(_types, "ControlMessage", "#4b5f53"), _defineProperty(_types, "DataMessage", "#971757"), _defineProperty(_types, "Waiting", "#FF0000"), _defineProperty(_types, "Busy", "#059dc0"), _types);
+var types = (_types = {}, _defineProperty(_types, "Processing", "#0b6623"), _defineProperty(_types, "Spinning", "#e48282"), _defineProperty(_types, "ControlMessage", "#4b5f53"), _defineProperty(_types, "DataMessage", "#971757"), _defineProperty(_types, "Waiting", "#FF0000"), _defineProperty(_types, "Busy", "#059dc0"), _defineProperty(_types, "Merging", "#d08c20"), _types);
 
 var margins = {
   left: 10,
@@ -194,14 +238,18 @@ var margins = {
 
 var pag = [];
 
+var opName = function opName(d) {
+  return d.on ? d.on + " (Op" + d.o + ")" : "Op" + d.o;
+};
+
 var genTitle = function genTitle(d) {
-  return d.type + " " + (d.o === 0 ? '' : 'Op' + d.o + ' ') + (d.l > 0 ? '(' + d.l + ')' : '');
+  return d.type + " " + (d.o === 0 ? '' : opName(d) + ' ') + (d.l > 0 ? '(' + d.l + ')' : '');
 };
 
 var tooltip = d3.select("body").append("div").attr("class", "tooltip").style("opacity", 0);
 
 var makeTooltip = function makeTooltip(d) {
-  return "\n    w" + d.src.w + ", " + d.src.t / 1000000 + " -> w" + d.dst.w + ", " + d.dst.t / 1000000 + " <br>\n    type: " + d.type + " <br>\n    operator: " + d.o + " <br>\n    length: " + d.l + " <br>\n    traversal: " + d.tr + " <br>";
+  return "\n    w" + d.src.w + ", " + d.src.t / 1000000 + " -> w" + d.dst.w + ", " + d.dst.t / 1000000 + " <br>\n    type: " + d.type + " <br>\n    operator: " + opName(d) + " <br>\n    length: " + d.l + " <br>\n    traversal: " + d.tr + " <br>";
 };
 
 var pagState = {};
@@ -348,6 +396,8 @@ function App() {
     socket.send(JSON.stringify({ type: 'AGG', epoch: epoch }));
     socket.send(JSON.stringify({ type: 'ALL', epoch: epoch }));
     socket.send(JSON.stringify({ type: 'MET', epoch: epoch }));
+    socket.send(JSON.stringify({ type: 'CP', epoch: epoch }));
+    socket.send(JSON.stringify({ type: 'SKEW', epoch: epoch }));
     d3.select(window).on('resize', updatePAG());
 
     socket.send(JSON.stringify({ type: 'INV' }));
@@ -364,6 +414,8 @@ function App() {
       socket.send(JSON.stringify({ type: 'AGG', epoch: epoch }));
       socket.send(JSON.stringify({ type: 'ALL', epoch: epoch }));
       socket.send(JSON.stringify({ type: 'MET', epoch: epoch }));
+      socket.send(JSON.stringify({ type: 'CP', epoch: epoch }));
+      socket.send(JSON.stringify({ type: 'SKEW', epoch: epoch }));
       pagState = Object.assign({}, pagState, { epoch: epoch });
     }
   };
@@ -427,9 +479,11 @@ function App() {
       "div",
       { style: { display: "flex", flexFlow: "row wrap" } },
       React.createElement(KHops, { epoch: epoch, showWaiting: showWaiting, splitWorker: splitWorker }),
+      React.createElement(CriticalParticipation, { epoch: epoch, showWaiting: showWaiting, splitWorker: splitWorker }),
       React.createElement(ActivityMetrics, { epoch: epoch, showWaiting: showWaiting, splitWorker: splitWorker }),
       React.createElement(CrossMetrics, { epoch: epoch, showWaiting: showWaiting, splitWorker: splitWorker }),
-      React.createElement(RecordMetrics, { epoch: epoch, showWaiting: showWaiting, splitWorker: splitWorker })
+      React.createElement(RecordMetrics, { epoch: epoch, showWaiting: showWaiting, splitWorker: splitWorker }),
+      React.createElement(DataSkew, { epoch: epoch })
     ),
     React.createElement(Invariants, null)
   );
@@ -878,6 +932,201 @@ function KHops(_ref6) {
   );
 }
 
+function CriticalParticipation(_ref12) {
+  var epoch = _ref12.epoch,
+      showWaiting = _ref12.showWaiting,
+      splitWorker = _ref12.splitWorker;
+
+  var _React$useState45 = React.useState(undefined),
+      _React$useState46 = _slicedToArray(_React$useState45, 2),
+      vis = _React$useState46[0],
+      setVis = _React$useState46[1];
+
+  var _React$useState47 = React.useState([]),
+      _React$useState48 = _slicedToArray(_React$useState47, 2),
+      visData = _React$useState48[0],
+      setVisData = _React$useState48[1];
+
+  var visRef = React.useRef(null);
+
+  var cpPrepper = function cpPrepper(data, showWaiting, splitWorker) {
+    var filtered = data.filter(function (_ref13) {
+      var a = _ref13.a;
+      return showWaiting || !a.startsWith("Wait") && !a.startsWith("Bus");
+    });
+
+    if (splitWorker) {
+      return filtered;
+    } else {
+      return filtered.reduce(function (acc, d) {
+        var idx = acc.findIndex(function (x) {
+          return x.ca === d.a;
+        });
+        if (idx > -1) {
+          acc[idx].cp += d.cp;
+          return acc;
+        } else {
+          return [].concat(_toConsumableArray(acc), [{ ca: d.a, cp: d.cp }]);
+        }
+      }, []);
+    }
+  };
+
+  React.useEffect(function () {
+    vegaEmbed(visRef.current, cpChart, { actions: false }).then(function (res) {
+      return setVis(res.view);
+    });
+
+    socket.addEventListener("message", function (e) {
+      var _JSON$parse7 = JSON.parse(e.data),
+          type = _JSON$parse7.type,
+          payload = _JSON$parse7.payload;
+
+      if (type === "CP") {
+        setVisData(payload.map(function (d) {
+          return Object.assign({}, d, { ca: [d.a, d.wf] });
+        }));
+      }
+    });
+  }, []);
+
+  React.useEffect(function () {
+    if (vis) {
+      vis.change('table', vega.changeset().remove(function () {
+        return true;
+      }).insert(cpPrepper(visData, showWaiting, splitWorker))).run();
+    }
+  });
+
+  return React.createElement(
+    "div",
+    null,
+    React.createElement(
+      "h1",
+      { style: { marginRight: "18px" } },
+      "Critical Participation (for epoch ",
+      epoch,
+      ")"
+    ),
+    React.createElement(
+      "div",
+      { style: { display: "flex", flexFlow: "row wrap" } },
+      React.createElement(
+        "div",
+        null,
+        React.createElement(
+          "h2",
+          null,
+          "By Activity"
+        ),
+        React.createElement("div", { ref: visRef })
+      )
+    )
+  );
+}
+
+function DataSkew(_ref14) {
+  var epoch = _ref14.epoch;
+
+  // Plot 1
+  // max/mean of records per operator
+  var _React$useState49 = React.useState(undefined),
+      _React$useState50 = _slicedToArray(_React$useState49, 2),
+      p1 = _React$useState50[0],
+      setP1 = _React$useState50[1];
+  // Plot 2
+  // max/mean of processing time per operator
+
+
+  var _React$useState51 = React.useState(undefined),
+      _React$useState52 = _slicedToArray(_React$useState51, 2),
+      p2 = _React$useState52[0],
+      setP2 = _React$useState52[1];
+
+  var _React$useState53 = React.useState([]),
+      _React$useState54 = _slicedToArray(_React$useState53, 2),
+      skewData = _React$useState54[0],
+      setSkewData = _React$useState54[1];
+
+  var p1Ref = React.useRef(null);
+  var p2Ref = React.useRef(null);
+
+  React.useEffect(function () {
+    vegaEmbed(p1Ref.current, skewChart, { actions: false }).then(function (res) {
+      return setP1(res.view);
+    });
+    vegaEmbed(p2Ref.current, skewChart, { actions: false }).then(function (res) {
+      return setP2(res.view);
+    });
+
+    socket.addEventListener("message", function (e) {
+      var _JSON$parse8 = JSON.parse(e.data),
+          type = _JSON$parse8.type,
+          payload = _JSON$parse8.payload;
+
+      if (type === "SKEW") {
+        setSkewData(payload.map(function (d) {
+          return Object.assign({}, d, { ol: opName(d) });
+        }));
+      }
+    });
+  }, []);
+
+  React.useEffect(function () {
+    if (p1) {
+      p1.change('table', vega.changeset().remove(function () {
+        return true;
+      }).insert(skewData.map(function (d) {
+        return Object.assign({}, d, { mm: d.rmm, g: d.rg });
+      }))).run();
+    }
+
+    if (p2) {
+      p2.change('table', vega.changeset().remove(function () {
+        return true;
+      }).insert(skewData.map(function (d) {
+        return Object.assign({}, d, { mm: d.pmm, g: d.pg });
+      }))).run();
+    }
+  });
+
+  return React.createElement(
+    "div",
+    null,
+    React.createElement(
+      "h1",
+      { style: { marginRight: "18px" } },
+      "Data Skew (for epoch ",
+      epoch,
+      ")"
+    ),
+    React.createElement(
+      "div",
+      { style: { display: "flex", flexFlow: "row wrap" } },
+      React.createElement(
+        "div",
+        null,
+        React.createElement(
+          "h2",
+          null,
+          "Records"
+        ),
+        React.createElement("div", { ref: p1Ref })
+      ),
+      React.createElement(
+        "div",
+        null,
+        React.createElement(
+          "h2",
+          null,
+          "Processing Time"
+        ),
+        React.createElement("div", { ref: p2Ref })
+      )
+    )
+  );
+}
+
 var formatE = function formatE(e) {
   if (e.length > 0) {
     return e.sort(function (a, b) {
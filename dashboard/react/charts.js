@@ -36,6 +36,55 @@ const weightedKhopsChart = {
   }
 };
 
+const cpChart = {
+  "width": 300,
+  "autosize": { "resize": true },
+  "mark": "bar",
+  "data": { "name": "table" },
+  "encoding": {
+    "x": {
+      "field": "ca",
+      "type": "nominal",
+      "title": "activity type"
+    },
+    "y": {
+      "field": "cp",
+      "type": "quantitative",
+      "title": "critical participation"
+    }
+  }
+};
+
+const skewChart = {
+  "width": 300,
+  "autosize": { "resize": true },
+  "mark": "bar",
+  "data": { "name": "table" },
+  "encoding": {
+    "x": {
+      "field": "ol",
+      "type": "nominal",
+      "title": "operator"
+    },
+    "y": {
+      "field": "mm",
+      "type": "quantitative",
+      "title": "max/mean across workers"
+    },
+    "color": {
+      "field": "s",
+      "type": "nominal",
+      "title": "skewed"
+    },
+    "tooltip": [
+      { "field": "ol", "type": "nominal", "title": "operator" },
+      { "field": "wc", "type": "quantitative", "title": "workers" },
+      { "field": "mm", "type": "quantitative", "title": "max/mean", "format": ".2f" },
+      { "field": "g", "type": "quantitative", "title": "Gini", "format": ".2f" }
+    ]
+  }
+};
+
 const activityCountChart = {
   "width": 300,
   "autosize": { "resize": true },
@@ -183,6 +232,7 @@ const types = {
   ["DataMessage"]: "#971757",
   ["Waiting"]: "#FF0000",
   ["Busy"]: "#059dc0",
+  ["Merging"]: "#d08c20",
 };
 
 const margins = {
@@ -193,7 +243,9 @@ const margins = {
 
 let pag = [];
 
-const genTitle = d => `${d.type} ${d.o === 0 ? '' : 'Op' + d.o + ' '}${d.l > 0 ? '(' + d.l + ')' : ''}`;
+const opName = d => d.on ? `${d.on} (Op${d.o})` : `Op${d.o}`;
+
+const genTitle = d => `${d.type} ${d.o === 0 ? '' : opName(d) + ' '}${d.l > 0 ? '(' + d.l + ')' : ''}`;
 
 const tooltip = d3.select("body").append("div")
   .attr("class", "tooltip")
@@ -202,7 +254,7 @@ const tooltip = d3.select("body").append("div")
 const makeTooltip = d => `
     w${d.src.w}, ${d.src.t / 1000000} -> w${d.dst.w}, ${d.dst.t / 1000000} <br>
     type: ${d.type} <br>
-    operator: ${d.o} <br>
+    operator: ${opName(d)} <br>
     length: ${d.l} <br>
     traversal: ${d.tr} <br>`;
 
@@ -340,6 +392,8 @@ function App() {
     socket.send(JSON.stringify({ type: 'AGG', epoch }));
     socket.send(JSON.stringify({ type: 'ALL', epoch }));
     socket.send(JSON.stringify({ type: 'MET', epoch }));
+    socket.send(JSON.stringify({ type: 'CP', epoch }));
+    socket.send(JSON.stringify({ type: 'SKEW', epoch }));
     d3.select(window).on('resize', updatePAG());
 
     socket.send(JSON.stringify({ type: 'INV' }));
@@ -354,6 +408,8 @@ function App() {
       socket.send(JSON.stringify({ type: 'AGG', epoch }));
       socket.send(JSON.stringify({ type: 'ALL', epoch }));
       socket.send(JSON.stringify({ type: 'MET', epoch }));
+      socket.send(JSON.stringify({ type: 'CP', epoch }));
+      socket.send(JSON.stringify({ type: 'SKEW', epoch }));
       pagState = { ...pagState, epoch };
     }
   };
@@ -382,9 +438,11 @@ function App() {
       </div>
       <div style={{ display: "flex", flexFlow: "row wrap" }}>
         <KHops epoch={epoch} showWaiting={showWaiting} splitWorker={splitWorker}></KHops>
+        <CriticalParticipation epoch={epoch} showWaiting={showWaiting} splitWorker={splitWorker}></CriticalParticipation>
         <ActivityMetrics epoch={epoch} showWaiting={showWaiting} splitWorker={splitWorker}></ActivityMetrics>
         <CrossMetrics epoch={epoch} showWaiting={showWaiting} splitWorker={splitWorker}></CrossMetrics>
         <RecordMetrics epoch={epoch} showWaiting={showWaiting} splitWorker={splitWorker}></RecordMetrics>
+        <DataSkew epoch={epoch}></DataSkew>
       </div>
       <Invariants></Invariants>
     </div >
@@ -626,6 +684,107 @@ function KHops({ epoch, showWaiting, splitWorker }) {
   );
 }
 
+function CriticalParticipation({ epoch, showWaiting, splitWorker }) {
+  const [vis, setVis] = React.useState(undefined);
+  const [visData, setVisData] = React.useState([]);
+
+  const visRef = React.useRef(null);
+
+  const cpPrepper = (data, showWaiting, splitWorker) => {
+    const filtered = data.filter(({ a }) => showWaiting || (!a.startsWith("Wait") && !a.startsWith("Bus")));
+
+    if (splitWorker) {
+      return filtered;
+    } else {
+      return filtered.reduce((acc, d) => {
+        let idx = acc.findIndex(x => x.ca === d.a);
+        if (idx > -1) {
+          acc[idx].cp += d.cp;
+          return acc;
+        } else {
+          return [...acc, { ca: d.a, cp: d.cp }];
+        }
+      }, []);
+    }
+  };
+
+  React.useEffect(() => {
+    vegaEmbed(visRef.current, cpChart, { actions: false }).then(res => setVis(res.view));
+
+    socket.addEventListener("message", e => {
+      const { type, payload } = JSON.parse(e.data);
+      if (type === "CP") { setVisData(payload.map(d => ({ ...d, ca: [d.a, d.wf] }))); }
+    });
+  }, []);
+
+  React.useEffect(() => {
+    if (vis) {
+      vis.change('table', vega.changeset().remove(() => true).insert(cpPrepper(visData, showWaiting, splitWorker))).run();
+    }
+  });
+
+  return (
+    <div>
+      <h1 style={{ marginRight: "18px" }}>Critical Participation (for epoch {epoch})</h1>
+      <div style={{ display: "flex", flexFlow: "row wrap" }}>
+        <div>
+          <h2>By Activity</h2>
+          <div ref={visRef}></div>
+        </div>
+      </div>
+    </div>
+  );
+}
+
+function DataSkew({ epoch }) {
+  // Plot 1
+  // max/mean of records per operator
+  const [p1, setP1] = React.useState(undefined);
+  // Plot 2
+  // max/mean of processing time per operator
+  const [p2, setP2] = React.useState(undefined);
+  const [skewData, setSkewData] = React.useState([]);
+
+  const p1Ref = React.useRef(null);
+  const p2Ref = React.useRef(null);
+
+  React.useEffect(() => {
+    vegaEmbed(p1Ref.current, skewChart, { actions: false }).then(res => setP1(res.view));
+    vegaEmbed(p2Ref.current, skewChart, { actions: false }).then(res => setP2(res.view));
+
+    socket.addEventListener("message", e => {
+      const { type, payload } = JSON.parse(e.data);
+      if (type === "SKEW") { setSkewData(payload.map(d => ({ ...d, ol: opName(d) }))); }
+    });
+  }, []);
+
+  React.useEffect(() => {
+    if (p1) {
+      p1.change('table', vega.changeset().remove(() => true).insert(skewData.map(d => ({ ...d, mm: d.rmm, g: d.rg })))).run();
+    }
+
+    if (p2) {
+      p2.change('table', vega.changeset().remove(() => true).insert(skewData.map(d => ({ ...d, mm: d.pmm, g: d.pg })))).run();
+    }
+  });
+
+  return (
+    <div>
+      <h1 style={{ marginRight: "18px" }}>Data Skew (for epoch {epoch})</h1>
+      <div style={{ display: "flex", flexFlow: "row wrap" }}>
+        <div>
+          <h2>Records</h2>
+          <div ref={p1Ref}></div>
+        </div>
+        <div>
+          <h2>Processing Time</h2>
+          <div ref={p2Ref}></div>
+        </div>
+      </div>
+    </div>
+  );
+}
+
 const formatE = e => {
   if (e.length > 0) {
     return e
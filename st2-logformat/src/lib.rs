@@ -8,6 +8,8 @@
 #[macro_use]
 extern crate abomonation_derive;
 
+pub mod trace;
+
 use std::cmp::Ordering;
 
 use serde::{Deserialize, Serialize};
@@ -42,12 +44,15 @@ pub enum ActivityType {
     /// In particular, operator doesn't depend on external input.
     /// (not emitted by profiling)
     Busy = 9,
+    /// Arrangement maintenance, i.e., a differential arrangement
+    /// inserting new batches and merging them
+    Merging = 10,
 }
 
 /// What "side" of the event did we log? E.g., for
 /// scheduling events, it might be the start or end of the event;
 /// for messages, we might log the sender or receiver.
-#[derive(Abomonation, PartialEq, Eq, PartialOrd, Ord, Debug, Hash, Clone, Copy)]
+#[derive(Abomonation, PartialEq, Eq, PartialOrd, Ord, Debug, Hash, Clone, Copy, Deserialize, Serialize)]
 pub enum EventType {
     /// Start of an event (e.g. ScheduleStart, Sending a Message)
     Start = 1,
@@ -75,8 +80,8 @@ pub type ChannelId = u64;
 /// log messages from various stream processors.
 ///
 /// It is the underlying structure from which the PAG construction starts.
-/// If necessary, it can also be serialized e.g. into a `msgpack` representation.
-#[derive(Abomonation, PartialEq, Eq, Hash, Clone, Debug)]
+/// It can also be serialized, e.g. into the portable trace format (cf. `trace`).
+#[derive(Abomonation, PartialEq, Eq, Hash, Clone, Debug, Deserialize, Serialize)]
 pub struct LogRecord {
     /// worker-unique identifier of a message, given in order the events are logged
     /// in the computation.
@@ -84,6 +89,7 @@ pub struct LogRecord {
     /// epoch of the computation this record belongs to
     pub epoch: u64,
     /// Event time in nanoseconds since the Epoch (midnight, January 1, 1970 UTC).
+    #[serde(with = "nanos")]
     pub timestamp: Timestamp,
     /// Context this event occured in; denotes which of the parallel timelines it belongs to.
     pub local_worker: Worker,
@@ -101,6 +107,42 @@ pub struct LogRecord {
     pub correlator_id: Option<u64>,
     /// Number of records to detect skew
     pub length: Option<usize>,
+    /// Activation of the innermost scope (e.g., an `iterate` loop) the event happened in, if any
+    #[serde(default)]
+    pub scope: Option<ScopeActivation>,
+    /// Activations of the scopes enclosing `scope`, outermost first
+    #[serde(default)]
+    pub outer_scopes: Vec<ScopeActivation>,
+}
+
+/// An activation of a scope, i.e., one time the scope's operator was scheduled.
+/// A scope schedules each of its operators at most once per activation,
+/// so for loops, activations approximate iterations. They aren't iterations,
+/// though: the scope might be scheduled several times per iteration, or not at all.
+#[derive(Abomonation, PartialEq, Eq, PartialOrd, Ord, Debug, Hash, Clone, Copy, Deserialize, Serialize)]
+pub struct ScopeActivation {
+    /// Worker-local operator id of the scope
+    pub scope_id: OperatorId,
+    /// Number of earlier activations of the scope within the epoch.
+    /// This counts schedules of the scope, not iterations of a loop.
+    pub round: u64,
+}
+
+/// (De)serializes timestamps as nanoseconds, which is easier
+/// to deal with in other languages than `Duration`'s representation.
+mod nanos {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::convert::TryInto;
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(timestamp: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        let nanos: u64 = timestamp.as_nanos().try_into().map_err(serde::ser::Error::custom)?;
+        serializer.serialize_u64(nanos)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_nanos(u64::deserialize(deserializer)?))
+    }
 }
 
 impl Ord for LogRecord {
@@ -115,6 +157,109 @@ impl PartialOrd for LogRecord {
     }
 }
 
+/// An operator of the source computation's dataflow, as seen by a worker.
+#[derive(Abomonation, PartialEq, Eq, Debug, Hash, Clone, Deserialize, Serialize)]
+pub struct OperatorInfo {
+    /// Worker the operator belongs to
+    pub worker: Worker,
+    /// Worker-local operator id
+    pub id: OperatorId,
+    /// Operator name, e.g. `Map`, `Join`, `Reduce`
+    pub name: String,
+    /// Address path of the operator. Its prefixes are the addresses of
+    /// the scopes it is nested in.
+    pub addr: Vec<usize>,
+}
+
+impl OperatorInfo {
+    /// Number of scopes the operator is nested in
+    /// (direct children of a dataflow are at depth 1).
+    pub fn depth(&self) -> usize {
+        self.addr.len().saturating_sub(1)
+    }
+}
+
+/// A channel of the source computation's dataflow, as seen by a worker.
+#[derive(Abomonation, PartialEq, Eq, Debug, Hash, Clone, Deserialize, Serialize)]
+pub struct ChannelInfo {
+    /// Worker the channel belongs to
+    pub worker: Worker,
+    /// Worker-local channel id
+    pub id: ChannelId,
+    /// Address of the scope the channel connects operators in
+    pub scope_addr: Vec<usize>,
+    /// Source operator (its index within the scope) and output port.
+    /// Index 0 is the scope itself, i.e., one of its inputs.
+    pub source: (usize, usize),
+    /// Target operator (its index within the scope) and input port.
+    /// Index 0 is the scope itself, i.e., one of its outputs.
+    pub target: (usize, usize),
+}
+
+impl ChannelInfo {
+    /// Address of the channel's source operator
+    pub fn source_addr(&self) -> Vec<usize> {
+        Self::addr(&self.scope_addr, self.source.0)
+    }
+
+    /// Address of the channel's target operator
+    pub fn target_addr(&self) -> Vec<usize> {
+        Self::addr(&self.scope_addr, self.target.0)
+    }
+
+    fn addr(scope_addr: &[usize], index: usize) -> Vec<usize> {
+        let mut addr = scope_addr.to_vec();
+        if index > 0 {
+            addr.push(index);
+        }
+        addr
+    }
+}
+
+/// Why events couldn't be used for constructing the PAG.
+#[derive(Abomonation, PartialEq, Eq, Debug, Hash, Clone, Copy, Deserialize, Serialize)]
+pub enum AnomalyReason {
+    /// An operator was scheduled before its `Operates` event has been seen
+    UnknownOperator,
+    /// A record happened before its predecessor on the same worker
+    OutOfOrder,
+    /// A data message right after a schedule has ended
+    DataMessageOutsideSchedule,
+    /// A control message within a schedule
+    ControlMessageWithinSchedule,
+    /// A record count at an event that doesn't process records
+    UnexpectedLength,
+    /// The records of a local edge belong to different workers
+    NotLocal,
+    /// Two consecutive activities that don't form a known edge
+    UnknownEdge,
+    /// An activity that was logged together with the dataflow structure, before the first epoch
+    EventBeforeFirstEpoch,
+    /// A data message whose record count differs from the one it was logged with
+    LengthMismatch,
+    /// A remote message without a correlator id, so that its other side can't be found
+    MissingCorrelator,
+    /// A control message that was received by the worker that sent it
+    LocalControlMessage,
+}
+
+/// Malformed events that were skipped while constructing the PAG,
+/// together with the offending `LogRecord`s.
+#[derive(Abomonation, PartialEq, Eq, Debug, Hash, Clone, Deserialize, Serialize)]
+pub struct Anomaly {
+    /// What went wrong
+    pub reason: AnomalyReason,
+    /// The offending records
+    pub records: Vec<LogRecord>,
+}
+
+impl Anomaly {
+    /// Creates a new anomaly for the offending `records`.
+    pub fn new(reason: AnomalyReason, records: Vec<LogRecord>) -> Self {
+        Anomaly { reason, records }
+    }
+}
+
 
 /// This module contains a definition of a new timestamp time, a "pair" or product.
 ///
@@ -130,8 +275,10 @@ pub mod pair {
 
     use std::fmt::{Formatter, Error, Debug};
 
+    use serde::{Deserialize, Serialize};
+
     /// A pair of timestamps, partially ordered by the product order.
-    #[derive(Hash, Default, Clone, Eq, PartialEq, Ord, PartialOrd, Abomonation)]
+    #[derive(Hash, Default, Clone, Eq, PartialEq, Ord, PartialOrd, Abomonation, Deserialize, Serialize)]
     pub struct Pair<S, T> {
         /// first part of timestamp
         pub first: S,
@@ -0,0 +1,216 @@
+//! Portable trace format for `LogRecord`s.
+//!
+//! Unlike the `*.dump` files written by timely's logging infrastructure,
+//! which are abomonated and tied to the exact Rust types, traces in this
+//! format can be read by any language with a JSON or msgpack library.
+//!
+//! A trace is a sequence of `TraceEntry`s, either as line-delimited JSON
+//! (one entry per line) or as concatenated msgpack values (maps with named
+//! fields). The first entry is always a `TraceEntry::Header` carrying the
+//! `FORMAT_VERSION`. Cf. `docs/trace-format.md` for a full description.
+
+use std::io::{self, BufRead, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ChannelInfo, LogRecord, OperatorInfo};
+
+/// Version of the trace format written by `TraceWriter`.
+/// It is bumped on every change to the serialized types that older readers
+/// can't handle. Traces of older versions remain readable.
+pub const FORMAT_VERSION: u32 = 5;
+
+/// Encodings of the trace format
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// Line-delimited JSON
+    Json,
+    /// Concatenated msgpack values
+    Msgpack,
+}
+
+/// A single entry of a trace
+#[derive(PartialEq, Eq, Debug, Clone, Deserialize, Serialize)]
+pub enum TraceEntry {
+    /// First entry of every trace
+    Header {
+        /// Trace format version, cf. `FORMAT_VERSION`
+        version: u32,
+    },
+    /// A log record
+    Record(LogRecord),
+    /// An operator of the source computation's dataflow
+    Operator(OperatorInfo),
+    /// A channel of the source computation's dataflow
+    Channel(ChannelInfo),
+}
+
+/// Writes `TraceEntry`s in the given `TraceFormat`.
+pub struct TraceWriter<W: Write> {
+    out: W,
+    format: TraceFormat,
+}
+
+impl<W: Write> TraceWriter<W> {
+    /// Starts a new trace on `out` by writing its header.
+    pub fn new(out: W, format: TraceFormat) -> io::Result<Self> {
+        let mut writer = TraceWriter { out, format };
+        writer.write(&TraceEntry::Header { version: FORMAT_VERSION })?;
+        Ok(writer)
+    }
+
+    /// Appends `entry` to the trace.
+    pub fn write(&mut self, entry: &TraceEntry) -> io::Result<()> {
+        match self.format {
+            TraceFormat::Json => {
+                serde_json::to_writer(&mut self.out, entry).map_err(invalid_data)?;
+                writeln!(self.out)
+            }
+            TraceFormat::Msgpack => rmp_serde::encode::write_named(&mut self.out, entry).map_err(invalid_data),
+        }
+    }
+
+    /// Flushes the underlying writer.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Reads `TraceEntry`s in the given `TraceFormat`.
+/// The header is checked on creation and not returned by the iterator.
+pub struct TraceReader<R: BufRead> {
+    input: R,
+    format: TraceFormat,
+    line: String,
+}
+
+impl<R: BufRead> TraceReader<R> {
+    /// Opens a trace on `input`, failing if it doesn't start with a
+    /// header of a supported version, i.e., one up to `FORMAT_VERSION`.
+    pub fn new(input: R, format: TraceFormat) -> io::Result<Self> {
+        let mut reader = TraceReader { input, format, line: String::new() };
+
+        match reader.read()? {
+            Some(TraceEntry::Header { version }) if version >= 1 && version <= FORMAT_VERSION => Ok(reader),
+            Some(TraceEntry::Header { version }) if version > FORMAT_VERSION => Err(invalid_data(format!(
+                "trace format version {} is newer than the supported version {}, upgrade ST2 to read it",
+                version, FORMAT_VERSION))),
+            Some(TraceEntry::Header { version }) => Err(invalid_data(format!("unsupported trace format version {}", version))),
+            _ => Err(invalid_data("trace doesn't start with a header")),
+        }
+    }
+
+    /// Reads the next entry, or `None` at the end of the trace.
+    pub fn read(&mut self) -> io::Result<Option<TraceEntry>> {
+        match self.format {
+            TraceFormat::Json => {
+                self.line.clear();
+                while self.line.trim().is_empty() {
+                    self.line.clear();
+                    if self.input.read_line(&mut self.line)? == 0 {
+                        return Ok(None);
+                    }
+                }
+                serde_json::from_str(&self.line).map(Some).map_err(invalid_data)
+            }
+            TraceFormat::Msgpack => {
+                if self.input.fill_buf()?.is_empty() {
+                    return Ok(None);
+                }
+                rmp_serde::decode::from_read(&mut self.input).map(Some).map_err(invalid_data)
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read().transpose()
+    }
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::{ActivityType, EventType, ScopeActivation};
+
+    fn entries() -> Vec<TraceEntry> {
+        vec![
+            TraceEntry::Operator(OperatorInfo { worker: 0, id: 2, name: "Map".to_string(), addr: vec![0, 2] }),
+            TraceEntry::Channel(ChannelInfo { worker: 0, id: 1, scope_addr: vec![0], source: (1, 0), target: (2, 0) }),
+            TraceEntry::Record(LogRecord {
+                seq_no: 3,
+                epoch: 1,
+                timestamp: Duration::new(1, 500),
+                local_worker: 0,
+                activity_type: ActivityType::DataMessage,
+                event_type: EventType::Sent,
+                remote_worker: Some(1),
+                operator_id: None,
+                channel_id: Some(1),
+                correlator_id: Some(42),
+                length: Some(10),
+                scope: Some(ScopeActivation { scope_id: 1, round: 2 }),
+                outer_scopes: vec![ScopeActivation { scope_id: 0, round: 1 }],
+            }),
+        ]
+    }
+
+    fn write(format: TraceFormat, version: u32, entries: &[TraceEntry]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut writer = TraceWriter { out: &mut out, format };
+        writer.write(&TraceEntry::Header { version }).unwrap();
+        for entry in entries {
+            writer.write(entry).unwrap();
+        }
+        out
+    }
+
+    fn round_trip(format: TraceFormat) {
+        let mut out = Vec::new();
+        let mut writer = TraceWriter::new(&mut out, format).unwrap();
+        for entry in entries() {
+            writer.write(&entry).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let read = TraceReader::new(&out[..], format).unwrap().collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(read, entries());
+    }
+
+    #[test]
+    fn json_round_trip() {
+        round_trip(TraceFormat::Json);
+    }
+
+    #[test]
+    fn msgpack_round_trip() {
+        round_trip(TraceFormat::Msgpack);
+    }
+
+    #[test]
+    fn reject_newer_version() {
+        for format in &[TraceFormat::Json, TraceFormat::Msgpack] {
+            let out = write(*format, FORMAT_VERSION + 1, &entries());
+            let err = TraceReader::new(&out[..], *format).err().expect("newer version accepted");
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn reject_missing_header() {
+        for format in &[TraceFormat::Json, TraceFormat::Msgpack] {
+            let mut out = write(*format, FORMAT_VERSION, &entries());
+            let header = write(*format, FORMAT_VERSION, &[]);
+            out.drain(.. header.len());
+            assert!(TraceReader::new(&out[..], *format).is_err());
+        }
+    }
+}
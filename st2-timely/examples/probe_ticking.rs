@@ -0,0 +1,32 @@
+use timely::dataflow::InputHandle;
+use timely::dataflow::operators::{Input, Exchange, Inspect, Probe};
+
+use st2_timely::config::AdapterConfig;
+use st2_timely::connect::{Adapter, EpochTicking};
+
+fn main() {
+    timely::execute_from_args(std::env::args(), |worker| {
+        // (A) Create SnailTrail adapter at the beginning of the worker closure.
+        // Epochs are derived from the probe's frontier, so there's no need to tick them.
+        let config = AdapterConfig::new().epoch_ticking(EpochTicking::Probe);
+        let adapter = Adapter::attach_configured(worker, config).expect("couldn't attach SnailTrail");
+
+        // Some computation
+        let mut input = InputHandle::new();
+        let probe = worker.dataflow(|scope|
+            scope.input_from(&mut input)
+                 .exchange(|x| *x as u64 + 1)
+                 .inspect(move |x| println!("record {}", x))
+                 .probe()
+        );
+
+        // (B) Tell the adapter which frontier marks epoch completion
+        adapter.tick_on_probe(&probe);
+
+        for round in 0..100 {
+            if worker.index() == 0 { (0..20).for_each(|i| input.send(i) ) }
+            input.advance_to(round + 1);
+            while probe.less_than(input.time()) { worker.step(); }
+        }
+    }).unwrap();
+}
@@ -0,0 +1,531 @@
+//! Background writers, so that logging never stalls the source computation.
+//!
+//! A `BackgroundWriter` serializes events on the worker thread and hands them
+//! to a dedicated writer thread through a bounded queue. What happens if the
+//! queue is full is decided by its `OverflowPolicy`.
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use abomonation::Abomonation;
+
+use timely::dataflow::operators::capture::event::{Event, EventPusher};
+
+use st2_logformat::pair::Pair;
+
+
+/// What a `BackgroundWriter` does with events that don't fit into its queue.
+/// Progress events and the dataflow structure (epoch 0) are never dropped,
+/// as ST2 can't make sense of a trace without them. Only `Block` ever stalls the worker.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait until there's room in the queue, stalling the worker
+    Block,
+    /// Drop the rest of the epoch. ST2 sees it truncated. Progress events and
+    /// the dataflow structure are held back until there's room, and pending
+    /// progress events are coalesced into one.
+    DropEpochs,
+    /// Write events to temporary files in the given directory until there's
+    /// room in the queue again. The writer thread sends them later on.
+    Spill(PathBuf),
+}
+
+/// Configuration of background writers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackgroundConfig {
+    /// Number of batches that can be queued per writer
+    pub capacity: usize,
+    /// What happens if the queue is full
+    pub overflow: OverflowPolicy,
+}
+
+/// Counters of a `BackgroundWriter` (shared with its writer thread),
+/// a `ReconnectingWriter` or a `SegmentedWriter`
+#[derive(Default, Debug)]
+pub struct WriterStats {
+    /// Batches written to the underlying writer
+    pub written_batches: AtomicU64,
+    /// Batches dropped because the queue was full, the writer failed,
+    /// or the connection was lost
+    pub dropped_batches: AtomicU64,
+    /// Batches spilled to disk because the queue was full
+    pub spilled_batches: AtomicU64,
+    /// Epochs that were truncated by dropping batches
+    pub dropped_epochs: AtomicU64,
+    /// Connections re-established after a failure
+    pub reconnects: AtomicU64,
+}
+
+impl WriterStats {
+    /// Returns the current counts.
+    pub fn counts(&self) -> WriterCounts {
+        WriterCounts {
+            written_batches: self.written_batches.load(Ordering::Relaxed),
+            dropped_batches: self.dropped_batches.load(Ordering::Relaxed),
+            spilled_batches: self.spilled_batches.load(Ordering::Relaxed),
+            dropped_epochs: self.dropped_epochs.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A snapshot of `WriterStats`
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriterCounts {
+    /// Batches written to the underlying writer
+    pub written_batches: u64,
+    /// Batches dropped because the queue was full, the writer failed,
+    /// or the connection was lost
+    pub dropped_batches: u64,
+    /// Batches spilled to disk because the queue was full
+    pub spilled_batches: u64,
+    /// Epochs that were truncated by dropping batches
+    pub dropped_epochs: u64,
+    /// Connections re-established after a failure
+    pub reconnects: u64,
+}
+
+impl std::ops::Add for WriterCounts {
+    type Output = WriterCounts;
+
+    fn add(self, other: WriterCounts) -> WriterCounts {
+        WriterCounts {
+            written_batches: self.written_batches + other.written_batches,
+            dropped_batches: self.dropped_batches + other.dropped_batches,
+            spilled_batches: self.spilled_batches + other.spilled_batches,
+            dropped_epochs: self.dropped_epochs + other.dropped_epochs,
+            reconnects: self.reconnects + other.reconnects,
+        }
+    }
+}
+
+/// Work for the writer thread
+enum Batch {
+    /// A serialized event
+    Bytes(Vec<u8>),
+    /// A file of serialized events and their number,
+    /// to be removed once it has been sent
+    Spilled(PathBuf, u64),
+}
+
+/// Structure and progress events that are held back until there's room in the queue,
+/// for `OverflowPolicy::DropEpochs`
+enum Pending {
+    /// A serialized event
+    Bytes(Vec<u8>),
+    /// Coalesced progress changes, and their serialized event
+    Progress(Vec<(Pair<u64, Duration>, i64)>, Vec<u8>),
+}
+
+/// An open spill file
+struct Spill {
+    path: PathBuf,
+    file: BufWriter<File>,
+    batches: u64,
+}
+
+/// Writes events to `W` on a background thread.
+pub struct BackgroundWriter {
+    sender: Option<SyncSender<Batch>>,
+    thread: Option<JoinHandle<()>>,
+    overflow: OverflowPolicy,
+    stats: Arc<WriterStats>,
+    /// Epoch whose remaining events are dropped, for `OverflowPolicy::DropEpochs`
+    dropping: Option<u64>,
+    /// Spill file events are currently written to, for `OverflowPolicy::Spill`
+    spill: Option<Spill>,
+    /// Events that have to be sent before any other, for `OverflowPolicy::DropEpochs`
+    pending: VecDeque<Pending>,
+    /// Name prefix for spill files
+    name: String,
+    spill_count: u64,
+}
+
+impl BackgroundWriter {
+    /// Spawns a writer thread for `writer`. `name` identifies the writer in logs and spill files.
+    pub fn new<W: Write + Send + 'static>(mut writer: W, name: String, config: BackgroundConfig) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel::<Batch>(config.capacity);
+        let stats: Arc<WriterStats> = Default::default();
+
+        let thread_stats = Arc::clone(&stats);
+        let thread_name = name.clone();
+        let thread = std::thread::Builder::new()
+            .name(format!("snailtrail-{}", name))
+            .spawn(move || {
+                for batch in receiver {
+                    let result = match batch {
+                        Batch::Bytes(bytes) => writer.write_all(&bytes).map(|_| 1),
+                        Batch::Spilled(path, batches) => File::open(&path)
+                            .and_then(|file| io::copy(&mut BufReader::new(file), &mut writer))
+                            .and_then(|_| fs::remove_file(&path))
+                            .map(|_| batches),
+                    };
+
+                    match result.and_then(|batches| writer.flush().map(|_| batches)) {
+                        Ok(batches) => { thread_stats.written_batches.fetch_add(batches, Ordering::Relaxed); }
+                        Err(e) => {
+                            // dropping the receiver makes every further batch count as dropped
+                            error!("{}: background writer failed: {}", thread_name, e);
+                            return;
+                        }
+                    }
+                }
+            })?;
+
+        if let OverflowPolicy::Spill(dir) = &config.overflow {
+            fs::create_dir_all(dir)?;
+        }
+
+        Ok(BackgroundWriter {
+            sender: Some(sender),
+            thread: Some(thread),
+            overflow: config.overflow,
+            stats,
+            dropping: None,
+            spill: None,
+            pending: VecDeque::new(),
+            name,
+            spill_count: 0,
+        })
+    }
+
+    /// Returns the writer's counters.
+    pub fn stats(&self) -> Arc<WriterStats> {
+        Arc::clone(&self.stats)
+    }
+
+    /// Queues `batch`, waiting for room in the queue.
+    fn send_blocking(&mut self, batch: Batch) {
+        let sender = self.sender.as_ref().expect("writer already closed");
+        if let Err(mpsc::SendError(batch)) = sender.send(batch) {
+            let batches = if let Batch::Spilled(_, batches) = batch { batches } else { 1 };
+            self.stats.dropped_batches.fetch_add(batches, Ordering::Relaxed);
+        }
+    }
+
+    /// Queues held back events in order, as long as there's room in the queue
+    /// (or `force`d to wait for it). Returns whether all of them were queued.
+    fn flush_pending(&mut self, force: bool) -> bool {
+        while let Some(pending) = self.pending.pop_front() {
+            let (changes, bytes) = match pending {
+                Pending::Bytes(bytes) => (None, bytes),
+                Pending::Progress(changes, bytes) => (Some(changes), bytes),
+            };
+
+            if force {
+                self.send_blocking(Batch::Bytes(bytes));
+                continue;
+            }
+
+            match self.sender.as_ref().expect("writer already closed").try_send(Batch::Bytes(bytes)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(Batch::Bytes(bytes))) => {
+                    self.pending.push_front(match changes {
+                        Some(changes) => Pending::Progress(changes, bytes),
+                        None => Pending::Bytes(bytes),
+                    });
+                    return false;
+                }
+                Err(_) => { self.stats.dropped_batches.fetch_add(1, Ordering::Relaxed); }
+            }
+        }
+
+        true
+    }
+
+    /// Hands the current spill file to the writer thread, if there's room in the queue
+    /// (or `force`d to wait for it).
+    fn close_spill(&mut self, force: bool) {
+        if let Some(mut spill) = self.spill.take() {
+            if let Err(e) = spill.file.flush() {
+                error!("{}: couldn't write spill file {}: {}", self.name, spill.path.display(), e);
+            }
+
+            let batch = Batch::Spilled(spill.path.clone(), spill.batches);
+            if force {
+                self.send_blocking(batch);
+            } else {
+                match self.sender.as_ref().expect("writer already closed").try_send(batch) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => self.spill = Some(spill),
+                    Err(TrySendError::Disconnected(_)) => {
+                        self.stats.dropped_batches.fetch_add(spill.batches, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Appends `bytes` to the current spill file, opening a new one if necessary.
+    fn spill_bytes(&mut self, dir: PathBuf, bytes: &[u8]) -> io::Result<()> {
+        if self.spill.is_none() {
+            let path = dir.join(format!("{}-{}.spill", self.name, self.spill_count));
+            self.spill_count += 1;
+            self.spill = Some(Spill { file: BufWriter::new(File::create(&path)?), path, batches: 0 });
+        }
+
+        let spill = self.spill.as_mut().expect("no spill file");
+        spill.file.write_all(bytes)?;
+        spill.batches += 1;
+        Ok(())
+    }
+
+    /// Holds back a structure or progress event that doesn't fit into the queue.
+    /// Progress `changes` are coalesced with directly preceding pending progress.
+    fn hold_back<D: Abomonation>(&mut self, bytes: Vec<u8>, changes: Option<Vec<(Pair<u64, Duration>, i64)>>) {
+        let changes = match changes {
+            Some(changes) => changes,
+            None => {
+                self.pending.push_back(Pending::Bytes(bytes));
+                return;
+            }
+        };
+
+        if let Some(Pending::Progress(pending, pending_bytes)) = self.pending.back_mut() {
+            pending.extend(changes);
+            consolidate(pending);
+            pending_bytes.clear();
+            let event: Event<Pair<u64, Duration>, D> = Event::Progress(pending.clone());
+            unsafe { abomonation::encode(&event, pending_bytes).expect("event abomonation failed"); }
+            return;
+        }
+
+        self.pending.push_back(Pending::Progress(changes, bytes));
+    }
+}
+
+/// Sums up the changes per time, dropping those that cancel out.
+fn consolidate(changes: &mut Vec<(Pair<u64, Duration>, i64)>) {
+    changes.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let mut consolidated: Vec<(Pair<u64, Duration>, i64)> = Vec::with_capacity(changes.len());
+    for (time, diff) in changes.drain(..) {
+        match consolidated.last_mut() {
+            Some((last, sum)) if *last == time => *sum += diff,
+            _ => consolidated.push((time, diff)),
+        }
+    }
+    consolidated.retain(|(_, diff)| *diff != 0);
+    *changes = consolidated;
+}
+
+impl<D: Abomonation> EventPusher<Pair<u64, Duration>, D> for BackgroundWriter {
+    fn push(&mut self, event: Event<Pair<u64, Duration>, D>) {
+        // structure & progress are never dropped
+        let epoch = match &event {
+            Event::Messages(time, _) if time.first > 0 => Some(time.first),
+            _ => None,
+        };
+
+        let mut bytes = Vec::new();
+        unsafe { abomonation::encode(&event, &mut bytes).expect("event abomonation failed"); }
+
+        match self.overflow.clone() {
+            OverflowPolicy::Block => self.send_blocking(Batch::Bytes(bytes)),
+            OverflowPolicy::DropEpochs => {
+                // held back events have to be sent first
+                let flushed = self.flush_pending(false);
+
+                let epoch = if let Some(epoch) = epoch {
+                    epoch
+                } else {
+                    let changes = if let Event::Progress(changes) = event { Some(changes) } else { None };
+                    if !flushed {
+                        self.hold_back::<D>(bytes, changes);
+                        return;
+                    }
+                    match self.sender.as_ref().expect("writer already closed").try_send(Batch::Bytes(bytes)) {
+                        Ok(()) => {}
+                        Err(TrySendError::Full(Batch::Bytes(bytes))) => self.hold_back::<D>(bytes, changes),
+                        Err(_) => { self.stats.dropped_batches.fetch_add(1, Ordering::Relaxed); }
+                    }
+                    return;
+                };
+
+                if self.dropping == Some(epoch) {
+                    self.stats.dropped_batches.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+
+                // data can't overtake held back events
+                let full = if flushed {
+                    match self.sender.as_ref().expect("writer already closed").try_send(Batch::Bytes(bytes)) {
+                        Ok(()) => return,
+                        Err(TrySendError::Full(_)) => true,
+                        Err(TrySendError::Disconnected(_)) => false,
+                    }
+                } else {
+                    true
+                };
+
+                if full {
+                    warn!("{}: queue full, dropping the rest of epoch {}", self.name, epoch);
+                    self.dropping = Some(epoch);
+                    self.stats.dropped_epochs.fetch_add(1, Ordering::Relaxed);
+                }
+                self.stats.dropped_batches.fetch_add(1, Ordering::Relaxed);
+            }
+            OverflowPolicy::Spill(dir) => {
+                // keep the order: once spilling, everything goes through the spill file
+                // until it can be handed to the writer thread
+                self.close_spill(false);
+
+                let bytes = if self.spill.is_none() {
+                    match self.sender.as_ref().expect("writer already closed").try_send(Batch::Bytes(bytes)) {
+                        Ok(()) => return,
+                        Err(TrySendError::Full(Batch::Bytes(bytes))) => bytes,
+                        Err(_) => {
+                            self.stats.dropped_batches.fetch_add(1, Ordering::Relaxed);
+                            return;
+                        }
+                    }
+                } else {
+                    bytes
+                };
+
+                match self.spill_bytes(dir, &bytes) {
+                    Ok(()) => { self.stats.spilled_batches.fetch_add(1, Ordering::Relaxed); }
+                    Err(e) => {
+                        error!("{}: couldn't spill: {}", self.name, e);
+                        self.stats.dropped_batches.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for BackgroundWriter {
+    fn drop(&mut self) {
+        self.flush_pending(true);
+        self.close_spill(true);
+
+        // closing the queue stops the writer thread once it has been drained
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("{}: background writer panicked", self.name);
+            }
+        }
+
+        info!("{}: background writer wrapping up: {:?}", self.name, self.stats.counts());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::sync::mpsc::Receiver;
+
+    type TestEvent = Event<Pair<u64, Duration>, u64>;
+
+    /// Writes to a shared buffer, but stalls on its first write until `release` is dropped.
+    struct Stalled {
+        release: Option<Receiver<()>>,
+        out: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Write for Stalled {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if let Some(release) = self.release.take() {
+                let _ = release.recv();
+            }
+            self.out.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn time(epoch: u64) -> Pair<u64, Duration> {
+        Pair::new(epoch, Default::default())
+    }
+
+    /// The dataflow structure, followed by progress and data of 49 epochs
+    fn events() -> Vec<TestEvent> {
+        let mut events = vec![Event::Messages(time(0), vec![0])];
+        for epoch in 1 .. 50 {
+            events.push(Event::Progress(vec![(time(epoch), 1), (time(epoch - 1), -1)]));
+            events.push(Event::Messages(time(epoch), vec![epoch]));
+        }
+        events
+    }
+
+    /// Pushes `events()` while the writer thread is stalled, so that the queue is full.
+    /// Fails if pushing blocks. Returns the events written once the writer is released.
+    fn push_into_full_queue(overflow: OverflowPolicy) -> (Vec<TestEvent>, WriterCounts) {
+        let (release, stalled) = mpsc::channel();
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let writer = Stalled { release: Some(stalled), out: Arc::clone(&out) };
+        let mut background = BackgroundWriter::new(writer, "test".to_string(), BackgroundConfig { capacity: 1, overflow }).unwrap();
+        let stats = background.stats();
+
+        let (done, pushed) = mpsc::channel();
+        let pusher = std::thread::spawn(move || {
+            for event in events() {
+                background.push(event);
+            }
+            done.send(()).unwrap();
+            background
+        });
+        let result = pushed.recv_timeout(Duration::from_secs(10));
+
+        drop(release);
+        drop(pusher.join().unwrap());
+        assert!(result.is_ok(), "push blocked on a full queue");
+
+        let mut bytes = out.lock().unwrap().clone();
+        let mut written = Vec::new();
+        let mut remaining: &mut [u8] = &mut bytes;
+        while !remaining.is_empty() {
+            let (event, rest) = unsafe { abomonation::decode::<TestEvent>(remaining) }.expect("truncated event");
+            written.push(event.clone());
+            remaining = rest;
+        }
+
+        (written, stats.counts())
+    }
+
+    #[test]
+    fn drop_epochs_without_blocking() {
+        let (written, counts) = push_into_full_queue(OverflowPolicy::DropEpochs);
+
+        assert_eq!(written[0], Event::Messages(time(0), vec![0]));
+        assert_eq!(counts.written_batches, written.len() as u64);
+        assert!(counts.dropped_epochs > 0);
+
+        // held back progress is coalesced, but adds up to the same frontier
+        let mut changes = written.iter()
+            .flat_map(|x| if let Event::Progress(changes) = x { changes.clone() } else { Vec::new() })
+            .collect::<Vec<_>>();
+        consolidate(&mut changes);
+        assert_eq!(changes, vec![(time(0), -1), (time(49), 1)]);
+
+        // data that was written is complete and in order
+        let data = written.iter()
+            .flat_map(|x| match x { Event::Messages(time, data) if time.first > 0 => data.clone(), _ => Vec::new() })
+            .collect::<Vec<_>>();
+        assert!(data.windows(2).all(|x| x[0] < x[1]));
+        assert_eq!(data.len() as u64 + counts.dropped_batches, 49);
+    }
+
+    #[test]
+    fn spill_without_blocking() {
+        let dir = std::env::temp_dir().join(format!("st2-spill-test-{}", std::process::id()));
+        let (written, counts) = push_into_full_queue(OverflowPolicy::Spill(dir.clone()));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(written, events());
+        assert!(counts.spilled_batches > 0);
+        assert_eq!(counts.dropped_batches, 0);
+    }
+}
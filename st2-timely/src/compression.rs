@@ -0,0 +1,159 @@
+//! Streaming compression of `*.dump` files.
+//!
+//! Dumps are written as `{idx}.dump.zst` or `{idx}.dump.lz4` if a
+//! `Compression` is configured (cf. `AdapterConfig::compression`).
+//! `open_dump` reads any dump, detecting compression by file extension
+//! or, failing that, by the magic bytes of zstd and lz4 frames.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+
+/// zstd frame magic number (little endian)
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+/// lz4 frame magic number (little endian)
+const LZ4_MAGIC: [u8; 4] = [0x04, 0x22, 0x4d, 0x18];
+
+/// Compression algorithm for dumps
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// zstd at the given level (1 - 21, 3 is zstd's default)
+    Zstd(i32),
+    /// lz4, trading compression ratio for speed
+    Lz4,
+}
+
+impl Compression {
+    /// File extension appended to `.dump`
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::Zstd(_) => "zst",
+            Compression::Lz4 => "lz4",
+        }
+    }
+
+    /// Wraps `writer` to compress everything written to it.
+    /// The compressed stream is completed once the returned writer is dropped.
+    pub fn writer<W: Write + Send + 'static>(&self, writer: W) -> io::Result<Box<dyn Write + Send>> {
+        match self {
+            Compression::Zstd(level) => Ok(Box::new(zstd::stream::write::Encoder::new(writer, *level)?.auto_finish())),
+            Compression::Lz4 => Ok(Box::new(Lz4Writer(Some(lz4::EncoderBuilder::new().build(writer)?)))),
+        }
+    }
+
+    /// Detects the compression of a dump from its extension or magic bytes.
+    /// The zstd level is irrelevant for reading.
+    fn detect<R: BufRead>(path: &Path, reader: &mut R) -> io::Result<Option<Self>> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("zst") => return Ok(Some(Compression::Zstd(0))),
+            Some("lz4") => return Ok(Some(Compression::Lz4)),
+            _ => {}
+        }
+
+        let magic = reader.fill_buf()?;
+        if magic.starts_with(&ZSTD_MAGIC) {
+            Ok(Some(Compression::Zstd(0)))
+        } else if magic.starts_with(&LZ4_MAGIC) {
+            Ok(Some(Compression::Lz4))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Opens the dump at `path`, transparently decompressing it if necessary.
+pub fn open_dump(path: &Path) -> io::Result<Box<dyn Read>> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    match Compression::detect(path, &mut reader)? {
+        Some(Compression::Zstd(_)) => Ok(Box::new(zstd::stream::read::Decoder::with_buffer(reader)?)),
+        Some(Compression::Lz4) => Ok(Box::new(lz4::Decoder::new(reader)?)),
+        None => Ok(Box::new(reader)),
+    }
+}
+
+/// lz4 encoder that completes its frame when dropped
+struct Lz4Writer<W: Write>(Option<lz4::Encoder<W>>);
+
+impl<W: Write> Write for Lz4Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.as_mut().expect("lz4 writer already finished").write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.as_mut().expect("lz4 writer already finished").flush()
+    }
+}
+
+impl<W: Write> Drop for Lz4Writer<W> {
+    fn drop(&mut self) {
+        if let Some(encoder) = self.0.take() {
+            let (mut writer, result) = encoder.finish();
+            if let Err(e) = result.and_then(|_| writer.flush()) {
+                error!("couldn't complete lz4 dump: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn dump() -> Vec<u8> {
+        (0 .. 100_000u32).flat_map(|x| (x % 251).to_le_bytes().to_vec()).collect()
+    }
+
+    /// Writes `data` to `name` in the temp dir, compressed with `compression`.
+    fn write_dump(name: &str, compression: Option<Compression>, data: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("st2-compression-{}-{}", std::process::id(), name));
+        let file = File::create(&path).unwrap();
+        let mut writer: Box<dyn Write + Send> = match compression {
+            Some(compression) => compression.writer(file).unwrap(),
+            None => Box::new(file),
+        };
+        writer.write_all(data).unwrap();
+        drop(writer);
+        path
+    }
+
+    fn read_dump(path: &Path) -> Vec<u8> {
+        let mut data = Vec::new();
+        open_dump(path).unwrap().read_to_end(&mut data).unwrap();
+        std::fs::remove_file(path).unwrap();
+        data
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = dump();
+        for (name, compression) in vec![
+            ("0.dump.zst", Some(Compression::Zstd(3))),
+            ("0.dump.lz4", Some(Compression::Lz4)),
+            ("0.dump", None),
+        ] {
+            let path = write_dump(name, compression, &data);
+            if compression.is_some() {
+                assert!(std::fs::metadata(&path).unwrap().len() < data.len() as u64, "{} isn't compressed", name);
+            }
+            assert_eq!(read_dump(&path), data, "{}", name);
+        }
+    }
+
+    #[test]
+    fn detect_by_magic_bytes() {
+        let data = dump();
+        for (name, compression) in vec![("zstd.dump", Compression::Zstd(1)), ("lz4.dump", Compression::Lz4)] {
+            let path = write_dump(name, Some(compression), &data);
+            assert_eq!(read_dump(&path), data, "{}", name);
+        }
+
+        let detect = |bytes: &[u8]| Compression::detect(Path::new("0.dump"), &mut &bytes[..]).unwrap();
+        assert_eq!(detect(&[0x28, 0xb5, 0x2f, 0xfd, 0]), Some(Compression::Zstd(0)));
+        assert_eq!(detect(&[0x04, 0x22, 0x4d, 0x18, 0]), Some(Compression::Lz4));
+        assert_eq!(detect(b"ST2DUMP\0"), None);
+        assert_eq!(detect(&[]), None);
+    }
+}
@@ -0,0 +1,609 @@
+//! Configuration of the `PAGLogger` that is attached to a source computation.
+//!
+//! Build an `AdapterConfig` explicitly, or load it from environment variables
+//! (`from_env`) or a TOML file (`from_toml_file`), and pass it to
+//! `Adapter::attach_configured`.
+
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::connect::EpochTicking;
+use crate::background::{BackgroundConfig, OverflowPolicy};
+use crate::reconnect::ReconnectPolicy;
+use crate::compression::Compression;
+use crate::segments::Segmenting;
+use crate::sampling::EpochSampling;
+
+
+/// Creates the writer with the given index for `Transport::Custom`.
+pub type WriterFactory = Box<dyn FnMut(usize) -> io::Result<Box<dyn Write>>>;
+
+/// Where a `PAGLogger` writes its events to
+pub enum Transport {
+    /// `{dir}/{writer index}.dump` files, for later (offline) replay.
+    /// Compressed dumps get an additional extension (cf. `AdapterConfig::compression`),
+    /// segmented dumps are indexed (cf. `AdapterConfig::segmenting`).
+    Files(PathBuf),
+    /// A TCP connection per writer to `<IP>:<Port>`, for online analysis
+    Tcp(String),
+    /// Custom writers, one per writer index
+    Custom(WriterFactory),
+}
+
+impl std::fmt::Debug for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Transport::Files(dir) => write!(f, "Files({:?})", dir),
+            Transport::Tcp(addr) => write!(f, "Tcp({:?})", addr),
+            Transport::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+/// Errors while configuring or attaching a `PAGLogger`
+#[derive(Debug)]
+pub enum AdapterError {
+    /// A writer couldn't be created
+    Io(io::Error),
+    /// An invalid configuration value
+    Config(String),
+}
+
+impl std::fmt::Display for AdapterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AdapterError::Io(e) => write!(f, "io error: {}", e),
+            AdapterError::Config(e) => write!(f, "invalid configuration: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AdapterError {}
+
+impl From<io::Error> for AdapterError {
+    fn from(error: io::Error) -> Self {
+        AdapterError::Io(error)
+    }
+}
+
+/// Configuration of a `PAGLogger`
+#[derive(Debug)]
+pub struct AdapterConfig {
+    /// Where events are written to
+    pub transport: Transport,
+    /// Number of writers per source worker. Epochs are spread across them,
+    /// so that more ST2 workers can process a source worker's events.
+    pub load_balance_factor: usize,
+    /// Maximum number of events per batch within an epoch
+    pub max_fuel: usize,
+    /// How epochs are derived
+    pub epoch_ticking: EpochTicking,
+    /// Write on background threads with bounded queues, so that logging
+    /// doesn't block the worker (only for TCP and file transports)
+    pub background: Option<BackgroundConfig>,
+    /// Retry connecting if the TCP connection can't be established or breaks,
+    /// instead of failing (only for TCP transports, not in the background)
+    pub reconnect: Option<ReconnectPolicy>,
+    /// Compress dumps while writing them (only for file transports)
+    pub compression: Option<Compression>,
+    /// Split dumps into indexed segments (only for file transports, not in the background)
+    pub segmenting: Option<Segmenting>,
+    /// Which epochs are recorded
+    pub sampling: EpochSampling,
+    /// Also log data messages between operators on the same worker
+    pub local_messages: bool,
+}
+
+impl Default for AdapterConfig {
+    fn default() -> Self {
+        AdapterConfig {
+            transport: Transport::Files(PathBuf::from(".")),
+            load_balance_factor: 1,
+            max_fuel: 4096,
+            epoch_ticking: EpochTicking::Manual,
+            background: None,
+            reconnect: None,
+            compression: None,
+            segmenting: None,
+            sampling: EpochSampling::All,
+            local_messages: false,
+        }
+    }
+}
+
+/// Keys of a TOML configuration file, all optional
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    addr: Option<String>,
+    dump_dir: Option<PathBuf>,
+    load_balance_factor: Option<usize>,
+    max_fuel: Option<usize>,
+    epoch_ticking: Option<String>,
+    epoch_interval_ms: Option<u64>,
+    queue_capacity: Option<usize>,
+    overflow: Option<String>,
+    spill_dir: Option<PathBuf>,
+    reconnect: Option<bool>,
+    reconnect_backoff_ms: Option<u64>,
+    reconnect_max_backoff_ms: Option<u64>,
+    compression: Option<String>,
+    compression_level: Option<i32>,
+    segment_epochs: Option<u64>,
+    segment_bytes: Option<u64>,
+    sample_every: Option<u64>,
+    sample_fraction: Option<f64>,
+    local_messages: Option<bool>,
+}
+
+impl AdapterConfig {
+    /// Creates the default configuration: dumps to the working directory,
+    /// a single writer, a fuel of 4096 and manual epoch ticking.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Writes `{dir}/{writer index}.dump` files.
+    pub fn dump_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.transport = Transport::Files(dir.into());
+        self
+    }
+
+    /// Streams events to ST2 listening on `addr` (`<IP>:<Port>`).
+    pub fn tcp<A: Into<String>>(mut self, addr: A) -> Self {
+        self.transport = Transport::Tcp(addr.into());
+        self
+    }
+
+    /// Writes events to custom writers, created per writer index.
+    pub fn custom_writer<F: FnMut(usize) -> io::Result<Box<dyn Write>> + 'static>(mut self, factory: F) -> Self {
+        self.transport = Transport::Custom(Box::new(factory));
+        self
+    }
+
+    /// Sets the number of writers per source worker.
+    pub fn load_balance_factor(mut self, load_balance_factor: usize) -> Self {
+        self.load_balance_factor = load_balance_factor;
+        self
+    }
+
+    /// Sets the maximum number of events per batch within an epoch.
+    pub fn max_fuel(mut self, max_fuel: usize) -> Self {
+        self.max_fuel = max_fuel;
+        self
+    }
+
+    /// Sets how epochs are derived.
+    pub fn epoch_ticking(mut self, epoch_ticking: EpochTicking) -> Self {
+        self.epoch_ticking = epoch_ticking;
+        self
+    }
+
+    /// Writes on background threads, queueing up to `capacity` batches per writer.
+    /// `overflow` decides what happens if a queue is full.
+    pub fn background(mut self, capacity: usize, overflow: OverflowPolicy) -> Self {
+        self.background = Some(BackgroundConfig { capacity, overflow });
+        self
+    }
+
+    /// Retries connecting according to `policy` if the TCP connection can't be
+    /// established or breaks. Logging resumes at the next epoch boundary.
+    pub fn reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect = Some(policy);
+        self
+    }
+
+    /// Compresses dumps with `compression`, writing `{idx}.dump.zst` or `{idx}.dump.lz4` files.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Splits dumps into `{idx}.{segment}.dump` files, indexed by `{idx}.index`,
+    /// so that they can be replayed from an arbitrary epoch (cf. `segments`).
+    pub fn segmenting(mut self, segmenting: Segmenting) -> Self {
+        self.segmenting = Some(segmenting);
+        self
+    }
+
+    /// Only records the epochs selected by `sampling`, discarding all others.
+    pub fn sampling(mut self, sampling: EpochSampling) -> Self {
+        self.sampling = sampling;
+        self
+    }
+
+    /// Also logs data messages that stay on a worker, i.e., records that operators
+    /// hand over to each other along local channels. By default, they're only
+    /// counted towards the receiving operator's schedule. This considerably
+    /// increases the number of logged events.
+    pub fn local_messages(mut self, local_messages: bool) -> Self {
+        self.local_messages = local_messages;
+        self
+    }
+
+    /// Checks the configuration for invalid values.
+    pub fn validate(&self) -> Result<(), AdapterError> {
+        if self.load_balance_factor == 0 {
+            return Err(AdapterError::Config("load balance factor has to be at least 1".to_string()));
+        }
+        if self.max_fuel == 0 {
+            return Err(AdapterError::Config("max fuel has to be at least 1".to_string()));
+        }
+        if let EpochTicking::Interval(interval) = self.epoch_ticking {
+            if interval == Duration::from_secs(0) {
+                return Err(AdapterError::Config("epoch interval has to be positive".to_string()));
+            }
+        }
+        if let Some(background) = &self.background {
+            if background.capacity == 0 {
+                return Err(AdapterError::Config("queue capacity has to be at least 1".to_string()));
+            }
+            if let Transport::Custom(_) = self.transport {
+                return Err(AdapterError::Config("background writers require a TCP or file transport".to_string()));
+            }
+        }
+        if let Some(reconnect) = &self.reconnect {
+            match self.transport {
+                Transport::Tcp(_) => {}
+                _ => return Err(AdapterError::Config("reconnecting requires a TCP transport".to_string())),
+            }
+            if self.background.is_some() {
+                return Err(AdapterError::Config("reconnecting writers can't write in the background".to_string()));
+            }
+            if reconnect.initial_backoff == Duration::from_secs(0) || reconnect.timeout == Duration::from_secs(0) {
+                return Err(AdapterError::Config("reconnect backoff and timeout have to be positive".to_string()));
+            }
+            if reconnect.max_backoff < reconnect.initial_backoff {
+                return Err(AdapterError::Config("max reconnect backoff is smaller than the initial backoff".to_string()));
+            }
+        }
+        if let Some(compression) = self.compression {
+            match self.transport {
+                Transport::Files(_) => {}
+                _ => return Err(AdapterError::Config("compression requires a file transport".to_string())),
+            }
+            if let Compression::Zstd(level) = compression {
+                if level < 1 || level > 21 {
+                    return Err(AdapterError::Config("zstd level has to be between 1 and 21".to_string()));
+                }
+            }
+        }
+        if let Some(segmenting) = self.segmenting {
+            match self.transport {
+                Transport::Files(_) => {}
+                _ => return Err(AdapterError::Config("segmenting requires a file transport".to_string())),
+            }
+            if self.background.is_some() {
+                return Err(AdapterError::Config("segmented dumps can't be written in the background".to_string()));
+            }
+            if segmenting.epochs.is_none() && segmenting.bytes.is_none() {
+                return Err(AdapterError::Config("segmenting requires a number of epochs or bytes".to_string()));
+            }
+            if segmenting.epochs == Some(0) || segmenting.bytes == Some(0) {
+                return Err(AdapterError::Config("segments have to span at least one epoch or byte".to_string()));
+            }
+        }
+        match self.sampling {
+            EpochSampling::EveryNth(0) => return Err(AdapterError::Config("sampling every Nth epoch requires N to be at least 1".to_string())),
+            EpochSampling::Fraction(fraction) if !(fraction > 0.0 && fraction <= 1.0) =>
+                return Err(AdapterError::Config("sampled fraction has to be between 0 and 1".to_string())),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Loads the configuration from environment variables, using defaults for unset ones:
+    /// - `SNAILTRAIL_ADDR=<IP>:<Port>` to log online via TCP
+    /// - `SNAILTRAIL_DUMP_DIR` to log to files in that directory
+    /// - `SNAILTRAIL_LOAD_BALANCE_FACTOR`, `SNAILTRAIL_MAX_FUEL`
+    /// - `SNAILTRAIL_EPOCH_TICKING` (`manual`, `probe` or `interval`) and
+    ///   `SNAILTRAIL_EPOCH_INTERVAL_MS`
+    /// - `SNAILTRAIL_QUEUE_CAPACITY` to write in the background,
+    ///   `SNAILTRAIL_OVERFLOW` (`block`, `drop_epochs` or `spill`) and `SNAILTRAIL_SPILL_DIR`
+    /// - `SNAILTRAIL_RECONNECT=true` to reconnect, `SNAILTRAIL_RECONNECT_BACKOFF_MS`
+    ///   and `SNAILTRAIL_RECONNECT_MAX_BACKOFF_MS`
+    /// - `SNAILTRAIL_COMPRESSION` (`zstd` or `lz4`) and `SNAILTRAIL_COMPRESSION_LEVEL` (for zstd)
+    /// - `SNAILTRAIL_SEGMENT_EPOCHS` and `SNAILTRAIL_SEGMENT_BYTES` to segment dumps
+    /// - `SNAILTRAIL_SAMPLE_EVERY` or `SNAILTRAIL_SAMPLE_FRACTION` to sample epochs
+    /// - `SNAILTRAIL_LOCAL_MESSAGES=true` to log worker-local data messages
+    pub fn from_env() -> Result<Self, AdapterError> {
+        let var = |name: &str| std::env::var(name).ok();
+
+        let file_config = FileConfig {
+            addr: var("SNAILTRAIL_ADDR"),
+            dump_dir: var("SNAILTRAIL_DUMP_DIR").map(PathBuf::from),
+            load_balance_factor: var("SNAILTRAIL_LOAD_BALANCE_FACTOR").map(|x| parse("SNAILTRAIL_LOAD_BALANCE_FACTOR", &x)).transpose()?,
+            max_fuel: var("SNAILTRAIL_MAX_FUEL").map(|x| parse("SNAILTRAIL_MAX_FUEL", &x)).transpose()?,
+            epoch_ticking: var("SNAILTRAIL_EPOCH_TICKING"),
+            epoch_interval_ms: var("SNAILTRAIL_EPOCH_INTERVAL_MS").map(|x| parse("SNAILTRAIL_EPOCH_INTERVAL_MS", &x)).transpose()?,
+            queue_capacity: var("SNAILTRAIL_QUEUE_CAPACITY").map(|x| parse("SNAILTRAIL_QUEUE_CAPACITY", &x)).transpose()?,
+            overflow: var("SNAILTRAIL_OVERFLOW"),
+            spill_dir: var("SNAILTRAIL_SPILL_DIR").map(PathBuf::from),
+            reconnect: var("SNAILTRAIL_RECONNECT").map(|x| parse("SNAILTRAIL_RECONNECT", &x)).transpose()?,
+            reconnect_backoff_ms: var("SNAILTRAIL_RECONNECT_BACKOFF_MS").map(|x| parse("SNAILTRAIL_RECONNECT_BACKOFF_MS", &x)).transpose()?,
+            reconnect_max_backoff_ms: var("SNAILTRAIL_RECONNECT_MAX_BACKOFF_MS").map(|x| parse("SNAILTRAIL_RECONNECT_MAX_BACKOFF_MS", &x)).transpose()?,
+            compression: var("SNAILTRAIL_COMPRESSION"),
+            compression_level: var("SNAILTRAIL_COMPRESSION_LEVEL").map(|x| parse("SNAILTRAIL_COMPRESSION_LEVEL", &x)).transpose()?,
+            segment_epochs: var("SNAILTRAIL_SEGMENT_EPOCHS").map(|x| parse("SNAILTRAIL_SEGMENT_EPOCHS", &x)).transpose()?,
+            segment_bytes: var("SNAILTRAIL_SEGMENT_BYTES").map(|x| parse("SNAILTRAIL_SEGMENT_BYTES", &x)).transpose()?,
+            sample_every: var("SNAILTRAIL_SAMPLE_EVERY").map(|x| parse("SNAILTRAIL_SAMPLE_EVERY", &x)).transpose()?,
+            sample_fraction: var("SNAILTRAIL_SAMPLE_FRACTION").map(|x| parse("SNAILTRAIL_SAMPLE_FRACTION", &x)).transpose()?,
+            local_messages: var("SNAILTRAIL_LOCAL_MESSAGES").map(|x| parse("SNAILTRAIL_LOCAL_MESSAGES", &x)).transpose()?,
+        };
+
+        Self::from_file_config(file_config)
+    }
+
+    /// Loads the configuration from a TOML file, using defaults for missing keys.
+    /// Keys are `addr`, `dump_dir`, `load_balance_factor`, `max_fuel`,
+    /// `epoch_ticking` (`"manual"`, `"probe"` or `"interval"`), `epoch_interval_ms`,
+    /// `queue_capacity`, `overflow` (`"block"`, `"drop_epochs"` or `"spill"`), `spill_dir`,
+    /// `reconnect` (a bool), `reconnect_backoff_ms`, `reconnect_max_backoff_ms`,
+    /// `compression` (`"zstd"` or `"lz4"`), `compression_level` (for zstd),
+    /// `segment_epochs`, `segment_bytes`, `sample_every`, `sample_fraction`
+    /// and `local_messages` (a bool).
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> Result<Self, AdapterError> {
+        Self::from_toml_str(&std::fs::read_to_string(path)?)
+    }
+
+    /// Loads the configuration from a TOML string, cf. `from_toml_file`.
+    pub fn from_toml_str(toml: &str) -> Result<Self, AdapterError> {
+        let file_config = toml::from_str(toml).map_err(|e| AdapterError::Config(e.to_string()))?;
+        Self::from_file_config(file_config)
+    }
+
+    fn from_file_config(file_config: FileConfig) -> Result<Self, AdapterError> {
+        let mut config = AdapterConfig::new();
+
+        match (file_config.addr, file_config.dump_dir) {
+            (Some(_), Some(_)) => return Err(AdapterError::Config("both an address and a dump directory are set".to_string())),
+            (Some(addr), None) => config = config.tcp(addr),
+            (None, Some(dir)) => config = config.dump_dir(dir),
+            (None, None) => {}
+        }
+
+        if let Some(load_balance_factor) = file_config.load_balance_factor {
+            config = config.load_balance_factor(load_balance_factor);
+        }
+        if let Some(max_fuel) = file_config.max_fuel {
+            config = config.max_fuel(max_fuel);
+        }
+
+        let interval = file_config.epoch_interval_ms.map(Duration::from_millis);
+        config.epoch_ticking = match (file_config.epoch_ticking.as_ref().map(|x| x.as_str()), interval) {
+            (None, None) | (Some("manual"), None) => EpochTicking::Manual,
+            (Some("probe"), None) => EpochTicking::Probe,
+            (None, Some(interval)) | (Some("interval"), Some(interval)) => EpochTicking::Interval(interval),
+            (Some("interval"), None) => return Err(AdapterError::Config("interval epoch ticking requires an epoch interval".to_string())),
+            (Some("manual"), Some(_)) | (Some("probe"), Some(_)) => return Err(AdapterError::Config("an epoch interval requires interval epoch ticking".to_string())),
+            (Some(x), _) => return Err(AdapterError::Config(format!("invalid epoch ticking: {}", x))),
+        };
+
+        let overflow = match (file_config.overflow.as_ref().map(|x| x.as_str()), file_config.spill_dir) {
+            (None, None) | (Some("block"), None) => OverflowPolicy::Block,
+            (Some("drop_epochs"), None) => OverflowPolicy::DropEpochs,
+            (None, Some(dir)) | (Some("spill"), Some(dir)) => OverflowPolicy::Spill(dir),
+            (Some("spill"), None) => return Err(AdapterError::Config("spilling requires a spill directory".to_string())),
+            (Some("block"), Some(_)) | (Some("drop_epochs"), Some(_)) => return Err(AdapterError::Config("a spill directory requires spilling".to_string())),
+            (Some(x), _) => return Err(AdapterError::Config(format!("invalid overflow policy: {}", x))),
+        };
+        match file_config.queue_capacity {
+            Some(capacity) => config = config.background(capacity, overflow),
+            None if overflow != OverflowPolicy::Block => return Err(AdapterError::Config("an overflow policy requires a queue capacity".to_string())),
+            None => {}
+        }
+
+        match (file_config.reconnect, file_config.reconnect_backoff_ms, file_config.reconnect_max_backoff_ms) {
+            (Some(true), backoff, max_backoff) => {
+                let mut policy = ReconnectPolicy::default();
+                if let Some(backoff) = backoff {
+                    policy.initial_backoff = Duration::from_millis(backoff);
+                }
+                if let Some(max_backoff) = max_backoff {
+                    policy.max_backoff = Duration::from_millis(max_backoff);
+                }
+                config = config.reconnect(policy);
+            }
+            (_, None, None) => {}
+            _ => return Err(AdapterError::Config("reconnect backoffs require reconnecting".to_string())),
+        }
+
+        match (file_config.compression.as_ref().map(|x| x.as_str()), file_config.compression_level) {
+            (None, None) => {}
+            (Some("zstd"), level) => config = config.compression(Compression::Zstd(level.unwrap_or(3))),
+            (Some("lz4"), None) => config = config.compression(Compression::Lz4),
+            (Some("lz4"), Some(_)) | (None, Some(_)) => return Err(AdapterError::Config("a compression level requires zstd compression".to_string())),
+            (Some(x), _) => return Err(AdapterError::Config(format!("invalid compression: {}", x))),
+        }
+
+        if file_config.segment_epochs.is_some() || file_config.segment_bytes.is_some() {
+            config = config.segmenting(Segmenting { epochs: file_config.segment_epochs, bytes: file_config.segment_bytes });
+        }
+
+        match (file_config.sample_every, file_config.sample_fraction) {
+            (None, None) => {}
+            (Some(n), None) => config = config.sampling(EpochSampling::EveryNth(n)),
+            (None, Some(fraction)) => config = config.sampling(EpochSampling::Fraction(fraction)),
+            (Some(_), Some(_)) => return Err(AdapterError::Config("both every Nth and a fraction of epochs are sampled".to_string())),
+        }
+
+        if let Some(local_messages) = file_config.local_messages {
+            config = config.local_messages(local_messages);
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, AdapterError>
+where T::Err: std::fmt::Display {
+    value.parse().map_err(|e| AdapterError::Config(format!("invalid {}: {}", name, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The message of a configuration error
+    fn invalid(result: Result<AdapterConfig, AdapterError>) -> String {
+        match result {
+            Err(AdapterError::Config(e)) => e,
+            other => panic!("expected a configuration error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn from_toml() {
+        let mut config = AdapterConfig::from_toml_str(r#"
+            dump_dir = "/tmp/dumps"
+            load_balance_factor = 2
+            max_fuel = 100
+            epoch_interval_ms = 50
+            queue_capacity = 16
+            overflow = "drop_epochs"
+            compression = "zstd"
+            compression_level = 9
+            sample_every = 4
+            local_messages = true
+        "#).unwrap();
+
+        match &config.transport {
+            Transport::Files(dir) => assert_eq!(dir, &PathBuf::from("/tmp/dumps")),
+            other => panic!("unexpected transport {:?}", other),
+        }
+        assert_eq!(config.load_balance_factor, 2);
+        assert_eq!(config.max_fuel, 100);
+        assert_eq!(config.epoch_ticking, EpochTicking::Interval(Duration::from_millis(50)));
+        assert_eq!(config.background, Some(BackgroundConfig { capacity: 16, overflow: OverflowPolicy::DropEpochs }));
+        assert_eq!(config.compression, Some(Compression::Zstd(9)));
+        assert_eq!(config.segmenting, None);
+        assert!(config.sampling.samples(8) && !config.sampling.samples(9));
+        assert!(config.local_messages);
+
+        let config = AdapterConfig::from_toml_str(r#"
+            addr = "127.0.0.1:8000"
+            epoch_ticking = "probe"
+            reconnect = true
+            reconnect_backoff_ms = 10
+        "#).unwrap();
+        match &config.transport {
+            Transport::Tcp(addr) => assert_eq!(addr, "127.0.0.1:8000"),
+            other => panic!("unexpected transport {:?}", other),
+        }
+        assert_eq!(config.epoch_ticking, EpochTicking::Probe);
+        assert_eq!(config.reconnect.map(|policy| policy.initial_backoff), Some(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn from_toml_file() {
+        let path = std::env::temp_dir().join(format!("st2-config-test-{}.toml", std::process::id()));
+        std::fs::write(&path, "segment_epochs = 10\nsample_fraction = 0.5\n").unwrap();
+        let config = AdapterConfig::from_toml_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(config.segmenting, Some(Segmenting { epochs: Some(10), bytes: None }));
+        match config.sampling {
+            EpochSampling::Fraction(fraction) => assert_eq!(fraction, 0.5),
+            other => panic!("unexpected sampling {:?}", other),
+        }
+
+        match AdapterConfig::from_toml_file(path) {
+            Err(AdapterError::Io(_)) => {}
+            other => panic!("expected an io error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reject_invalid_toml() {
+        assert!(invalid(AdapterConfig::from_toml_str("unknown = 1")).contains("unknown"));
+        assert!(invalid(AdapterConfig::from_toml_str("max_fuel = \"many\"")).contains("max_fuel"));
+
+        for (toml, error) in vec![
+            ("addr = \"127.0.0.1:8000\"\ndump_dir = \"x\"", "both an address and a dump directory are set"),
+            ("epoch_ticking = \"interval\"", "interval epoch ticking requires an epoch interval"),
+            ("epoch_ticking = \"probe\"\nepoch_interval_ms = 5", "an epoch interval requires interval epoch ticking"),
+            ("epoch_ticking = \"sometimes\"", "invalid epoch ticking: sometimes"),
+            ("overflow = \"spill\"\nqueue_capacity = 1", "spilling requires a spill directory"),
+            ("overflow = \"drop_epochs\"", "an overflow policy requires a queue capacity"),
+            ("reconnect_backoff_ms = 10", "reconnect backoffs require reconnecting"),
+            ("compression = \"lz4\"\ncompression_level = 3", "a compression level requires zstd compression"),
+            ("sample_every = 2\nsample_fraction = 0.5", "both every Nth and a fraction of epochs are sampled"),
+            // loaded configurations are validated
+            ("max_fuel = 0", "max fuel has to be at least 1"),
+        ] {
+            assert_eq!(invalid(AdapterConfig::from_toml_str(toml)), error);
+        }
+    }
+
+    #[test]
+    fn validate() {
+        assert!(AdapterConfig::new().validate().is_ok());
+
+        let policy = ReconnectPolicy { max_backoff: Duration::from_millis(1), ..Default::default() };
+        for (config, error) in vec![
+            (AdapterConfig::new().load_balance_factor(0), "load balance factor has to be at least 1"),
+            (AdapterConfig::new().epoch_ticking(EpochTicking::Interval(Duration::from_secs(0))), "epoch interval has to be positive"),
+            (AdapterConfig::new().background(0, OverflowPolicy::Block), "queue capacity has to be at least 1"),
+            (AdapterConfig::new().custom_writer(|_| Ok(Box::new(io::sink()) as Box<dyn Write>)).background(1, OverflowPolicy::Block),
+             "background writers require a TCP or file transport"),
+            (AdapterConfig::new().reconnect(ReconnectPolicy::default()), "reconnecting requires a TCP transport"),
+            (AdapterConfig::new().tcp("127.0.0.1:8000").reconnect(policy), "max reconnect backoff is smaller than the initial backoff"),
+            (AdapterConfig::new().tcp("127.0.0.1:8000").compression(Compression::Lz4), "compression requires a file transport"),
+            (AdapterConfig::new().compression(Compression::Zstd(22)), "zstd level has to be between 1 and 21"),
+            (AdapterConfig::new().segmenting(Segmenting::default()), "segmenting requires a number of epochs or bytes"),
+            (AdapterConfig::new().segmenting(Segmenting { epochs: Some(0), bytes: None }), "segments have to span at least one epoch or byte"),
+            (AdapterConfig::new().segmenting(Segmenting { epochs: Some(1), bytes: None }).background(1, OverflowPolicy::Block),
+             "segmented dumps can't be written in the background"),
+            (AdapterConfig::new().sampling(EpochSampling::EveryNth(0)), "sampling every Nth epoch requires N to be at least 1"),
+            (AdapterConfig::new().sampling(EpochSampling::Fraction(1.5)), "sampled fraction has to be between 0 and 1"),
+        ] {
+            match config.validate() {
+                Err(AdapterError::Config(e)) => assert_eq!(e, error),
+                other => panic!("expected {:?}, got {:?}", error, other),
+            }
+        }
+    }
+
+    // the only test that touches `SNAILTRAIL_*` variables, as tests run concurrently
+    #[test]
+    fn from_env() {
+        let vars = ["SNAILTRAIL_ADDR", "SNAILTRAIL_MAX_FUEL", "SNAILTRAIL_EPOCH_TICKING", "SNAILTRAIL_SAMPLE_EVERY"];
+
+        std::env::set_var("SNAILTRAIL_ADDR", "127.0.0.1:8000");
+        std::env::set_var("SNAILTRAIL_MAX_FUEL", "128");
+        std::env::set_var("SNAILTRAIL_EPOCH_TICKING", "probe");
+        std::env::set_var("SNAILTRAIL_SAMPLE_EVERY", "3");
+        let config = AdapterConfig::from_env();
+
+        std::env::set_var("SNAILTRAIL_MAX_FUEL", "lots");
+        let error = AdapterConfig::from_env();
+
+        for var in vars.iter() {
+            std::env::remove_var(var);
+        }
+        let default = AdapterConfig::from_env();
+
+        let mut config = config.unwrap();
+        match &config.transport {
+            Transport::Tcp(addr) => assert_eq!(addr, "127.0.0.1:8000"),
+            other => panic!("unexpected transport {:?}", other),
+        }
+        assert_eq!(config.max_fuel, 128);
+        assert_eq!(config.epoch_ticking, EpochTicking::Probe);
+        assert!(config.sampling.samples(6) && !config.sampling.samples(7));
+
+        assert!(invalid(error).starts_with("invalid SNAILTRAIL_MAX_FUEL"));
+
+        let default = default.unwrap();
+        match &default.transport {
+            Transport::Files(dir) => assert_eq!(dir, &PathBuf::from(".")),
+            other => panic!("unexpected transport {:?}", other),
+        }
+        assert_eq!(default.max_fuel, 4096);
+    }
+}
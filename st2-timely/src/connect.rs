@@ -1,41 +1,103 @@
 //! Helpers to log traces for PAG construction from timely & differential.
 //!
-//! To log a computation, see `Adapter`'s docstring. If `SNAILTRAIL_ADDR=<IP>:<Port>`
-//! is set as env variable, the computation will be logged online via TCP.
+//! To log a computation, see `Adapter`'s docstring. It is configured with
+//! an `AdapterConfig` (cf. `config`), e.g. to log online via TCP.
 //!
 //! Replay a log trace with `replay_into` or `replay_throttled`.
 
 use std::{
-    error::Error,
     fs::File,
-    io::Write,
+    io::{self, Read, Write},
     net::TcpStream,
-    path::Path,
     time::Duration,
 };
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::rc::Rc;
+use std::sync::Arc;
 use std::cell::RefCell;
 
 use timely::{
     communication::allocator::Generic,
     dataflow::operators::capture::{event::EventPusher, Event, EventReader, EventWriter},
+    dataflow::ProbeHandle,
     logging::{TimelyEvent, WorkerIdentifier, StartStop, Logger},
+    progress::Timestamp,
     worker::Worker,
 };
 
 use TimelyEvent::{Messages, Operates, Channels, Progress, Schedule, Text};
 
 use differential_dataflow::logging::DifferentialEvent;
-use DifferentialEvent::Merge;
+use DifferentialEvent::{Batch, Merge, MergeShortfall};
 
 use st2_logformat::pair::Pair;
 
+use crate::config::{AdapterConfig, AdapterError, Transport};
+use crate::background::{BackgroundWriter, WriterCounts, WriterStats};
+use crate::reconnect::ReconnectingWriter;
+use crate::segments::SegmentedWriter;
+use crate::sampling::EpochSampling;
+
 
 /// A prepared computation event: (epoch, seq_no, Option<event_length>, event)
 /// The seq_no is a worker-unique identifier of the message and given
 /// in the order the events are logged.
-pub type CompEvent = (u64, u64, Option<usize>, (Duration, WorkerIdentifier, TimelyEvent));
+pub type CompEvent = (u64, u64, Option<usize>, (Duration, WorkerIdentifier, LoggedEvent));
+
+/// An event of the source computation, as it's written for SnailTrail
+#[derive(Abomonation, Clone, Debug)]
+pub enum LoggedEvent {
+    /// A `TimelyEvent`
+    Timely(TimelyEvent),
+    /// Start or end of an arrangement's maintenance, derived from `DifferentialEvent`s
+    Maintenance(MaintenanceEvent),
+}
+
+/// Start or end of a differential arrangement's maintenance within a
+/// schedule of its operator, i.e., inserting new batches and merging them.
+#[derive(Abomonation, Clone, Debug, PartialEq, Eq)]
+pub struct MaintenanceEvent {
+    /// Worker-unique id of the arrangement's operator
+    pub operator: usize,
+    /// Whether maintenance starts or ends
+    pub start_stop: StartStop,
+}
+
+/// Marks the start of every dump (file, segment, or TCP stream) written by a
+/// `PAGLogger`. It's followed by the `DUMP_VERSION` as a little-endian `u64`.
+pub const DUMP_MAGIC: [u8; 8] = *b"ST2DUMP\0";
+
+/// Version of the dump format, i.e., of `CompEvent`. Bump it whenever `CompEvent` changes.
+/// Version 1 dumps, written before `LoggedEvent` was introduced, have no header.
+pub const DUMP_VERSION: u64 = 2;
+
+/// Writes the header that identifies a dump and its version.
+pub fn write_dump_header<W: Write>(writer: &mut W) -> io::Result<()> {
+    writer.write_all(&DUMP_MAGIC)?;
+    writer.write_all(&DUMP_VERSION.to_le_bytes())
+}
+
+/// Reads a dump's header, failing if it's not a dump of the current `DUMP_VERSION`.
+pub fn read_dump_header<R: Read>(reader: &mut R) -> io::Result<()> {
+    let mut header = [0u8; 16];
+    reader.read_exact(&mut header)?;
+
+    if header[.. 8] != DUMP_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "not a SnailTrail dump, or one written by an older version without a header \
+                                   (re-record it with the current st2-timely)"));
+    }
+
+    let mut version = [0u8; 8];
+    version.copy_from_slice(&header[8 ..]);
+    let version = u64::from_le_bytes(version);
+    if version != DUMP_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("unsupported dump version {} (expected {})", version, DUMP_VERSION)));
+    }
+
+    Ok(())
+}
 
 /// A replayer that reads data to be streamed into timely
 pub type Replayer<T, R> = EventReader<T, CompEvent, R>;
@@ -43,6 +105,9 @@ pub type Replayer<T, R> = EventReader<T, CompEvent, R>;
 /// A ReplayWriter that writes data to be streamed into timely
 pub type ReplayWriter<T, R> = EventWriter<T, CompEvent, R>;
 
+/// A writer that events for SnailTrail are pushed to
+type BoxedPusher = Box<dyn EventPusher<Pair<u64, Duration>, CompEvent>>;
+
 /// Wrapper around a `Vec` of `(Duration, usize, DifferentialEvent|TimelyEvent)`
 pub enum DataflowEvents <'a> {
     /// A `TimelyEvent` batch
@@ -57,6 +122,10 @@ pub enum TcpStreamOrFile {
     Tcp(TcpStream),
     /// a file-backed offline reader
     File(File),
+    /// a user-provided writer (cf. `Transport::Custom`)
+    Custom(Box<dyn Write>),
+    /// a compressing file-backed offline reader (cf. `AdapterConfig::compression`)
+    Compressed(Box<dyn Write + Send>),
 }
 
 impl Write for TcpStreamOrFile {
@@ -64,6 +133,8 @@ impl Write for TcpStreamOrFile {
         match self {
             TcpStreamOrFile::Tcp(stream) => stream.write(buf),
             TcpStreamOrFile::File(file) => file.write(buf),
+            TcpStreamOrFile::Custom(writer) => writer.write(buf),
+            TcpStreamOrFile::Compressed(writer) => writer.write(buf),
         }
     }
 
@@ -71,41 +142,107 @@ impl Write for TcpStreamOrFile {
         match self {
             TcpStreamOrFile::Tcp(stream) => stream.flush(),
             TcpStreamOrFile::File(file) => file.flush(),
+            TcpStreamOrFile::Custom(writer) => writer.flush(),
+            TcpStreamOrFile::Compressed(writer) => writer.flush(),
         }
     }
 }
 
+/// How a `PAGLogger` finds out that a source computation epoch has ended.
+/// Automatically derived epochs end the next time the worker is about to
+/// schedule a dataflow, so that no schedule spans two epochs. The epoch
+/// of the dataflow structure ends with the first schedule.
+/// Calls to `Adapter::tick_epoch` always end the current epoch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EpochTicking {
+    /// Epochs end when `Adapter::tick_epoch` is called
+    Manual,
+    /// Epochs end after (at least) the given wall-clock interval
+    Interval(Duration),
+    /// Epochs end whenever the frontier of the probe passed to
+    /// `Adapter::tick_on_probe` has advanced
+    Probe,
+}
+
+impl Default for EpochTicking {
+    fn default() -> Self {
+        EpochTicking::Manual
+    }
+}
+
+/// Checks whether a watched frontier has advanced since it was last checked.
+/// Shared between an `Adapter` and its `PAGLogger` for `EpochTicking::Probe`.
+pub type FrontierWatcher = Rc<RefCell<Option<Box<dyn FnMut() -> bool>>>>;
+
 /// Timely Adapter API
 /// 1. Create an instance with `attach`.
 ///    *IMPORTANT:* This instance should be created at the very beginning
 ///    of the timely scope, otherwise some event messages might not be
 ///    correctly picked up
 /// 2. Call `tick_epoch()` every time a source computation epoch closes.
+///    Alternatively, configure the instance to derive epochs automatically
+///    (cf. `EpochTicking`).
 ///
-/// For live analysis, provide `SNAILTRAIL_ADDR` as env variable.
-/// Else, the computation will log to file for later replay.
+/// Use `attach_configured` with an `AdapterConfig` to choose where events are
+/// logged to, e.g. via TCP for live analysis. By default, `attach` reads the
+/// configuration from env variables (cf. `AdapterConfig::from_env`), and the
+/// computation will log to file for later replay.
 pub struct Adapter {
     /// This adapter's logger, used to communicate epoch ticks.
-    logger: Logger<TimelyEvent>
+    logger: Logger<TimelyEvent>,
+    /// Frontier to derive epochs from, for `EpochTicking::Probe`
+    watcher: FrontierWatcher,
+    /// Counters of background, reconnecting or segmented writers, if used
+    writer_stats: Vec<Arc<WriterStats>>,
 }
 
 impl Adapter {
-    /// Creates a `PAGLogger` instance and attaches it to the computation.
+    /// Creates a `PAGLogger` instance configured from env variables and
+    /// attaches it to the computation. Panics if that fails.
     pub fn attach(worker: &Worker<Generic>) -> Self {
-        Self::attach_configured(worker, None, None, ".".to_string())
+        let config = AdapterConfig::from_env().expect("invalid SnailTrail configuration");
+        Self::attach_configured(worker, config).expect("couldn't attach SnailTrail")
     }
 
     /// Creates a customized `PAGLogger` instance and attaches it to the computation.
-    pub fn attach_configured(worker: &Worker<Generic>, load_balance_factor: Option<usize>, max_fuel: Option<usize>, path: String) -> Self {
-        PAGLogger::create_and_attach(worker, load_balance_factor, max_fuel, path);
+    pub fn attach_configured(worker: &Worker<Generic>, config: AdapterConfig) -> Result<Self, AdapterError> {
+        let pag_logger = PAGLogger::new(worker, config)?;
+        let watcher = pag_logger.frontier_watcher();
+        let writer_stats = pag_logger.writer_stats();
+        pag_logger.attach(worker);
         let logger = worker.log_register().get::<TimelyEvent>("timely").expect("timely logger not found");
-        Adapter { logger }
+        Ok(Adapter { logger, watcher, writer_stats })
+    }
+
+    /// Returns the summed up counters of this worker's background or reconnecting
+    /// writers, e.g. to monitor how many batches were dropped or spilled.
+    pub fn writer_stats(&self) -> WriterCounts {
+        self.writer_stats.iter()
+            .map(|stats| stats.counts())
+            .fold(Default::default(), |acc, counts| acc + counts)
     }
 
     /// Communicates epoch completion to the underlying `PAGLogger`.
     pub fn tick_epoch(&self) {
         self.logger.log(TimelyEvent::Text(Default::default()));
     }
+
+    /// Derives epochs from `probe`'s frontier, for `EpochTicking::Probe`.
+    /// Call it once the dataflow containing `probe` has been built.
+    pub fn tick_on_probe<T: Timestamp>(&self, probe: &ProbeHandle<T>) {
+        let probe = probe.clone();
+        let mut last = probe.with_frontier(|f| f.to_vec());
+
+        *self.watcher.borrow_mut() = Some(Box::new(move || {
+            let curr = probe.with_frontier(|f| f.to_vec());
+            if curr != last {
+                last = curr;
+                true
+            } else {
+                false
+            }
+        }));
+    }
 }
 
 
@@ -113,7 +250,9 @@ impl Adapter {
 /// If the computation is bounded, capabilities will be dropped correctly at the end of computation.
 pub struct PAGLogger {
     /// Writers log messages can be written to.
-    writers: Vec<ReplayWriter<Pair<u64, Duration>, TcpStreamOrFile>>,
+    writers: Vec<BoxedPusher>,
+    /// Counters of background, reconnecting or segmented writers, if used
+    writer_stats: Vec<Arc<WriterStats>>,
     /// Current writer used to log messages to. Used for load balancing
     /// with the `load_balance_factor`
     curr_writer: usize,
@@ -139,8 +278,6 @@ pub struct PAGLogger {
     fuel: usize,
     /// Stores mapping `operator address (target of channel) -> vec![channel ids]`
     op_addr_to_ch_target: HashMap<usize, Vec<usize>>,
-    /// Stores mapping `operator address (source of channel) -> channel id`
-    op_addr_to_ch_source: HashMap<usize, usize>,
     /// Stores mapping `operator id -> operator addr`
     op_id_to_op_addr: HashMap<usize, usize>,
     /// Stores current record count for a given channel id
@@ -153,62 +290,137 @@ pub struct PAGLogger {
     pag_messages: u64,
     /// For debugging (elapsed time)
     elapsed: std::time::Instant,
-    /// For benchmarking (blow up PAG arbitrarily)
-    epoch_count: u64,
+    /// Which epochs are recorded
+    sampling: EpochSampling,
+    /// Whether the current epoch is recorded. Events of skipped
+    /// epochs are discarded right away.
+    sampled: bool,
+    /// How epochs are derived
+    epoch_ticking: EpochTicking,
+    /// Frontier to derive epochs from, for `EpochTicking::Probe`
+    frontier_watcher: FrontierWatcher,
+    /// Time the current epoch was started at, for `EpochTicking::Interval`
+    epoch_start: Duration,
+    /// Number of currently running schedules. Automatic epoch ticks only
+    /// happen when no operator is scheduled.
+    schedule_depth: usize,
+    /// Differential events that haven't been ordered in between
+    /// timely events yet (cf. `drain_differential`)
+    pending_differential: VecDeque<(Duration, WorkerIdentifier, DifferentialEvent)>,
+    /// Arrangement maintenance within the current schedule, if any
+    maintenance: Option<Maintenance>,
+    /// Whether data messages between operators on the same worker are logged
+    local_messages: bool,
+}
+
+/// Arrangement maintenance observed within a schedule, summarizing
+/// the `Batch`, `Merge` and `MergeShortfall` events of an operator
+struct Maintenance {
+    operator: usize,
+    worker: WorkerIdentifier,
+    /// Time of the first event
+    start: Duration,
+    /// Time of the last event
+    end: Duration,
+    /// Records in inserted batches and completed merges
+    records: usize,
 }
 
 impl PAGLogger {
     /// Convenience method to create and directly attach a `PAGLogger`
-    pub fn create_and_attach(worker: &Worker<Generic>, load_balance_factor: Option<usize>, max_fuel: Option<usize>, path: String) {
-        let pag_logger = Self::new(worker, load_balance_factor, max_fuel, path);
+    pub fn create_and_attach(worker: &Worker<Generic>, config: AdapterConfig) -> Result<(), AdapterError> {
+        let pag_logger = Self::new(worker, config)?;
         pag_logger.attach(worker);
+        Ok(())
     }
 
-    /// Creates a new PAGLogger. Events are logged to TCP or file.
+    /// Creates a new PAGLogger. Events are logged according to `config`.
     /// Commonly called indirectly from `create_and_attach`
-    pub fn new(worker: &Worker<Generic>, load_balance_factor: Option<usize>, max_fuel: Option<usize>, path: String) -> Self {
-        let load_balance_factor = if let Some(load_balance_factor) = load_balance_factor {
-            load_balance_factor
-        } else {
-            1
-        };
+    pub fn new(worker: &Worker<Generic>, config: AdapterConfig) -> Result<Self, AdapterError> {
+        config.validate()?;
+        let AdapterConfig { transport, load_balance_factor, max_fuel, epoch_ticking, background, reconnect, compression, segmenting, sampling, local_messages } = config;
 
-        let max_fuel = if let Some(max_fuel) = max_fuel {
-            max_fuel
-        } else {
-            4096
+        let name = |i: usize| format!("w{}-{}", worker.index(), i);
+        // writes to `output` directly or on a background thread
+        let wrap = |i: usize, mut output: TcpStreamOrFile| -> Result<(BoxedPusher, Option<Arc<WriterStats>>), AdapterError> {
+            write_dump_header(&mut output)?;
+
+            let background = if let Some(background) = &background {
+                background.clone()
+            } else {
+                let writer: BoxedPusher = Box::new(EventWriter::new(output));
+                return Ok((writer, None));
+            };
+
+            let writer = match output {
+                TcpStreamOrFile::Tcp(stream) => BackgroundWriter::new(stream, name(i), background)?,
+                TcpStreamOrFile::File(file) => BackgroundWriter::new(file, name(i), background)?,
+                TcpStreamOrFile::Compressed(writer) => BackgroundWriter::new(writer, name(i), background)?,
+                TcpStreamOrFile::Custom(_) => unreachable!("background writers for custom transports are rejected by validation"),
+            };
+            let stats = writer.stats();
+            let writer: BoxedPusher = Box::new(writer);
+            Ok((writer, Some(stats)))
         };
 
-        let writers = if let Ok(addr) = ::std::env::var("SNAILTRAIL_ADDR") {
-            info!("w{} registers logger @{:?}: lbf{}, fuel{}", worker.index(), &addr, load_balance_factor, max_fuel);
-            (0 .. load_balance_factor)
-                .map(|_| TcpStream::connect(&addr).expect("could not connect to logging stream"))
-                .map(|stream| {
-                    // SnailTrail should be able to keep up with an online computation.
-                    // If batch sizes are too large, they should be buffered. Blocking the
-                    // TCP connection is not an option as it slows down the main computation.
-                    // stream
-                    //    .set_nonblocking(true)
-                    //    .expect("set_nonblocking call failed");
-
-                    EventWriter::<Pair<u64, Duration>, _, _>::new(TcpStreamOrFile::Tcp(stream))
-                })
-                .collect::<Vec<_>>()
-        } else {
-            (0 .. load_balance_factor).map(|i| {
-                let name = format!("{}/{}.dump", path, (worker.index() + i * worker.peers()));
-                info!("creating {}", name);
-                let path = Path::new(&name);
-                let file = match File::create(&path) {
-                    Err(why) => panic!("couldn't create {}: {}", path.display(), why.description()),
-                    Ok(file) => file,
-                };
-                EventWriter::<Pair<u64, Duration>, _, _>::new(TcpStreamOrFile::File(file))
-            }).collect::<Vec<_>>()
+        let writers = match transport {
+            Transport::Tcp(addr) => {
+                info!("w{} registers logger @{:?}: lbf{}, fuel{}", worker.index(), &addr, load_balance_factor, max_fuel);
+                (0 .. load_balance_factor)
+                    .map(|i| {
+                        if let Some(policy) = &reconnect {
+                            // connects on its own thread and resumes at the next epoch boundary
+                            let writer = ReconnectingWriter::new(addr.clone(), name(i), policy.clone())?;
+                            let stats = writer.stats();
+                            let writer: BoxedPusher = Box::new(writer);
+                            return Ok((writer, Some(stats)));
+                        }
+
+                        let stream = TcpStream::connect(&addr)?;
+                        // SnailTrail should be able to keep up with an online computation.
+                        // If batch sizes are too large, they should be buffered. Blocking the
+                        // TCP connection is not an option as it slows down the main computation.
+                        // Use `AdapterConfig::background` for this.
+                        wrap(i, TcpStreamOrFile::Tcp(stream))
+                    })
+                    .collect::<Result<Vec<_>, AdapterError>>()?
+            }
+            Transport::Files(dir) => {
+                (0 .. load_balance_factor).map(|i| {
+                    if let Some(segmenting) = segmenting {
+                        let writer = SegmentedWriter::new(dir.clone(), worker.index() + i * worker.peers(), segmenting, compression)?;
+                        let stats = writer.stats();
+                        let writer: BoxedPusher = Box::new(writer);
+                        return Ok((writer, Some(stats)));
+                    }
+
+                    let mut path = dir.join(format!("{}.dump", (worker.index() + i * worker.peers())));
+                    if let Some(compression) = &compression {
+                        path.set_extension(format!("dump.{}", compression.extension()));
+                    }
+                    info!("creating {}", path.display());
+                    let file = File::create(&path)
+                        .map_err(|e| std::io::Error::new(e.kind(), format!("couldn't create {}: {}", path.display(), e)))?;
+                    match compression {
+                        Some(compression) => wrap(i, TcpStreamOrFile::Compressed(compression.writer(file)?)),
+                        None => wrap(i, TcpStreamOrFile::File(file)),
+                    }
+                }).collect::<Result<Vec<_>, AdapterError>>()?
+            }
+            Transport::Custom(mut factory) => {
+                (0 .. load_balance_factor).map(|i| {
+                    let writer = factory(worker.index() + i * worker.peers())?;
+                    wrap(i, TcpStreamOrFile::Custom(writer))
+                }).collect::<Result<Vec<_>, AdapterError>>()?
+            }
         };
 
-        PAGLogger {
+        let (writers, writer_stats): (Vec<_>, Vec<_>) = writers.into_iter().unzip();
+        let writer_stats = writer_stats.into_iter().flatten().collect();
+
+        Ok(PAGLogger {
             writers,
+            writer_stats,
             curr_writer: 0,
             curr_cap: Default::default(),
             next_cap: Pair::new(1, Default::default()),
@@ -218,38 +430,82 @@ impl PAGLogger {
             max_fuel,
             fuel: max_fuel,
             op_addr_to_ch_target: HashMap::new(),
-            op_addr_to_ch_source: HashMap::new(),
             op_id_to_op_addr: HashMap::new(),
             channel_records: HashMap::new(),
             worker_index: worker.index(),
             overall_messages: 0,
             pag_messages: 0,
             elapsed: std::time::Instant::now(),
-            epoch_count: 0,
+            sampling,
+            sampled: true,
+            epoch_ticking,
+            frontier_watcher: Rc::new(RefCell::new(None)),
+            epoch_start: Default::default(),
+            schedule_depth: 0,
+            pending_differential: VecDeque::new(),
+            maintenance: None,
+            local_messages,
+        })
+    }
+
+    /// Returns the counters of background, reconnecting or segmented writers
+    /// (cf. `AdapterConfig::background`, `AdapterConfig::reconnect` and `AdapterConfig::segmenting`).
+    pub fn writer_stats(&self) -> Vec<Arc<WriterStats>> {
+        self.writer_stats.clone()
+    }
+
+    /// Returns the watcher used for `EpochTicking::Probe`.
+    pub fn frontier_watcher(&self) -> FrontierWatcher {
+        Rc::clone(&self.frontier_watcher)
+    }
+
+    /// Whether an epoch should automatically end before an event at `t`.
+    fn should_tick(&mut self, t: &Duration) -> bool {
+        // the dataflow structure is complete once the worker starts scheduling
+        if self.epoch_ticking != EpochTicking::Manual && self.curr_cap == Default::default() {
+            return true;
+        }
+
+        match self.epoch_ticking {
+            EpochTicking::Manual => false,
+            EpochTicking::Interval(interval) => *t >= self.epoch_start + interval,
+            EpochTicking::Probe => {
+                if let Some(watcher) = self.frontier_watcher.borrow_mut().as_mut() {
+                    watcher()
+                } else {
+                    false
+                }
+            }
         }
     }
 
     /// Redirects all events from the `TimelyEvent` logger to self.
     pub fn attach(self, worker: &Worker<Generic>) {
         // if there's already a logger attached, we won't override it
-        if let Err(_) = ::std::env::var("TIMELY_WORKER_LOG_ADDR") {
+        if ::std::env::var("TIMELY_WORKER_LOG_ADDR").is_ok() {
+            warn!("w{}: TIMELY_WORKER_LOG_ADDR is set, so SnailTrail won't log", self.worker_index);
+        } else {
             let timely_logger = Rc::new(RefCell::new(self));
 
-            // TODO: Merge events aren't guaranteed to be picked up early enough
-            // so that they're assigned to the correct SchedulesEvent.
-            // They are generated at the "right" time, but there exists a race
-            // condition due to the way we read out timely & differential events in `attach`.
-            // For this reason, we currently don't add arrangement information.
-            // let differential_logger = timely_logger.clone();
-            // worker
-            //     .log_register()
-            //     .insert::<DifferentialEvent, _>("differential/arrange", move |_time, data| {
-            //         differential_logger.borrow_mut().publish_batch(DataflowEvents::Differential(data));
-            //     });
+            // Differential events are logged independently of timely events, and
+            // each logger only hands over its events once its buffer is full. So that
+            // arrangement maintenance is assigned to the correct schedule, differential
+            // events are queued and ordered in between timely events by time.
+            let differential_logger = Rc::clone(&timely_logger);
+            worker
+                .log_register()
+                .insert::<DifferentialEvent, _>("differential/arrange", move |_time, data| {
+                    differential_logger.borrow_mut().publish_batch(DataflowEvents::Differential(data));
+                });
+            let mut differential = worker.log_register().get::<DifferentialEvent>("differential/arrange");
 
             worker
                 .log_register()
                 .insert::<TimelyEvent, _>("timely", move |_time, data| {
+                    // queues all differential events that happened before the timely events
+                    if let Some(differential) = &mut differential {
+                        differential.flush();
+                    }
                     timely_logger.borrow_mut().publish_batch(DataflowEvents::Timely(data));
                 });
         }
@@ -259,26 +515,12 @@ impl PAGLogger {
     /// Publishes a batch of logged events and advances the capability.
     pub fn publish_batch(&mut self, data: DataflowEvents) {
         match data {
-            DataflowEvents::Differential(data) => {
-                for (_t, _wid, x) in data.drain(..) {
-                    match &x {
-                        Merge(e) if e.complete.is_some() => {
-                            let op_addr = self.op_id_to_op_addr.get(&e.operator).expect("op id in addr not found");
-                            // we need `ch_source` here, since we're not interested in the operator id
-                            // provided by the `Batch` event, but where the batch event's records flow to
-                            // (e.g., a join operator).
-                            let ch_id = self.op_addr_to_ch_source.get(&op_addr).expect("op addr in chs not found");
-
-                            let counter = self.channel_records.entry(*ch_id).or_insert(0);
-                            *counter += e.complete.unwrap();
-                        }
-                        _ => {}
-                    }
-                }
-            }
+            // logged once the timely events around them are known
+            DataflowEvents::Differential(data) => self.pending_differential.extend(data.drain(..)),
             DataflowEvents::Timely(data) => {
                 for (t, wid, x) in data.drain(..) {
                     self.overall_messages += 1;
+                    self.drain_differential(&t);
 
                     match &x {
                         TimelyEvent::Operates(_) | TimelyEvent::Channels(_) |
@@ -289,6 +531,28 @@ impl PAGLogger {
                         _ => {}
                     }
 
+                    // automatically derived epochs end before the next schedule
+                    if let Schedule(e) = &x {
+                        if e.start_stop == StartStop::Start {
+                            if self.schedule_depth == 0 && self.should_tick(&t) {
+                                self.epoch_start = t;
+                                self.tick_epoch();
+                            }
+                            self.schedule_depth += 1;
+                        } else {
+                            self.schedule_depth = self.schedule_depth.saturating_sub(1);
+                        }
+                    }
+
+                    // Skipped epochs only have to be ended. The dataflow
+                    // structure is logged in epoch 0, which is always sampled.
+                    if !self.sampled {
+                        if let Text(_) = &x {
+                            self.tick_epoch();
+                        }
+                        continue;
+                    }
+
                     match &x {
                         Text(_) => self.tick_epoch(),
                         Operates(e) => {
@@ -302,14 +566,20 @@ impl PAGLogger {
 
                             self.op_id_to_op_addr.insert(e.id, *e.addr.last().expect("addr empty"));
 
-                            self.buffer.push((self.curr_cap.first, self.seq_no, None, (Default::default(), wid, x)));
+                            self.buffer.push((self.curr_cap.first, self.seq_no, None, (Default::default(), wid, LoggedEvent::Timely(x))));
                         }
                         Channels(e) => {
                             let ids = self.op_addr_to_ch_target.entry(e.target.0).or_insert(Vec::new());
                             ids.push(e.id);
-                            self.op_addr_to_ch_source.insert(e.source.0, e.id);
+
+                            // channels are part of the dataflow structure, just as operators
+                            self.pag_messages += 1;
+                            self.fuel -= 1;
+                            self.seq_no += 1;
+                            self.buffer.push((self.curr_cap.first, self.seq_no, None, (Default::default(), wid, LoggedEvent::Timely(x))));
                         }
                         Schedule(e) => {
+                            self.end_maintenance();
                             self.pag_messages += 1;
                             // extend buffer size by 1 to avoid breaking up repositioning of
                             // schedule start events and consequent data messages
@@ -324,10 +594,14 @@ impl PAGLogger {
 
                             // fetch length
                             let length = if e.start_stop == StartStop::Stop {
-                                let op_addr = self.op_id_to_op_addr.get(&e.id).expect("op id not found");
+                                let op_addr = self.op_id_to_op_addr.get(&e.id);
+                                if op_addr.is_none() {
+                                    // ST2 reports the schedule as an `UnknownOperator` anomaly
+                                    warn!("w{}: operator {} scheduled before it was logged", self.worker_index, e.id);
+                                }
 
                                 // TODO: refactor
-                                if let Some(ch_ids) = self.op_addr_to_ch_target.get(&op_addr) {
+                                if let Some(ch_ids) = op_addr.and_then(|op_addr| self.op_addr_to_ch_target.get(op_addr)) {
                                     let mut len = 0;
                                     for ch_id in ch_ids.iter() {
                                         if let Some(l) = self.channel_records.remove(ch_id) {
@@ -348,10 +622,11 @@ impl PAGLogger {
                                 None
                             };
 
-                            self.buffer.push((self.curr_cap.first, self.seq_no, length, (t, wid, x)));
+                            self.buffer.push((self.curr_cap.first, self.seq_no, length, (t, wid, LoggedEvent::Timely(x))));
                         }
                         // Remote progress events
                         Progress(e) if e.is_send || e.source != wid => {
+                            self.end_maintenance();
                             self.pag_messages += 1;
                             self.fuel -= 1;
                             self.seq_no += 1;
@@ -360,7 +635,7 @@ impl PAGLogger {
                                 self.advance_cap(&t);
                             }
 
-                            self.buffer.push((self.curr_cap.first, self.seq_no, None, (t, wid, x)));
+                            self.buffer.push((self.curr_cap.first, self.seq_no, None, (t, wid, LoggedEvent::Timely(x))));
                         }
                         // Data receive events
                         Messages(e) if e.is_send == false => {
@@ -369,12 +644,13 @@ impl PAGLogger {
                             let counter = self.channel_records.entry(e.channel).or_insert(0);
                             *counter += e.length;
 
-                            // B. if remote message: add to pag events
-                            if e.source != e.target {
+                            // B. if remote message (or logging local ones): add to pag events
+                            if e.source != e.target || self.local_messages {
+                                self.end_maintenance();
                                 self.pag_messages += 1;
                                 self.seq_no += 1;
 
-                                self.buffer.push((self.curr_cap.first, self.seq_no, Some(e.length), (t, wid, x)));
+                                self.buffer.push((self.curr_cap.first, self.seq_no, Some(e.length), (t, wid, LoggedEvent::Timely(x))));
 
                                 // let (last_epoch, last_seq, last_length, (last_t, last_wid, last_x)) = self.buffer.pop()
                                     // .expect("non-empty buffer required");
@@ -389,17 +665,18 @@ impl PAGLogger {
                                 // self.buffer.push((last_epoch, self.seq_no, last_length, (last_t, last_wid, last_x)));
                             }
                         }
-                        // remote data send events
-                        Messages(e) if e.source != e.target => {
+                        // remote (or, if logged, local) data send events
+                        Messages(e) if e.source != e.target || self.local_messages => {
                             assert!(e.source == wid);
 
+                            self.end_maintenance();
                             self.pag_messages += 1;
                             self.fuel -= 1;
                             self.seq_no += 1;
                             if self.tick_sys {
                                 self.advance_cap(&t);
                             }
-                            self.buffer.push((self.curr_cap.first, self.seq_no, Some(e.length), (t, wid, x)));
+                            self.buffer.push((self.curr_cap.first, self.seq_no, Some(e.length), (t, wid, LoggedEvent::Timely(x))));
                         }
                         _ => {}
                     }
@@ -412,34 +689,99 @@ impl PAGLogger {
         }
     }
 
-    /// Advances the PAGLogger's epoch.
+    /// Advances the PAGLogger's epoch. The next epoch is only recorded
+    /// if it's sampled (cf. `AdapterConfig::sampling`).
     pub fn tick_epoch(&mut self) {
         trace!("w{}@{:?} tick epoch", self.worker_index, self.curr_cap);
 
-        if self.epoch_count % 1 == 0 {
-            if self.curr_cap.first > 0 {
-                // println!("{}|{}|{}|{}|{}", self.worker_index, self.curr_cap.first - 1, self.elapsed.elapsed().as_nanos(), self.overall_messages, self.pag_messages);
-            }
-            self.elapsed = std::time::Instant::now();
-            self.overall_messages = 0;
-            self.pag_messages = 0;
+        // maintenance never spans epochs
+        self.end_maintenance();
 
-            self.next_cap.first += 1;
+        if self.curr_cap.first > 0 && self.sampled {
+            // println!("{}|{}|{}|{}|{}", self.worker_index, self.curr_cap.first - 1, self.elapsed.elapsed().as_nanos(), self.overall_messages, self.pag_messages);
         }
+        self.elapsed = std::time::Instant::now();
+        self.overall_messages = 0;
+        self.pag_messages = 0;
+
+        self.next_cap.first += 1;
 
         if self.curr_cap == Default::default() {
             // The dataflow structure is propagated to all writers.
-            self.flush_to_all();
-        } else {
-            if self.epoch_count % 1 == 0 {
-                self.flush_buffer();
-                self.curr_writer = (self.curr_writer + 1) % self.writers.len();
+            // If the first epochs are skipped, there's nothing left to propagate.
+            if self.sampled {
+                self.flush_to_all();
+            }
+        } else if self.sampled {
+            self.flush_buffer();
+            self.curr_writer = (self.curr_writer + 1) % self.writers.len();
+        }
+
+        let was_sampled = self.sampled;
+        self.sampled = self.sampling.samples(self.next_cap.first);
+        if self.sampled && !was_sampled {
+            // records received in skipped epochs don't count towards sampled schedules
+            self.channel_records.clear();
+        } else if !self.sampled {
+            trace!("w{} skips epoch {}", self.worker_index, self.next_cap.first);
+        }
+    }
+
+
+    /// Logs the queued differential events that happened up to `t`,
+    /// i.e., before the timely event that's logged next.
+    fn drain_differential(&mut self, t: &Duration) {
+        while self.pending_differential.front().map_or(false, |(time, _, _)| time <= t) {
+            let (time, wid, event) = self.pending_differential.pop_front().expect("no pending event");
+            // events of skipped epochs are discarded
+            if self.sampled {
+                self.log_maintenance(time, wid, event);
             }
         }
+    }
+
+    /// Extends the current arrangement maintenance by a differential event,
+    /// or starts a new one.
+    fn log_maintenance(&mut self, t: Duration, wid: WorkerIdentifier, event: DifferentialEvent) {
+        let (operator, records) = match event {
+            Batch(e) => (e.operator, e.length),
+            Merge(e) => (e.operator, e.complete.unwrap_or(0)),
+            MergeShortfall(e) => (e.operator, 0),
+            // sharing and dropping traces isn't maintenance
+            _ => return,
+        };
 
-        self.epoch_count += 1;
+        if let Some(maintenance) = self.maintenance.as_mut().filter(|m| m.operator == operator) {
+            maintenance.end = t;
+            maintenance.records += records;
+        } else {
+            self.end_maintenance();
+            self.maintenance = Some(Maintenance { operator, worker: wid, start: t, end: t, records });
+        }
     }
 
+    /// Logs the current arrangement maintenance, if any, as a pair of
+    /// `MaintenanceEvent`s. It's called before any other event is logged, so
+    /// that events stay in order. Maintenance without a measurable duration,
+    /// e.g., a single `Batch` event, is dropped.
+    fn end_maintenance(&mut self) {
+        let Maintenance { operator, worker, start, end, records } = match self.maintenance.take() {
+            Some(maintenance) if maintenance.end > maintenance.start => maintenance,
+            _ => return,
+        };
+
+        if self.tick_sys {
+            self.advance_cap(&start);
+        }
+
+        self.pag_messages += 2;
+        self.seq_no += 1;
+        self.buffer.push((self.curr_cap.first, self.seq_no, None,
+                          (start, worker, LoggedEvent::Maintenance(MaintenanceEvent { operator, start_stop: StartStop::Start }))));
+        self.seq_no += 1;
+        self.buffer.push((self.curr_cap.first, self.seq_no, Some(records),
+                          (end, worker, LoggedEvent::Maintenance(MaintenanceEvent { operator, start_stop: StartStop::Stop }))));
+    }
 
     /// Flushes the buffer repeatedly, until all writers have received its content.
     fn flush_to_all(&mut self) {
@@ -492,9 +834,358 @@ impl Drop for PAGLogger {
         info!("w{}@ep{:?}: timely logging wrapping up", self.worker_index, self.curr_cap);
         // assert!(self.buffer.len() == 0, "flush buffer before wrap up!");
 
+        // automatically derived epochs aren't closed by a final tick
+        if self.epoch_ticking != EpochTicking::Manual {
+            // differential events logged after the last timely event
+            if let Some((t, _, _)) = self.pending_differential.back() {
+                let t = *t;
+                self.drain_differential(&t);
+            }
+            self.end_maintenance();
+            self.flush_buffer();
+        }
+
         // free capabilities
         for writer in self.writers.iter_mut() {
             writer.push(Event::Progress(vec![(self.curr_cap.clone(), -1)]));
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::sync::Mutex;
+    use timely::logging::{ChannelsEvent, MessagesEvent, OperatesEvent, ScheduleEvent};
+    use differential_dataflow::logging::{BatchEvent, MergeEvent};
+
+    type LoggedBatch = Event<Pair<u64, Duration>, CompEvent>;
+
+    /// Writes to a buffer that outlives the writer
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Creates a `PAGLogger` configured by `config` that writes to a buffer,
+    /// hands it to `log`, and returns what it wrote once it's dropped.
+    fn logged<C, L>(config: C, log: L) -> Vec<LoggedBatch>
+    where C: Fn(AdapterConfig) -> AdapterConfig + Send + Sync + 'static,
+          L: Fn(&mut PAGLogger) + Send + Sync + 'static
+    {
+        let out = Shared::default();
+        let writer = out.clone();
+        let guards = timely::execute(timely::Configuration::Thread, move |worker| {
+            let writer = writer.clone();
+            let config = config(AdapterConfig::new().custom_writer(move |_| Ok(Box::new(writer.clone()) as Box<dyn Write>)));
+            let mut logger = PAGLogger::new(worker, config).expect("couldn't create logger");
+            log(&mut logger);
+        }).expect("couldn't execute");
+        for result in guards.join() {
+            result.expect("worker failed");
+        }
+
+        let mut bytes = out.0.lock().unwrap().clone();
+        read_dump_header(&mut &bytes[..]).expect("invalid header");
+        let mut batches = Vec::new();
+        let mut remaining: &mut [u8] = &mut bytes[16 ..];
+        while !remaining.is_empty() {
+            let (batch, rest) = unsafe { abomonation::decode::<LoggedBatch>(remaining) }.expect("truncated event");
+            batches.push(batch.clone());
+            remaining = rest;
+        }
+        batches
+    }
+
+    fn operates(id: usize) -> TimelyEvent {
+        Operates(OperatesEvent { id, addr: vec![0, id], name: format!("op{}", id) })
+    }
+
+    fn schedule(id: usize, start_stop: StartStop) -> TimelyEvent {
+        Schedule(ScheduleEvent { id, start_stop })
+    }
+
+    /// Publishes `events` of worker 0, each at the given millisecond.
+    fn publish(logger: &mut PAGLogger, events: Vec<(u64, TimelyEvent)>) {
+        let mut batch = events.into_iter()
+            .map(|(ms, event)| (Duration::from_millis(ms), 0, event))
+            .collect();
+        logger.publish_batch(DataflowEvents::Timely(&mut batch));
+    }
+
+    /// Logged schedules as `(epoch, operator, start_stop)`
+    fn schedules(batches: &[LoggedBatch]) -> Vec<(u64, usize, StartStop)> {
+        batches.iter()
+            .flat_map(|batch| match batch {
+                Event::Messages(_, data) => data.clone(),
+                Event::Progress(_) => Vec::new(),
+            })
+            .filter_map(|(epoch, _, _, (_, _, event))| match event {
+                LoggedEvent::Timely(Schedule(e)) => Some((epoch, e.id, e.start_stop)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Capability changes of the last logged event
+    fn last_progress(batches: &[LoggedBatch]) -> Vec<(Pair<u64, Duration>, i64)> {
+        match batches.last() {
+            Some(Event::Progress(changes)) => changes.clone(),
+            _ => panic!("the last event isn't progress"),
+        }
+    }
+
+    /// Logged operators of the structure epoch
+    fn structure(batches: &[LoggedBatch]) -> Vec<usize> {
+        batches.iter()
+            .flat_map(|batch| match batch {
+                Event::Messages(time, data) if time.first == 0 => data.clone(),
+                _ => Vec::new(),
+            })
+            .filter_map(|(_, _, _, (_, _, event))| match event {
+                LoggedEvent::Timely(Operates(e)) => Some(e.id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tick_on_interval() {
+        let batches = logged(|config| config.epoch_ticking(EpochTicking::Interval(Duration::from_millis(10))), |logger| {
+            publish(logger, vec![
+                (0, operates(1)),
+                (0, operates(2)),
+                // ends the structure epoch
+                (1, schedule(1, StartStop::Start)),
+                (2, schedule(1, StartStop::Stop)),
+                (5, schedule(1, StartStop::Start)),
+                (6, schedule(1, StartStop::Stop)),
+                (12, schedule(1, StartStop::Start)),
+                // no epoch ends within a schedule
+                (25, schedule(2, StartStop::Start)),
+                (26, schedule(2, StartStop::Stop)),
+                (27, schedule(1, StartStop::Stop)),
+                (30, schedule(1, StartStop::Start)),
+                (31, schedule(1, StartStop::Stop)),
+            ]);
+        });
+
+        assert_eq!(structure(&batches), vec![1, 2]);
+        assert_eq!(schedules(&batches), vec![
+            (2, 1, StartStop::Start), (2, 1, StartStop::Stop),
+            (2, 1, StartStop::Start), (2, 1, StartStop::Stop),
+            (3, 1, StartStop::Start), (3, 2, StartStop::Start),
+            (3, 2, StartStop::Stop), (3, 1, StartStop::Stop),
+            // flushed when the logger is dropped
+            (4, 1, StartStop::Start), (4, 1, StartStop::Stop),
+        ]);
+        assert_eq!(last_progress(&batches), vec![(Pair::new(4, Duration::from_millis(30)), -1)]);
+    }
+
+    #[test]
+    fn tick_on_probe() {
+        let batches = logged(|config| config.epoch_ticking(EpochTicking::Probe), |logger| {
+            let advanced = Rc::new(Cell::new(false));
+            let watched = Rc::clone(&advanced);
+            *logger.frontier_watcher().borrow_mut() = Some(Box::new(move || watched.replace(false)));
+
+            publish(logger, vec![
+                (0, operates(1)),
+                (1, schedule(1, StartStop::Start)),
+                (2, schedule(1, StartStop::Stop)),
+            ]);
+            advanced.set(true);
+            publish(logger, vec![
+                (3, schedule(1, StartStop::Start)),
+                (4, schedule(1, StartStop::Stop)),
+                (5, schedule(1, StartStop::Start)),
+                (6, schedule(1, StartStop::Stop)),
+            ]);
+            advanced.set(true);
+            publish(logger, vec![
+                (7, schedule(1, StartStop::Start)),
+                (8, schedule(1, StartStop::Stop)),
+            ]);
+        });
+
+        assert_eq!(structure(&batches), vec![1]);
+        assert_eq!(schedules(&batches), vec![
+            (2, 1, StartStop::Start), (2, 1, StartStop::Stop),
+            (3, 1, StartStop::Start), (3, 1, StartStop::Stop),
+            (3, 1, StartStop::Start), (3, 1, StartStop::Stop),
+            (4, 1, StartStop::Start), (4, 1, StartStop::Stop),
+        ]);
+        assert_eq!(last_progress(&batches), vec![(Pair::new(4, Duration::from_millis(7)), -1)]);
+    }
+
+    /// Capability changes of all logged progress events
+    fn progress(batches: &[LoggedBatch]) -> Vec<Vec<(Pair<u64, Duration>, i64)>> {
+        batches.iter()
+            .filter_map(|batch| match batch {
+                Event::Progress(changes) => Some(changes.clone()),
+                Event::Messages(..) => None,
+            })
+            .collect()
+    }
+
+    fn at(epoch: u64, ms: u64) -> Pair<u64, Duration> {
+        Pair::new(epoch, Duration::from_millis(ms))
+    }
+
+    /// Five epochs with a schedule each, ticked manually
+    fn ticked_epochs(logger: &mut PAGLogger) {
+        let mut events = vec![(0, operates(1))];
+        for ms in (0 .. 10).step_by(2) {
+            events.push((ms, Text(String::new())));
+            events.push((ms + 1, schedule(1, StartStop::Start)));
+            events.push((ms + 2, schedule(1, StartStop::Stop)));
+        }
+        events.push((11, Text(String::new())));
+        publish(logger, events);
+    }
+
+    #[test]
+    fn sample_with_capability_gaps() {
+        // the first epoch after the structure is epoch 2
+        let batches = logged(|config| config.sampling(EpochSampling::EveryNth(2)), ticked_epochs);
+        assert_eq!(structure(&batches), vec![1]);
+        assert_eq!(schedules(&batches), vec![
+            (2, 1, StartStop::Start), (2, 1, StartStop::Stop),
+            (4, 1, StartStop::Start), (4, 1, StartStop::Stop),
+            (6, 1, StartStop::Start), (6, 1, StartStop::Stop),
+        ]);
+        assert_eq!(progress(&batches), vec![
+            vec![(at(2, 1), 1), (at(0, 0), -1)],
+            vec![(at(4, 5), 1), (at(2, 1), -1)],
+            vec![(at(6, 9), 1), (at(4, 5), -1)],
+            vec![(at(6, 9), -1)],
+        ]);
+
+        let batches = logged(|config| config.sampling(EpochSampling::Custom(Box::new(|epoch| epoch == 4))), ticked_epochs);
+        assert_eq!(structure(&batches), vec![1]);
+        assert_eq!(schedules(&batches), vec![(4, 1, StartStop::Start), (4, 1, StartStop::Stop)]);
+        assert_eq!(progress(&batches), vec![
+            vec![(at(4, 5), 1), (at(0, 0), -1)],
+            vec![(at(4, 5), -1)],
+        ]);
+    }
+
+    /// Logged activities of the epochs after the structure,
+    /// as `(epoch, time in ms, activity, length)`
+    fn activities(batches: &[LoggedBatch]) -> Vec<(u64, u64, &'static str, Option<usize>)> {
+        batches.iter()
+            .flat_map(|batch| match batch {
+                Event::Messages(time, data) if time.first > 0 => data.clone(),
+                _ => Vec::new(),
+            })
+            .filter_map(|(epoch, _, length, (t, _, event))| {
+                let activity = match event {
+                    LoggedEvent::Timely(Schedule(e)) => if e.start_stop == StartStop::Start { "schedule start" } else { "schedule stop" },
+                    LoggedEvent::Maintenance(e) => if e.start_stop == StartStop::Start { "maintenance start" } else { "maintenance stop" },
+                    LoggedEvent::Timely(Messages(e)) => if e.is_send { "send" } else { "receive" },
+                    _ => return None,
+                };
+                Some((epoch, t.as_millis() as u64, activity, length))
+            })
+            .collect()
+    }
+
+    fn publish_differential(logger: &mut PAGLogger, events: Vec<(u64, DifferentialEvent)>) {
+        let mut batch = events.into_iter()
+            .map(|(ms, event)| (Duration::from_millis(ms), 0, event))
+            .collect();
+        logger.publish_batch(DataflowEvents::Differential(&mut batch));
+    }
+
+    #[test]
+    fn order_maintenance_within_schedules() {
+        let batches = logged(|config| config, |logger| {
+            publish(logger, vec![(0, operates(1)), (0, Text(String::new()))]);
+            // differential events are handed over before the timely events around them
+            publish_differential(logger, vec![
+                (3, Batch(BatchEvent { operator: 1, length: 10 })),
+                (4, Merge(MergeEvent { operator: 1, scale: 0, length1: 5, length2: 5, complete: Some(10) })),
+            ]);
+            publish(logger, vec![
+                (1, schedule(1, StartStop::Start)),
+                (6, schedule(1, StartStop::Stop)),
+                (7, Text(String::new())),
+            ]);
+            // a single batch takes no measurable time
+            publish_differential(logger, vec![(9, Batch(BatchEvent { operator: 1, length: 3 }))]);
+            publish(logger, vec![
+                (8, schedule(1, StartStop::Start)),
+                (10, schedule(1, StartStop::Stop)),
+                (11, Text(String::new())),
+            ]);
+        });
+
+        assert_eq!(activities(&batches), vec![
+            (2, 1, "schedule start", None),
+            (2, 3, "maintenance start", None),
+            (2, 4, "maintenance stop", Some(20)),
+            (2, 6, "schedule stop", None),
+            (3, 8, "schedule start", None),
+            (3, 10, "schedule stop", None),
+        ]);
+
+        // sequence numbers follow the logged order
+        let seq_nos = batches.iter()
+            .flat_map(|batch| match batch {
+                Event::Messages(_, data) => data.iter().map(|(_, seq_no, _, _)| *seq_no).collect(),
+                Event::Progress(_) => Vec::new(),
+            })
+            .collect::<Vec<_>>();
+        assert!(seq_nos.windows(2).all(|x| x[0] < x[1]));
+    }
+
+    /// Operator 1 sends five records to operator 2 on the same worker
+    fn local_message(logger: &mut PAGLogger) {
+        let message = |is_send| Messages(MessagesEvent { is_send, channel: 3, source: 0, target: 0, seq_no: 0, length: 5 });
+        publish(logger, vec![
+            (0, operates(1)),
+            (0, operates(2)),
+            (0, Channels(ChannelsEvent { id: 3, scope_addr: vec![0], source: (1, 0), target: (2, 0) })),
+            (0, Text(String::new())),
+            (1, schedule(1, StartStop::Start)),
+            (2, message(true)),
+            (3, schedule(1, StartStop::Stop)),
+            (4, schedule(2, StartStop::Start)),
+            (5, message(false)),
+            (6, schedule(2, StartStop::Stop)),
+            (7, Text(String::new())),
+        ]);
+    }
+
+    #[test]
+    fn log_local_messages() {
+        // by default, local records only count towards the receiving schedule
+        let batches = logged(|config| config, local_message);
+        assert_eq!(activities(&batches), vec![
+            (2, 1, "schedule start", None),
+            (2, 3, "schedule stop", None),
+            (2, 4, "schedule start", None),
+            (2, 6, "schedule stop", Some(5)),
+        ]);
+
+        let batches = logged(|config| config.local_messages(true), local_message);
+        assert_eq!(activities(&batches), vec![
+            (2, 1, "schedule start", None),
+            (2, 2, "send", Some(5)),
+            (2, 3, "schedule stop", None),
+            (2, 4, "schedule start", None),
+            (2, 5, "receive", Some(5)),
+            (2, 6, "schedule stop", Some(5)),
+        ]);
+    }
+}
@@ -4,127 +4,330 @@
 
 #[macro_use]
 extern crate log;
+#[macro_use]
+extern crate abomonation_derive;
 
 pub mod connect;
-use crate::connect::{Replayer, CompEvent};
+pub mod config;
+pub mod background;
+pub mod reconnect;
+pub mod compression;
+pub mod segments;
+pub mod sampling;
+use crate::connect::{CompEvent, LoggedEvent};
 pub mod replay_throttled;
-use crate::replay_throttled::ReplayThrottled;
+use crate::replay_throttled::{ReplayThrottled, EpochWindow};
 
-use st2_logformat::{ActivityType, EventType, LogRecord};
+use st2_logformat::{ActivityType, EventType, LogRecord, Anomaly, AnomalyReason, OperatorInfo, ChannelInfo, ScopeActivation};
 use st2_logformat::pair::Pair;
 
-use std::io::Read;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::time::Duration;
 
 use timely::{
     dataflow::{
         channels::pact::Pipeline,
         operators::generic::operator::Operator,
+        operators::map::Map,
+        operators::broadcast::Broadcast,
+        operators::capture::event::EventIterator,
+        operators::Capability,
         Scope, Stream,
     },
     logging::{
         StartStop,
-        TimelyEvent::{Messages, Operates, Progress, Schedule},
+        TimelyEvent::{Channels, Messages, Operates, Progress, Schedule},
     },
 };
 
-/// Returns a `Stream` of `LogRecord`s that can be used for PAG construction.
-pub fn create_lrs<S, R>(
+/// Returns a `Stream` of `LogRecord`s that can be used for PAG construction,
+/// together with the source computation's operators and channels, and malformed events that were skipped.
+/// Only events of epochs in `window` are replayed.
+/// If `strict`, malformed events panic instead.
+/// `replayers` are commonly `Replayer`s, or `SegmentReader`s for segmented dumps.
+pub fn create_lrs<S, E>(
     scope: &mut S,
-    replayers: Vec<Replayer<S::Timestamp, R>>,
+    replayers: Vec<E>,
     index: usize,
     throttle: u64,
-) -> Stream<S, LogRecord>
+    strict: bool,
+    window: EpochWindow,
+) -> LrStreams<S>
 where
     S: Scope<Timestamp = Pair<u64, Duration>>,
-    R: Read + 'static,
+    E: EventIterator<Pair<u64, Duration>, CompEvent> + 'static,
 {
     replayers
-        .replay_throttled_into(index, scope, None, throttle)
-        .construct_lrs(index)
+        .replay_throttled_into(index, scope, None, throttle, window)
+        .construct_lrs(index, strict)
+}
+
+/// Outputs of `LogRecord` construction
+pub struct LrStreams<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// `LogRecord`s to be used for PAG construction
+    pub lrs: Stream<S, LogRecord>,
+    /// Operators of the source computation, one per worker and `Operates` event
+    pub operators: Stream<S, OperatorInfo>,
+    /// Channels of the source computation, one per worker and `Channels` event
+    pub channels: Stream<S, ChannelInfo>,
+    /// Malformed events that were skipped
+    pub anomalies: Stream<S, Anomaly>,
+}
+
+/// An event of an innermost operator, together with the activations
+/// of the scopes it happened in, outermost first
+pub type ScopedEvent = (CompEvent, Vec<ScopeActivation>);
+
+/// Output of `peel_ops`
+#[derive(Clone)]
+enum Peeled {
+    /// An event of an innermost operator
+    Event(ScopedEvent),
+    /// The dataflow structure
+    Operator(OperatorInfo),
+    /// A malformed event
+    Anomaly(Anomaly),
 }
 
 /// Operator that converts a Stream of TimelyEvents to their LogRecord representation
 pub trait ConstructLRs<S: Scope<Timestamp = Pair<u64, Duration>>> {
     /// Constructs a stream of log records to be used in PAG construction from an event stream.
-    fn construct_lrs(&self, index: usize) -> Stream<S, LogRecord>;
+    /// Also returns the dataflow structure and malformed events that were skipped
+    /// (panics on them if `strict`).
+    fn construct_lrs(&self, index: usize, strict: bool) -> LrStreams<S>;
     /// Strips an event `Stream` of encompassing operators
     /// (e.g. the dataflow operator for every direct child,
     /// the surrounding iterate operators for loops).
-    fn peel_ops(&self, index: usize) -> Stream<S, CompEvent>;
+    /// Instead, every remaining event is tagged with the activations of
+    /// the scopes it happened in, keeping the scope hierarchy.
+    /// Also returns all operators (from `Operates` events), and events
+    /// of operators it doesn't know about (panics on them if `strict`).
+    fn peel_ops(&self, index: usize, strict: bool) -> (Stream<S, ScopedEvent>, Stream<S, OperatorInfo>, Stream<S, Anomaly>);
+    /// Extracts the channels (from `Channels` events), each reported by a single ST2 peer.
+    fn channels(&self, index: usize) -> Stream<S, ChannelInfo>;
+    /// Builds a log record at differential time `time` from the supplied computation event,
+    /// given the activations of the scopes it happened in (outermost first).
+    fn build_lr(comp_event: CompEvent, scopes: Vec<ScopeActivation>) -> Option<LogRecord>;
+}
+
+/// Operator that converts a Stream of peeled events to their LogRecord representation
+pub trait MakeLRs<S: Scope<Timestamp = Pair<u64, Duration>>> {
     /// Makes a stream of log records from an event stream.
     fn make_lrs(&self, index: usize) -> Stream<S, LogRecord>;
-    /// Builds a log record at differential time `time` from the supplied computation event.
-    fn build_lr(comp_event: CompEvent) -> Option<LogRecord>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> MakeLRs<S> for Stream<S, ScopedEvent> {
+    fn make_lrs(&self, _index: usize) -> Stream<S, LogRecord> {
+        let mut vector = Vec::new();
+
+        self.unary(Pipeline, "LogRecordConstruct", move |_, _| { move |input, output| {
+            input.for_each(|cap, data| {
+                data.swap(&mut vector);
+                output.session(&cap).give_iterator(vector.drain(..).flat_map(|(x, scopes)| {
+                    <Stream<S, CompEvent> as ConstructLRs<S>>::build_lr(x, scopes).into_iter()
+                }));
+            });
+        }})
+    }
 }
 
 impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructLRs<S> for Stream<S, CompEvent>
 {
-    fn construct_lrs(&self, index: usize) -> Stream<S, LogRecord> {
-        self.peel_ops(index)
-            .make_lrs(index)
+    fn construct_lrs(&self, index: usize, strict: bool) -> LrStreams<S> {
+        let (peeled, operators, anomalies) = self.peel_ops(index, strict);
+
+        LrStreams {
+            lrs: peeled.make_lrs(index),
+            operators,
+            channels: self.channels(index),
+            anomalies,
+        }
     }
 
-    fn peel_ops(&self, _index: usize) -> Stream<S, CompEvent> {
-        let mut vector = Vec::new();
-        let mut outer_operates = std::collections::BTreeSet::new();
-        let mut ids_to_addrs = std::collections::HashMap::new();
+    fn peel_ops(&self, index: usize, strict: bool) -> (Stream<S, ScopedEvent>, Stream<S, OperatorInfo>, Stream<S, Anomaly>) {
+        let peers = self.scope().peers();
 
-        self.unary(Pipeline, "Peel", move |_, _| { move |input, output| {
-            input.for_each(|cap, data| {
-                data.swap(&mut vector);
-                for (epoch, seq_no, length, (t, wid, x)) in vector.drain(..) {
-                    match x {
-                        Operates(e) => {
-                            if wid == 0 {
-                                // Dataflow structure logging
-                                info!("{:?}", e);
-                            }
-                            let mut addr = e.addr.clone();
-                            addr.pop();
-                            outer_operates.insert(addr);
+        // For a load balance factor > 1, an ST2 peer doesn't necessarily read the
+        // structure of every source worker it sees events of, so the structure is
+        // broadcast. Every peer then knows all operators.
+        let structure = self
+            .flat_map(|(_epoch, _seq_no, _length, (_t, wid, x))| match x {
+                LoggedEvent::Timely(Operates(e)) => Some(OperatorInfo {
+                    worker: wid as u64,
+                    id: e.id as u64,
+                    name: e.name,
+                    addr: e.addr,
+                }),
+                _ => None,
+            })
+            .broadcast();
+
+        let mut vector1 = Vec::new();
+        let mut vector2 = Vec::new();
+        let mut stash: Vec<(Capability<Pair<u64, Duration>>, Vec<CompEvent>)> = Vec::new();
+        // per worker: addresses of scopes
+        let mut outer_operates: HashMap<u64, BTreeSet<Vec<usize>>> = HashMap::new();
+        // per worker and operator id: address
+        let mut ids_to_addrs: HashMap<(u64, u64), Vec<usize>> = HashMap::new();
+        // per worker: (epoch, activations of the scopes that are currently scheduled, innermost last)
+        let mut open_scopes: HashMap<usize, (u64, Vec<ScopeActivation>)> = HashMap::new();
+        // per worker and scope: (epoch, activations within that epoch)
+        let mut rounds: HashMap<(usize, usize), (u64, u64)> = HashMap::new();
+
+        let peeled = self.binary_frontier(&structure, Pipeline, Pipeline, "Peel", move |_, _| { move |input1, input2, output| {
+            input2.for_each(|cap, data| {
+                data.swap(&mut vector2);
+                for operator in vector2.drain(..) {
+                    // the structure might be logged to several writers per source worker
+                    if ids_to_addrs.contains_key(&(operator.worker, operator.id)) {
+                        continue;
+                    }
+
+                    let mut addr = operator.addr.clone();
+                    addr.pop();
+                    outer_operates.entry(operator.worker).or_insert_with(BTreeSet::new).insert(addr);
+                    ids_to_addrs.insert((operator.worker, operator.id), operator.addr.clone());
+
+                    // every operator is reported by a single ST2 peer
+                    if operator.worker as usize % peers == index {
+                        if operator.worker == 0 {
+                            // Dataflow structure logging
+                            info!("{:?}", operator);
+                        }
+                        output.session(&cap).give(Peeled::Operator(operator));
+                    }
+                }
+            });
+
+            input1.for_each(|cap, data| {
+                data.swap(&mut vector1);
+                stash.push((cap.delayed(cap.time()), vector1.drain(..).collect()));
+            });
+
+            // operators are created before they are scheduled
+            let frontier = input2.frontier();
+            let (ready, pending): (Vec<_>, Vec<_>) = stash.drain(..)
+                .partition(|(cap, _)| !frontier.less_equal(cap.time()));
+            stash = pending;
+
+            for (cap, events) in ready {
+                for (epoch, seq_no, length, (t, wid, x)) in events {
+                    // scopes are only ever scheduled within an epoch, and a worker's
+                    // epochs might be spread across ST2 peers
+                    let (open_epoch, open) = open_scopes.entry(wid).or_insert((epoch, Vec::new()));
+                    if *open_epoch != epoch {
+                        *open_epoch = epoch;
+                        open.clear();
+                    }
+
+                    // handled by the broadcast structure, and `channels`
+                    if let LoggedEvent::Timely(Operates(_)) | LoggedEvent::Timely(Channels(_)) = x {
+                        continue;
+                    }
 
-                            ids_to_addrs.insert(e.id, e.addr);
+                    let malformed = if *cap.time() == Default::default() {
+                        Some(AnomalyReason::EventBeforeFirstEpoch)
+                    } else if let LoggedEvent::Timely(Messages(ref e)) = x {
+                        if length != Some(e.length) { Some(AnomalyReason::LengthMismatch) } else { None }
+                    } else {
+                        None
+                    };
+
+                    if let Some(reason) = malformed {
+                        let records = Self::build_lr((epoch, seq_no, length, (t, wid, x)), open.clone()).into_iter().collect();
+                        let anomaly = Anomaly::new(reason, records);
+                        if strict {
+                            panic!("w{}: {:?}", index, anomaly);
                         }
-                        Schedule(ref e) => {
-                            assert!(cap.time() > &Pair::new(0, Default::default()));
-
-                            // @TODO: For LBF > 1, we might not have seen all `Operates` events
-                            // at all workers, so this fails.
-                            let addr = ids_to_addrs.get(&e.id).expect("operates went wrong");
-                            if !outer_operates.contains(addr) {
-                                output.session(&cap).give((epoch, seq_no, length, (t, wid, x)));
+                        output.session(&cap).give(Peeled::Anomaly(anomaly));
+                        continue;
+                    }
+
+                    match x {
+                        LoggedEvent::Timely(Schedule(ref e)) => {
+                            match ids_to_addrs.get(&(wid as u64, e.id as u64)) {
+                                Some(addr) if outer_operates.get(&(wid as u64)).map_or(false, |scopes| scopes.contains(addr)) => {
+                                    // scopes aren't part of the PAG, but their activations are
+                                    if e.start_stop == StartStop::Start {
+                                        let count = rounds.entry((wid, e.id)).or_insert((epoch, 0));
+                                        if count.0 != epoch {
+                                            *count = (epoch, 0);
+                                        }
+                                        open.push(ScopeActivation { scope_id: e.id as u64, round: count.1 });
+                                        count.1 += 1;
+                                    } else if let Some(pos) = open.iter().rposition(|s| s.scope_id == e.id as u64) {
+                                        open.truncate(pos);
+                                    }
+                                }
+                                Some(_) => {
+                                    output.session(&cap).give(Peeled::Event(((epoch, seq_no, length, (t, wid, x)), open.clone())));
+                                }
+                                None => {
+                                    let records = Self::build_lr((epoch, seq_no, length, (t, wid, x)), open.clone()).into_iter().collect();
+                                    let anomaly = Anomaly::new(AnomalyReason::UnknownOperator, records);
+                                    if strict {
+                                        panic!("operates went wrong: {:?}", anomaly);
+                                    }
+                                    output.session(&cap).give(Peeled::Anomaly(anomaly));
+                                }
                             }
                         }
                         _ => {
-                            assert!(cap.time() > &Pair::new(0, Default::default()));
-
-                            output.session(&cap).give((epoch, seq_no, length, (t, wid, x)));
+                            output.session(&cap).give(Peeled::Event(((epoch, seq_no, length, (t, wid, x)), open.clone())));
                         }
                     }
                 }
-            });
-        }})
+            }
+        }});
+
+        let events = peeled.flat_map(|x| if let Peeled::Event(x) = x { Some(x) } else { None });
+        let operators = peeled.flat_map(|x| if let Peeled::Operator(x) = x { Some(x) } else { None });
+        let anomalies = peeled.flat_map(|x| if let Peeled::Anomaly(x) = x { Some(x) } else { None });
+
+        (events, operators, anomalies)
     }
 
-    fn make_lrs(&self, _index: usize) -> Stream<S, LogRecord> {
+    fn channels(&self, index: usize) -> Stream<S, ChannelInfo> {
+        let peers = self.scope().peers();
         let mut vector = Vec::new();
+        let mut seen = HashSet::new();
 
-        self.unary(Pipeline, "LogRecordConstruct", move |_, _| { move |input, output| {
-            input.for_each(|cap, data| {
-                data.swap(&mut vector);
-                output.session(&cap).give_iterator(vector.drain(..).flat_map(|x| Self::build_lr(x).into_iter()));
-            });
-        }})
+        // Like operators, channels might be logged to several writers per source worker,
+        // which might be read by different ST2 peers.
+        self
+            .flat_map(|(_epoch, _seq_no, _length, (_t, wid, x))| match x {
+                LoggedEvent::Timely(Channels(e)) => Some(ChannelInfo {
+                    worker: wid as u64,
+                    id: e.id as u64,
+                    scope_addr: e.scope_addr,
+                    source: e.source,
+                    target: e.target,
+                }),
+                _ => None,
+            })
+            .broadcast()
+            .unary(Pipeline, "Channels", move |_, _| { move |input, output| {
+                input.for_each(|cap, data| {
+                    data.swap(&mut vector);
+                    for channel in vector.drain(..) {
+                        // every channel is reported by a single ST2 peer
+                        if seen.insert((channel.worker, channel.id)) && channel.worker as usize % peers == index {
+                            output.session(&cap).give(channel);
+                        }
+                    }
+                });
+            }})
     }
 
-    fn build_lr(comp_event: CompEvent) -> Option<LogRecord> {
+    fn build_lr(comp_event: CompEvent, mut scopes: Vec<ScopeActivation>) -> Option<LogRecord> {
         let (epoch, seq_no, length, (timestamp, wid, x)) = comp_event;
         let local_worker = wid as u64;
+        let scope = scopes.pop();
+        let outer_scopes = scopes;
 
         match x {
             // Scheduling & Processing
-            Schedule(event) => {
+            LoggedEvent::Timely(Schedule(event)) => {
                 let event_type = if event.start_stop == StartStop::Start {
                     EventType::Start
                 } else {
@@ -143,12 +346,37 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructLRs<S> for Stream<S, Co
                     channel_id: None,
                     correlator_id: None,
                     length,
+                    scope,
+                    outer_scopes,
                 })
             }
-            // remote data messages
-            Messages(event) => {
-                assert!(length.unwrap() == event.length);
+            // arrangement maintenance
+            LoggedEvent::Maintenance(event) => {
+                let event_type = if event.start_stop == StartStop::Start {
+                    EventType::Start
+                } else {
+                    EventType::End
+                };
 
+                Some(LogRecord {
+                    seq_no,
+                    epoch,
+                    timestamp,
+                    local_worker,
+                    activity_type: ActivityType::Merging,
+                    event_type,
+                    remote_worker: None,
+                    operator_id: Some(event.operator as u64),
+                    channel_id: None,
+                    correlator_id: None,
+                    length,
+                    scope,
+                    outer_scopes,
+                })
+            }
+            // remote data messages
+            // (`peel_ops` reports messages whose length doesn't match)
+            LoggedEvent::Timely(Messages(event)) => {
                 let remote_worker = if event.is_send {
                     Some(event.target as u64)
                 } else {
@@ -172,11 +400,13 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructLRs<S> for Stream<S, Co
                     operator_id: None,
                     channel_id: Some(event.channel as u64),
                     correlator_id: Some(event.seq_no as u64),
-                    length
+                    length,
+                    scope,
+                    outer_scopes,
                 })
             }
             // Control Messages
-            Progress(event) => {
+            LoggedEvent::Timely(Progress(event)) => {
                 let event_type = if event.is_send {
                     EventType::Sent
                 } else {
@@ -203,6 +433,8 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructLRs<S> for Stream<S, Co
                     channel_id: Some(event.channel as u64),
                     correlator_id: Some(event.seq_no as u64),
                     length: None,
+                    scope,
+                    outer_scopes,
                 })
             }
             // Channels / Operates events
@@ -211,6 +443,134 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructLRs<S> for Stream<S, Co
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::Receiver;
+    use timely::dataflow::operators::{Capture, Input};
+    use timely::dataflow::operators::capture::Event;
+    use timely::logging::{MessagesEvent, OperatesEvent, ScheduleEvent, TimelyEvent};
+
+    fn time(epoch: u64) -> Pair<u64, Duration> {
+        Pair::new(epoch, Default::default())
+    }
+
+    fn operates(wid: usize, id: usize) -> CompEvent {
+        let event = TimelyEvent::Operates(OperatesEvent { id, addr: vec![0, id], name: format!("op{}", id) });
+        (0, id as u64, None, (Default::default(), wid, LoggedEvent::Timely(event)))
+    }
+
+    fn schedule(epoch: u64, seq_no: u64, wid: usize, id: usize, start_stop: StartStop) -> CompEvent {
+        let event = TimelyEvent::Schedule(ScheduleEvent { id, start_stop });
+        (epoch, seq_no, None, (Duration::from_millis(seq_no), wid, LoggedEvent::Timely(event)))
+    }
+
+    fn captured<D>(receiver: Receiver<Event<Pair<u64, Duration>, D>>) -> Vec<D> {
+        receiver.try_iter()
+            .flat_map(|event| match event {
+                Event::Messages(_time, data) => data,
+                Event::Progress(_) => Vec::new(),
+            })
+            .collect()
+    }
+
+    /// Constructs `LogRecord`s from `events`, sent at their epochs on a single worker.
+    fn construct(events: Vec<CompEvent>, strict: bool) -> (Vec<LogRecord>, Vec<Anomaly>) {
+        let (lrs, anomalies) = timely::execute_directly(move |worker| {
+            let (mut input, lrs, anomalies) = worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                let (input, stream) = scope.new_input::<CompEvent>();
+                let streams = stream.construct_lrs(0, strict);
+                (input, streams.lrs.capture(), streams.anomalies.capture())
+            });
+            for event in events {
+                input.advance_to(time(event.0));
+                input.send(event);
+            }
+            (lrs, anomalies)
+        });
+
+        (captured(lrs), captured(anomalies))
+    }
+
+    #[test]
+    fn stash_until_structure_arrives() {
+        let guards = timely::execute(timely::Configuration::Process(2), |worker| {
+            let index = worker.index();
+            let (mut input, lrs, anomalies) = worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                let (input, stream) = scope.new_input::<CompEvent>();
+                let streams = stream.construct_lrs(index, false);
+                (input, streams.lrs.capture(), streams.anomalies.capture())
+            });
+
+            if index == 0 {
+                // events of source worker 1, whose structure is read by the other peer
+                input.advance_to(time(2));
+                input.send(schedule(2, 1, 1, 5, StartStop::Start));
+                input.send(schedule(2, 2, 1, 5, StartStop::Stop));
+            } else {
+                for _ in 0 .. 10 {
+                    worker.step();
+                }
+                input.send(operates(1, 5));
+            }
+            drop(input);
+            while worker.step() { }
+
+            (captured(lrs), captured(anomalies))
+        }).expect("couldn't execute");
+
+        let results = guards.join().into_iter().map(|result| result.expect("worker failed")).collect::<Vec<_>>();
+        let (lrs, anomalies) = &results[0];
+        assert_eq!(lrs.iter().map(|lr| (lr.epoch, lr.local_worker, lr.operator_id, lr.event_type)).collect::<Vec<_>>(), vec![
+            (2, 1, Some(5), EventType::Start),
+            (2, 1, Some(5), EventType::End),
+        ]);
+        assert!(anomalies.is_empty());
+        assert!(results[1].0.is_empty() && results[1].1.is_empty());
+    }
+
+    #[test]
+    fn unknown_operator() {
+        let (lrs, anomalies) = construct(vec![
+            operates(0, 1),
+            schedule(2, 2, 0, 1, StartStop::Start),
+            schedule(2, 3, 0, 7, StartStop::Start),
+            schedule(2, 4, 0, 7, StartStop::Stop),
+            schedule(2, 5, 0, 1, StartStop::Stop),
+        ], false);
+
+        assert_eq!(lrs.iter().map(|lr| (lr.seq_no, lr.operator_id)).collect::<Vec<_>>(), vec![(2, Some(1)), (5, Some(1))]);
+        assert_eq!(anomalies.iter().map(|anomaly| anomaly.reason).collect::<Vec<_>>(), vec![AnomalyReason::UnknownOperator; 2]);
+        assert_eq!(anomalies.iter().flat_map(|anomaly| anomaly.records.iter().map(|lr| (lr.seq_no, lr.operator_id))).collect::<Vec<_>>(),
+                   vec![(3, Some(7)), (4, Some(7))]);
+    }
+
+    #[test]
+    fn malformed_events() {
+        // a message logged with 2 records, whose event reports 3
+        let event = TimelyEvent::Messages(MessagesEvent { is_send: true, channel: 3, source: 0, target: 0, seq_no: 0, length: 3 });
+        let message = (2, 4, Some(2), (Duration::from_millis(4), 0, LoggedEvent::Timely(event)));
+
+        let (lrs, anomalies) = construct(vec![
+            operates(0, 1),
+            schedule(0, 1, 0, 1, StartStop::Start),
+            schedule(2, 2, 0, 1, StartStop::Start),
+            schedule(2, 3, 0, 1, StartStop::Stop),
+            message,
+        ], false);
+
+        assert_eq!(lrs.iter().map(|lr| (lr.seq_no, lr.operator_id)).collect::<Vec<_>>(), vec![(2, Some(1)), (3, Some(1))]);
+        assert_eq!(anomalies.iter().map(|anomaly| anomaly.reason).collect::<Vec<_>>(),
+                   vec![AnomalyReason::EventBeforeFirstEpoch, AnomalyReason::LengthMismatch]);
+    }
+
+    #[test]
+    #[should_panic(expected = "operates went wrong")]
+    fn unknown_operator_strict() {
+        construct(vec![operates(0, 1), schedule(2, 2, 0, 7, StartStop::Start)], true);
+    }
+}
+
 // let mut vector = Vec::new();
 // .inner
 // .unary_frontier(Pipeline, "Logger", move |_, _| { move |input, output| {
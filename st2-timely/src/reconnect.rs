@@ -0,0 +1,511 @@
+//! Reconnecting TCP writers, so that online logging survives ST2 restarts
+//! and network hiccups.
+//!
+//! A `ReconnectingWriter` never fails or stalls the source computation: events
+//! are handed to a connection thread through a bounded queue, and only that
+//! thread connects and writes. If the connection breaks, events are dropped
+//! and connecting is retried with exponential backoff. Writing only resumes at
+//! an epoch boundary, so that ST2 never sees a partial epoch after
+//! reconnecting. On every new connection, the dataflow structure is re-sent and
+//! the reader's capability is advanced to the current epoch, so that a freshly
+//! started ST2 instance can pick up a computation that is already running.
+//!
+//! A connection is closed as soon as a write fails, so an event that was only
+//! partially written is never followed by further bytes: the reader sees the
+//! stream end in the middle of that event instead of misreading what follows.
+//! Note that a running ST2 instance reads from the connections it accepted at
+//! startup only. It has to be restarted to pick up a computation again after
+//! a connection was lost.
+
+use std::io::{self, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use abomonation::Abomonation;
+
+use timely::dataflow::operators::capture::event::{Event, EventPusher};
+
+use st2_logformat::pair::Pair;
+
+use crate::background::WriterStats;
+use crate::connect::write_dump_header;
+
+
+/// Number of events that can be queued for the connection thread.
+/// If the queue is full, the connection is dropped and resumed later on.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// How a `ReconnectingWriter` retries connecting
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// Wait time after the first failed attempt
+    pub initial_backoff: Duration,
+    /// Upper bound for the wait time, which doubles with every failed attempt
+    pub max_backoff: Duration,
+    /// Timeout for connecting and writing. A write that times out
+    /// counts as a broken connection.
+    pub timeout: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// What a fresh reader needs to pick up a running computation:
+/// the dataflow structure and the source's current capability.
+pub(crate) struct Resume<D> {
+    /// Capability held by the source, as of the last progress event
+    pub(crate) cap: Pair<u64, Duration>,
+    /// Events of epoch 0, i.e., the dataflow structure
+    structure: Vec<D>,
+}
+
+impl<D: Clone> Resume<D> {
+    pub(crate) fn new() -> Self {
+        Resume { cap: Default::default(), structure: Vec::new() }
+    }
+
+    /// Whether `event` advances the source's capability to a new epoch.
+    pub(crate) fn starts_epoch(&self, event: &Event<Pair<u64, Duration>, D>) -> bool {
+        match event {
+            Event::Progress(updates) => updates.iter().any(|(time, diff)| *diff > 0 && time.first > self.cap.first),
+            Event::Messages(..) => false,
+        }
+    }
+
+    /// Keeps track of the structure and capability from `event`.
+    pub(crate) fn observe(&mut self, event: &Event<Pair<u64, Duration>, D>) {
+        match event {
+            Event::Messages(time, data) => if time.first == 0 {
+                self.structure.extend(data.iter().cloned());
+            }
+            Event::Progress(updates) => if let Some((time, _)) = updates.iter().find(|(_, diff)| *diff > 0) {
+                self.cap = time.clone();
+            }
+        }
+    }
+
+    /// Events that bring a fresh reader, which holds the initial capability,
+    /// up to date: the structure, and a capability advanced to the current one.
+    pub(crate) fn prelude(&self) -> Vec<Event<Pair<u64, Duration>, D>> {
+        let mut prelude = Vec::new();
+        if !self.structure.is_empty() {
+            prelude.push(Event::Messages(Default::default(), self.structure.clone()));
+        }
+        if self.cap != Default::default() {
+            prelude.push(Event::Progress(vec![(self.cap.clone(), 1), (Default::default(), -1)]));
+        }
+        prelude
+    }
+}
+
+/// An event handed to the connection thread
+struct Frame<D> {
+    event: Event<Pair<u64, Duration>, D>,
+    /// Epoch of the source's capability once `event` is applied
+    epoch: u64,
+    /// Whether `event` starts a new epoch, i.e., whether writing may resume at it
+    boundary: bool,
+    /// Events that bring a fresh reader up to date at `event`. Only
+    /// prepared at epoch boundaries while there's no connection.
+    prelude: Option<Vec<Event<Pair<u64, Duration>, D>>>,
+}
+
+/// State shared by a `ReconnectingWriter` and its connection thread
+#[derive(Default)]
+struct Link {
+    /// Whether the connection thread currently has a connection
+    connected: AtomicBool,
+    /// Set if events were lost because the queue was full. As the reader
+    /// missed them, the connection thread drops its connection.
+    overflowed: AtomicBool,
+}
+
+/// Writes events to a TCP connection that is re-established if it breaks.
+pub struct ReconnectingWriter<D> {
+    sender: Option<SyncSender<Frame<D>>>,
+    thread: Option<JoinHandle<()>>,
+    /// Identifies the writer in logs
+    name: String,
+    /// What's re-sent on reconnect
+    resume: Resume<D>,
+    link: Arc<Link>,
+    stats: Arc<WriterStats>,
+}
+
+impl<D: Abomonation + Clone + Send + 'static> ReconnectingWriter<D> {
+    /// Spawns a connection thread for `addr` (`<IP>:<Port>`). If the first
+    /// attempt to connect fails, it retries at the following epoch boundaries.
+    /// `name` identifies the writer in logs.
+    pub fn new(addr: String, name: String, policy: ReconnectPolicy) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let stats: Arc<WriterStats> = Default::default();
+        let link: Arc<Link> = Default::default();
+
+        let mut connection = Connection {
+            addr,
+            stream: None,
+            name: name.clone(),
+            backoff: policy.initial_backoff,
+            next_attempt: Instant::now(),
+            policy,
+            link: Arc::clone(&link),
+            stats: Arc::clone(&stats),
+            dropped: None,
+            bytes: Vec::new(),
+        };
+        let thread = std::thread::Builder::new()
+            .name(format!("snailtrail-{}", name))
+            .spawn(move || connection.run(receiver))?;
+
+        Ok(ReconnectingWriter {
+            sender: Some(sender),
+            thread: Some(thread),
+            name,
+            resume: Resume::new(),
+            link,
+            stats,
+        })
+    }
+
+    /// Returns the writer's counters.
+    pub fn stats(&self) -> Arc<WriterStats> {
+        Arc::clone(&self.stats)
+    }
+}
+
+impl<D: Abomonation + Clone + Send + 'static> EventPusher<Pair<u64, Duration>, D> for ReconnectingWriter<D> {
+    fn push(&mut self, event: Event<Pair<u64, Duration>, D>) {
+        let boundary = self.resume.starts_epoch(&event);
+        self.resume.observe(&event);
+
+        // the connection thread can only resume at this event if it knows the prelude
+        let resumable = !self.link.connected.load(Ordering::Relaxed) || self.link.overflowed.load(Ordering::Relaxed);
+        let prelude = if boundary && resumable { Some(self.resume.prelude()) } else { None };
+        let frame = Frame { event, epoch: self.resume.cap.first, boundary, prelude };
+
+        let sender = self.sender.as_ref().expect("sender is only taken on drop");
+        if let Err(e) = sender.try_send(frame) {
+            let frame = match e {
+                TrySendError::Full(frame) => {
+                    if !self.link.overflowed.swap(true, Ordering::Relaxed) {
+                        warn!("{}: queue full, dropping the connection until the next epoch", self.name);
+                    }
+                    frame
+                }
+                TrySendError::Disconnected(frame) => frame,
+            };
+            // the structure isn't lost, it's sent once reconnected
+            if let Event::Messages(time, _) = &frame.event {
+                if time.first > 0 {
+                    self.stats.dropped_batches.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+impl<D> Drop for ReconnectingWriter<D> {
+    fn drop(&mut self) {
+        // closing the queue stops the connection thread once it has been drained
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("{}: connection thread panicked", self.name);
+            }
+        }
+
+        info!("{}: reconnecting writer wrapping up: {:?}", self.name, self.stats.counts());
+    }
+}
+
+/// The connection thread's end of a `ReconnectingWriter`
+struct Connection {
+    addr: String,
+    policy: ReconnectPolicy,
+    stream: Option<TcpStream>,
+    /// Identifies the writer in logs
+    name: String,
+    /// Current wait time between attempts
+    backoff: Duration,
+    next_attempt: Instant,
+    link: Arc<Link>,
+    stats: Arc<WriterStats>,
+    /// Last epoch counted as dropped, so that no epoch is counted twice
+    dropped: Option<u64>,
+    /// Reused serialization buffer
+    bytes: Vec<u8>,
+}
+
+impl Connection {
+    fn run<D: Abomonation>(&mut self, receiver: Receiver<Frame<D>>) {
+        // A fresh reader holds the initial capability, so there's nothing to resume yet.
+        match self.connect() {
+            Ok(stream) => {
+                self.stream = Some(stream);
+                self.link.connected.store(true, Ordering::Relaxed);
+            }
+            Err(e) => {
+                warn!("{}: couldn't connect to {}, retrying at the next epoch: {}", self.name, self.addr, e);
+                self.back_off();
+            }
+        }
+
+        for frame in receiver {
+            if self.link.overflowed.swap(false, Ordering::Relaxed) && self.stream.is_some() {
+                self.disconnect();
+                self.drop_epoch(frame.epoch);
+            }
+
+            if self.stream.is_none() && frame.boundary {
+                if let Some(prelude) = &frame.prelude {
+                    if Instant::now() >= self.next_attempt {
+                        self.reconnect(prelude, frame.epoch);
+                    }
+                }
+                if self.stream.is_some() {
+                    // resuming covers this progress event
+                    continue;
+                }
+                self.drop_epoch(frame.epoch);
+            }
+
+            if self.stream.is_some() {
+                self.write(&frame.event, frame.epoch);
+            } else if let Event::Messages(time, _) = &frame.event {
+                // the structure isn't lost, it's sent once connected
+                if time.first > 0 {
+                    self.stats.dropped_batches.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, format!("couldn't resolve {}", self.addr));
+        for addr in self.addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.policy.timeout) {
+                Ok(mut stream) => {
+                    stream.set_write_timeout(Some(self.policy.timeout))?;
+                    // every connection is read as a dump of its own
+                    write_dump_header(&mut stream)?;
+                    return Ok(stream);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Schedules the next attempt to connect.
+    fn back_off(&mut self) {
+        self.next_attempt = Instant::now() + self.backoff;
+        self.backoff = std::cmp::min(self.backoff * 2, self.policy.max_backoff);
+    }
+
+    /// Counts `epoch` as truncated, unless it already has been.
+    fn drop_epoch(&mut self, epoch: u64) {
+        if self.dropped != Some(epoch) {
+            self.dropped = Some(epoch);
+            self.stats.dropped_epochs.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Closes the connection. Nothing is written after a partially written
+    /// event, so the reader never misreads the bytes that would follow it.
+    fn disconnect(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        self.link.connected.store(false, Ordering::Relaxed);
+    }
+
+    /// Tries to connect. On success, the new reader is brought up to date
+    /// with `prelude`, which resumes at `epoch`.
+    fn reconnect<D: Abomonation>(&mut self, prelude: &[Event<Pair<u64, Duration>, D>], epoch: u64) {
+        match self.connect() {
+            Ok(stream) => {
+                info!("{}: connected to {}, resuming at epoch {}", self.name, self.addr, epoch);
+                self.stream = Some(stream);
+                self.backoff = self.policy.initial_backoff;
+                self.stats.reconnects.fetch_add(1, Ordering::Relaxed);
+
+                for event in prelude {
+                    if self.stream.is_some() {
+                        self.write(event, epoch);
+                    }
+                }
+                if self.stream.is_some() {
+                    self.link.connected.store(true, Ordering::Relaxed);
+                }
+            }
+            Err(e) => {
+                debug!("{}: couldn't reconnect to {}: {}", self.name, self.addr, e);
+                self.back_off();
+            }
+        }
+    }
+
+    /// Writes `event` of `epoch` to the current connection, dropping the connection if that fails.
+    fn write<D: Abomonation>(&mut self, event: &Event<Pair<u64, Duration>, D>, epoch: u64) {
+        self.bytes.clear();
+        unsafe { abomonation::encode(event, &mut self.bytes).expect("event abomonation failed"); }
+
+        let stream = self.stream.as_mut().expect("not connected");
+        match stream.write_all(&self.bytes) {
+            Ok(()) => if let Event::Messages(..) = event {
+                self.stats.written_batches.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                warn!("{}: lost connection to {}, dropping epochs until reconnected: {}", self.name, self.addr, e);
+                self.disconnect();
+                self.drop_epoch(epoch);
+                if let Event::Messages(..) = event {
+                    self.stats.dropped_batches.fetch_add(1, Ordering::Relaxed);
+                }
+                self.back_off();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    use crate::connect::read_dump_header;
+
+    type TestEvent = Event<Pair<u64, Duration>, u64>;
+
+    fn time(epoch: u64) -> Pair<u64, Duration> {
+        Pair::new(epoch, Default::default())
+    }
+
+    fn advance(epoch: u64) -> TestEvent {
+        Event::Progress(vec![(time(epoch), 1), (time(epoch - 1), -1)])
+    }
+
+    #[test]
+    fn prelude() {
+        let mut resume = Resume::<u64>::new();
+        assert!(resume.prelude().is_empty());
+
+        let events = vec![
+            Event::Messages(time(0), vec![1, 2]),
+            advance(1),
+            Event::Messages(time(1), vec![10]),
+            Event::Progress(vec![(time(1), -1), (time(1), 1)]),
+            advance(2),
+        ];
+        let boundaries = events.iter()
+            .map(|event| {
+                let boundary = resume.starts_epoch(event);
+                resume.observe(event);
+                boundary
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(boundaries, vec![false, true, false, false, true]);
+        assert_eq!(resume.prelude(), vec![
+            Event::Messages(time(0), vec![1, 2]),
+            Event::Progress(vec![(time(2), 1), (time(0), -1)]),
+        ]);
+    }
+
+    /// Reads events from `stream` until `count` of them could be decoded.
+    fn read_events(stream: &mut TcpStream, count: usize) -> Vec<TestEvent> {
+        let mut bytes = Vec::new();
+        let mut buffer = [0u8; 1024];
+        loop {
+            let mut events = Vec::new();
+            let mut decoding = bytes.clone();
+            let mut rest = &mut decoding[..];
+            while let Some((event, remaining)) = unsafe { abomonation::decode::<TestEvent>(rest) } {
+                events.push(event.clone());
+                rest = remaining;
+            }
+            if events.len() >= count {
+                return events;
+            }
+
+            let read = stream.read(&mut buffer).expect("couldn't read events");
+            assert!(read > 0, "connection closed after {} events", events.len());
+            bytes.extend_from_slice(&buffer[.. read]);
+        }
+    }
+
+    #[test]
+    fn resume_on_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let policy = ReconnectPolicy {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(10),
+            timeout: Duration::from_secs(1),
+        };
+        let mut writer = ReconnectingWriter::<u64>::new(addr, "test".to_string(), policy).unwrap();
+        let stats = writer.stats();
+
+        let (mut first, _) = listener.accept().unwrap();
+        first.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        read_dump_header(&mut first).unwrap();
+
+        writer.push(Event::Messages(time(0), vec![1, 2]));
+        writer.push(advance(1));
+        writer.push(Event::Messages(time(1), vec![10]));
+        assert_eq!(read_events(&mut first, 3), vec![
+            Event::Messages(time(0), vec![1, 2]),
+            advance(1),
+            Event::Messages(time(1), vec![10]),
+        ]);
+
+        // writing fails once the reader is gone, and it resumes on a new connection
+        drop(first);
+        listener.set_nonblocking(true).unwrap();
+        let mut epoch = 1;
+        let mut second = loop {
+            assert!(epoch < 1000, "writer didn't reconnect");
+            match listener.accept() {
+                Ok((stream, _)) => break stream,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    epoch += 1;
+                    writer.push(advance(epoch));
+                    writer.push(Event::Messages(time(epoch), vec![epoch]));
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Err(e) => panic!("couldn't accept: {}", e),
+            }
+        };
+
+        second.set_nonblocking(false).unwrap();
+        second.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        read_dump_header(&mut second).unwrap();
+        let prelude = read_events(&mut second, 2);
+        assert_eq!(prelude[0], Event::Messages(time(0), vec![1, 2]));
+        match &prelude[1] {
+            Event::Progress(changes) => {
+                assert_eq!(changes.len(), 2);
+                assert!(changes[0].0.first > 1 && changes[0].0.first <= epoch && changes[0].1 == 1);
+                assert_eq!(changes[1], (time(0), -1));
+            }
+            other => panic!("expected progress, got {:?}", other),
+        }
+
+        drop(writer);
+        let counts = stats.counts();
+        assert_eq!(counts.reconnects, 1);
+        assert!(counts.dropped_epochs >= 1 && counts.dropped_epochs < epoch);
+    }
+}
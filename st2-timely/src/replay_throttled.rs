@@ -4,6 +4,7 @@
 //! and throttling the number of epochs in flight that are introduced by it.
 //! It also provides events in order from multiple files. For this to work
 //! properly, all events of one epoch have to be written to the same file.
+//! Replay can be restricted to a window of epochs (cf. `EpochWindow`).
 
 use std::sync::{Arc, atomic::AtomicBool, atomic::Ordering};
 
@@ -11,24 +12,49 @@ use timely::{Data, dataflow::{Scope, Stream}};
 use timely::dataflow::channels::pushers::{Counter as PushCounter, buffer::Buffer as PushBuffer};
 use timely::dataflow::operators::generic::builder_raw::OperatorBuilder;
 use timely::progress::frontier::MutableAntichain;
+use timely::progress::ChangeBatch;
 
 use timely::dataflow::operators::capture::event::{Event, EventIterator};
 
 use st2_logformat::pair::Pair;
 use std::time::Duration;
 
+/// Epochs to replay. The dataflow structure (epoch 0) is always replayed,
+/// as `LogRecord` construction depends on it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EpochWindow {
+    /// First epoch to replay
+    pub from: u64,
+    /// Last epoch to replay, if any. Replay stops once it has passed it.
+    pub to: Option<u64>,
+}
+
+impl Default for EpochWindow {
+    fn default() -> Self {
+        EpochWindow { from: 0, to: None }
+    }
+}
+
+impl EpochWindow {
+    /// Whether events of `epoch` are replayed
+    pub fn contains(&self, epoch: u64) -> bool {
+        epoch == 0 || (epoch >= self.from && self.to.map_or(true, |to| epoch <= to))
+    }
+}
+
 /// Replay a capture stream into a scope with the same timestamp.
 /// This replay operator preserves ordering across an arbitrary amount of files,
 /// and can control how many epochs should be put into flight simultaneously.
 pub trait ReplayThrottled<D: Data + std::fmt::Debug> {
     /// Replays `self` into the provided scope, as a `Stream<S, D>`.
-    fn replay_throttled_into<S: Scope<Timestamp=Pair<u64, Duration>>>(self, worker: usize, scope: &mut S, is_running: Option<Arc<AtomicBool>>, epochs_in_flight: u64) -> Stream<S, D>;
+    /// Only events of epochs in `window` are replayed.
+    fn replay_throttled_into<S: Scope<Timestamp=Pair<u64, Duration>>>(self, worker: usize, scope: &mut S, is_running: Option<Arc<AtomicBool>>, epochs_in_flight: u64, window: EpochWindow) -> Stream<S, D>;
 }
 
 impl<D: Data + std::fmt::Debug, I> ReplayThrottled<D> for I
 where I : IntoIterator,
       <I as IntoIterator>::Item: EventIterator<Pair<u64, Duration>, D>+'static {
-    fn replay_throttled_into<S: Scope<Timestamp=Pair<u64, Duration>>>(self, worker: usize, scope: &mut S, is_running: Option<Arc<AtomicBool>>, epochs_in_flight: u64) -> Stream<S, D> {
+    fn replay_throttled_into<S: Scope<Timestamp=Pair<u64, Duration>>>(self, worker: usize, scope: &mut S, is_running: Option<Arc<AtomicBool>>, epochs_in_flight: u64, window: EpochWindow) -> Stream<S, D> {
         let mut builder = OperatorBuilder::new("ReplayThrottled".to_owned(), scope.clone());
 
         let address = builder.operator_info().address;
@@ -43,6 +69,10 @@ where I : IntoIterator,
         let mut future_progress: Vec<Vec<(Pair<_,_>, i64)>> = Vec::new();
 
         let mut antichain: MutableAntichain<Pair<u64, Duration>> = MutableAntichain::new();
+        // capability changes held back until replay reaches `window`
+        let mut pending: ChangeBatch<Pair<u64, Duration>> = ChangeBatch::new();
+        // whether replay has passed `window`
+        let mut finished = false;
 
         let mut started = false;
 
@@ -65,7 +95,9 @@ where I : IntoIterator,
                     started = true;
                 }
 
-                let running = if let Some(x) = &is_running {
+                let running = if finished {
+                    false
+                } else if let Some(x) = &is_running {
                     x.load(Ordering::Acquire)
                 } else {
                     true
@@ -78,27 +110,29 @@ where I : IntoIterator,
                     if let Some(f) = frontier {
                         // apply future progress where possible
                         future_progress.iter().for_each(|vec| {
-                            if vec[0].0.first <= f.first + epochs_in_flight {
+                            if in_flight(vec, f, epochs_in_flight, &window) {
                                 antichain.update_iter(vec.iter().cloned());
-                                internal[0].extend(vec.iter().cloned());
+                                report(vec, &mut pending, &mut internal[0], &window);
                             }
                         });
-                        future_progress.retain(|vec| vec[0].0.first > f.first + epochs_in_flight);
+                        future_progress.retain(|vec| !in_flight(vec, f, epochs_in_flight, &window));
 
                         // consume new events
                         for event_stream in event_streams.iter_mut() {
                             while let Some(event) = event_stream.next() {
                                 match event {
                                     Event::Progress(ref vec) => {
-                                        if vec[0].0.first <= f.first + epochs_in_flight {
+                                        if in_flight(vec, f, epochs_in_flight, &window) {
                                             antichain.update_iter(vec.iter().cloned());
-                                            internal[0].extend(vec.iter().cloned());
+                                            report(vec, &mut pending, &mut internal[0], &window);
                                         } else {
                                             future_progress.push(vec.clone());
                                             break;
                                         }
                                     },
-                                    Event::Messages(time, data) => { buffer.push((time.clone(), data.clone())); }
+                                    Event::Messages(time, data) => if window.contains(time.first) {
+                                        buffer.push((time.clone(), data.clone()));
+                                    }
                                 }
                             }
                         }
@@ -119,6 +153,12 @@ where I : IntoIterator,
                                 }
                             });
                             buffer.retain(|(time, _data)| time > curr_f);
+
+                            // stop once replay has passed the window
+                            if window.to.map_or(false, |to| curr_f.first > to) {
+                                info!("w{} replay_throttled: passed epoch window {:?}", worker, window);
+                                finished = true;
+                            }
                         } else {
                             // sort buffered events by time
                             buffer.sort_by_key(|(time, _data)| time.clone());
@@ -143,6 +183,7 @@ where I : IntoIterator,
                     output.cease();
                     output.inner().produced().borrow_mut().drain_into(&mut produced[0]);
                 } else {
+                    internal[0].extend(pending.drain());
                     while !antichain.is_empty() {
                         let elements = antichain.frontier().iter().map(|t| (t.clone(), -1)).collect::<Vec<_>>();
                         for (t, c) in elements.iter() {
@@ -161,3 +202,120 @@ where I : IntoIterator,
         stream
     }
 }
+
+/// Whether a progress update `vec` can be applied at frontier `f`.
+/// Updates that advance a capability at the frontier's epoch are always
+/// applied, even if they skip epochs: a stream that picks up a running
+/// computation (cf. `ReconnectingWriter`) jumps from the initial time to
+/// the computation's current epoch, and a sampling computation (cf. `EpochSampling`)
+/// jumps between sampled epochs. Both would otherwise hold back the frontier.
+/// Epochs before `window` aren't throttled, as they are skipped anyway.
+fn in_flight(vec: &[(Pair<u64, Duration>, i64)], f: &Pair<u64, Duration>, epochs_in_flight: u64, window: &EpochWindow) -> bool {
+    vec[0].0.first <= f.first + epochs_in_flight
+        || vec[0].0.first < window.from
+        || vec.iter().any(|(t, diff)| *diff < 0 && t.first <= f.first)
+}
+
+/// Reports capability changes `vec` to `internal`. Changes are held back in
+/// `pending` until replay reaches `window`, so that skipped epochs don't
+/// cause progress tracking work downstream.
+fn report(vec: &[(Pair<u64, Duration>, i64)], pending: &mut ChangeBatch<Pair<u64, Duration>>, internal: &mut ChangeBatch<Pair<u64, Duration>>, window: &EpochWindow) {
+    pending.extend(vec.iter().cloned());
+    let reaches_window = vec.iter().any(|(t, diff)| *diff > 0 && t.first >= window.from);
+    let releases = vec.iter().all(|(_, diff)| *diff < 0);
+    if reaches_window || releases {
+        internal.extend(pending.drain());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use timely::dataflow::operators::Capture;
+    use timely::dataflow::operators::capture::event::EventPusher;
+    use timely::dataflow::operators::capture::event::link::EventLink;
+
+    fn time(epoch: u64) -> Pair<u64, Duration> {
+        Pair::new(epoch, Default::default())
+    }
+
+    fn advance(epoch: u64) -> Vec<(Pair<u64, Duration>, i64)> {
+        vec![(time(epoch), 1), (time(epoch - 1), -1)]
+    }
+
+    #[test]
+    fn window_bounds() {
+        let window = EpochWindow { from: 3, to: Some(5) };
+        assert_eq!((0 .. 8).filter(|epoch| window.contains(*epoch)).collect::<Vec<_>>(), vec![0, 3, 4, 5]);
+
+        let window = EpochWindow { from: 3, to: None };
+        assert!(window.contains(0) && !window.contains(2) && window.contains(1_000));
+        assert!((0 .. 10).all(|epoch| EpochWindow::default().contains(epoch)));
+    }
+
+    #[test]
+    fn hold_back_progress_in_flight() {
+        let window = EpochWindow::default();
+        // two epochs in flight at frontier 3
+        assert!(in_flight(&advance(5), &time(3), 2, &window));
+        assert!(!in_flight(&advance(6), &time(3), 2, &window));
+        // jumps from the frontier are never held back
+        assert!(in_flight(&[(time(9), 1), (time(3), -1)], &time(3), 2, &window));
+        // epochs before the window aren't throttled
+        assert!(in_flight(&advance(6), &time(3), 2, &EpochWindow { from: 7, to: None }));
+    }
+
+    #[test]
+    fn report_once_in_window() {
+        let window = EpochWindow { from: 3, to: None };
+        let mut pending = ChangeBatch::new();
+        let mut internal = ChangeBatch::new();
+
+        report(&advance(1), &mut pending, &mut internal, &window);
+        report(&advance(2), &mut pending, &mut internal, &window);
+        assert!(internal.is_empty());
+
+        report(&advance(3), &mut pending, &mut internal, &window);
+        assert!(pending.is_empty());
+        assert_eq!(internal.drain().collect::<Vec<_>>(), vec![(time(0), -1), (time(3), 1)]);
+
+        // releasing the capability isn't held back
+        let window = EpochWindow { from: 10, to: None };
+        report(&[(time(3), -1)], &mut pending, &mut internal, &window);
+        assert_eq!(internal.drain().collect::<Vec<_>>(), vec![(time(3), -1)]);
+    }
+
+    #[test]
+    fn replay_window() {
+        let captured = timely::execute_directly(|worker| {
+            worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                let link = Rc::new(EventLink::new());
+                let mut pusher = Rc::clone(&link);
+                pusher.push(Event::Messages(time(0), vec![0]));
+                for epoch in 1 ..= 6 {
+                    pusher.push(Event::Progress(advance(epoch)));
+                    pusher.push(Event::Messages(time(epoch), vec![epoch]));
+                }
+                pusher.push(Event::Progress(vec![(time(6), -1)]));
+
+                vec![link].replay_throttled_into(0, scope, None, 1, EpochWindow { from: 3, to: Some(4) }).capture()
+            })
+        });
+
+        let events = captured.try_iter().collect::<Vec<_>>();
+        let data = events.iter()
+            .flat_map(|event| match event {
+                Event::Messages(time, data) => data.iter().map(|x| (time.first, *x)).collect(),
+                Event::Progress(_) => Vec::new(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(data, vec![(0, 0), (3, 3), (4, 4)]);
+
+        // skipped epochs never reach the frontier
+        assert!(events.iter().all(|event| match event {
+            Event::Progress(changes) => changes.iter().all(|(time, _)| time.first == 0 || time.first >= 3),
+            Event::Messages(..) => true,
+        }));
+    }
+}
@@ -0,0 +1,108 @@
+//! Epoch sampling, so that always-on tracing only records some epochs.
+//!
+//! A `PAGLogger` with an `EpochSampling` policy (cf. `AdapterConfig::sampling`)
+//! discards the events of epochs that aren't sampled as soon as they are logged.
+//! Sampled epochs keep their numbers, so ST2 sees gaps in the epoch numbering.
+//! The dataflow structure (epoch 0) is always recorded.
+//!
+//! Every worker decides on its own which epochs to sample. All policies have
+//! to be deterministic in the epoch, so that all workers sample the same epochs
+//! and both sides of remote messages are recorded.
+
+
+/// Decides whether an epoch is sampled, for `EpochSampling::Custom`.
+pub type SamplingCallback = Box<dyn FnMut(u64) -> bool>;
+
+/// Which epochs a `PAGLogger` records
+pub enum EpochSampling {
+    /// Every epoch
+    All,
+    /// Every Nth epoch, i.e., epochs N, 2N, 3N, ...
+    EveryNth(u64),
+    /// A pseudo-random fraction (between 0 and 1) of epochs, selected by hashing the epoch
+    Fraction(f64),
+    /// Epochs for which the callback returns `true`. It's called once
+    /// per epoch and worker, and has to return the same on all workers.
+    Custom(SamplingCallback),
+}
+
+impl Default for EpochSampling {
+    fn default() -> Self {
+        EpochSampling::All
+    }
+}
+
+impl std::fmt::Debug for EpochSampling {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            EpochSampling::All => write!(f, "All"),
+            EpochSampling::EveryNth(n) => write!(f, "EveryNth({})", n),
+            EpochSampling::Fraction(fraction) => write!(f, "Fraction({})", fraction),
+            EpochSampling::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+impl EpochSampling {
+    /// Whether `epoch` is recorded.
+    pub fn samples(&mut self, epoch: u64) -> bool {
+        if epoch == 0 {
+            return true;
+        }
+
+        match self {
+            EpochSampling::All => true,
+            EpochSampling::EveryNth(n) => epoch % *n == 0,
+            EpochSampling::Fraction(fraction) => (mix(epoch) as f64) < *fraction * (u64::max_value() as f64),
+            EpochSampling::Custom(callback) => callback(epoch),
+        }
+    }
+}
+
+/// splitmix64's finalizer, to spread consecutive epochs evenly
+fn mix(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sampled(sampling: &mut EpochSampling, epochs: std::ops::Range<u64>) -> Vec<u64> {
+        epochs.filter(|epoch| sampling.samples(*epoch)).collect()
+    }
+
+    #[test]
+    fn every_nth() {
+        assert_eq!(sampled(&mut EpochSampling::EveryNth(3), 0 .. 10), vec![0, 3, 6, 9]);
+        assert_eq!(sampled(&mut EpochSampling::EveryNth(1), 0 .. 4), vec![0, 1, 2, 3]);
+        assert_eq!(sampled(&mut EpochSampling::All, 0 .. 4), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn fraction() {
+        let quarter = sampled(&mut EpochSampling::Fraction(0.25), 1 .. 10_001);
+        assert!(quarter.len() > 2_200 && quarter.len() < 2_800, "sampled {} of 10000 epochs", quarter.len());
+        // all workers sample the same epochs
+        assert_eq!(sampled(&mut EpochSampling::Fraction(0.25), 1 .. 10_001), quarter);
+
+        assert_eq!(sampled(&mut EpochSampling::Fraction(1.0), 0 .. 100).len(), 100);
+        assert_eq!(sampled(&mut EpochSampling::Fraction(1e-12), 0 .. 100), vec![0]);
+    }
+
+    #[test]
+    fn custom() {
+        let mut sampling = EpochSampling::Custom(Box::new(|epoch| epoch % 5 == 2));
+        assert_eq!(sampled(&mut sampling, 0 .. 13), vec![0, 2, 7, 12]);
+
+        // the structure epoch is sampled without asking
+        let mut sampling = EpochSampling::Custom(Box::new(|epoch| {
+            assert!(epoch > 0);
+            false
+        }));
+        assert_eq!(sampled(&mut sampling, 0 .. 3), vec![0]);
+    }
+}
@@ -0,0 +1,513 @@
+//! Segmented dumps with an epoch index.
+//!
+//! A `SegmentedWriter` rolls over to a new segment file `{idx}.{segment}.dump`
+//! every N epochs or once a segment has grown beyond a number of bytes
+//! (cf. `AdapterConfig::segmenting`). Every segment after the first starts
+//! with the dataflow structure and the capability of the source at that point,
+//! so that it can be replayed on its own. The index `{idx}.index` maps the
+//! first epoch of every segment to its file, one `<epoch> <file>` line per segment.
+//!
+//! A `SegmentReader` uses the index to start replaying at an arbitrary epoch,
+//! chaining the segments from there on.
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use abomonation::Abomonation;
+
+use timely::dataflow::operators::capture::event::{Event, EventIterator, EventPusher};
+use timely::dataflow::operators::capture::EventReader;
+
+use st2_logformat::pair::Pair;
+
+use crate::background::WriterStats;
+use crate::compression::{self, Compression};
+use crate::connect::{read_dump_header, write_dump_header};
+use crate::reconnect::Resume;
+
+
+/// When a `SegmentedWriter` rolls over to a new segment. Segments only
+/// ever end at epoch boundaries, so they might exceed the byte limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Segmenting {
+    /// Maximum number of epochs per segment
+    pub epochs: Option<u64>,
+    /// Number of (uncompressed) bytes after which a segment ends
+    /// with the current epoch
+    pub bytes: Option<u64>,
+}
+
+/// Writes events to segment files in a directory, and indexes them.
+/// If writing a segment fails, the error is logged and events are dropped
+/// until the next epoch, which starts a new segment.
+pub struct SegmentedWriter<D> {
+    dir: PathBuf,
+    /// Writer index, i.e., the dump's name
+    idx: usize,
+    segmenting: Segmenting,
+    compression: Option<Compression>,
+    index: File,
+    /// The current segment, if writing it didn't fail
+    file: Option<Box<dyn Write>>,
+    segment: u64,
+    segment_epochs: u64,
+    segment_bytes: u64,
+    /// What every new segment starts with
+    resume: Resume<D>,
+    stats: Arc<WriterStats>,
+    /// Reused serialization buffer
+    bytes: Vec<u8>,
+}
+
+impl<D: Abomonation + Clone> SegmentedWriter<D> {
+    /// Creates the first segment and the index for writer `idx` in `dir`.
+    pub fn new(dir: PathBuf, idx: usize, segmenting: Segmenting, compression: Option<Compression>) -> io::Result<Self> {
+        let index_path = dir.join(format!("{}.index", idx));
+        info!("creating {}", index_path.display());
+        let index = OpenOptions::new().create(true).write(true).truncate(true).open(&index_path)?;
+
+        let mut writer = SegmentedWriter {
+            dir,
+            idx,
+            segmenting,
+            compression,
+            index,
+            file: None,
+            segment: 0,
+            segment_epochs: 0,
+            segment_bytes: 0,
+            resume: Resume::new(),
+            stats: Default::default(),
+            bytes: Vec::new(),
+        };
+        writer.file = Some(writer.create_segment(0)?);
+
+        Ok(writer)
+    }
+
+    /// Returns the writer's counters.
+    pub fn stats(&self) -> Arc<WriterStats> {
+        Arc::clone(&self.stats)
+    }
+
+    /// Creates the file for the segment starting at `epoch` and indexes it.
+    fn create_segment(&mut self, epoch: u64) -> io::Result<Box<dyn Write>> {
+        let mut name = format!("{}.{}.dump", self.idx, self.segment);
+        if let Some(compression) = &self.compression {
+            name = format!("{}.{}", name, compression.extension());
+        }
+
+        let path = self.dir.join(&name);
+        info!("creating {}", path.display());
+        let file = File::create(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("couldn't create {}: {}", path.display(), e)))?;
+
+        writeln!(self.index, "{} {}", epoch, name)?;
+        self.index.flush()?;
+
+        let file = BufWriter::new(file);
+        let mut writer: Box<dyn Write> = match self.compression {
+            Some(compression) => compression.writer(file)?,
+            None => Box::new(file),
+        };
+        // every segment can be replayed on its own
+        write_dump_header(&mut writer)?;
+        Ok(writer)
+    }
+
+    fn should_roll(&self) -> bool {
+        self.segmenting.epochs.map_or(false, |epochs| self.segment_epochs >= epochs)
+            || self.segmenting.bytes.map_or(false, |bytes| self.segment_bytes >= bytes)
+    }
+
+    /// Ends the current segment and starts the next one at `epoch`.
+    fn roll(&mut self, epoch: u64) {
+        // completes the current segment
+        if let Some(mut file) = self.file.take() {
+            if let Err(e) = file.flush() {
+                error!("w{}: couldn't complete segment {}: {}", self.idx, self.segment, e);
+            }
+        }
+
+        self.segment += 1;
+        self.segment_epochs = 0;
+        self.segment_bytes = 0;
+        match self.create_segment(epoch) {
+            Ok(file) => self.file = Some(file),
+            Err(e) => {
+                error!("w{}: couldn't start segment {}, dropping epoch {}: {}", self.idx, self.segment, epoch, e);
+                self.stats.dropped_epochs.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+
+        for event in self.resume.prelude() {
+            self.write(&event);
+        }
+    }
+
+    /// Writes `event` to the current segment, dropping the segment if that fails.
+    fn write(&mut self, event: &Event<Pair<u64, Duration>, D>) {
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => {
+                // the structure isn't lost, it's part of every new segment
+                if let Event::Messages(time, _) = event {
+                    if time.first > 0 {
+                        self.stats.dropped_batches.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                return;
+            }
+        };
+
+        self.bytes.clear();
+        unsafe { abomonation::encode(event, &mut self.bytes).expect("event abomonation failed"); }
+        match file.write_all(&self.bytes) {
+            Ok(()) => {
+                self.segment_bytes += self.bytes.len() as u64;
+                if let Event::Messages(..) = event {
+                    self.stats.written_batches.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Err(e) => {
+                error!("w{}: writing segment {} failed, dropping events until the next epoch: {}", self.idx, self.segment, e);
+                self.file = None;
+                self.stats.dropped_epochs.fetch_add(1, Ordering::Relaxed);
+                if let Event::Messages(..) = event {
+                    self.stats.dropped_batches.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+impl<D: Abomonation + Clone> EventPusher<Pair<u64, Duration>, D> for SegmentedWriter<D> {
+    fn push(&mut self, event: Event<Pair<u64, Duration>, D>) {
+        // the structure epoch neither counts nor ends a segment
+        if self.resume.starts_epoch(&event) && self.resume.cap.first > 0 {
+            self.segment_epochs += 1;
+            // a failed segment is replaced at the next epoch
+            if self.file.is_none() || self.should_roll() {
+                let epoch = match &event {
+                    Event::Progress(updates) => updates.iter().map(|(time, _)| time.first).max().unwrap_or(0),
+                    Event::Messages(time, _) => time.first,
+                };
+                self.roll(epoch);
+            } else if let Some(Err(e)) = self.file.as_mut().map(|file| file.flush()) {
+                // complete epochs are readable while the segment is still written to
+                error!("w{}: writing segment {} failed, dropping events until the next epoch: {}", self.idx, self.segment, e);
+                self.file = None;
+                self.stats.dropped_epochs.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        self.resume.observe(&event);
+        self.write(&event);
+    }
+}
+
+/// Reads the index at `path`, returning the first epoch and path of every segment.
+pub fn read_index(path: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let invalid = |line: &str| io::Error::new(io::ErrorKind::InvalidData, format!("invalid index entry in {}: {}", path.display(), line));
+
+    BufReader::new(File::open(path)?).lines()
+        .map(|line| {
+            let line = line?;
+            let mut parts = line.splitn(2, ' ');
+            let epoch = parts.next().and_then(|x| x.parse().ok()).ok_or_else(|| invalid(&line))?;
+            let name = parts.next().ok_or_else(|| invalid(&line))?;
+            Ok((epoch, dir.join(name)))
+        })
+        .collect()
+}
+
+/// Marks a reader as exhausted once it returns no more bytes,
+/// or can't be read any further (e.g., a segment whose writer failed).
+struct EofReader {
+    reader: Box<dyn Read>,
+    eof: Rc<Cell<bool>>,
+}
+
+impl Read for EofReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf);
+        match &read {
+            Ok(0) if !buf.is_empty() => self.eof.set(true),
+            Err(e) if e.kind() != io::ErrorKind::Interrupted && e.kind() != io::ErrorKind::WouldBlock => self.eof.set(true),
+            _ => {}
+        }
+        read
+    }
+}
+
+/// Replays a (segmented) dump, starting at an arbitrary epoch.
+/// Messages of earlier epochs in the first segment are skipped,
+/// apart from the dataflow structure.
+pub struct SegmentReader<D> {
+    /// Segments yet to be opened
+    segments: VecDeque<PathBuf>,
+    reader: Option<(EventReader<Pair<u64, Duration>, D, EofReader>, Rc<Cell<bool>>)>,
+    /// Whether the current segment continues a previous one, so that
+    /// its structure and initial capability have to be skipped
+    continuation: bool,
+    /// Whether the current segment's initial capability has been skipped
+    skipped_prelude: bool,
+    /// Capability held as of the progress events returned so far
+    cap: Pair<u64, Duration>,
+    from_epoch: u64,
+    /// The event that was returned last
+    event: Option<Event<Pair<u64, Duration>, D>>,
+}
+
+impl<D: Abomonation + Clone> SegmentReader<D> {
+    /// Opens the dump at `path` to be replayed from `from_epoch` on. If `path`
+    /// is an index (`*.index`), replay starts at the segment containing `from_epoch`.
+    /// Otherwise, `path` is replayed as a single (possibly compressed) dump.
+    pub fn open(path: &Path, from_epoch: u64) -> io::Result<Self> {
+        let segments = if path.extension().map_or(false, |ext| ext == "index") {
+            let segments = read_index(path)?;
+            let start = segments.iter().rposition(|(epoch, _)| *epoch <= from_epoch).unwrap_or(0);
+            segments.into_iter().skip(start).map(|(_, path)| path).collect()
+        } else {
+            Some(path.to_path_buf()).into_iter().collect()
+        };
+
+        let mut reader = SegmentReader {
+            segments,
+            reader: None,
+            continuation: false,
+            skipped_prelude: false,
+            cap: Default::default(),
+            from_epoch,
+            event: None,
+        };
+        reader.open_next()?;
+
+        Ok(reader)
+    }
+
+    /// Opens the next segment, if any.
+    fn open_next(&mut self) -> io::Result<()> {
+        if let Some(path) = self.segments.pop_front() {
+            let eof = Rc::new(Cell::new(false));
+            let mut reader = compression::open_dump(&path)?;
+            read_dump_header(&mut reader)
+                .map_err(|e| io::Error::new(e.kind(), format!("couldn't read {}: {}", path.display(), e)))?;
+            let reader = EofReader { reader, eof: Rc::clone(&eof) };
+
+            self.continuation = self.reader.is_some();
+            self.skipped_prelude = false;
+            self.reader = Some((EventReader::new(reader), eof));
+        }
+
+        Ok(())
+    }
+}
+
+impl<D: Abomonation + Clone> EventIterator<Pair<u64, Duration>, D> for SegmentReader<D> {
+    fn next(&mut self) -> Option<&Event<Pair<u64, Duration>, D>> {
+        loop {
+            let (event, eof) = {
+                let (reader, eof) = self.reader.as_mut()?;
+                (reader.next().cloned(), eof.get())
+            };
+
+            match event {
+                Some(event) => {
+                    let (keep, event) = match event {
+                        Event::Messages(time, data) => {
+                            let keep = if time.first == 0 { !self.continuation } else { time.first >= self.from_epoch };
+                            (keep, Event::Messages(time, data))
+                        }
+                        Event::Progress(updates) if self.continuation && !self.skipped_prelude => {
+                            // The previous segment usually advanced the capability already. If
+                            // it was cut short, the capability is moved on from where it ended.
+                            self.skipped_prelude = true;
+                            match updates.iter().find(|(_, diff)| *diff > 0) {
+                                Some((start, _)) if *start != self.cap => {
+                                    let rebased = vec![(start.clone(), 1), (self.cap.clone(), -1)];
+                                    self.cap = start.clone();
+                                    (true, Event::Progress(rebased))
+                                }
+                                _ => (false, Event::Progress(updates)),
+                            }
+                        }
+                        Event::Progress(updates) => {
+                            if let Some((time, _)) = updates.iter().find(|(_, diff)| *diff > 0) {
+                                self.cap = time.clone();
+                            }
+                            (true, Event::Progress(updates))
+                        }
+                    };
+
+                    if keep {
+                        self.event = Some(event);
+                        return self.event.as_ref();
+                    }
+                }
+                // the last segment might still be written to
+                None if eof && !self.segments.is_empty() => self.open_next().expect("couldn't open segment"),
+                None => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    type TestEvent = Event<Pair<u64, Duration>, u64>;
+
+    fn time(epoch: u64) -> Pair<u64, Duration> {
+        Pair::new(epoch, Default::default())
+    }
+
+    fn advance(epoch: u64) -> TestEvent {
+        Event::Progress(vec![(time(epoch), 1), (time(epoch - 1), -1)])
+    }
+
+    /// Creates an empty directory for a test's segments.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("st2-segments-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn read(path: &Path, from_epoch: u64) -> Vec<TestEvent> {
+        let mut reader = SegmentReader::<u64>::open(path, from_epoch).unwrap();
+        let mut events = Vec::new();
+        // the reader returns nothing while it reads more bytes
+        let mut idle = 0;
+        while idle < 10 {
+            match reader.next() {
+                Some(event) => {
+                    events.push(event.clone());
+                    idle = 0;
+                }
+                None => idle += 1,
+            }
+        }
+        events
+    }
+
+    /// Data of `events`, together with its epoch
+    fn data(events: &[TestEvent]) -> Vec<(u64, u64)> {
+        events.iter()
+            .flat_map(|event| match event {
+                Event::Messages(time, data) => data.iter().map(|x| (time.first, *x)).collect(),
+                Event::Progress(_) => Vec::new(),
+            })
+            .collect()
+    }
+
+    /// Progress changes of `events`, consolidated
+    fn progress(events: &[TestEvent]) -> Vec<(u64, i64)> {
+        let mut changes: Vec<(u64, i64)> = Vec::new();
+        for event in events {
+            if let Event::Progress(updates) = event {
+                for (time, diff) in updates {
+                    match changes.iter_mut().find(|(epoch, _)| *epoch == time.first) {
+                        Some(change) => change.1 += diff,
+                        None => changes.push((time.first, *diff)),
+                    }
+                }
+            }
+        }
+        changes.retain(|(_, diff)| *diff != 0);
+        changes.sort();
+        changes
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = test_dir("round-trip");
+        let mut writer = SegmentedWriter::new(dir.clone(), 0, Segmenting { epochs: Some(2), bytes: None }, None).unwrap();
+        writer.push(Event::Messages(time(0), vec![0]));
+        for epoch in 1 ..= 5 {
+            writer.push(advance(epoch));
+            writer.push(Event::Messages(time(epoch), vec![epoch]));
+        }
+        drop(writer);
+
+        let index = dir.join("0.index");
+        assert_eq!(read_index(&index).unwrap(), vec![
+            (0, dir.join("0.0.dump")),
+            (3, dir.join("0.1.dump")),
+            (5, dir.join("0.2.dump")),
+        ]);
+
+        // a continuation starts with the structure and the capability at its start
+        let segment = read(&dir.join("0.1.dump"), 0);
+        assert_eq!(segment[.. 3].to_vec(), vec![
+            Event::Messages(time(0), vec![0]),
+            Event::Progress(vec![(time(2), 1), (time(0), -1)]),
+            advance(3),
+        ]);
+
+        // ... which is skipped when chaining segments
+        let events = read(&index, 0);
+        assert_eq!(data(&events), vec![(0, 0), (1, 1), (2, 2), (3, 3), (4, 4), (5, 5)]);
+        assert_eq!(progress(&events), vec![(0, -1), (5, 1)]);
+
+        let events = read(&index, 4);
+        assert_eq!(data(&events), vec![(0, 0), (4, 4), (5, 5)]);
+        assert_eq!(progress(&events), vec![(0, -1), (5, 1)]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    struct Failing;
+
+    impl Write for Failing {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::Other, "disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn start_new_segment_after_write_error() {
+        let dir = test_dir("write-error");
+        let mut writer = SegmentedWriter::new(dir.clone(), 0, Segmenting::default(), None).unwrap();
+        let stats = writer.stats();
+        writer.push(Event::Messages(time(0), vec![0]));
+        writer.push(advance(1));
+        writer.push(Event::Messages(time(1), vec![1]));
+
+        // epoch 2 is lost, epoch 3 starts a new segment
+        writer.file = Some(Box::new(Failing));
+        writer.push(advance(2));
+        writer.push(Event::Messages(time(2), vec![2]));
+        writer.push(advance(3));
+        writer.push(Event::Messages(time(3), vec![3]));
+        drop(writer);
+
+        let counts = stats.counts();
+        assert_eq!((counts.dropped_epochs, counts.dropped_batches), (1, 1));
+
+        let index = dir.join("0.index");
+        assert_eq!(read_index(&index).unwrap(), vec![(0, dir.join("0.0.dump")), (3, dir.join("0.1.dump"))]);
+
+        // the capability is moved on from where the first segment ended
+        let events = read(&index, 0);
+        assert_eq!(data(&events), vec![(0, 0), (1, 1), (3, 3)]);
+        assert_eq!(progress(&events), vec![(0, -1), (3, 1)]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
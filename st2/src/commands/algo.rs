@@ -19,28 +19,28 @@ use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
 use std::collections::hash_map::DefaultHasher;
+use std::rc::Rc;
+use std::cell::RefCell;
 
 use st2_logformat::pair::Pair;
 use st2_logformat::ActivityType;
 
-use tdiag_connect::receive as connect;
-use tdiag_connect::receive::ReplaySource;
+use crate::ingest::LogSource;
 
 
 
 /// Runs graph algorithms on ST2.
 pub fn run(
     timely_configuration: timely::Configuration,
-    replay_source: ReplaySource) -> Result<(), STError> {
+    source: LogSource,
+    options: pag::PagOptions) -> Result<(), STError> {
 
     timely::execute(timely_configuration, move |worker| {
         let index = worker.index();
-
-        // read replayers from file (offline) or TCP stream (online)
-        let readers = connect::make_readers(replay_source.clone(), worker.index(), worker.peers()).expect("couldn't create readers");
+        let peers = worker.peers();
 
         worker.dataflow(|scope| {
-            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)>  = pag::create_pag(scope, readers, index, 1);
+            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)>  = source.create_pag(scope, index, peers, 1, options);
 
             pag
                 .khops()
@@ -135,58 +135,172 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> KHops<S> for Stream<S, (PagEdge,
 
 
 trait KHop<S: Scope<Timestamp = Pair<u64, Duration>>>{
-    fn hop(&self, other: &Stream<S, (Duration, (PagEdge, u64))>) -> Stream<S, (Duration, (PagEdge, u64))>;
+    fn hop(&self, other: &Stream<S, (Duration, (PagEdge, u64))>) -> Stream<S, (Duration, (PagEdge, u64))> {
+        self.hop_with_state(other, Default::default())
+    }
+
+    /// Like `hop`, but keeps the edges it joins in `state`.
+    fn hop_with_state(&self, other: &Stream<S, (Duration, (PagEdge, u64))>, state: Rc<RefCell<HopState>>) -> Stream<S, (Duration, (PagEdge, u64))>;
+}
+
+/// Edges of `KHop::hop`'s inputs, per epoch and key
+#[derive(Default)]
+struct HopState {
+    left: HashMap<u64, HashMap<Duration, Vec<PagEdge>>>,
+    right: HashMap<u64, HashMap<Duration, Vec<PagEdge>>>,
+}
+
+impl HopState {
+    /// Drops the edges of epochs that have `passed`.
+    fn release<F: Fn(&u64) -> bool>(&mut self, passed: F) {
+        self.left.retain(|epoch, _| !passed(epoch));
+        self.right.retain(|epoch, _| !passed(epoch));
+    }
+
+    /// Epochs that edges are kept for
+    #[cfg(test)]
+    fn epochs(&self) -> BTreeSet<u64> {
+        self.left.keys().chain(self.right.keys()).cloned().collect()
+    }
 }
 
 impl<S: Scope<Timestamp = Pair<u64, Duration>>> KHop<S>
     for Stream<S, (Duration, (PagEdge, u64))>
 {
-    fn hop(&self, other: &Stream<S, (Duration, (PagEdge, u64))>) -> Stream<S, (Duration, (PagEdge, u64))> {
+    fn hop_with_state(&self, other: &Stream<S, (Duration, (PagEdge, u64))>, state: Rc<RefCell<HopState>>) -> Stream<S, (Duration, (PagEdge, u64))> {
         use std::convert::TryInto;
         let exchange = Exchange::new(|(x, _): &(Duration, _)| x.as_nanos().try_into().unwrap());
         let exchange2 = Exchange::new(|(x, _): &(Duration, _)| x.as_nanos().try_into().unwrap());
 
-        // @TODO: in this join implementation, state continually grows.
-        // To fix this, check frontier and remove all state that is from older epochs
-        // (cross-epochs join shouldn't happen anyways)
-        self.binary(&other, exchange, exchange2, "HashJoin", |_capability, _info| {
-            let mut map1 = HashMap::<_, Vec<PagEdge>>::new();
-            let mut map2 = HashMap::<_, Vec<PagEdge>>::new();
-
+        // State is kept per epoch. Cross-epoch joins shouldn't happen, so once the
+        // frontier has passed an epoch (i.e., `epoch + 1` for the delayed edges), its state is dropped.
+        self.binary_frontier(&other, exchange, exchange2, "HashJoin", |_capability, _info| {
             let mut vector1 = Vec::new();
             let mut vector2 = Vec::new();
 
             move |input1, input2, output| {
+                let mut state = state.borrow_mut();
+                let HopState { left, right } = &mut *state;
+
                 // Drain first input, check second map, update first map.
                 input1.for_each(|cap, data| {
                     data.swap(&mut vector1);
+                    let epoch = cap.time().first;
                     let mut session = output.session(&cap);
                     for (key, (val1, _)) in vector1.drain(..) {
-                        if let Some(values) = map2.get(&key) {
+                        if let Some(values) = right.get(&epoch).and_then(|m| m.get(&key)) {
                             for val2 in values.iter() {
                                 session.give((val2.source.timestamp, (val2.clone(), val2.duration())));
                             }
                         }
 
                         // weigh with activity duration
-                        map1.entry(key).or_insert(Vec::new()).push(val1);
+                        left.entry(epoch).or_insert(HashMap::new()).entry(key).or_insert(Vec::new()).push(val1);
                     }
                 });
 
                 input2.for_each(|cap, data| {
                     data.swap(&mut vector2);
+                    let epoch = cap.time().first;
                     let mut session = output.session(&cap);
                     for (key, (val2, _)) in vector2.drain(..) {
-                        if let Some(values) = map1.get(&key) {
+                        if let Some(values) = left.get(&epoch).and_then(|m| m.get(&key)) {
                             for _val1 in values.iter() {
                                 session.give((val2.source.timestamp, (val2.clone(), val2.duration())));
                             }
                         }
 
-                        map2.entry(key).or_insert(Vec::new()).push(val2);
+                        right.entry(epoch).or_insert(HashMap::new()).entry(key).or_insert(Vec::new()).push(val2);
                     }
                 });
+
+                // drop state of epochs that have been passed by both frontiers
+                let frontier1 = input1.frontier().frontier();
+                let frontier2 = input2.frontier().frontier();
+                state.release(|epoch| frontier1.iter().chain(frontier2.iter()).all(|t| t.first > *epoch));
             }
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use timely::dataflow::operators::{Capture, Input, Probe};
+    use timely::dataflow::operators::capture::event::Event;
+
+    fn time(epoch: u64) -> Pair<u64, Duration> {
+        Pair::new(epoch, Default::default())
+    }
+
+    /// An edge from `from` to `to` ns, keyed by `key` ns
+    fn keyed(key: u64, from: u64, to: u64) -> (Duration, (PagEdge, u64)) {
+        let edge = PagEdge {
+            source: pag::PagNode { timestamp: Duration::from_nanos(from), ..Default::default() },
+            destination: pag::PagNode { timestamp: Duration::from_nanos(to), ..Default::default() },
+            ..Default::default()
+        };
+        (Duration::from_nanos(key), (edge, 0))
+    }
+
+    /// Steps `worker` until `probe` has passed `epoch`, returning whether it did.
+    fn step_past<A: timely::communication::Allocate>(worker: &mut timely::worker::Worker<A>, probe: &timely::dataflow::ProbeHandle<Pair<u64, Duration>>, epoch: u64) -> bool {
+        for _ in 0 .. 100 {
+            if !probe.less_equal(&time(epoch)) {
+                return true;
+            }
+            worker.step();
+        }
+        !probe.less_equal(&time(epoch))
+    }
+
+    #[test]
+    fn hop_releases_passed_epochs() {
+        let (hops, retained, released, late_hops) = timely::execute_directly(|worker| {
+            let state = Rc::new(RefCell::new(HopState::default()));
+            let (mut left, mut right, probe, captured) = worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                let (left_input, left) = scope.new_input::<(Duration, (PagEdge, u64))>();
+                let (right_input, right) = scope.new_input::<(Duration, (PagEdge, u64))>();
+                let hopped = left
+                    .hop_with_state(&right, Rc::clone(&state))
+                    .map(|(key, (edge, weight))| (key.as_nanos() as u64, edge.destination.timestamp.as_nanos() as u64, weight));
+                (left_input, right_input, hopped.probe(), hopped.capture())
+            });
+
+            let collect = || captured.try_iter().flat_map(|event| match event {
+                Event::Messages(_time, data) => data,
+                Event::Progress(_) => Vec::new(),
+            }).collect::<Vec<_>>();
+
+            // edges are delayed to `epoch + 1` by `khops`
+            left.advance_to(time(2));
+            right.advance_to(time(2));
+            left.send(keyed(20, 10, 20));
+            right.send(keyed(20, 20, 50));
+            right.send(keyed(30, 30, 40));
+            for _ in 0 .. 10 {
+                worker.step();
+            }
+            let hops = collect();
+            let retained = state.borrow().epochs();
+
+            left.advance_to(time(3));
+            right.advance_to(time(3));
+            // isn't joined with epoch 2's edges
+            right.send(keyed(20, 20, 60));
+            assert!(step_past(worker, &probe, 2), "epoch 2 is still pending");
+            let released = state.borrow().epochs();
+
+            left.advance_to(time(4));
+            right.advance_to(time(4));
+            assert!(step_past(worker, &probe, 3), "epoch 3 is still pending");
+
+            (hops, retained, released, collect())
+        });
+
+        assert_eq!(hops, vec![(20, 50, 30)]);
+        assert_eq!(retained, vec![2].into_iter().collect());
+        assert_eq!(released, vec![3].into_iter().collect());
+        assert!(late_hops.is_empty(), "{:?}", late_hops);
+    }
+}
@@ -0,0 +1,440 @@
+use crate::pag;
+use crate::pag::PagEdge;
+use crate::commands::metrics::calculate_hash;
+
+use timely::dataflow::Scope;
+use timely::dataflow::Stream;
+use timely::dataflow::channels::pact::Pipeline;
+use timely::dataflow::operators::Capability;
+use timely::dataflow::operators::inspect::Inspect;
+use timely::dataflow::operators::map::Map;
+use timely::dataflow::operators::concat::Concat;
+use timely::dataflow::operators::broadcast::Broadcast;
+use timely::dataflow::operators::generic::operator::Operator;
+use timely::dataflow::operators::aggregation::aggregate::Aggregate;
+use timely::dataflow::operators::delay::Delay;
+
+use std::collections::HashMap;
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::io::Write;
+
+use st2_logformat::pair::Pair;
+use st2_logformat::{ActivityType, EventType, LogRecord, ChannelId, ChannelInfo, OperatorInfo, Worker};
+
+use crate::ingest::LogSource;
+
+use crate::{csv_field, STError};
+
+
+/// Writes the data volume and message latencies of every channel of `source` per epoch
+/// to `output_path`, and the traffic between every pair of workers to `matrix_path`.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    source: LogSource,
+    output_path: &std::path::Path,
+    matrix_path: &std::path::Path,
+    options: pag::PagOptions) -> Result<(), STError> {
+
+    let file = Arc::new(Mutex::new(std::fs::File::create(output_path).map_err(|e| STError(format!("io error: {}", e)))?));
+    let matrix_file = Arc::new(Mutex::new(std::fs::File::create(matrix_path).map_err(|e| STError(format!("io error: {}", e)))?));
+
+    timely::execute(timely_configuration, move |worker| {
+        let index = worker.index();
+        let peers = worker.peers();
+
+        worker.dataflow(|scope| {
+            let file = Arc::clone(&file);
+            let matrix_file = Arc::clone(&matrix_file);
+
+            if index == 0 {
+                expect_write(writeln!(*file.lock().unwrap(), "epoch,channel_id,source,target,#(messages sent),#(records sent),#(messages received),#(records received),#(latencies),latency_min,latency_p50,latency_p90,latency_p99,latency_max,latency_mean"));
+                expect_write(writeln!(*matrix_file.lock().unwrap(), "epoch,channel_id,sender,receiver,#(messages),#(records)"));
+            }
+
+            let lr_streams = source.create_lrs(scope, index, peers, 1, options.strict);
+            let lrs = lr_streams.lrs.clone();
+            let pag = pag::create_pag_streams_from_lrs(lr_streams, index, options);
+
+            lrs
+                .channel_volumes(&pag.edges)
+                .name_channels(&pag.operators, &pag.channels)
+                .inspect(move |x| {
+                    let latencies = match &x.latencies {
+                        Some(l) => format!("{},{},{},{},{},{},{}", l.count, l.min, l.p50, l.p90, l.p99, l.max, l.mean),
+                        None => "0,,,,,,".to_string(),
+                    };
+                    expect_write(
+                        writeln!(*file.lock().unwrap(),
+                                 "{},{},{},{},{},{},{},{},{}",
+                                 x.epoch, x.channel_id,
+                                 csv_field(x.source_name.as_ref().map(|x| x.as_str()).unwrap_or("")),
+                                 csv_field(x.target_name.as_ref().map(|x| x.as_str()).unwrap_or("")),
+                                 x.messages_sent, x.records_sent, x.messages_received, x.records_received,
+                                 latencies))
+                });
+
+            lrs
+                .channel_matrix()
+                .inspect(move |x| expect_write(
+                    writeln!(*matrix_file.lock().unwrap(),
+                             "{},{},{},{},{},{}",
+                             x.epoch, x.channel_id, x.sender, x.receiver, x.messages, x.records)
+                ));
+        });
+    })
+        .map_err(|x| STError(format!("error in the timely computation: {}", x)))?;
+
+    Ok(())
+}
+
+/// Data exchanged over a channel within an epoch, summed up across workers.
+/// Timely doesn't log the size of messages, so bytes aren't available.
+#[derive(Abomonation, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ChannelVolume {
+    /// Epoch of the messages
+    pub epoch: u64,
+    /// Channel id, which is the same on all workers
+    pub channel_id: ChannelId,
+    /// Name of the channel's source operator, if known
+    pub source_name: Option<String>,
+    /// Name of the channel's target operator, if known
+    pub target_name: Option<String>,
+    /// Messages sent over the channel
+    pub messages_sent: u64,
+    /// Records sent over the channel
+    pub records_sent: u64,
+    /// Messages received from the channel
+    pub messages_received: u64,
+    /// Records received from the channel
+    pub records_received: u64,
+    /// Latencies of the channel's data message edges, if any were matched
+    pub latencies: Option<Latencies>,
+}
+
+/// Distribution of message latencies (in ns)
+#[derive(Abomonation, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Latencies {
+    /// Number of messages
+    pub count: u64,
+    /// Minimum latency
+    pub min: u64,
+    /// Median latency
+    pub p50: u64,
+    /// 90th percentile
+    pub p90: u64,
+    /// 99th percentile
+    pub p99: u64,
+    /// Maximum latency
+    pub max: u64,
+    /// Mean latency
+    pub mean: u64,
+}
+
+impl Latencies {
+    /// Computes the distribution of `latencies`, which mustn't be empty.
+    fn new(mut latencies: Vec<u64>) -> Self {
+        latencies.sort_unstable();
+        // nearest rank
+        let percentile = |p: usize| latencies[((latencies.len() * p + 99) / 100).saturating_sub(1)];
+
+        Latencies {
+            count: latencies.len() as u64,
+            min: latencies[0],
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: latencies[latencies.len() - 1],
+            mean: latencies.iter().sum::<u64>() / latencies.len() as u64,
+        }
+    }
+}
+
+/// Data a worker sent to another worker over a channel within an epoch
+#[derive(Abomonation, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ChannelTraffic {
+    /// Epoch of the messages
+    pub epoch: u64,
+    /// Channel id
+    pub channel_id: ChannelId,
+    /// Sending worker
+    pub sender: Worker,
+    /// Receiving worker
+    pub receiver: Worker,
+    /// Messages sent
+    pub messages: u64,
+    /// Records sent
+    pub records: u64,
+}
+
+/// What's known about a channel's messages
+#[derive(Abomonation, Clone, Debug)]
+enum ChannelEvent {
+    /// A message with a number of records was sent
+    Sent(u64),
+    /// A message with a number of records was received
+    Received(u64),
+    /// A message edge took some time (in ns)
+    Latency(u64),
+}
+
+/// Sums up the data exchanged over channels
+pub trait ChannelVolumes<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Sums up the data messages per epoch and channel, and the latencies of
+    /// the data message `edges` on them. Results are emitted at `epoch + 1`.
+    /// Messages that stay on a worker are only counted if the source computation
+    /// logged them (cf. `AdapterConfig::local_messages`).
+    fn channel_volumes(&self, edges: &Stream<S, (PagEdge, S::Timestamp, isize)>) -> Stream<S, ChannelVolume>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> ChannelVolumes<S> for Stream<S, LogRecord> {
+    fn channel_volumes(&self, edges: &Stream<S, (PagEdge, S::Timestamp, isize)>) -> Stream<S, ChannelVolume> {
+        let messages = self
+            .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+            .flat_map(|lr| {
+                let records = lr.length.unwrap_or(0) as u64;
+                let event = match lr.event_type {
+                    EventType::Sent => Some(ChannelEvent::Sent(records)),
+                    EventType::Received => Some(ChannelEvent::Received(records)),
+                    _ => None,
+                };
+                lr.channel_id
+                    .filter(|_| lr.activity_type == ActivityType::DataMessage)
+                    .and_then(|channel| event.map(|event| ((lr.epoch, channel), event)))
+            });
+
+        let latencies = edges
+            .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+            .flat_map(|(edge, _t, _diff)| edge.channel_id
+                      .filter(|_| edge.edge_type == ActivityType::DataMessage)
+                      .map(|channel| ((edge.source.epoch, channel), ChannelEvent::Latency(edge.duration()))));
+
+        messages
+            .concat(&latencies)
+            .aggregate::<_,(u64, u64, u64, u64, Vec<u64>),_,_,_>(
+                |_key, event, acc| match event {
+                    ChannelEvent::Sent(records) => {
+                        acc.0 += 1;
+                        acc.1 += records;
+                    }
+                    ChannelEvent::Received(records) => {
+                        acc.2 += 1;
+                        acc.3 += records;
+                    }
+                    ChannelEvent::Latency(latency) => acc.4.push(latency),
+                },
+                |(epoch, channel_id), (messages_sent, records_sent, messages_received, records_received, latencies)| ChannelVolume {
+                    epoch, channel_id,
+                    source_name: None,
+                    target_name: None,
+                    messages_sent, records_sent, messages_received, records_received,
+                    latencies: if latencies.is_empty() { None } else { Some(Latencies::new(latencies)) },
+                },
+                |key| calculate_hash(key))
+    }
+}
+
+/// Breaks down the data sent over channels by worker pairs
+pub trait ChannelMatrix<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Sums up the data messages sent per epoch, channel, sender and receiver.
+    /// Results are emitted at `epoch + 1`.
+    fn channel_matrix(&self) -> Stream<S, ChannelTraffic>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> ChannelMatrix<S> for Stream<S, LogRecord> {
+    fn channel_matrix(&self) -> Stream<S, ChannelTraffic> {
+        self
+            .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+            .flat_map(|lr| match (lr.activity_type, lr.event_type, lr.channel_id, lr.remote_worker) {
+                (ActivityType::DataMessage, EventType::Sent, Some(channel), Some(receiver)) =>
+                    Some(((lr.epoch, channel, lr.local_worker, receiver), lr.length.unwrap_or(0) as u64)),
+                _ => None,
+            })
+            .aggregate::<_,(u64, u64),_,_,_>(
+                |_key, records, acc| {
+                    acc.0 += 1;
+                    acc.1 += records;
+                },
+                |(epoch, channel_id, sender, receiver), (messages, records)| ChannelTraffic {
+                    epoch, channel_id, sender, receiver, messages, records,
+                },
+                |key| calculate_hash(key))
+    }
+}
+
+/// Part of the source computation's dataflow structure
+#[derive(Abomonation, Clone, Debug)]
+enum Structure {
+    Operator(OperatorInfo),
+    Channel(ChannelInfo),
+}
+
+/// Resolves channels to the operators they connect
+pub trait NameChannels<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Sets `ChannelVolume::source_name` and `ChannelVolume::target_name` from the source
+    /// computation's `operators` and `channels`. Volumes are held back until the structure
+    /// up to their time is known.
+    fn name_channels(&self, operators: &Stream<S, OperatorInfo>, channels: &Stream<S, ChannelInfo>) -> Stream<S, ChannelVolume>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> NameChannels<S> for Stream<S, ChannelVolume> {
+    fn name_channels(&self, operators: &Stream<S, OperatorInfo>, channels: &Stream<S, ChannelInfo>) -> Stream<S, ChannelVolume> {
+        // volumes of a channel are summed up at a single ST2 peer
+        let structure = operators
+            .map(Structure::Operator)
+            .concat(&channels.map(Structure::Channel))
+            .broadcast();
+
+        self.binary_frontier(&structure, Pipeline, Pipeline, "NameChannels", |_capability, _info| {
+            let mut names: HashMap<(Worker, Vec<usize>), String> = HashMap::new();
+            // the dataflow is the same on all workers, so any worker's channel will do
+            let mut channels: HashMap<ChannelId, ChannelInfo> = HashMap::new();
+            let mut stash: Vec<(Capability<S::Timestamp>, Vec<ChannelVolume>)> = Vec::new();
+
+            let mut vector1 = Vec::new();
+            let mut vector2 = Vec::new();
+
+            move |input1, input2, output| {
+                input2.for_each(|_cap, data| {
+                    data.swap(&mut vector2);
+                    for x in vector2.drain(..) {
+                        match x {
+                            Structure::Operator(operator) => { names.insert((operator.worker, operator.addr), operator.name); }
+                            Structure::Channel(channel) => { channels.entry(channel.id).or_insert(channel); }
+                        }
+                    }
+                });
+
+                input1.for_each(|cap, data| {
+                    data.swap(&mut vector1);
+                    stash.push((cap.delayed(cap.time()), vector1.drain(..).collect()));
+                });
+
+                let frontier = input2.frontier();
+                let (ready, pending): (Vec<_>, Vec<_>) = stash.drain(..)
+                    .partition(|(cap, _)| !frontier.less_equal(cap.time()));
+                stash = pending;
+
+                for (cap, mut volumes) in ready {
+                    for volume in volumes.iter_mut() {
+                        if let Some(channel) = channels.get(&volume.channel_id) {
+                            volume.source_name = names.get(&(channel.worker, channel.source_addr())).cloned();
+                            volume.target_name = names.get(&(channel.worker, channel.target_addr())).cloned();
+                        }
+                    }
+                    output.session(&cap).give_vec(&mut volumes);
+                }
+            }
+        })
+    }
+}
+
+/// Unwraps a write.
+fn expect_write(e: Result<(), std::io::Error>) {
+    e.expect("write failed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pag::{PagNode, TraversalType};
+    use timely::dataflow::operators::{Capture, ToStream};
+    use timely::dataflow::operators::capture::event::Event;
+    use std::sync::mpsc::Receiver;
+    use st2_logformat::ActivityType::{ControlMessage, DataMessage};
+    use st2_logformat::EventType::{Sent, Received};
+
+    fn record(local_worker: Worker, activity_type: ActivityType, event_type: EventType, remote_worker: Worker, channel_id: ChannelId, length: usize) -> LogRecord {
+        LogRecord {
+            seq_no: 0, epoch: 1, timestamp: Duration::default(), local_worker, activity_type, event_type,
+            remote_worker: Some(remote_worker), operator_id: None, channel_id: Some(channel_id), correlator_id: None,
+            length: Some(length), scope: None, outer_scopes: Vec::new(),
+        }
+    }
+
+    fn message(from: Worker, sent: u64, to: Worker, received: u64, channel_id: ChannelId) -> (PagEdge, Pair<u64, Duration>, isize) {
+        let node = |worker_id, t| PagNode { timestamp: Duration::from_nanos(t), worker_id, epoch: 1, seq_no: 0 };
+        (PagEdge {
+            source: node(from, sent), destination: node(to, received), edge_type: DataMessage, traverse: TraversalType::Unbounded,
+            operator_id: None, operator_name: None, length: None, scope: None, outer_scopes: Vec::new(), scope_name: None, channel_id: Some(channel_id)
+        }, Pair::new(1, Default::default()), 1)
+    }
+
+    /// w0 sends two messages over channel 7 to w1, which receives them.
+    /// w1 sends one message over channel 8 to w0, which is never received.
+    fn records() -> Vec<LogRecord> {
+        vec![
+            record(0, DataMessage, Sent, 1, 7, 10),
+            record(1, DataMessage, Received, 0, 7, 10),
+            record(0, DataMessage, Sent, 1, 7, 5),
+            record(1, DataMessage, Received, 0, 7, 5),
+            record(1, DataMessage, Sent, 0, 8, 3),
+            // not data
+            record(0, ControlMessage, Sent, 1, 7, 100),
+        ]
+    }
+
+    fn collect<D>(captured: Receiver<Event<Pair<u64, Duration>, D>>) -> Vec<D> {
+        captured.try_iter().flat_map(|event| match event {
+            Event::Messages(_time, data) => data,
+            Event::Progress(_) => Vec::new(),
+        }).collect()
+    }
+
+    #[test]
+    fn latencies_of_single_message() {
+        assert_eq!(Latencies::new(vec![7]), Latencies { count: 1, min: 7, p50: 7, p90: 7, p99: 7, max: 7, mean: 7 });
+    }
+
+    #[test]
+    fn latencies_of_two_messages() {
+        assert_eq!(Latencies::new(vec![30, 10]), Latencies { count: 2, min: 10, p50: 10, p90: 30, p99: 30, max: 30, mean: 20 });
+    }
+
+    #[test]
+    fn latencies_of_hundred_messages() {
+        assert_eq!(Latencies::new((1 ..= 100).rev().collect()),
+                   Latencies { count: 100, min: 1, p50: 50, p90: 90, p99: 99, max: 100, mean: 50 });
+    }
+
+    #[test]
+    fn volumes_per_channel() {
+        let captured = timely::execute_directly(|worker| {
+            worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                let edges = vec![message(0, 100, 1, 150, 7), message(0, 200, 1, 260, 7)].to_stream(scope);
+                records().to_stream(scope).channel_volumes(&edges).capture()
+            })
+        });
+
+        let mut volumes = collect(captured);
+        volumes.sort_by_key(|x| x.channel_id);
+
+        assert_eq!(volumes, vec![
+            ChannelVolume {
+                epoch: 1, channel_id: 7, source_name: None, target_name: None,
+                messages_sent: 2, records_sent: 15, messages_received: 2, records_received: 15,
+                latencies: Some(Latencies::new(vec![50, 60])),
+            },
+            ChannelVolume {
+                epoch: 1, channel_id: 8, source_name: None, target_name: None,
+                messages_sent: 1, records_sent: 3, messages_received: 0, records_received: 0,
+                latencies: None,
+            },
+        ]);
+    }
+
+    #[test]
+    fn traffic_per_worker_pair() {
+        let captured = timely::execute_directly(|worker| {
+            worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| records().to_stream(scope).channel_matrix().capture())
+        });
+
+        let mut traffic = collect(captured);
+        traffic.sort_by_key(|x| x.channel_id);
+
+        assert_eq!(traffic, vec![
+            ChannelTraffic { epoch: 1, channel_id: 7, sender: 0, receiver: 1, messages: 2, records: 15 },
+            ChannelTraffic { epoch: 1, channel_id: 8, sender: 1, receiver: 0, messages: 1, records: 3 },
+        ]);
+    }
+}
@@ -0,0 +1,61 @@
+use crate::STError;
+
+use timely::dataflow::operators::inspect::Inspect;
+
+use std::sync::{Arc, Mutex};
+use std::io::BufWriter;
+
+use st2_logformat::trace::{TraceEntry, TraceFormat, TraceWriter};
+
+use crate::ingest::LogSource;
+
+
+/// Converts the log traces provided by `source` to the portable
+/// trace format (cf. `st2_logformat::trace`) at `output_path`.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    source: LogSource,
+    output_path: &std::path::Path,
+    format: TraceFormat,
+    strict: bool) -> Result<(), STError> {
+
+    let file = std::fs::File::create(output_path).map_err(|e| STError(format!("io error: {}", e)))?;
+    let writer = TraceWriter::new(BufWriter::new(file), format).map_err(|e| STError(format!("io error: {}", e)))?;
+    let writer = Arc::new(Mutex::new(writer));
+    let worker_writer = Arc::clone(&writer);
+
+    timely::execute(timely_configuration, move |worker| {
+        let index = worker.index();
+        let peers = worker.peers();
+
+        worker.dataflow(|scope| {
+            let lr_streams = source.create_lrs(scope, index, peers, 1, strict);
+
+            lr_streams.anomalies
+                .inspect(|x| warn!("skipped malformed events ({:?}): {:?}", x.reason, x.records));
+
+            let writer = Arc::clone(&worker_writer);
+            lr_streams.operators
+                .inspect(move |x| {
+                    writer.lock().unwrap().write(&TraceEntry::Operator(x.clone())).expect("write failed");
+                });
+
+            let writer = Arc::clone(&worker_writer);
+            lr_streams.channels
+                .inspect(move |x| {
+                    writer.lock().unwrap().write(&TraceEntry::Channel(x.clone())).expect("write failed");
+                });
+
+            let writer = Arc::clone(&worker_writer);
+            lr_streams.lrs
+                .inspect(move |x| {
+                    writer.lock().unwrap().write(&TraceEntry::Record(x.clone())).expect("write failed");
+                });
+        });
+    })
+        .map_err(|x| STError(format!("error in the timely computation: {}", x)))?;
+
+    writer.lock().unwrap().flush().map_err(|e| STError(format!("io error: {}", e)))?;
+
+    Ok(())
+}
@@ -0,0 +1,173 @@
+use crate::pag;
+use crate::pag::{PagEdge, PagNode, TraversalType, CollectEpochs};
+use crate::STError;
+
+use timely::dataflow::Scope;
+use timely::dataflow::Stream;
+use timely::dataflow::operators::map::Map;
+use timely::dataflow::operators::inspect::Inspect;
+
+use std::time::Duration;
+use std::collections::{HashMap, HashSet};
+
+use st2_logformat::pair::Pair;
+
+use crate::ingest::LogSource;
+
+
+/// Ranks PAG edges by critical participation per epoch.
+/// The `top` edges of every epoch are logged to `stdout`.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    source: LogSource,
+    top: usize,
+    options: pag::PagOptions) -> Result<(), STError> {
+
+    timely::execute(timely_configuration, move |worker| {
+        let index = worker.index();
+        let peers = worker.peers();
+
+        if index == 0 {
+            println!("epoch,cp,from_epoch,from_timestamp,from_workerid,from_seqno,to_epoch,to_timestamp,to_workerid,to_seqno,edge_type,edge_operatorid,edge_operatorname,edge_length,edge_scopeid,edge_round,edge_channelid");
+        }
+
+        worker.dataflow(|scope| {
+            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)>  = source.create_pag(scope, index, peers, 1, options);
+
+            pag
+                .critical_participation()
+                .inspect(move |(epoch, ranking)| {
+                    for (edge, cp) in ranking.iter().take(top) {
+                        println!("{},{:.6},{:?}", epoch, cp, edge);
+                    }
+                });
+        });
+    })
+        .map_err(|x| STError(format!("error in the timely computation: {}", x)))?;
+
+    Ok(())
+}
+
+
+/// Rank edges of the provided `Stream` by their critical participation.
+pub trait CriticalParticipation<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Computes the critical participation (CP) of every edge per epoch.
+    /// Returns `(epoch, ranking)` at `epoch + 1`, where `ranking` contains all
+    /// traversable edges of the epoch, sorted by descending CP.
+    fn critical_participation(&self) -> Stream<S, (u64, Vec<(PagEdge, f64)>)>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> CriticalParticipation<S> for Stream<S, (PagEdge, S::Timestamp, isize)> {
+    fn critical_participation(&self) -> Stream<S, (u64, Vec<(PagEdge, f64)>)> {
+        self.collect_epochs()
+            .map(|(epoch, edges)| (epoch, rank_edges(&edges)))
+    }
+}
+
+/// Ranks the edges of a single epoch's PAG by critical participation.
+///
+/// Critical paths start at a node without predecessors (the first activity of
+/// a worker in this epoch) and end at a node without successors (its last activity).
+/// They may only cross `TraversalType::Unbounded` edges, so `Waiting` edges never
+/// lie on a critical path. An edge's CP is the fraction of critical paths
+/// it lies on, weighted by the fraction of the epoch's duration it takes up.
+pub fn rank_edges(edges: &[PagEdge]) -> Vec<(PagEdge, f64)> {
+    let first = edges.iter().map(|e| e.source.timestamp.as_nanos()).min();
+    let last = edges.iter().map(|e| e.destination.timestamp.as_nanos()).max();
+    let window = match (first, last) {
+        (Some(first), Some(last)) if last > first => (last - first) as f64,
+        _ => return Vec::new(),
+    };
+
+    let (from_start, to_end, total) = count_paths(edges);
+    if total == 0.0 {
+        return Vec::new();
+    }
+
+    let mut ranking: Vec<(PagEdge, f64)> = edges
+        .iter()
+        .filter(|e| e.traverse == TraversalType::Unbounded)
+        .map(|e| {
+            let before = from_start.get(&e.source).cloned().unwrap_or(0.0);
+            let after = to_end.get(&e.destination).cloned().unwrap_or(0.0);
+            let cp = (before * after / total) * (e.duration() as f64 / window);
+            (e.clone(), cp)
+        })
+        .collect();
+
+    ranking.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+    ranking
+}
+
+/// Counts critical paths through an epoch's PAG.
+/// Returns the number of paths from any start node to each node, the number of
+/// paths from each node to any end node, and the total number of critical paths.
+/// Counts are kept as `f64`, as the number of paths grows exponentially.
+fn count_paths(edges: &[PagEdge]) -> (HashMap<PagNode, f64>, HashMap<PagNode, f64>, f64) {
+    let mut has_pred = HashSet::new();
+    let mut has_succ = HashSet::new();
+    for edge in edges.iter() {
+        has_succ.insert(edge.source);
+        has_pred.insert(edge.destination);
+    }
+
+    let (order, successors) = pag::traversal_order(edges);
+
+    let mut from_start: HashMap<PagNode, f64> = HashMap::new();
+    for node in order.iter() {
+        let is_start = if has_pred.contains(node) { 0.0 } else { 1.0 };
+        let count = from_start.get(node).cloned().unwrap_or(0.0) + is_start;
+        from_start.insert(*node, count);
+
+        for succ in successors.get(node).into_iter().flatten() {
+            *from_start.entry(*succ).or_insert(0.0) += count;
+        }
+    }
+
+    let mut to_end: HashMap<PagNode, f64> = HashMap::new();
+    for node in order.iter().rev() {
+        let is_end = if has_succ.contains(node) { 0.0 } else { 1.0 };
+        let count = successors.get(node).into_iter().flatten()
+            .map(|succ| to_end.get(succ).cloned().unwrap_or(0.0))
+            .sum::<f64>() + is_end;
+        to_end.insert(*node, count);
+    }
+
+    let total = order.iter()
+        .filter(|node| !has_succ.contains(*node))
+        .map(|node| from_start.get(node).cloned().unwrap_or(0.0))
+        .sum();
+
+    (from_start, to_end, total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rank_single_critical_path() {
+        use st2_logformat::ActivityType::{Processing, Waiting, DataMessage};
+
+        let node = |worker_id, seq_no, t| PagNode { timestamp: Duration::from_nanos(t), worker_id, epoch: 1, seq_no };
+        let edge = |source, destination, edge_type, traverse| PagEdge {
+            source, destination, edge_type, traverse, operator_id: None, operator_name: None, length: None,
+            scope: None, outer_scopes: Vec::new(), scope_name: None, channel_id: None
+        };
+
+        // w0 processes, then waits for w1's data message
+        let edges = vec![
+            edge(node(0, 0, 0), node(0, 1, 10), Processing, TraversalType::Unbounded),
+            edge(node(0, 1, 10), node(0, 2, 20), Waiting, TraversalType::Block),
+            edge(node(1, 0, 0), node(1, 1, 5), Processing, TraversalType::Unbounded),
+            edge(node(1, 1, 5), node(0, 2, 20), DataMessage, TraversalType::Unbounded),
+        ];
+
+        let ranking = rank_edges(&edges);
+        assert_eq!(ranking.len(), 3);
+        assert_eq!(ranking[0].0.edge_type, DataMessage);
+        assert!((ranking[0].1 - 0.75).abs() < 1e-9);
+        assert!((ranking[1].1 - 0.25).abs() < 1e-9);
+        assert!(ranking[2].1 == 0.0);
+    }
+}
@@ -0,0 +1,337 @@
+use crate::pag;
+use crate::pag::{PagEdge, PagNode, TraversalType, CollectEpochs};
+use crate::{csv_field, STError};
+
+use timely::dataflow::Scope;
+use timely::dataflow::Stream;
+use timely::dataflow::operators::map::Map;
+use timely::dataflow::operators::inspect::Inspect;
+
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::io::Write;
+use std::convert::TryInto;
+
+use st2_logformat::pair::Pair;
+use st2_logformat::{OperatorId, Worker};
+
+use crate::ingest::LogSource;
+
+use serde::Serialize;
+
+
+/// Output formats for extracted critical paths
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PathFormat {
+    /// One CSV line per critical path edge
+    Csv,
+    /// One JSON object per epoch
+    Json,
+}
+
+/// Extracts the critical path of every epoch in `source` and writes it to `output_path`.
+/// For `PathFormat::Csv`, the operators' and workers' shares of the epochs' wall time
+/// are written to `shares_path`.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    source: LogSource,
+    output_path: &std::path::Path,
+    shares_path: &std::path::Path,
+    format: PathFormat,
+    options: pag::PagOptions) -> Result<(), STError> {
+
+    let file = Arc::new(Mutex::new(std::fs::File::create(output_path).map_err(|e| STError(format!("io error: {}", e)))?));
+    let shares_file = match format {
+        PathFormat::Csv => Some(std::fs::File::create(shares_path).map_err(|e| STError(format!("io error: {}", e)))?),
+        PathFormat::Json => None,
+    };
+    let shares_file = Arc::new(Mutex::new(shares_file));
+
+    timely::execute(timely_configuration, move |worker| {
+        let index = worker.index();
+        let peers = worker.peers();
+
+        worker.dataflow(|scope| {
+            let file = Arc::clone(&file);
+            let shares_file = Arc::clone(&shares_file);
+
+            if index == 0 && format == PathFormat::Csv {
+                expect_write(writeln!(*file.lock().unwrap(), "epoch,position,from_epoch,from_timestamp,from_workerid,from_seqno,to_epoch,to_timestamp,to_workerid,to_seqno,edge_type,edge_operatorid,edge_operatorname,edge_length,edge_scopeid,edge_round,edge_channelid,duration"));
+                if let Some(shares_file) = shares_file.lock().unwrap().as_mut() {
+                    expect_write(writeln!(shares_file, "epoch,wall_time,kind,id,name,share"));
+                }
+            }
+
+            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)>  = source.create_pag(scope, index, peers, 1, options);
+
+            pag
+                .critical_path()
+                .inspect(move |path| {
+                    let mut file = file.lock().unwrap();
+                    match format {
+                        PathFormat::Csv => {
+                            for (position, edge) in path.edges.iter().enumerate() {
+                                expect_write(writeln!(*file, "{},{},{:?},{}", path.epoch, position, edge, edge.duration()));
+                            }
+                            if let Some(shares_file) = shares_file.lock().unwrap().as_mut() {
+                                write_shares(shares_file, path);
+                            }
+
+                            println!("Epoch {}: {} edges on critical path ({:.2}% communication). Workers: {}. Operators: {}.",
+                                     path.epoch,
+                                     path.edges.len(),
+                                     path.communication * 100.0,
+                                     format_shares(&path.workers, |id| format!("w{}", id)),
+                                     format_shares(&path.operators, |id| match path.operator_names.get(&id) {
+                                         Some(name) => format!("{} (op{})", name, id),
+                                         None => format!("op{}", id),
+                                     }));
+                        }
+                        PathFormat::Json => {
+                            let json = serde_json::to_string(path).expect("couldn't serialize critical path");
+                            expect_write(writeln!(*file, "{}", json));
+                        }
+                    }
+                });
+        });
+    })
+        .map_err(|x| STError(format!("error in the timely computation: {}", x)))?;
+
+    Ok(())
+}
+
+fn format_shares<F: Fn(u64) -> String>(shares: &[(u64, f64)], label: F) -> String {
+    shares.iter()
+        .map(|(id, share)| format!("{} {:.2}%", label(*id), share * 100.0))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Writes one CSV line per operator and worker on `path`, and one for communication.
+fn write_shares<W: Write>(writer: &mut W, path: &CriticalPath) {
+    for (id, share) in path.operators.iter() {
+        let name = path.operator_names.get(id).map(|name| csv_field(name)).unwrap_or_default();
+        expect_write(writeln!(writer, "{},{},operator,{},{},{}", path.epoch, path.wall_time, id, name, share));
+    }
+    for (id, share) in path.workers.iter() {
+        expect_write(writeln!(writer, "{},{},worker,{},,{}", path.epoch, path.wall_time, id, share));
+    }
+    expect_write(writeln!(writer, "{},{},communication,,,{}", path.epoch, path.wall_time, path.communication));
+}
+
+/// Unwraps a write.
+fn expect_write(e: Result<(), std::io::Error>) {
+    e.expect("write failed");
+}
+
+
+/// The critical path through an epoch's PAG
+#[derive(Serialize, Clone, Debug)]
+pub struct CriticalPath {
+    /// Epoch this path belongs to
+    pub epoch: u64,
+    /// Wall time of the epoch in ns, from its first to its last `PagNode`
+    pub wall_time: u64,
+    /// Edges on the critical path, in order
+    pub edges: Vec<PagEdge>,
+    /// Share of the epoch's wall time per operator on the path, in descending order
+    pub operators: Vec<(OperatorId, f64)>,
+    /// Names of the operators on the path, if known
+    pub operator_names: HashMap<OperatorId, String>,
+    /// Share of the epoch's wall time per worker (worker-local edges only), in descending order
+    pub workers: Vec<(Worker, f64)>,
+    /// Share of the epoch's wall time spent in remote messages on the path
+    pub communication: f64,
+}
+
+/// Extract critical paths from the provided `Stream`.
+pub trait ExtractCriticalPath<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Extracts the critical path of every epoch.
+    /// Paths are emitted at `epoch + 1`.
+    fn critical_path(&self) -> Stream<S, CriticalPath>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> ExtractCriticalPath<S> for Stream<S, (PagEdge, S::Timestamp, isize)> {
+    fn critical_path(&self) -> Stream<S, CriticalPath> {
+        self.collect_epochs()
+            .flat_map(|(epoch, edges)| longest_path(epoch, &edges))
+    }
+}
+
+/// Finds the longest path (by duration) through an epoch's PAG.
+///
+/// The path starts at the epoch's first `PagNode` (any of them, if several
+/// workers start at the same time), ends at its last `PagNode`, and only crosses
+/// `TraversalType::Unbounded` edges: at a `Waiting` edge, it follows the message
+/// that ended the wait instead, possibly crossing to another worker.
+/// If the last node can only be reached by waiting, the path ends at the latest
+/// node reachable from the first one instead.
+pub fn longest_path(epoch: u64, edges: &[PagEdge]) -> Option<CriticalPath> {
+    let first = edges.iter().map(|e| e.source.timestamp).min()?;
+    let last = edges.iter().map(|e| e.destination.timestamp).max()?;
+    if last <= first {
+        return None;
+    }
+    let wall_time: u64 = (last - first).as_nanos().try_into().unwrap();
+
+    let (order, _) = pag::traversal_order(edges);
+
+    let mut incoming: HashMap<PagNode, Vec<&PagEdge>> = HashMap::new();
+    for edge in edges.iter().filter(|e| e.traverse == TraversalType::Unbounded) {
+        incoming.entry(edge.destination).or_insert(Vec::new()).push(edge);
+    }
+
+    // longest path from the first node to every node reachable from it,
+    // together with the last edge on it
+    let mut longest: HashMap<PagNode, (u64, Option<&PagEdge>)> = HashMap::new();
+    for node in order.iter() {
+        if node.timestamp == first {
+            longest.insert(*node, (0, None));
+            continue;
+        }
+
+        let best = incoming.get(node).into_iter().flatten()
+            .filter_map(|edge| longest.get(&edge.source).map(|(before, _)| (before + edge.duration(), Some(*edge))))
+            .max_by_key(|(duration, _)| *duration);
+        if let Some(best) = best {
+            longest.insert(*node, best);
+        }
+    }
+
+    // the latest reachable node, ties broken deterministically
+    let mut node = *longest.iter()
+        .filter(|(_, (_, edge))| edge.is_some())
+        .max_by_key(|(node, (duration, _))| (node.timestamp, *duration, node.worker_id, node.seq_no))?
+        .0;
+    let mut path = Vec::new();
+    while let Some((_, Some(edge))) = longest.get(&node) {
+        path.push((*edge).clone());
+        node = edge.source;
+    }
+    path.reverse();
+
+    let mut operators: HashMap<OperatorId, u64> = HashMap::new();
+    let mut operator_names: HashMap<OperatorId, String> = HashMap::new();
+    let mut workers: HashMap<Worker, u64> = HashMap::new();
+    let mut communication = 0;
+    for edge in path.iter() {
+        if edge.source.worker_id == edge.destination.worker_id {
+            *workers.entry(edge.source.worker_id).or_insert(0) += edge.duration();
+        } else {
+            communication += edge.duration();
+        }
+
+        if let Some(operator_id) = edge.operator_id {
+            *operators.entry(operator_id).or_insert(0) += edge.duration();
+
+            if let Some(name) = &edge.operator_name {
+                operator_names.insert(operator_id, name.clone());
+            }
+        }
+    }
+
+    let shares = |durations: HashMap<u64, u64>| {
+        let mut shares = durations.into_iter()
+            .map(|(id, duration)| (id, duration as f64 / wall_time as f64))
+            .collect::<Vec<_>>();
+        shares.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+        shares
+    };
+
+    Some(CriticalPath {
+        epoch,
+        wall_time,
+        edges: path,
+        operators: shares(operators),
+        operator_names,
+        workers: shares(workers),
+        communication: communication as f64 / wall_time as f64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use st2_logformat::ActivityType::{self, Processing, Waiting, DataMessage};
+
+    fn node(worker_id: Worker, seq_no: u64, t: u64) -> PagNode {
+        PagNode { timestamp: Duration::from_nanos(t), worker_id, epoch: 1, seq_no }
+    }
+
+    fn edge(source: PagNode, destination: PagNode, edge_type: ActivityType, operator_id: Option<OperatorId>) -> PagEdge {
+        let traverse = if edge_type == Waiting { TraversalType::Block } else { TraversalType::Unbounded };
+        PagEdge {
+            source, destination, edge_type, traverse, operator_id, operator_name: None, length: None,
+            scope: None, outer_scopes: Vec::new(), scope_name: None, channel_id: None
+        }
+    }
+
+    #[test]
+    fn follow_message_that_ended_wait() {
+        // w0 waits for w1's data message, then processes it.
+        // w2's late message to w0 is on a shorter path.
+        let mut join = edge(node(1, 0, 0), node(1, 1, 30), Processing, Some(3));
+        join.operator_name = Some("Join".to_string());
+        let message = edge(node(1, 1, 30), node(0, 2, 40), DataMessage, None);
+        let map = edge(node(0, 2, 40), node(0, 3, 50), Processing, Some(2));
+
+        let edges = vec![
+            edge(node(0, 0, 0), node(0, 1, 10), Processing, Some(1)),
+            edge(node(0, 1, 10), node(0, 2, 40), Waiting, None),
+            map.clone(),
+            join.clone(),
+            message.clone(),
+            edge(node(2, 0, 45), node(0, 3, 50), DataMessage, None),
+        ];
+
+        let path = longest_path(1, &edges).expect("no critical path");
+        assert_eq!(path.epoch, 1);
+        assert_eq!(path.wall_time, 50);
+        assert_eq!(path.edges, vec![join, message, map]);
+        assert_eq!(path.operators, vec![(3, 0.6), (2, 0.2)]);
+        assert_eq!(path.operator_names.get(&3).map(|x| x.as_str()), Some("Join"));
+        assert_eq!(path.workers, vec![(1, 0.6), (0, 0.2)]);
+        assert_eq!(path.communication, 0.2);
+    }
+
+    #[test]
+    fn start_at_first_node() {
+        // w0 waits until 40 for w1, which only starts at 20.
+        // The path can't start at w1, so it ends before w0's wait.
+        let process = edge(node(0, 0, 0), node(0, 1, 10), Processing, Some(1));
+        let edges = vec![
+            process.clone(),
+            edge(node(0, 1, 10), node(0, 2, 40), Waiting, None),
+            edge(node(1, 0, 20), node(1, 1, 35), Processing, Some(2)),
+            edge(node(1, 1, 35), node(0, 2, 40), DataMessage, None),
+        ];
+
+        let path = longest_path(1, &edges).expect("no critical path");
+        assert_eq!(path.wall_time, 40);
+        assert_eq!(path.edges, vec![process]);
+        assert_eq!(path.operators, vec![(1, 0.25)]);
+    }
+
+    #[test]
+    fn shares_csv() {
+        let mut join = edge(node(0, 0, 0), node(0, 1, 30), Processing, Some(3));
+        join.operator_name = Some("Join, left".to_string());
+        let edges = vec![join, edge(node(0, 1, 30), node(1, 2, 40), DataMessage, None)];
+        let path = longest_path(1, &edges).expect("no critical path");
+
+        let mut csv = Vec::new();
+        write_shares(&mut csv, &path);
+        assert_eq!(String::from_utf8(csv).unwrap(), "\
+            1,40,operator,3,\"Join, left\",0.75\n\
+            1,40,worker,0,,0.75\n\
+            1,40,communication,,,0.25\n");
+    }
+
+    #[test]
+    fn no_path_without_duration() {
+        let edges = vec![edge(node(0, 0, 10), node(0, 1, 10), Processing, Some(1))];
+        assert!(longest_path(1, &edges).is_none());
+        assert!(longest_path(1, &[]).is_none());
+    }
+}
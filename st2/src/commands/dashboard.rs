@@ -7,6 +7,10 @@ use crate::{MetricsData, KHopSummaryData};
 use crate::commands::metrics::Metrics;
 use crate::InvariantData;
 use crate::commands::invariants::Invariants;
+use crate::commands::cp::CriticalParticipation;
+use crate::CpSummaryData;
+use crate::commands::imbalance::OperatorImbalances;
+use crate::SkewData;
 use crate::{EpochData, OperatorData, MessageData};
 
 use timely::dataflow::Stream;
@@ -16,21 +20,23 @@ use std::time::Duration;
 use std::sync::mpsc;
 use std::sync::{Mutex, Arc};
 use std::convert::TryInto;
+use std::collections::HashMap;
 
 use st2_logformat::pair::Pair;
 
-use tdiag_connect::receive as connect;
-use tdiag_connect::receive::ReplaySource;
+use crate::ingest::LogSource;
 
 
 /// Creates an online dashboard for ST2.
 pub fn run(
     timely_configuration: timely::Configuration,
-    replay_source: ReplaySource,
+    source: LogSource,
     pag_send: Arc<Mutex<mpsc::Sender<(u64, PagData)>>>,
     epoch_max: Option<u64>,
     operator_max: Option<u64>,
     message_max: Option<u64>,
+    skew_threshold: f64,
+    options: pag::PagOptions,
 ) -> Result<(), STError> {
 
     timely::execute(timely_configuration, move |worker| {
@@ -43,12 +49,12 @@ pub fn run(
         let pag_send6 = pag_send.lock().expect("cannot lock pag_send").clone();
         let pag_send7 = pag_send.lock().expect("cannot lock pag_send").clone();
         let pag_send8 = pag_send.lock().expect("cannot lock pag_send").clone();
-
-        // read replayers from file (offline) or TCP stream (online)
-        let readers = connect::make_readers(replay_source.clone(), worker.index(), worker.peers()).expect("couldn't create readers");
+        let pag_send9 = pag_send.lock().expect("cannot lock pag_send").clone();
+        let pag_send10 = pag_send.lock().expect("cannot lock pag_send").clone();
+        let peers = worker.peers();
 
         worker.dataflow(|scope| {
-            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)>  = pag::create_pag(scope, readers, index, 1);
+            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)>  = source.create_pag(scope, index, peers, 1, options);
 
             // log PAG to socket
             pag.inspect(move |(x, t, _)| {
@@ -94,6 +100,42 @@ pub fn run(
             });
 
 
+            let cp = pag.critical_participation();
+
+            // log critical participation summary to socket
+            cp.inspect(move |(epoch, ranking)| {
+                let mut summary = HashMap::new();
+                for (edge, cp) in ranking.iter() {
+                    *summary.entry((edge.edge_type, edge.source.worker_id)).or_insert(0.0) += cp;
+                }
+
+                for ((a, wf), cp) in summary.into_iter() {
+                    pag_send9
+                        .send((*epoch, PagData::Cp(CpSummaryData { a, wf, cp })))
+                        .expect("cp_summary")
+                }
+            });
+
+
+            let skew = pag.operator_imbalance(skew_threshold);
+
+            // log data skew per operator to socket
+            skew.inspect(move |x| {
+                pag_send10
+                    .send((x.epoch, PagData::Skew(SkewData {
+                        o: x.operator_id,
+                        on: x.operator_name.clone(),
+                        wc: x.workers,
+                        rmm: x.records.max_mean,
+                        rg: x.records.gini,
+                        pmm: x.processing.max_mean,
+                        pg: x.processing.gini,
+                        s: x.skewed,
+                    })))
+                    .expect("skew")
+            });
+
+
             if let Some(epoch_max) = epoch_max {
                 let max = Duration::from_millis(epoch_max);
                 let max_nanos: u64 = max.as_nanos().try_into().unwrap();
@@ -0,0 +1,204 @@
+use crate::pag;
+use crate::pag::{PagEdge, CollectEpochs};
+use crate::STError;
+
+use timely::dataflow::Stream;
+use timely::dataflow::operators::inspect::Inspect;
+
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, HashSet};
+use std::io::{BufWriter, Write};
+
+use st2_logformat::pair::Pair;
+use st2_logformat::{ActivityType, ScopeActivation, Worker};
+
+use crate::ingest::LogSource;
+
+use serde_json::json;
+
+
+/// Export formats for the PAG
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    /// Chrome Trace Event JSON, to be opened in Perfetto or `chrome://tracing`
+    ChromeTrace,
+}
+
+/// Exports the PAG of `source` to `output_path`.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    source: LogSource,
+    output_path: &std::path::Path,
+    format: ExportFormat,
+    options: pag::PagOptions) -> Result<(), STError> {
+
+    let file = std::fs::File::create(output_path).map_err(|e| STError(format!("io error: {}", e)))?;
+    let writer = Arc::new(Mutex::new(match format {
+        ExportFormat::ChromeTrace => ChromeTraceWriter::new(BufWriter::new(file))?,
+    }));
+    let worker_writer = Arc::clone(&writer);
+
+    timely::execute(timely_configuration, move |worker| {
+        let index = worker.index();
+        let peers = worker.peers();
+
+        worker.dataflow(|scope| {
+            let writer = Arc::clone(&worker_writer);
+
+            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)>  = source.create_pag(scope, index, peers, 1, options);
+
+            pag
+                .collect_epochs()
+                .inspect(move |(epoch, edges)| {
+                    writer.lock().unwrap().write_epoch(*epoch, edges).expect("write failed");
+                });
+        });
+    })
+        .map_err(|x| STError(format!("error in the timely computation: {}", x)))?;
+
+    writer.lock().unwrap().finish()?;
+
+    Ok(())
+}
+
+
+/// Writes PAG epochs as Chrome Trace Events.
+///
+/// Every source worker becomes a track (thread) of a single process.
+/// Local edges become duration slices named after their operator,
+/// messages become flow events (between tracks, unless they stay on
+/// a worker), and every epoch
+/// is marked by a global instant event at its first activity.
+/// Scope activations (e.g., loop iterations) become async slices
+/// spanning the activity of their operators.
+pub struct ChromeTraceWriter<W: Write> {
+    out: W,
+    /// No event has been written yet
+    first: bool,
+    /// Workers that already have a named track
+    workers: HashSet<Worker>,
+    /// Next unused flow or async event id
+    next_flow_id: u64,
+}
+
+impl<W: Write> ChromeTraceWriter<W> {
+    /// Starts a new trace on `out`.
+    pub fn new(mut out: W) -> std::io::Result<Self> {
+        write!(out, "{{\"displayTimeUnit\":\"ns\",\"traceEvents\":[")?;
+
+        let mut writer = ChromeTraceWriter {
+            out,
+            first: true,
+            workers: HashSet::new(),
+            next_flow_id: 0,
+        };
+        writer.write_event(json!({"name": "process_name", "ph": "M", "pid": 0, "args": {"name": "SnailTrail"}}))?;
+
+        Ok(writer)
+    }
+
+    /// Writes all edges of an epoch's PAG.
+    pub fn write_epoch(&mut self, epoch: u64, edges: &[PagEdge]) -> std::io::Result<()> {
+        if let Some(start) = edges.iter().map(|e| e.source.timestamp).min() {
+            self.write_event(json!({
+                "name": format!("epoch {}", epoch),
+                "ph": "i",
+                "s": "g",
+                "ts": micros(start),
+                "pid": 0,
+                "tid": 0,
+            }))?;
+        }
+
+        let mut activations: BTreeMap<(Worker, ScopeActivation), (Duration, Duration, Option<String>)> = BTreeMap::new();
+        for edge in edges.iter().filter(|e| e.source.worker_id == e.destination.worker_id) {
+            if let Some(scope) = edge.scope {
+                let span = activations.entry((edge.source.worker_id, scope))
+                    .or_insert((edge.source.timestamp, edge.destination.timestamp, edge.scope_name.clone()));
+                span.0 = std::cmp::min(span.0, edge.source.timestamp);
+                span.1 = std::cmp::max(span.1, edge.destination.timestamp);
+            }
+        }
+
+        for ((worker, scope), (start, end, name)) in activations {
+            let id = self.next_flow_id;
+            self.next_flow_id += 1;
+
+            let name = format!("{} round {}", name.unwrap_or_else(|| format!("scope {}", scope.scope_id)), scope.round);
+            self.write_event(json!({
+                "name": name, "cat": "scope", "ph": "b", "id": id,
+                "ts": micros(start), "pid": 0, "tid": worker,
+                "args": {"epoch": epoch, "scope_id": scope.scope_id, "round": scope.round},
+            }))?;
+            self.write_event(json!({
+                "name": name, "cat": "scope", "ph": "e", "id": id,
+                "ts": micros(end), "pid": 0, "tid": worker,
+            }))?;
+        }
+
+        for edge in edges.iter() {
+            for worker in [edge.source.worker_id, edge.destination.worker_id].iter() {
+                if self.workers.insert(*worker) {
+                    self.write_event(json!({"name": "thread_name", "ph": "M", "pid": 0, "tid": worker, "args": {"name": format!("worker {}", worker)}}))?;
+                }
+            }
+
+            if edge.edge_type == ActivityType::DataMessage || edge.edge_type == ActivityType::ControlMessage {
+                let id = self.next_flow_id;
+                self.next_flow_id += 1;
+
+                let name = format!("{:?}", edge.edge_type);
+                self.write_event(json!({
+                    "name": name, "cat": name, "ph": "s", "id": id,
+                    "ts": micros(edge.source.timestamp), "pid": 0, "tid": edge.source.worker_id,
+                    "args": {"epoch": epoch, "length": edge.length},
+                }))?;
+                self.write_event(json!({
+                    "name": name, "cat": name, "ph": "f", "bp": "e", "id": id,
+                    "ts": micros(edge.destination.timestamp), "pid": 0, "tid": edge.destination.worker_id,
+                }))?;
+            } else if edge.source.worker_id == edge.destination.worker_id {
+                let name = match (&edge.operator_name, edge.operator_id) {
+                    (Some(name), _) => name.clone(),
+                    (None, Some(id)) => format!("operator {}", id),
+                    (None, None) => format!("{:?}", edge.edge_type),
+                };
+
+                self.write_event(json!({
+                    "name": name,
+                    "cat": format!("{:?}", edge.edge_type),
+                    "ph": "X",
+                    "ts": micros(edge.source.timestamp),
+                    "dur": edge.duration() as f64 / 1000.0,
+                    "pid": 0,
+                    "tid": edge.source.worker_id,
+                    "args": {"epoch": epoch, "operator_id": edge.operator_id, "operator_name": edge.operator_name, "length": edge.length},
+                }))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Terminates the trace.
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        writeln!(self.out, "]}}")?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn write_event(&mut self, event: serde_json::Value) -> std::io::Result<()> {
+        if !self.first {
+            write!(self.out, ",")?;
+        }
+        self.first = false;
+        write!(self.out, "\n{}", event)?;
+        Ok(())
+    }
+}
+
+/// Trace event timestamps are in µs.
+fn micros(timestamp: Duration) -> f64 {
+    timestamp.as_nanos() as f64 / 1000.0
+}
@@ -0,0 +1,229 @@
+use crate::pag;
+use crate::pag::PagEdge;
+use crate::commands::metrics::calculate_hash;
+
+use timely::dataflow::Scope;
+use timely::dataflow::Stream;
+use timely::dataflow::operators::inspect::Inspect;
+use timely::dataflow::operators::map::Map;
+use timely::dataflow::operators::aggregation::aggregate::Aggregate;
+use timely::dataflow::operators::delay::Delay;
+
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::io::Write;
+
+use st2_logformat::pair::Pair;
+use st2_logformat::{ActivityType, OperatorId, Worker};
+
+use crate::ingest::LogSource;
+
+use crate::{csv_field, STError};
+
+
+/// Writes the distribution of work across workers for every operator of `source`
+/// per epoch to `output_path`, and logs operators whose skew exceeds `threshold`.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    source: LogSource,
+    output_path: &std::path::Path,
+    threshold: f64,
+    options: pag::PagOptions) -> Result<(), STError> {
+
+    let file = Arc::new(Mutex::new(std::fs::File::create(output_path).map_err(|e| STError(format!("io error: {}", e)))?));
+
+    timely::execute(timely_configuration, move |worker| {
+        let index = worker.index();
+        let peers = worker.peers();
+
+        worker.dataflow(|scope| {
+            let file = Arc::clone(&file);
+
+            if index == 0 {
+                expect_write(writeln!(*file.lock().unwrap(), "epoch,operator_id,operator_name,#(workers),\
+                                      #(records),records_max,records_max_worker,records_max/mean,records_gini,\
+                                      t(processing),processing_max,processing_max_worker,processing_max/mean,processing_gini,skewed"));
+            }
+
+            let pag = source.create_pag(scope, index, peers, 1, options);
+
+            pag
+                .operator_imbalance(threshold)
+                .inspect(move |x| {
+                    if x.skewed {
+                        println!("Skew Issue: e{}: Operator {} ({}) is imbalanced across {} workers: \
+                                  w{} got {} of {} records (max/mean {:.2}, Gini {:.2}), \
+                                  w{} processed {}ns of {}ns (max/mean {:.2}, Gini {:.2}). Maximum allowed max/mean is {}.",
+                                 x.epoch, x.operator_id, x.operator_name.as_ref().map(|x| x.as_str()).unwrap_or("?"), x.workers,
+                                 x.records.max_worker, x.records.max, x.records.total, x.records.max_mean, x.records.gini,
+                                 x.processing.max_worker, x.processing.max, x.processing.total, x.processing.max_mean, x.processing.gini,
+                                 threshold);
+                    }
+
+                    expect_write(
+                        writeln!(*file.lock().unwrap(),
+                                 "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                                 x.epoch, x.operator_id, csv_field(x.operator_name.as_ref().map(|x| x.as_str()).unwrap_or("")), x.workers,
+                                 x.records.total, x.records.max, x.records.max_worker, x.records.max_mean, x.records.gini,
+                                 x.processing.total, x.processing.max, x.processing.max_worker, x.processing.max_mean, x.processing.gini,
+                                 x.skewed))
+                });
+        });
+    })
+        .map_err(|x| STError(format!("error in the timely computation: {}", x)))?;
+
+    Ok(())
+}
+
+/// How evenly an operator's work within an epoch is spread across workers
+#[derive(Abomonation, Clone, Debug, PartialEq)]
+pub struct OperatorImbalance {
+    /// Epoch of the work
+    pub epoch: u64,
+    /// Worker-local operator id, which is the same on all workers
+    pub operator_id: OperatorId,
+    /// The operator's name, if known
+    pub operator_name: Option<String>,
+    /// Number of workers that scheduled the operator
+    pub workers: u64,
+    /// Distribution of processed records
+    pub records: Imbalance,
+    /// Distribution of processing time (in ns)
+    pub processing: Imbalance,
+    /// Whether either distribution's max/mean exceeds the threshold
+    pub skewed: bool,
+}
+
+/// Distribution of a quantity across workers
+#[derive(Abomonation, Clone, Debug, Default, PartialEq)]
+pub struct Imbalance {
+    /// Sum across workers
+    pub total: u64,
+    /// Largest value of a worker
+    pub max: u64,
+    /// Worker with the largest value
+    pub max_worker: Worker,
+    /// Ratio of the largest value to the mean, 1 if perfectly balanced
+    /// (and if nothing happened at all)
+    pub max_mean: f64,
+    /// Gini coefficient, from 0 if perfectly balanced to `1 - 1/workers`
+    /// if a single worker did everything
+    pub gini: f64,
+}
+
+impl Imbalance {
+    /// Computes the distribution of per-worker `values`, which mustn't be empty.
+    fn new(values: &[(Worker, u64)]) -> Self {
+        let n = values.len() as f64;
+        let total: u64 = values.iter().map(|(_, x)| x).sum();
+        let (max_worker, max) = values.iter().cloned().max_by_key(|(worker, x)| (*x, std::cmp::Reverse(*worker))).expect("no values");
+
+        if total == 0 {
+            return Imbalance { total, max, max_worker, max_mean: 1.0, gini: 0.0 };
+        }
+
+        let mut sorted: Vec<u64> = values.iter().map(|(_, x)| *x).collect();
+        sorted.sort_unstable();
+        let weighted: f64 = sorted.iter().enumerate().map(|(i, x)| (i + 1) as f64 * *x as f64).sum();
+
+        Imbalance {
+            total,
+            max,
+            max_worker,
+            max_mean: max as f64 * n / total as f64,
+            gini: 2.0 * weighted / (n * total as f64) - (n + 1.0) / n,
+        }
+    }
+}
+
+/// Detects operators whose work is unevenly spread across workers
+pub trait OperatorImbalances<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Computes the distribution of records and processing time per epoch and operator
+    /// across workers, from the operator's `Processing` edges. Their lengths are the records
+    /// of the data messages the operator received. Workers that only scheduled the operator
+    /// without processing anything (`Spinning` edges) count as having done no work.
+    /// Operators are flagged as skewed if either distribution's max/mean exceeds `threshold`.
+    /// Results are emitted at `epoch + 1`.
+    fn operator_imbalance(&self, threshold: f64) -> Stream<S, OperatorImbalance>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> OperatorImbalances<S> for Stream<S, (PagEdge, S::Timestamp, isize)> {
+    fn operator_imbalance(&self, threshold: f64) -> Stream<S, OperatorImbalance> {
+        self
+            .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+            .flat_map(|(edge, _t, _diff)| match (edge.edge_type, edge.operator_id) {
+                (ActivityType::Processing, Some(id)) | (ActivityType::Spinning, Some(id)) =>
+                    Some(((edge.source.epoch, id, edge.source.worker_id), edge)),
+                _ => None,
+            })
+            .aggregate::<_,(u64, u64, Option<String>),_,_,_>(
+                |_key, edge, acc| {
+                    if edge.edge_type == ActivityType::Processing {
+                        acc.0 += edge.length.unwrap_or(0) as u64;
+                        acc.1 += edge.duration();
+                    }
+                    if acc.2.is_none() {
+                        acc.2 = edge.operator_name;
+                    }
+                },
+                |(epoch, id, worker), (records, processing, name)| ((epoch, id), (worker, records, processing, name)),
+                |key| calculate_hash(key))
+            .aggregate::<_,Vec<(Worker, u64, u64, Option<String>)>,_,_,_>(
+                |_key, worker, acc| acc.push(worker),
+                move |(epoch, operator_id), workers| {
+                    let records = Imbalance::new(&workers.iter().map(|(w, r, _, _)| (*w, *r)).collect::<Vec<_>>());
+                    let processing = Imbalance::new(&workers.iter().map(|(w, _, p, _)| (*w, *p)).collect::<Vec<_>>());
+                    let skewed = records.max_mean > threshold || processing.max_mean > threshold;
+
+                    OperatorImbalance {
+                        epoch,
+                        operator_id,
+                        operator_name: workers.iter().filter_map(|(_, _, _, name)| name.clone()).next(),
+                        workers: workers.len() as u64,
+                        records,
+                        processing,
+                        skewed,
+                    }
+                },
+                |key| calculate_hash(key))
+    }
+}
+
+/// Unwraps a write.
+fn expect_write(e: Result<(), std::io::Error>) {
+    e.expect("write failed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn balanced_workers() {
+        let imbalance = Imbalance::new(&[(0, 10), (1, 10), (2, 10), (3, 10)]);
+        assert_eq!((imbalance.total, imbalance.max, imbalance.max_worker), (40, 10, 0));
+        assert_close(imbalance.max_mean, 1.0);
+        assert_close(imbalance.gini, 0.0);
+    }
+
+    #[test]
+    fn single_hot_worker() {
+        let imbalance = Imbalance::new(&[(0, 0), (1, 0), (2, 30), (3, 0)]);
+        assert_eq!((imbalance.total, imbalance.max, imbalance.max_worker), (30, 30, 2));
+        assert_close(imbalance.max_mean, 4.0);
+        // the maximum for four workers
+        assert_close(imbalance.gini, 0.75);
+    }
+
+    #[test]
+    fn idle_workers() {
+        let imbalance = Imbalance::new(&[(0, 0), (1, 0)]);
+        assert_eq!((imbalance.total, imbalance.max, imbalance.max_worker), (0, 0, 0));
+        assert_close(imbalance.max_mean, 1.0);
+        assert_close(imbalance.gini, 0.0);
+    }
+}
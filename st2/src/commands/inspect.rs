@@ -14,21 +14,19 @@ use std::time::Instant;
 
 use st2_logformat::pair::Pair;
 
-use tdiag_connect::receive as connect;
-use tdiag_connect::receive::ReplaySource;
+use crate::ingest::LogSource;
 
 use crate::STError;
 
 /// Inspects a running SnailTrail computation, e.g. for benchmarking of SnailTrail itself.
 pub fn run(
     timely_configuration: timely::Configuration,
-    replay_source: ReplaySource) -> Result<(), STError> {
+    source: LogSource,
+    options: pag::PagOptions) -> Result<(), STError> {
 
     timely::execute(timely_configuration, move |worker| {
         let index = worker.index();
-
-        // read replayers from file (offline) or TCP stream (online)
-        let readers = connect::make_readers(replay_source.clone(), worker.index(), worker.peers()).expect("couldn't create readers");
+        let peers = worker.peers();
 
         let probe: ProbeHandle<Pair<u64, Duration>> = worker.dataflow(|scope| {
             // use timely::dataflow::operators::inspect::Inspect;
@@ -40,7 +38,7 @@ pub fn run(
             //     .inspect(|x| println!("{:?}", x))
             //     .probe()
 
-            pag::create_pag(scope, readers, index, 1)
+            source.create_pag(scope, index, peers, 1, options)
                 // .bench(index)
                 .probe()
         });
@@ -18,27 +18,38 @@ use std::time::Duration;
 use st2_logformat::pair::Pair;
 use st2_logformat::ActivityType;
 
-use tdiag_connect::receive as connect;
-use tdiag_connect::receive::ReplaySource;
+use crate::ingest::LogSource;
 
 
-/// Checks invariants on the log traces provided by `replay_source`.
+/// Checks invariants on the log traces provided by `source`.
 pub fn run(timely_configuration: timely::Configuration,
-           replay_source: ReplaySource,
+           source: LogSource,
            temporal_epoch: Option<u64>,
            temporal_operator: Option<u64>,
            temporal_message: Option<u64>,
-           progress_max: Option<u64>) -> Result<(), STError> {
+           progress_max: Option<u64>,
+           options: pag::PagOptions) -> Result<(), STError> {
 
     timely::execute(timely_configuration, move |worker| {
         let index = worker.index();
         let peers = worker.peers();
 
-        // read replayers from file (offline) or TCP stream (online)
-        let readers = connect::make_readers(replay_source.clone(), worker.index(), worker.peers()).expect("couldn't create readers");
-
         worker.dataflow(|scope| {
-            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)>  = pag::create_pag(scope, readers, index, 1);
+            let streams = source.create_pag_streams(scope, index, peers, 1, options);
+            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)> = streams.edges;
+
+            streams.anomalies
+                .inspect(|x| println!("Trace Issue: skipped malformed events ({:?}): {:?}", x.reason, x.records));
+
+            if let Some(offsets) = streams.clock_offsets {
+                offsets
+                    .inspect(|x| println!("Clock Skew: e{}: w{}'s clock is {}ns ahead of w{}'s (round trip {}ns).",
+                                          x.epoch, x.to, x.offset, x.from, x.round_trip));
+            }
+
+            streams.unmatched
+                .inspect(|x| println!("Message Issue: e{}: {} {:?}s were sent without a matching receive, {} received without a matching send.",
+                                      x.epoch, x.sent, x.activity_type, x.received));
 
             pag.some_progress(peers)
                 .inspect_time(move |t, x| if x.1 < (peers as u64 - 1) {
@@ -63,8 +74,9 @@ pub fn run(timely_configuration: timely::Configuration,
                 let max = Duration::from_millis(temporal_operator);
                 pag.max_operator(max)
                     .inspect(move |(first_edge, last_edge)| {
-                        println!("Temporal Issue: Operator {} in w{}@e{} ({:?}, {} records processed) ran from {:?} to {:?}, taking {:?}. \
+                        println!("Temporal Issue: Operator {} ({}) in w{}@e{} ({:?}, {} records processed) ran from {:?} to {:?}, taking {:?}. \
                                   Maximum allowed is {:?}.",
+                                 first_edge.operator_name.as_ref().map(|x| x.as_str()).unwrap_or("?"),
                                  first_edge.operator_id.expect("not an operator?"),
                                  first_edge.source.worker_id,
                                  first_edge.source.epoch,
@@ -245,6 +257,7 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> Invariants<S> for Stream<S, (Pag
         self
             .map(|(edge, _t, _diff)| edge)
             .filter(|edge| edge.edge_type == ActivityType::ControlMessage || edge.edge_type == ActivityType::DataMessage)
+            // skewed clocks might make messages arrive before they were sent (cf. `--correct-skew`)
             .filter(move |edge|
                     edge.destination.timestamp > edge.source.timestamp &&
                     (edge.destination.timestamp - edge.source.timestamp > max))
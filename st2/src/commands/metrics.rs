@@ -18,17 +18,17 @@ use std::convert::TryInto;
 use st2_logformat::pair::Pair;
 use st2_logformat::ActivityType;
 
-use tdiag_connect::receive as connect;
-use tdiag_connect::receive::ReplaySource;
+use crate::ingest::LogSource;
 
 use crate::STError;
 
 
-/// Computes aggregate metrics for the computation traces in `replay_source`.
+/// Computes aggregate metrics for the computation traces in `source`.
 pub fn run(
     timely_configuration: timely::Configuration,
-    replay_source: ReplaySource,
-    output_path: &std::path::Path) -> Result<(), STError> {
+    source: LogSource,
+    output_path: &std::path::Path,
+    options: pag::PagOptions) -> Result<(), STError> {
 
     let throttle = 1;
 
@@ -36,9 +36,7 @@ pub fn run(
 
     timely::execute(timely_configuration, move |worker| {
         let index = worker.index();
-
-        // read replayers from file (offline) or TCP stream (online)
-        let readers = connect::make_readers(replay_source.clone(), worker.index(), worker.peers()).expect("couldn't create readers");
+        let peers = worker.peers();
 
         worker.dataflow(|scope| {
             let file = Arc::clone(&file);
@@ -47,7 +45,7 @@ pub fn run(
                 expect_write(writeln!(*file.lock().unwrap(), "epoch,from_worker,to_worker,activity_type,#(activities),t(activities),#(records)"));
             }
 
-            let pag = pag::create_pag(scope, readers, index, throttle);
+            let pag = source.create_pag(scope, index, peers, throttle, options);
 
             pag
                 .metrics()
@@ -63,7 +61,7 @@ pub fn run(
     Ok(())
 }
 
-fn calculate_hash<T: Hash>(t: &T) -> u64 {
+pub(crate) fn calculate_hash<T: Hash>(t: &T) -> u64 {
     let mut s = DefaultHasher::new();
     t.hash(&mut s);
     s.finish()
@@ -10,5 +10,21 @@ pub mod inspect;
 pub mod algo;
 /// Invariants checker
 pub mod invariants;
+/// Trace validation
+pub mod validate;
 /// Online dashboard
 pub mod dashboard;
+/// Critical participation
+pub mod cp;
+/// Critical path extraction
+pub mod critical_path;
+/// PAG export to trace viewers
+pub mod export;
+/// Trace conversion to the portable trace format
+pub mod convert;
+/// Activity per scope activation
+pub mod scopes;
+/// Data volume and latency per channel
+pub mod channels;
+/// Data skew across workers
+pub mod imbalance;
@@ -0,0 +1,182 @@
+use crate::pag;
+use crate::pag::PagEdge;
+use crate::commands::metrics::calculate_hash;
+
+use timely::dataflow::Scope;
+use timely::dataflow::Stream;
+use timely::dataflow::operators::inspect::Inspect;
+use timely::dataflow::operators::map::Map;
+use timely::dataflow::operators::aggregation::aggregate::Aggregate;
+use timely::dataflow::operators::delay::Delay;
+
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::io::Write;
+
+use st2_logformat::pair::Pair;
+use st2_logformat::{ActivityType, OperatorId, ScopeActivation, Worker};
+
+use crate::ingest::LogSource;
+
+use crate::STError;
+
+
+/// Writes the activity within every scope activation of `source` to `output_path`.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    source: LogSource,
+    output_path: &std::path::Path,
+    options: pag::PagOptions) -> Result<(), STError> {
+
+    let file = Arc::new(Mutex::new(std::fs::File::create(output_path).map_err(|e| STError(format!("io error: {}", e)))?));
+
+    timely::execute(timely_configuration, move |worker| {
+        let index = worker.index();
+        let peers = worker.peers();
+
+        worker.dataflow(|scope| {
+            let file = Arc::clone(&file);
+
+            if index == 0 {
+                expect_write(writeln!(*file.lock().unwrap(), "epoch,worker,scope_id,scope_name,activation,#(schedules),t(processing),t(scope),#(records)"));
+            }
+
+            let pag = source.create_pag(scope, index, peers, 1, options);
+
+            pag
+                .scope_rounds()
+                .inspect(move |x| expect_write(
+                    writeln!(*file.lock().unwrap(),
+                             "{},{},{},{},{},{},{},{},{}",
+                             x.epoch, x.worker, x.scope_id, x.scope_name.as_ref().map(|x| x.as_str()).unwrap_or(""),
+                             x.activation, x.schedules, x.processing, x.total, x.records)
+                ));
+        });
+    })
+        .map_err(|x| STError(format!("error in the timely computation: {}", x)))?;
+
+    Ok(())
+}
+
+/// Activity within a single activation of a scope on a worker. For loops, activations
+/// approximate iterations, but the loop might be scheduled several times per iteration.
+#[derive(Abomonation, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ScopeRound {
+    /// Epoch of the activation
+    pub epoch: u64,
+    /// Worker the scope was scheduled on
+    pub worker: Worker,
+    /// Operator id of the scope
+    pub scope_id: OperatorId,
+    /// The scope's name, if known
+    pub scope_name: Option<String>,
+    /// Number of earlier activations (schedules) of the scope within the epoch
+    pub activation: u64,
+    /// Schedules of the scope's operators, including those of nested scopes
+    pub schedules: u64,
+    /// Time the scope's operators spent processing records (in ns)
+    pub processing: u64,
+    /// Time spent within the scope, including spinning operators
+    /// and scheduling in between them (in ns)
+    pub total: u64,
+    /// Records processed by the scope's operators
+    pub records: u64,
+}
+
+/// Attributes PAG edges to the scope activations they happened in
+pub trait ScopeRounds<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Sums up the activity of every scope activation per epoch and worker.
+    /// Only edges of operators nested in a scope are considered, each attributed
+    /// to its innermost scope and all scopes enclosing it, so that nested scopes
+    /// roll up into their parents. Results are emitted at `epoch + 1`.
+    fn scope_rounds(&self) -> Stream<S, ScopeRound>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> ScopeRounds<S> for Stream<S, (PagEdge, S::Timestamp, isize)> {
+    fn scope_rounds(&self) -> Stream<S, ScopeRound> {
+        self
+            .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+            .flat_map(|(edge, _t, _diff)| {
+                let key = |scope: &ScopeActivation| (edge.source.epoch, edge.source.worker_id, scope.scope_id, scope.round);
+                // `scope_name` only names the innermost scope
+                let mut scopes = edge.outer_scopes.iter()
+                    .map(|scope| (key(scope), (edge.clone(), false)))
+                    .collect::<Vec<_>>();
+                scopes.extend(edge.scope.as_ref().map(|scope| (key(scope), (edge.clone(), true))));
+                scopes
+            })
+            .aggregate::<_,(u64, u64, u64, u64, Option<String>),_,_,_>(
+                |_key, (edge, innermost), acc| {
+                    let duration = edge.duration();
+                    if edge.edge_type == ActivityType::Processing || edge.edge_type == ActivityType::Spinning {
+                        acc.0 += 1;
+                    }
+                    if edge.edge_type == ActivityType::Processing {
+                        acc.1 += duration;
+                    }
+                    acc.2 += duration;
+                    acc.3 += edge.length.unwrap_or(0) as u64;
+                    if innermost && acc.4.is_none() {
+                        acc.4 = edge.scope_name;
+                    }
+                },
+                |(epoch, worker, scope_id, activation), (schedules, processing, total, records, scope_name)| ScopeRound {
+                    epoch, worker, scope_id, scope_name, activation, schedules, processing, total, records,
+                },
+                |key| calculate_hash(key))
+    }
+}
+
+/// Unwraps a write.
+fn expect_write(e: Result<(), std::io::Error>) {
+    e.expect("write failed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pag::{PagNode, TraversalType};
+    use timely::dataflow::operators::{Capture, ToStream};
+    use timely::dataflow::operators::capture::event::Event;
+
+    fn edge(t: u64, scope: ScopeActivation, outer_scopes: Vec<ScopeActivation>) -> (PagEdge, Pair<u64, Duration>, isize) {
+        let node = |t| PagNode { timestamp: Duration::from_nanos(t), worker_id: 0, epoch: 1, seq_no: t };
+        let edge = PagEdge {
+            source: node(t),
+            destination: node(t + 10),
+            edge_type: ActivityType::Processing,
+            traverse: TraversalType::Unbounded,
+            length: Some(1),
+            scope: Some(scope),
+            outer_scopes,
+            ..Default::default()
+        };
+        (edge, Pair::new(1, Default::default()), 1)
+    }
+
+    #[test]
+    fn nested_scopes_roll_up() {
+        let outer = ScopeActivation { scope_id: 1, round: 0 };
+        let inner = |round| ScopeActivation { scope_id: 5, round };
+
+        let captured = timely::execute_directly(move |worker| {
+            worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                vec![edge(0, outer, Vec::new()), edge(10, inner(0), vec![outer]), edge(20, inner(1), vec![outer])]
+                    .to_stream(scope)
+                    .scope_rounds()
+                    .capture()
+            })
+        });
+
+        let mut rounds = captured.try_iter()
+            .flat_map(|event| match event {
+                Event::Messages(_time, data) => data,
+                Event::Progress(_) => Vec::new(),
+            })
+            .map(|x| (x.scope_id, x.activation, x.schedules, x.total, x.records))
+            .collect::<Vec<_>>();
+        rounds.sort();
+
+        assert_eq!(rounds, vec![(1, 0, 3, 30, 3), (5, 0, 1, 10, 1), (5, 1, 1, 10, 1)]);
+    }
+}
@@ -0,0 +1,390 @@
+use crate::STError;
+
+use timely::dataflow::Scope;
+use timely::dataflow::Stream;
+use timely::dataflow::operators::map::Map;
+use timely::dataflow::operators::filter::Filter;
+use timely::dataflow::operators::delay::Delay;
+use timely::dataflow::operators::concat::Concat;
+use timely::dataflow::operators::inspect::Inspect;
+use timely::dataflow::operators::aggregation::aggregate::Aggregate;
+use timely::dataflow::channels::pact::Pipeline;
+use timely::dataflow::operators::generic::operator::Operator;
+
+use std::time::Duration;
+use std::collections::HashMap;
+
+use st2_logformat::pair::Pair;
+use st2_logformat::{ActivityType, EventType, LogRecord, Worker};
+use ActivityType::{Scheduling, ControlMessage, DataMessage, Merging};
+use EventType::{Start, End, Sent, Received};
+
+use crate::ingest::LogSource;
+
+use abomonation::Abomonation;
+
+
+/// Validates the log traces provided by `source`.
+/// Violations and a per-epoch, per-worker summary are logged to `stdout`.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    source: LogSource,
+    strict: bool) -> Result<(), STError> {
+
+    timely::execute(timely_configuration, move |worker| {
+        let index = worker.index();
+        let peers = worker.peers();
+
+        worker.dataflow(|scope| {
+            let lr_streams = source.create_lrs(scope, index, peers, 1, strict);
+
+            lr_streams.anomalies
+                .inspect(|x| println!("Trace Issue: skipped malformed events ({:?}): {:?}", x.reason, x.records));
+
+            let violations = lr_streams.lrs.validate();
+
+            violations
+                .inspect(|x| println!("Trace Issue: w{}@e{}: {:?} {:?}", x.worker, x.epoch, x.reason, x.records));
+
+            violations
+                .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+                .map(|x| ((x.epoch, x.worker), ()))
+                .aggregate::<_,u64,_,_,_>(
+                    |_key, (), acc| *acc += 1,
+                    |key, acc| (key, acc),
+                    |key| key.0)
+                .inspect(|((epoch, worker), count)| println!("Trace Summary: w{}@e{}: {} violations", worker, epoch, count));
+        });
+    })
+        .map_err(|x| STError(format!("error in the timely computation: {}", x)))?;
+
+    Ok(())
+}
+
+
+/// Why a `LogRecord` timeline isn't sane
+#[derive(Abomonation, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ViolationReason {
+    /// `Start` / `End` for something other than scheduling or maintenance, or
+    /// `Sent` / `Received` for something other than a remote message
+    MismatchedEventType,
+    /// An activity type that instrumentation doesn't emit
+    UnexpectedActivity,
+    /// A schedule started before the previous one ended
+    InterleavedSchedule,
+    /// A schedule ended without having started, or while another operator was scheduled
+    UnmatchedScheduleEnd,
+    /// A schedule hasn't ended within its epoch
+    UnclosedSchedule,
+    /// A record happened before its predecessor on the same worker
+    NonMonotonicTimestamp,
+    /// A record belongs to an earlier epoch than its predecessor on the same worker
+    NonMonotonicEpoch,
+    /// A remote message without a remote worker
+    MissingRemoteWorker,
+    /// A record count at an event that doesn't process records
+    UnexpectedLength,
+    /// A different number of data messages was sent from `worker` to `receiver` than received
+    UnbalancedMessages {
+        /// receiving worker
+        receiver: Worker,
+        /// messages sent
+        sent: u64,
+        /// messages received
+        received: u64,
+    },
+}
+
+/// A violation found in a `LogRecord` timeline
+#[derive(Abomonation, Clone, Debug)]
+pub struct TraceViolation {
+    /// Epoch of the violation
+    pub epoch: u64,
+    /// Worker whose timeline is violated
+    pub worker: Worker,
+    /// What went wrong
+    pub reason: ViolationReason,
+    /// The offending records
+    pub records: Vec<LogRecord>,
+}
+
+impl TraceViolation {
+    /// Creates a violation at the last of the offending `records`.
+    fn new(reason: ViolationReason, records: &[&LogRecord]) -> Self {
+        let record = records.last().expect("violation without records");
+        TraceViolation {
+            epoch: record.epoch,
+            worker: record.local_worker,
+            reason,
+            records: records.iter().map(|x| (*x).clone()).collect(),
+        }
+    }
+}
+
+/// Checks `LogRecord` timelines for sanity, e.g. to tell a bad trace from an ST2 bug.
+pub trait ValidateTrace<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Checks each worker's timeline and the balance of data messages
+    /// between workers per epoch. Returns all violations found.
+    fn validate(&self) -> Stream<S, TraceViolation>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> ValidateTrace<S> for Stream<S, LogRecord> {
+    fn validate(&self) -> Stream<S, TraceViolation> {
+        let timelines = self.unary(Pipeline, "ValidateTimelines", move |_, _| {
+            let mut vector = Vec::new();
+            let mut timelines: HashMap<Worker, Timeline> = HashMap::new();
+
+            move |input, output| {
+                input.for_each(|cap, data| {
+                    data.swap(&mut vector);
+                    let mut session = output.session(&cap);
+                    for lr in vector.drain(..) {
+                        let timeline = timelines.entry(lr.local_worker).or_insert(Default::default());
+                        session.give_iterator(timeline.check(lr).into_iter());
+                    }
+                });
+            }
+        });
+
+        // every data message sent from a worker to another should be received there
+        let balance = self
+            .filter(|x| x.activity_type == DataMessage)
+            .flat_map(|x| {
+                let remote = x.remote_worker?;
+                if x.event_type == Sent {
+                    Some(((x.epoch, x.local_worker, remote), (1, 0)))
+                } else {
+                    Some(((x.epoch, remote, x.local_worker), (0, 1)))
+                }
+            })
+            .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+            .aggregate::<_,(u64, u64),_,_,_>(
+                |_key, (sent, received), acc| {
+                    *acc = (acc.0 + sent, acc.1 + received);
+                },
+                |key, acc| (key, acc),
+                |key| key.0)
+            .filter(|(_key, (sent, received))| sent != received)
+            .map(|((epoch, worker, receiver), (sent, received))| TraceViolation {
+                epoch,
+                worker,
+                reason: ViolationReason::UnbalancedMessages { receiver, sent, received },
+                records: Vec::new(),
+            });
+
+        timelines.concat(&balance)
+    }
+}
+
+/// A single worker's timeline, as seen by the validator
+#[derive(Default)]
+struct Timeline {
+    /// Last record seen
+    last: Option<LogRecord>,
+    /// Start of the currently running schedule
+    open_schedule: Option<LogRecord>,
+}
+
+impl Timeline {
+    /// Checks `lr` against the timeline and advances it.
+    fn check(&mut self, lr: LogRecord) -> Vec<TraceViolation> {
+        let mut violations = Vec::new();
+
+        match (lr.activity_type, lr.event_type) {
+            (Scheduling, Start) | (Scheduling, End) |
+            (Merging, Start) | (Merging, End) |
+            (ControlMessage, Sent) | (ControlMessage, Received) |
+            (DataMessage, Sent) | (DataMessage, Received) => {}
+            (Scheduling, _) | (Merging, _) | (ControlMessage, _) | (DataMessage, _) => {
+                violations.push(TraceViolation::new(ViolationReason::MismatchedEventType, &[&lr]));
+            }
+            _ => violations.push(TraceViolation::new(ViolationReason::UnexpectedActivity, &[&lr])),
+        }
+
+        // outgoing control messages are broadcasts without a remote worker
+        let needs_remote = lr.activity_type == DataMessage ||
+            (lr.activity_type == ControlMessage && lr.event_type == Received);
+        if needs_remote && lr.remote_worker.is_none() {
+            violations.push(TraceViolation::new(ViolationReason::MissingRemoteWorker, &[&lr]));
+        }
+
+        let may_have_length = lr.activity_type == DataMessage ||
+            ((lr.activity_type == Scheduling || lr.activity_type == Merging) && lr.event_type == End);
+        if lr.length.is_some() && !may_have_length {
+            violations.push(TraceViolation::new(ViolationReason::UnexpectedLength, &[&lr]));
+        }
+
+        if let Some(last) = &self.last {
+            if lr.epoch < last.epoch {
+                violations.push(TraceViolation::new(ViolationReason::NonMonotonicEpoch, &[last, &lr]));
+            } else if lr.timestamp < last.timestamp {
+                violations.push(TraceViolation::new(ViolationReason::NonMonotonicTimestamp, &[last, &lr]));
+            }
+        }
+
+        if let Some(open) = &self.open_schedule {
+            if open.epoch != lr.epoch {
+                violations.push(TraceViolation::new(ViolationReason::UnclosedSchedule, &[open]));
+                self.open_schedule = None;
+            }
+        }
+
+        if lr.activity_type == Scheduling {
+            if lr.event_type == Start {
+                if let Some(open) = self.open_schedule.replace(lr.clone()) {
+                    violations.push(TraceViolation::new(ViolationReason::InterleavedSchedule, &[&open, &lr]));
+                }
+            } else if lr.event_type == End {
+                match self.open_schedule.take() {
+                    Some(ref open) if open.operator_id == lr.operator_id => {}
+                    Some(open) => violations.push(TraceViolation::new(ViolationReason::UnmatchedScheduleEnd, &[&open, &lr])),
+                    None => violations.push(TraceViolation::new(ViolationReason::UnmatchedScheduleEnd, &[&lr])),
+                }
+            }
+        }
+
+        self.last = Some(lr);
+        violations
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lr(seq_no: u64, activity_type: ActivityType, event_type: EventType, operator_id: Option<u64>, length: Option<usize>) -> LogRecord {
+        LogRecord {
+            seq_no,
+            epoch: 1,
+            timestamp: Duration::from_nanos(seq_no * 10),
+            local_worker: 0,
+            activity_type,
+            event_type,
+            remote_worker: None,
+            operator_id,
+            channel_id: None,
+            correlator_id: None,
+            length,
+            scope: None,
+            outer_scopes: Vec::new(),
+        }
+    }
+
+    fn check(lrs: Vec<LogRecord>) -> Vec<ViolationReason> {
+        let mut timeline = Timeline::default();
+        lrs.into_iter().flat_map(|x| timeline.check(x)).map(|x| x.reason).collect()
+    }
+
+    #[test]
+    fn merge_within_schedule() {
+        let violations = check(vec![
+            lr(1, Scheduling, Start, Some(3), None),
+            lr(2, Merging, Start, Some(3), None),
+            lr(3, Merging, End, Some(3), Some(100)),
+            lr(4, Scheduling, End, Some(3), Some(10)),
+        ]);
+        assert_eq!(violations, vec![]);
+    }
+
+    #[test]
+    fn mismatched_event_type() {
+        let violations = check(vec![lr(1, Scheduling, Sent, Some(3), None)]);
+        assert_eq!(violations, vec![ViolationReason::MismatchedEventType]);
+    }
+
+    #[test]
+    fn unexpected_activity() {
+        let violations = check(vec![lr(1, ActivityType::Processing, Start, Some(3), None)]);
+        assert_eq!(violations, vec![ViolationReason::UnexpectedActivity]);
+    }
+
+    #[test]
+    fn interleaved_schedule() {
+        let violations = check(vec![
+            lr(1, Scheduling, Start, Some(3), None),
+            lr(2, Scheduling, Start, Some(4), None),
+            lr(3, Scheduling, End, Some(4), None),
+        ]);
+        assert_eq!(violations, vec![ViolationReason::InterleavedSchedule]);
+    }
+
+    #[test]
+    fn unmatched_schedule_end() {
+        let violations = check(vec![
+            lr(1, Scheduling, End, Some(3), None),
+            lr(2, Scheduling, Start, Some(3), None),
+            lr(3, Scheduling, End, Some(4), None),
+        ]);
+        assert_eq!(violations, vec![ViolationReason::UnmatchedScheduleEnd, ViolationReason::UnmatchedScheduleEnd]);
+    }
+
+    #[test]
+    fn unclosed_schedule() {
+        let mut next_epoch = lr(2, ControlMessage, Sent, None, None);
+        next_epoch.epoch = 2;
+        let violations = check(vec![lr(1, Scheduling, Start, Some(3), None), next_epoch]);
+        assert_eq!(violations, vec![ViolationReason::UnclosedSchedule]);
+    }
+
+    #[test]
+    fn non_monotonic_timestamp() {
+        let violations = check(vec![
+            lr(2, ControlMessage, Sent, None, None),
+            lr(1, ControlMessage, Sent, None, None),
+        ]);
+        assert_eq!(violations, vec![ViolationReason::NonMonotonicTimestamp]);
+    }
+
+    #[test]
+    fn non_monotonic_epoch() {
+        let mut earlier = lr(1, ControlMessage, Sent, None, None);
+        earlier.epoch = 2;
+        let violations = check(vec![earlier, lr(2, ControlMessage, Sent, None, None)]);
+        assert_eq!(violations, vec![ViolationReason::NonMonotonicEpoch]);
+    }
+
+    #[test]
+    fn missing_remote_worker() {
+        let violations = check(vec![lr(1, DataMessage, Sent, None, Some(5))]);
+        assert_eq!(violations, vec![ViolationReason::MissingRemoteWorker]);
+    }
+
+    #[test]
+    fn unexpected_length() {
+        let violations = check(vec![lr(1, Scheduling, Start, Some(3), Some(5))]);
+        assert_eq!(violations, vec![ViolationReason::UnexpectedLength]);
+    }
+
+    #[test]
+    fn unbalanced_messages() {
+        use timely::dataflow::operators::{Capture, ToStream};
+        use timely::dataflow::operators::capture::event::Event;
+
+        let message = |seq_no, local_worker, event_type, remote_worker| {
+            let mut message = lr(seq_no, DataMessage, event_type, None, Some(1));
+            message.local_worker = local_worker;
+            message.remote_worker = Some(remote_worker);
+            message
+        };
+
+        // w0 sends two messages to w1, only one of them arrives
+        let captured = timely::execute_directly(move |worker| {
+            worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                vec![message(1, 0, Sent, 1), message(2, 0, Sent, 1), message(1, 1, Received, 0)]
+                    .to_stream(scope)
+                    .validate()
+                    .capture()
+            })
+        });
+
+        let violations = captured.try_iter()
+            .flat_map(|event| match event {
+                Event::Messages(_time, data) => data,
+                Event::Progress(_) => Vec::new(),
+            })
+            .map(|x| (x.epoch, x.worker, x.reason))
+            .collect::<Vec<_>>();
+        assert_eq!(violations, vec![(1, 0, ViolationReason::UnbalancedMessages { receiver: 1, sent: 2, received: 1 })]);
+    }
+}
@@ -0,0 +1,361 @@
+//! Ingestion of `LogRecord`s from different sources.
+//! Besides timely's `*.dump` files and TCP streams, `LogRecord`s can be read
+//! directly from portable traces (cf. `st2_logformat::trace`). This way,
+//! any stream processor whose event log is converted to a trace gets
+//! the full PAG construction and analysis pipeline.
+//! Every source can be restricted to a window of epochs.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use timely::dataflow::{Scope, Stream};
+use timely::dataflow::operators::map::Map;
+use timely::dataflow::operators::generic::operator::{source, empty};
+
+use st2_logformat::{ChannelInfo, LogRecord, OperatorInfo};
+use st2_logformat::pair::Pair;
+use st2_logformat::trace::{TraceEntry, TraceFormat, TraceReader};
+use st2_timely::LrStreams;
+use st2_timely::connect::{CompEvent, Replayer};
+use st2_timely::segments::SegmentReader;
+use st2_timely::replay_throttled::EpochWindow;
+
+use tdiag_connect::receive as connect;
+use tdiag_connect::receive::ReplaySource;
+
+use crate::pag::{self, PagEdge, PagOptions, PagStreams};
+use crate::STError;
+
+
+/// Where the source computation's logs are read from
+#[derive(Clone)]
+pub enum LogInput {
+    /// timely `*.dump` files (offline, optionally compressed or segmented) or TCP streams (online)
+    Replay(ReplaySource),
+    /// Portable trace files of the given format (cf. `check_trace`). Every ST2 peer
+    /// reads them while replaying, but only keeps the records of its own workers.
+    Trace(Vec<PathBuf>, TraceFormat),
+}
+
+/// The source computation's logs to analyze
+#[derive(Clone)]
+pub struct LogSource {
+    /// Where the logs are read from
+    pub input: LogInput,
+    /// Epochs to analyze
+    pub window: EpochWindow,
+}
+
+impl LogSource {
+    /// Creates a source that reads all epochs from `input`.
+    pub fn new(input: LogInput) -> Self {
+        LogSource { input, window: Default::default() }
+    }
+
+    /// Restricts the source to the epochs in `window`.
+    pub fn window(mut self, window: EpochWindow) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Constructs `LogRecord`s for the ST2 peer `index` out of `peers`.
+    /// To be called from within a timely computation.
+    pub fn create_lrs<S: Scope<Timestamp = Pair<u64, Duration>>>(
+        &self,
+        scope: &mut S,
+        index: usize,
+        peers: usize,
+        throttle: u64,
+        strict: bool,
+    ) -> LrStreams<S> {
+        match &self.input {
+            LogInput::Replay(ReplaySource::Files(paths)) => {
+                // opened here instead of by `tdiag_connect` to decompress and chain segments
+                let readers = paths.lock().expect("dump paths poisoned").iter()
+                    .enumerate()
+                    .filter(|(i, _)| i % peers == index)
+                    .filter_map(|(_, path)| path.as_ref())
+                    .map(|path| SegmentReader::<CompEvent>::open(path, self.window.from))
+                    .collect::<io::Result<Vec<_>>>()
+                    .expect("couldn't open dumps");
+                st2_timely::create_lrs(scope, readers, index, throttle, strict, self.window)
+            }
+            LogInput::Replay(replay_source) => {
+                // read replayers from file (offline) or TCP stream (online)
+                let readers: Vec<Replayer<_, _>> = connect::make_readers(replay_source.clone(), index, peers).expect("couldn't create readers");
+                st2_timely::create_lrs(scope, readers, index, throttle, strict, self.window)
+            }
+            LogInput::Trace(paths, format) => {
+                let readers = paths.iter()
+                    .map(|path| open_trace(path, *format))
+                    .collect::<io::Result<Vec<_>>>()
+                    .expect("couldn't open traces");
+                replay_trace(scope, readers, index, peers, self.window)
+            }
+        }
+    }
+
+    /// Creates a PAG, cf. `pag::create_pag`.
+    pub fn create_pag<S: Scope<Timestamp = Pair<u64, Duration>>>(
+        &self,
+        scope: &mut S,
+        index: usize,
+        peers: usize,
+        throttle: u64,
+        options: PagOptions,
+    ) -> Stream<S, (PagEdge, S::Timestamp, isize)> {
+        let lr_streams = self.create_lrs(scope, index, peers, throttle, options.strict);
+        pag::create_pag_from_lrs(lr_streams, index, options)
+    }
+
+    /// Creates a PAG and its auxiliary streams, cf. `pag::create_pag_streams`.
+    pub fn create_pag_streams<S: Scope<Timestamp = Pair<u64, Duration>>>(
+        &self,
+        scope: &mut S,
+        index: usize,
+        peers: usize,
+        throttle: u64,
+        options: PagOptions,
+    ) -> PagStreams<S> {
+        let lr_streams = self.create_lrs(scope, index, peers, throttle, options.strict);
+        pag::create_pag_streams_from_lrs(lr_streams, index, options)
+    }
+}
+
+/// The part of a trace that belongs to a single ST2 peer
+#[derive(Default)]
+pub struct Trace {
+    /// Operators of the source computation
+    pub operators: Vec<OperatorInfo>,
+    /// Channels of the source computation
+    pub channels: Vec<ChannelInfo>,
+    /// `LogRecord`s per epoch, ordered by worker and `seq_no`
+    pub epochs: BTreeMap<u64, Vec<LogRecord>>,
+}
+
+impl Trace {
+    /// Keeps `entry` if it belongs to the ST2 peer `index` out of `peers`
+    /// and to an epoch in `window`. Source workers are spread across ST2 peers,
+    /// so that every peer sees all records of its workers.
+    fn insert(&mut self, entry: TraceEntry, index: usize, peers: usize, window: EpochWindow) {
+        let is_local = |worker: u64| worker as usize % peers == index;
+
+        match entry {
+            TraceEntry::Record(record) => if is_local(record.local_worker) && window.contains(record.epoch) {
+                self.epochs.entry(record.epoch).or_insert(Vec::new()).push(record);
+            }
+            TraceEntry::Operator(operator) => if is_local(operator.worker) {
+                self.operators.push(operator);
+            }
+            TraceEntry::Channel(channel) => if is_local(channel.worker) {
+                self.channels.push(channel);
+            }
+            TraceEntry::Header { .. } => {}
+        }
+    }
+
+    /// Orders every epoch's records by worker and `seq_no`.
+    fn sort(&mut self) {
+        for records in self.epochs.values_mut() {
+            records.sort_by_key(|x| (x.local_worker, x.seq_no));
+        }
+    }
+}
+
+/// Opens the trace file at `path`, checking its header.
+pub fn open_trace(path: &Path, format: TraceFormat) -> io::Result<TraceReader<BufReader<File>>> {
+    TraceReader::new(BufReader::new(File::open(path)?), format)
+}
+
+/// Reads through the trace files at `paths` once upfront, so that broken traces
+/// are reported before the timely computation starts. Nothing is kept in memory.
+pub fn check_trace(paths: &[PathBuf], format: TraceFormat) -> Result<(), STError> {
+    for path in paths {
+        let error = |e: io::Error| STError(format!("couldn't read trace {}: {}", path.display(), e));
+        for entry in open_trace(path, format).map_err(error)? {
+            entry.map_err(error)?;
+        }
+    }
+    Ok(())
+}
+
+/// Selects all `entries` that belong to the ST2 peer `index` out of `peers`
+/// and to an epoch in `window`, grouped by epoch and ordered by `seq_no` within a worker.
+pub fn load_trace<I: IntoIterator<Item = TraceEntry>>(entries: I, index: usize, peers: usize, window: EpochWindow) -> Trace {
+    let mut trace: Trace = Default::default();
+    for entry in entries {
+        trace.insert(entry, index, peers, window);
+    }
+    trace.sort();
+    trace
+}
+
+/// Number of trace entries read per activation of the replay operator
+const READ_FUEL: usize = 10_000;
+
+/// Replays the part of the traces read by `readers` that belongs to the ST2 peer
+/// `index` out of `peers` and to an epoch in `window` into `scope`. Entries are read
+/// a batch per activation, keeping only this peer's records. As traces aren't
+/// sorted by epoch, epochs are emitted once all entries have been read, one epoch
+/// per activation. Operators and channels are emitted at the initial time,
+/// `LogRecord`s at their epoch.
+pub fn replay_trace<S, R>(
+    scope: &mut S,
+    readers: Vec<TraceReader<R>>,
+    index: usize,
+    peers: usize,
+    window: EpochWindow,
+) -> LrStreams<S>
+where
+    S: Scope<Timestamp = Pair<u64, Duration>>,
+    R: BufRead + 'static,
+{
+    let scope: &S = scope;
+
+    let entries = source(scope, "ReplayTrace", move |capability, info| {
+        let activator = scope.activator_for(&info.address[..]);
+
+        let mut cap = Some(capability);
+        // read in order, dropping exhausted readers off the back
+        let mut readers = readers.into_iter().rev().collect::<Vec<_>>();
+        let mut trace: Trace = Default::default();
+        let mut epochs = None;
+
+        move |output| {
+            if let Some(cap_ref) = cap.as_mut() {
+                if !readers.is_empty() {
+                    for _ in 0 .. READ_FUEL {
+                        match readers.last_mut().and_then(|reader| reader.next()) {
+                            Some(entry) => trace.insert(entry.expect("couldn't read trace"), index, peers, window),
+                            None => if readers.pop().is_none() {
+                                break;
+                            }
+                        }
+                    }
+                    activator.activate();
+                    return;
+                }
+
+                if epochs.is_none() {
+                    trace.sort();
+                    let Trace { operators, channels, epochs: records } = std::mem::take(&mut trace);
+                    output.session(cap_ref).give_iterator(operators.into_iter().map(TraceEntry::Operator));
+                    output.session(cap_ref).give_iterator(channels.into_iter().map(TraceEntry::Channel));
+                    epochs = Some(records.into_iter());
+                }
+
+                if let Some((epoch, records)) = epochs.as_mut().and_then(|epochs| epochs.next()) {
+                    cap_ref.downgrade(&Pair::new(epoch, Default::default()));
+                    output.session(cap_ref).give_iterator(records.into_iter().map(TraceEntry::Record));
+                    activator.activate();
+                } else {
+                    cap = None;
+                }
+            }
+        }
+    });
+
+    LrStreams {
+        lrs: entries.flat_map(|x| if let TraceEntry::Record(x) = x { Some(x) } else { None }),
+        operators: entries.flat_map(|x| if let TraceEntry::Operator(x) = x { Some(x) } else { None }),
+        channels: entries.flat_map(|x| if let TraceEntry::Channel(x) = x { Some(x) } else { None }),
+        anomalies: empty(scope),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use st2_logformat::{ActivityType, EventType};
+    use st2_logformat::trace::TraceWriter;
+    use timely::dataflow::operators::Capture;
+    use timely::dataflow::operators::capture::Event;
+
+    fn record(local_worker: u64, epoch: u64, seq_no: u64) -> TraceEntry {
+        TraceEntry::Record(LogRecord {
+            seq_no, epoch, timestamp: Duration::from_nanos(seq_no), local_worker,
+            activity_type: ActivityType::ControlMessage, event_type: EventType::Sent,
+            remote_worker: None, operator_id: None, channel_id: None, correlator_id: None, length: None, scope: None, outer_scopes: Vec::new(),
+        })
+    }
+
+    fn operator(worker: u64) -> TraceEntry {
+        TraceEntry::Operator(OperatorInfo { worker, id: 1, name: "Map".to_string(), addr: vec![0, 1] })
+    }
+
+    fn entries() -> Vec<TraceEntry> {
+        vec![
+            operator(0), operator(1), operator(3),
+            record(1, 2, 5), record(3, 2, 1), record(1, 2, 2), record(0, 2, 1),
+            record(1, 1, 1), record(1, 3, 7), record(3, 3, 2), record(1, 4, 1),
+        ]
+    }
+
+    fn write(entries: &[TraceEntry]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut writer = TraceWriter::new(&mut out, TraceFormat::Json).unwrap();
+        for entry in entries.iter() {
+            writer.write(entry).unwrap();
+        }
+        drop(writer);
+        out
+    }
+
+    #[test]
+    fn load_local_part_of_trace() {
+        let out = write(&entries());
+        let entries = TraceReader::new(&out[..], TraceFormat::Json).unwrap()
+            .collect::<io::Result<Vec<_>>>().unwrap();
+        // workers 1 and 3 belong to the second of two ST2 peers
+        let trace = load_trace(entries, 1, 2, EpochWindow { from: 2, to: Some(3) });
+
+        assert_eq!(trace.operators.iter().map(|x| x.worker).collect::<Vec<_>>(), vec![1, 3]);
+        assert!(trace.channels.is_empty());
+
+        let epochs = trace.epochs.iter()
+            .map(|(epoch, records)| (*epoch, records.iter().map(|x| (x.local_worker, x.seq_no)).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        assert_eq!(epochs, vec![
+            (2, vec![(1, 2), (1, 5), (3, 1)]),
+            (3, vec![(1, 7), (3, 2)]),
+        ]);
+    }
+
+    #[test]
+    fn replay_local_part_of_traces() {
+        // the trace is split across two files, which are both read by every peer
+        let entries = entries();
+        let (first, second) = entries.split_at(5);
+        let files = vec![write(first), write(second)];
+
+        let (lrs, operators) = timely::execute_directly(move |worker| {
+            worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                let readers = files.iter()
+                    .map(|file| TraceReader::new(io::Cursor::new(file.clone()), TraceFormat::Json).unwrap())
+                    .collect::<Vec<_>>();
+                // workers 1 and 3 belong to the second of two ST2 peers
+                let streams = replay_trace(scope, readers, 1, 2, EpochWindow { from: 2, to: Some(3) });
+                (streams.lrs.capture(), streams.operators.capture())
+            })
+        });
+
+        let lrs = lrs.try_iter()
+            .flat_map(|event| match event {
+                Event::Messages(time, data) => data.into_iter().map(|x| (time.first, x.local_worker, x.seq_no)).collect(),
+                Event::Progress(_) => Vec::new(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(lrs, vec![(2, 1, 2), (2, 1, 5), (2, 3, 1), (3, 1, 7), (3, 3, 2)]);
+
+        let operators = operators.try_iter()
+            .flat_map(|event| match event {
+                Event::Messages(time, data) => data.into_iter().map(|x| (time.first, x.worker)).collect(),
+                Event::Progress(_) => Vec::new(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(operators, vec![(0, 1), (0, 3)]);
+    }
+}
@@ -25,6 +25,12 @@ pub mod algo;
 /// Contains the PAG construction
 pub mod pag;
 
+/// Contains clock skew estimation & correction
+pub mod skew;
+
+/// Contains `LogRecord` ingestion from dumps and portable traces
+pub mod ingest;
+
 /// Contains commands to execute ST2
 pub mod commands;
 
@@ -46,6 +52,11 @@ impl From<tdiag_connect::ConnectError> for STError {
     }
 }
 
+/// Quotes a CSV field, so that names containing commas or quotes
+/// (e.g., `Map(x, y)`) don't shift the columns of a row.
+pub(crate) fn csv_field(field: &str) -> String {
+    format!("\"{}\"", field.replace('"', "\"\""))
+}
 
 #[derive(Serialize, Debug)]
 /// Serialization type for socket
@@ -60,6 +71,10 @@ pub enum PagData {
     Met(MetricsData),
     /// invariants
     Inv(InvariantData),
+    /// critical participation
+    Cp(CpSummaryData),
+    /// data skew across workers
+    Skew(SkewData),
 }
 
 #[derive(Serialize, Debug)]
@@ -72,6 +87,30 @@ pub struct KHopSummaryData {
     wac: u64,
 }
 
+#[derive(Serialize, Debug)]
+/// Serialization type for critical participation summaries
+/// edge_type, worker_id, summed critical participation
+pub struct CpSummaryData {
+    a: ActivityType,
+    wf: u64,
+    cp: f64,
+}
+
+#[derive(Serialize, Debug)]
+/// Serialization type for data skew across workers
+/// operator_id, operator_name, #(workers), records max/mean, records gini,
+/// processing max/mean, processing gini, skewed
+pub struct SkewData {
+    o: u64,
+    on: Option<String>,
+    wc: u64,
+    rmm: f64,
+    rg: f64,
+    pmm: f64,
+    pg: f64,
+    s: bool,
+}
+
 #[derive(Serialize, Debug)]
 /// Serialization type for metrics
 /// from_worker,to_worker,activity_type,#(activities),t(activities),#(records)
@@ -97,6 +136,17 @@ pub enum InvariantData {
     Message(MessageData),
 }
 
+impl InvariantData {
+    /// Epoch the violation happened in
+    pub fn epoch(&self) -> u64 {
+        match self {
+            InvariantData::Epoch(x) => x.from.epoch,
+            InvariantData::Operator(x) => x.from.source.epoch,
+            InvariantData::Message(x) => x.msg.source.epoch,
+        }
+    }
+}
+
 #[derive(Serialize, Debug)]
 /// Serialization type for max epoch
 pub struct EpochData {
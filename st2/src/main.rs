@@ -15,6 +15,9 @@ use tdiag_connect::receive as connect;
 use tdiag_connect::receive::ReplaySource;
 
 use st2::STError;
+use st2::ingest::{LogSource, LogInput, check_trace};
+use st2_timely::replay_throttled::EpochWindow;
+use st2_logformat::trace::TraceFormat;
 use st2::PagData;
 use std::collections::HashMap;
 
@@ -43,7 +46,7 @@ fn run() -> Result<(), STError> {
              .short("i")
              .long("interface")
              .value_name("INTERFACE")
-             .conflicts_with("from_file")
+             .conflicts_with_all(&["from_file", "from_trace"])
              .help("Interface (ip address) to listen on. Set if you want to run online.")
              .takes_value(true))
         .arg(clap::Arg::with_name("port")
@@ -56,20 +59,44 @@ fn run() -> Result<(), STError> {
              .short("f")
              .long("from-file")
              .value_name("PATH")
-             .help("File path from which to load *.dump files (without trailing /). Set if you want to run offline.")
+             .help("File path from which to load *.dump files (without trailing /). Set if you want to run offline. Compressed *.dump.zst and *.dump.lz4 files are decompressed transparently, segmented dumps are read via their *.index files.")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("from_trace")
+             .long("from-trace")
+             .value_name("PATH")
+             .conflicts_with("from_file")
+             .multiple(true)
+             .number_of_values(1)
+             .help("Portable trace file (cf. `convert`) to load log records from, e.g. converted from another stream processor. Files ending in .msgpack are read as msgpack, others as line-delimited JSON. Can be given multiple times.")
              .takes_value(true))
         .arg(clap::Arg::with_name("source_peers")
              .short("s")
              .long("source-peers")
              .value_name("PEERS")
              .help("Number of workers in the source computation")
-             .required(true))
+             .required_unless("from_trace"))
         .arg(clap::Arg::with_name("snailtrail_workers")
              .short("w")
              .long("snailtrail-workers")
              .value_name("WORKERS")
              .help("Number of worker threads for SnailTrail")
              .default_value("1"))
+        .arg(clap::Arg::with_name("strict")
+             .long("strict")
+             .help("Fail on the first malformed event instead of skipping it during PAG construction"))
+        .arg(clap::Arg::with_name("from_epoch")
+             .long("from-epoch")
+             .value_name("EPOCH")
+             .help("First epoch to analyze. Earlier epochs are skipped during replay; segmented dumps are read from the segment containing it.")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("to_epoch")
+             .long("to-epoch")
+             .value_name("EPOCH")
+             .help("Last epoch to analyze. Replay stops after it.")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("correct_skew")
+             .long("correct-skew")
+             .help("Estimate clock offsets between source workers from remote messages and correct timestamps with them. Use this if the source computation runs across machines."))
         .subcommand(
             clap::SubCommand::with_name("metrics")
                 .about("Write dataflow metrics to file")
@@ -80,6 +107,48 @@ fn run() -> Result<(), STError> {
                     .help("The output path for the generated CSV file (don't forget the .CSV extension)")
                     .default_value("metrics.csv"))
         )
+        .subcommand(
+            clap::SubCommand::with_name("scopes")
+                .about("Write the activity within every scope activation (e.g., loop iteration) to file")
+                .arg(clap::Arg::with_name("output_path")
+                    .short("o")
+                    .long("out")
+                    .value_name("PATH")
+                    .help("The output path for the generated CSV file")
+                    .default_value("scopes.csv"))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("channels")
+                .about("Write the data volume and message latencies of every channel to file")
+                .arg(clap::Arg::with_name("output_path")
+                    .short("o")
+                    .long("out")
+                    .value_name("PATH")
+                    .help("The output path for the generated CSV file")
+                    .default_value("channels.csv"))
+                .arg(clap::Arg::with_name("matrix_path")
+                    .short("m")
+                    .long("matrix")
+                    .value_name("PATH")
+                    .help("The output path for the CSV file of data sent between workers")
+                    .default_value("channel_matrix.csv"))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("imbalance")
+                .about("Write the distribution of records and processing time across workers for every operator to file, and log skewed operators")
+                .arg(clap::Arg::with_name("output_path")
+                    .short("o")
+                    .long("out")
+                    .value_name("PATH")
+                    .help("The output path for the generated CSV file")
+                    .default_value("imbalance.csv"))
+                .arg(clap::Arg::with_name("threshold")
+                    .short("t")
+                    .long("threshold")
+                    .value_name("RATIO")
+                    .help("Flag operators as skewed if a worker processed more than RATIO times the mean records or processing time")
+                    .default_value("1.5"))
+        )
         .subcommand(
             clap::SubCommand::with_name("inspect")
                 .about("run ST2 inspector")
@@ -88,6 +157,73 @@ fn run() -> Result<(), STError> {
             clap::SubCommand::with_name("algo")
                 .about("run ST2 graph algorithms")
         )
+        .subcommand(
+            clap::SubCommand::with_name("cp")
+                .about("rank PAG edges by critical participation")
+                .arg(clap::Arg::with_name("top")
+                    .short("k")
+                    .long("top")
+                    .value_name("K")
+                    .help("Number of edges with the highest critical participation to log per epoch")
+                    .default_value("10"))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("critical-path")
+                .about("Write the critical path of every epoch to file")
+                .arg(clap::Arg::with_name("output_path")
+                    .short("o")
+                    .long("out")
+                    .value_name("PATH")
+                    .help("The output path for the generated file")
+                    .default_value("critical_path.csv"))
+                .arg(clap::Arg::with_name("shares_path")
+                    .long("shares")
+                    .value_name("PATH")
+                    .help("The output path for the operators' and workers' shares of every epoch (CSV format only)")
+                    .default_value("critical_path_shares.csv"))
+                .arg(clap::Arg::with_name("format")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .possible_values(&["csv", "json"])
+                    .help("Output format: one CSV line per path edge, or one JSON object per epoch")
+                    .default_value("csv"))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("export")
+                .about("Export the PAG to file, e.g. to view it in Perfetto")
+                .arg(clap::Arg::with_name("output_path")
+                    .short("o")
+                    .long("out")
+                    .value_name("PATH")
+                    .help("The output path for the generated file")
+                    .default_value("trace.json"))
+                .arg(clap::Arg::with_name("format")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .possible_values(&["chrome-trace"])
+                    .help("Output format: Chrome Trace Event JSON, for Perfetto or chrome://tracing")
+                    .default_value("chrome-trace"))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("convert")
+                .about("Convert the source computation's log traces to a portable JSON or msgpack trace")
+                .arg(clap::Arg::with_name("output_path")
+                    .short("o")
+                    .long("out")
+                    .value_name("PATH")
+                    .help("The output path for the converted trace")
+                    .default_value("trace.jsonl"))
+                .arg(clap::Arg::with_name("format")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .possible_values(&["json", "msgpack"])
+                    .help("Output format: line-delimited JSON or concatenated msgpack")
+                    .default_value("json"))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("validate")
+                .about("Check the source computation's log traces for sanity")
+        )
         .subcommand(
             clap::SubCommand::with_name("dashboard")
                 .about("run ST2 live dashboard")
@@ -106,6 +242,16 @@ fn run() -> Result<(), STError> {
                     .long("message-max")
                     .value_name("MS")
                     .help("Temporal invariant: the maximum milliseconds a control or data message is allowed to take"))
+                .arg(clap::Arg::with_name("retain_epochs")
+                    .short("r")
+                    .long("retain-epochs")
+                    .value_name("EPOCHS")
+                    .help("Memory-stable mode: only keep data of the latest EPOCHS epochs for the dashboard. Use this for long-running online analyses."))
+                .arg(clap::Arg::with_name("skew_threshold")
+                    .long("skew-threshold")
+                    .value_name("RATIO")
+                    .help("Flag operators as skewed if a worker processed more than RATIO times the mean records or processing time")
+                    .default_value("1.5"))
         )
         .subcommand(
             clap::SubCommand::with_name("invariants")
@@ -146,26 +292,111 @@ fn run() -> Result<(), STError> {
         n => timely::Configuration::Process(n),
     };
 
+    let options = st2::pag::PagOptions {
+        strict: args.is_present("strict"),
+        correct_skew: args.is_present("correct_skew"),
+    };
+
     match args.subcommand() {
         ("metrics", Some(metrics_args)) => {
             let output_path = std::path::Path::new(metrics_args.value_of("output_path").expect("error parsing metrics output args"));
 
-            let replay_source = make_replay_source(&args)?;
+            let source = make_log_source(&args)?;
+            println!("Connected!");
+
+            st2::commands::metrics::run(timely_configuration, source, output_path, options)
+        }
+        ("scopes", Some(scopes_args)) => {
+            let output_path = std::path::Path::new(scopes_args.value_of("output_path").expect("error parsing scopes output args"));
+
+            let source = make_log_source(&args)?;
+            println!("Connected!");
+
+            st2::commands::scopes::run(timely_configuration, source, output_path, options)
+        }
+        ("channels", Some(channels_args)) => {
+            let output_path = std::path::Path::new(channels_args.value_of("output_path").expect("error parsing channels output args"));
+            let matrix_path = std::path::Path::new(channels_args.value_of("matrix_path").expect("error parsing channels matrix args"));
+
+            let source = make_log_source(&args)?;
+            println!("Connected!");
+
+            st2::commands::channels::run(timely_configuration, source, output_path, matrix_path, options)
+        }
+        ("imbalance", Some(imbalance_args)) => {
+            let output_path = std::path::Path::new(imbalance_args.value_of("output_path").expect("error parsing imbalance output args"));
+            let threshold: f64 = imbalance_args.value_of("threshold").expect("error parsing imbalance threshold args")
+                .parse().map_err(|e| STError(format!("Invalid --threshold: {}", e)))?;
+
+            let source = make_log_source(&args)?;
             println!("Connected!");
 
-            st2::commands::metrics::run(timely_configuration, replay_source, output_path)
+            st2::commands::imbalance::run(timely_configuration, source, output_path, threshold, options)
         }
         ("inspect", Some(_inspect_args)) => {
-            let replay_source = make_replay_source(&args)?;
+            let source = make_log_source(&args)?;
             println!("Connected!");
 
-            st2::commands::inspect::run(timely_configuration, replay_source)
+            st2::commands::inspect::run(timely_configuration, source, options)
         }
         ("algo", Some(_algo_args)) => {
-            let replay_source = make_replay_source(&args)?;
+            let source = make_log_source(&args)?;
             println!("Connected!");
 
-            st2::commands::algo::run(timely_configuration, replay_source)
+            st2::commands::algo::run(timely_configuration, source, options)
+        }
+        ("cp", Some(cp_args)) => {
+            let top: usize = cp_args.value_of("top").expect("error parsing cp top args")
+                .parse().map_err(|e| STError(format!("Invalid --top: {}", e)))?;
+
+            let source = make_log_source(&args)?;
+            println!("Connected!");
+
+            st2::commands::cp::run(timely_configuration, source, top, options)
+        }
+        ("critical-path", Some(path_args)) => {
+            let output_path = std::path::Path::new(path_args.value_of("output_path").expect("error parsing critical path output args"));
+            let shares_path = std::path::Path::new(path_args.value_of("shares_path").expect("error parsing critical path shares args"));
+            let format = match path_args.value_of("format") {
+                Some("json") => st2::commands::critical_path::PathFormat::Json,
+                _ => st2::commands::critical_path::PathFormat::Csv,
+            };
+
+            let source = make_log_source(&args)?;
+            println!("Connected!");
+
+            st2::commands::critical_path::run(timely_configuration, source, output_path, shares_path, format, options)
+        }
+        ("export", Some(export_args)) => {
+            let output_path = std::path::Path::new(export_args.value_of("output_path").expect("error parsing export output args"));
+            let format = match export_args.value_of("format") {
+                Some("chrome-trace") | None => st2::commands::export::ExportFormat::ChromeTrace,
+                Some(x) => Err(STError(format!("Invalid --format: {}", x)))?,
+            };
+
+            let source = make_log_source(&args)?;
+            println!("Connected!");
+
+            st2::commands::export::run(timely_configuration, source, output_path, format, options)
+        }
+        ("convert", Some(convert_args)) => {
+            let output_path = std::path::Path::new(convert_args.value_of("output_path").expect("error parsing convert output args"));
+            let format = match convert_args.value_of("format") {
+                Some("json") | None => TraceFormat::Json,
+                Some("msgpack") => TraceFormat::Msgpack,
+                Some(x) => Err(STError(format!("Invalid --format: {}", x)))?,
+            };
+
+            let source = make_log_source(&args)?;
+            println!("Connected!");
+
+            st2::commands::convert::run(timely_configuration, source, output_path, format, options.strict)
+        }
+        ("validate", Some(_validate_args)) => {
+            let source = make_log_source(&args)?;
+            println!("Connected!");
+
+            st2::commands::validate::run(timely_configuration, source, options.strict)
         }
         ("dashboard", Some(dashboard_args)) => {
             let epoch_max: Option<u64> = if let Some(t) = dashboard_args.value_of("epoch_max") {
@@ -184,20 +415,32 @@ fn run() -> Result<(), STError> {
             } else {
                 None
             };
+            let retain_epochs: Option<u64> = if let Some(t) = dashboard_args.value_of("retain_epochs") {
+                Some(t.parse().map_err(|e| STError(format!("Invalid --retain-epochs: {}", e)))?)
+            } else {
+                None
+            };
+            let skew_threshold: f64 = dashboard_args.value_of("skew_threshold").expect("error parsing skew threshold args")
+                .parse().map_err(|e| STError(format!("Invalid --skew-threshold: {}", e)))?;
 
             println!("Waiting for source computation...");
-            let replay_source = make_replay_source(&args)?;
+            let source = make_log_source(&args)?;
             println!("Connected to source computation!");
 
             let (pag_send, pag_recv) = mpsc::channel();
             let pag_send = Arc::new(Mutex::new(pag_send));
 
+            // continuously collect PAG data, so that it doesn't pile up while no dashboard is connected
+            let pag_recvd = Arc::new(Mutex::new(HashMap::new()));
+            let collector_recvd = Arc::clone(&pag_recvd);
+            std::thread::spawn(move || collect_pag_data(pag_recv, collector_recvd, retain_epochs));
+
             println!("Waiting for dashboard connection...");
             let listener = std::thread::spawn(move || {
-                listen("127.0.0.1:3012", |out| { Server { out, pag_recv: &pag_recv, pag_recvd: HashMap::new() } } ).unwrap();
+                listen("127.0.0.1:3012", |out| { Server { out, pag_recvd: Arc::clone(&pag_recvd) } } ).unwrap();
             });
 
-            st2::commands::dashboard::run(timely_configuration, replay_source, pag_send, epoch_max, operator_max, message_max)?;
+            st2::commands::dashboard::run(timely_configuration, source, pag_send, epoch_max, operator_max, message_max, skew_threshold, options)?;
 
             listener.join().expect("couldn't join listener");
             Ok(())
@@ -225,10 +468,10 @@ fn run() -> Result<(), STError> {
                 None
             };
 
-            let replay_source = make_replay_source(&args)?;
+            let source = make_log_source(&args)?;
             println!("Connected!");
 
-            st2::commands::invariants::run(timely_configuration, replay_source, epoch_max, operator_max, message_max, progress_max)
+            st2::commands::invariants::run(timely_configuration, source, epoch_max, operator_max, message_max, progress_max, options)
         }
         _ => panic!("Invalid subcommand"),
     }?;
@@ -236,6 +479,46 @@ fn run() -> Result<(), STError> {
     Ok(())
 }
 
+/// reads the epoch window to analyze and where to read logs from
+fn make_log_source(args: &clap::ArgMatches) -> Result<LogSource, STError> {
+    let from: u64 = if let Some(from) = args.value_of("from_epoch") {
+        from.parse().map_err(|e| STError(format!("Invalid --from-epoch: {}", e)))?
+    } else {
+        0
+    };
+    let to: Option<u64> = if let Some(to) = args.value_of("to_epoch") {
+        Some(to.parse().map_err(|e| STError(format!("Invalid --to-epoch: {}", e)))?)
+    } else {
+        None
+    };
+    if to.map_or(false, |to| to < from) {
+        Err(STError("Invalid --to-epoch: must not be smaller than --from-epoch".to_string()))?
+    }
+
+    Ok(LogSource::new(make_log_input(args)?).window(EpochWindow { from, to }))
+}
+
+/// reads from portable traces if given, else from the source computation's dumps or sockets
+fn make_log_input(args: &clap::ArgMatches) -> Result<LogInput, STError> {
+    if let Some(paths) = args.values_of("from_trace") {
+        let paths = paths.map(PathBuf::from).collect::<Vec<_>>();
+        let format = if paths.iter().all(|p| p.extension().map_or(false, |ext| ext == "msgpack")) {
+            TraceFormat::Msgpack
+        } else if paths.iter().any(|p| p.extension().map_or(false, |ext| ext == "msgpack")) {
+            Err(STError("Invalid --from-trace: can't mix msgpack and JSON traces".to_string()))?
+        } else {
+            TraceFormat::Json
+        };
+
+        println!("Reading from {} trace files", paths.len());
+
+        check_trace(&paths, format)?;
+        Ok(LogInput::Trace(paths, format))
+    } else {
+        Ok(LogInput::Replay(make_replay_source(args)?))
+    }
+}
+
 /// creates one socket per worker in the computation we're examining
 fn make_replay_source(args: &clap::ArgMatches) -> Result<ReplaySource, STError> {
     let source_peers: usize = args.value_of("source_peers").expect("error parsing source peers args")
@@ -246,9 +529,13 @@ fn make_replay_source(args: &clap::ArgMatches) -> Result<ReplaySource, STError>
 
         println!("Reading from {} *.dump files", source_peers);
 
+        // dumps might be segmented or compressed (cf. `st2_timely::{segments, compression}`)
         let files = (0 .. source_peers)
-            .map(|idx| format!("{}/{}.dump", path, idx))
-            .map(|path| Some(PathBuf::from(path)))
+            .map(|idx| ["index", "dump", "dump.zst", "dump.lz4"].iter()
+                 .map(|ext| PathBuf::from(format!("{}/{}.{}", path, idx, ext)))
+                 .find(|path| path.exists())
+                 .unwrap_or_else(|| PathBuf::from(format!("{}/{}.dump", path, idx))))
+            .map(Some)
             .collect::<Vec<_>>();
 
         Ok(ReplaySource::Files(Arc::new(Mutex::new(files))))
@@ -260,24 +547,69 @@ fn make_replay_source(args: &clap::ArgMatches) -> Result<ReplaySource, STError>
 
         println!("Listening for {} connections on {}:{}", source_peers, ip_addr, port);
 
-        let sockets = connect::open_sockets(ip_addr, port, source_peers)?;
+        // Connections are only accepted here, at startup. Reconnecting writers
+        // (`st2_timely::reconnect`) that lost their connection can't resume with
+        // this instance, so ST2 has to be restarted to pick them up again.
+        let mut sockets = connect::open_sockets(ip_addr, port, source_peers)?;
+        for socket in sockets.iter_mut().flatten() {
+            // the header is read blocking, the events themselves are replayed non-blocking
+            socket.set_nonblocking(false)?;
+            st2_timely::connect::read_dump_header(socket)
+                .map_err(|e| STError(format!("couldn't read from {:?}: {}", socket.peer_addr().ok(), e)))?;
+            socket.set_nonblocking(true)?;
+        }
         Ok(ReplaySource::Tcp(Arc::new(Mutex::new(sockets))))
     }
 }
 
 
-struct Server<'a> { out: Sender, pag_recv: &'a mpsc::Receiver<(u64, PagData)>, pag_recvd: HashMap<u64, Vec<PagData>> }
-impl<'a> Handler for Server<'a> {
+/// Collects PAG data sent by the dashboard computation per epoch.
+/// If `retain_epochs` is set, only data of the latest `retain_epochs` epochs is kept
+/// (invariant violations, collected at epoch 0, are kept until the dashboard fetches them
+/// or their epoch expires).
+/// Epochs are counted as they are received, so that gaps (e.g., from sampling) don't count.
+fn collect_pag_data(pag_recv: mpsc::Receiver<(u64, PagData)>, pag_recvd: Arc<Mutex<HashMap<u64, Vec<PagData>>>>, retain_epochs: Option<u64>) {
+    let mut newest_epoch = 0;
+
+    while let Ok((epoch, pag_data)) = pag_recv.recv() {
+        let mut pag_recvd = pag_recvd.lock().expect("couldn't lock received PAG data");
+        pag_recvd.entry(epoch).or_insert(Vec::new()).push(pag_data);
+
+        if epoch > newest_epoch {
+            newest_epoch = epoch;
+
+            if let Some(retain_epochs) = retain_epochs {
+                let mut epochs = pag_recvd.keys().filter(|epoch| **epoch != 0).cloned().collect::<Vec<_>>();
+                epochs.sort();
+                let expired = epochs.len().saturating_sub(retain_epochs as usize);
+                for epoch in &epochs[.. expired] {
+                    pag_recvd.remove(epoch);
+                }
+
+                // invariant violations are collected at epoch 0 until the dashboard
+                // fetches them, so they expire together with the epoch they happened in
+                if let Some(newest_expired) = epochs[.. expired].last() {
+                    if let Some(invariants) = pag_recvd.get_mut(&0) {
+                        invariants.retain(|x| match x {
+                            PagData::Inv(x) => x.epoch() > *newest_expired,
+                            _ => true,
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct Server { out: Sender, pag_recvd: Arc<Mutex<HashMap<u64, Vec<PagData>>>> }
+impl Handler for Server {
     fn on_open(&mut self, _: Handshake) -> ws::Result<()> {
         println!("Connected to dashboard!");
         Ok(())
     }
 
     fn on_message(&mut self, msg: Message) -> ws::Result<()> {
-        // update internal state
-        while let Ok((epoch, pag_data)) = self.pag_recv.try_recv() {
-            self.pag_recvd.entry(epoch).or_insert(Vec::new()).push(pag_data);
-        }
+        let mut pag_recvd = self.pag_recvd.lock().expect("couldn't lock received PAG data");
 
         let payload: serde_json::Value = match msg {
             Message::Text(msg) => serde_json::from_str(&msg).unwrap(),
@@ -288,7 +620,7 @@ impl<'a> Handler for Server<'a> {
 
         match payload_type {
             "ALL" => {
-                if let Some(events) = self.pag_recvd.get(&payload["epoch"].as_u64().unwrap()) {
+                if let Some(events) = pag_recvd.get(&payload["epoch"].as_u64().unwrap()) {
                     let result: Vec<_> = events.iter().filter_map(|x| match x {
                         PagData::All(x) => Some(x),
                         _ => None
@@ -299,7 +631,7 @@ impl<'a> Handler for Server<'a> {
                 }
             },
             "AGG" => {
-                if let Some(events) = self.pag_recvd.get(&payload["epoch"].as_u64().unwrap()) {
+                if let Some(events) = pag_recvd.get(&payload["epoch"].as_u64().unwrap()) {
                     let result: Vec<_> = events.iter().filter_map(|x| match x {
                         PagData::Agg(x) => Some(x),
                         _ => None
@@ -310,7 +642,7 @@ impl<'a> Handler for Server<'a> {
                 }
             },
             "PAG" => {
-                if let Some(events) = self.pag_recvd.get(&payload["epoch"].as_u64().unwrap()) {
+                if let Some(events) = pag_recvd.get(&payload["epoch"].as_u64().unwrap()) {
                     let mut result: Vec<_> = events.iter()
                         .filter_map(|x| match x {
                             PagData::Pag(x) => {
@@ -323,6 +655,7 @@ impl<'a> Handler for Server<'a> {
                                               "w": x.destination.worker_id },
                                     "type": x.edge_type,
                                     "o": x.operator_id.unwrap_or(0),
+                                    "on": x.operator_name,
                                     "l": x.length.unwrap_or(0)
                                 }))
                             },
@@ -334,8 +667,30 @@ impl<'a> Handler for Server<'a> {
                     self.out.send(json!({"type": "PAG", "payload": Vec::<u64>::new() }).to_string())?;
                 }
             },
+            "CP" => {
+                if let Some(events) = pag_recvd.get(&payload["epoch"].as_u64().unwrap()) {
+                    let result: Vec<_> = events.iter().filter_map(|x| match x {
+                        PagData::Cp(x) => Some(x),
+                        _ => None
+                    }).collect();
+                    self.out.send(json!({"type": "CP", "payload": result }).to_string())?;
+                } else {
+                    self.out.send(json!({"type": "CP", "payload": Vec::<u64>::new() }).to_string())?;
+                }
+            },
+            "SKEW" => {
+                if let Some(events) = pag_recvd.get(&payload["epoch"].as_u64().unwrap()) {
+                    let result: Vec<_> = events.iter().filter_map(|x| match x {
+                        PagData::Skew(x) => Some(x),
+                        _ => None
+                    }).collect();
+                    self.out.send(json!({"type": "SKEW", "payload": result }).to_string())?;
+                } else {
+                    self.out.send(json!({"type": "SKEW", "payload": Vec::<u64>::new() }).to_string())?;
+                }
+            },
             "MET" => {
-                if let Some(events) = self.pag_recvd.get(&payload["epoch"].as_u64().unwrap()) {
+                if let Some(events) = pag_recvd.get(&payload["epoch"].as_u64().unwrap()) {
                     let result: Vec<_> = events.iter().filter_map(|x| match x {
                         PagData::Met(x) => Some(x),
                         _ => None
@@ -346,7 +701,7 @@ impl<'a> Handler for Server<'a> {
                 }
             }
             "INV" => {
-                if let Some(events) = self.pag_recvd.remove(&0) {
+                if let Some(events) = pag_recvd.remove(&0) {
                     let result: Vec<_> = events.iter().filter_map(|x| match x {
                         PagData::Inv(x) => Some(x),
                         _ => None
@@ -1,7 +1,7 @@
 //! Pag Construction
 //! Uses LogRecord representation to create a PAG that contains local and remote edges
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::{io::Read, time::Duration};
 use std::cmp::Ordering;
 use std::hash::Hash;
@@ -14,13 +14,21 @@ use timely::dataflow::operators::filter::Filter;
 use timely::dataflow::operators::map::Map;
 use timely::dataflow::operators::inspect::Inspect;
 use timely::dataflow::operators::concat::Concat;
+use timely::dataflow::operators::delay::Delay;
+use timely::dataflow::operators::aggregation::aggregate::Aggregate;
+use timely::dataflow::operators::Capability;
+use timely::dataflow::operators::broadcast::Broadcast;
+use timely::dataflow::operators::generic::operator::empty;
 use timely::Data;
 
-use st2_logformat::{ActivityType, EventType, LogRecord, OperatorId};
-use ActivityType::{Busy, Waiting, Scheduling, Processing, Spinning, ControlMessage, DataMessage};
+use st2_logformat::{ActivityType, EventType, LogRecord, OperatorId, ChannelId, Worker, Anomaly, AnomalyReason, OperatorInfo, ChannelInfo, ScopeActivation};
+use ActivityType::{Busy, Waiting, Scheduling, Processing, Spinning, ControlMessage, DataMessage, Merging};
 use EventType::{Sent, Received, Start, End};
 use st2_logformat::pair::Pair;
-use st2_timely::{connect::Replayer, create_lrs};
+use st2_timely::{connect::Replayer, create_lrs, LrStreams};
+use st2_timely::replay_throttled::EpochWindow;
+
+use crate::skew::{ClockOffset, ClockSkew, EdgeClockSkew};
 
 use abomonation::Abomonation;
 
@@ -105,16 +113,28 @@ pub struct PagEdge {
     pub edge_type: ActivityType,
     /// An optional operator ID
     pub operator_id: Option<OperatorId>,
+    /// The operator's name, if known
+    pub operator_name: Option<String>,
     /// Edge dependency information
     pub traverse: TraversalType,
     /// record count
     pub length: Option<usize>,
+    /// Activation of the innermost scope (e.g., an `iterate` loop)
+    /// the edge's activity happened in, if any
+    pub scope: Option<ScopeActivation>,
+    /// Activations of the scopes enclosing `scope`, outermost first
+    pub outer_scopes: Vec<ScopeActivation>,
+    /// The scope's name, if known
+    pub scope_name: Option<String>,
+    /// The channel of message edges
+    pub channel_id: Option<ChannelId>,
 }
 
 impl PagEdge {
     /// PagEdge's duration in ns.
     /// Due to clock skew, we can't give guarantees that `to.timestamp > from.timestamp`.
     /// We report a duration of 0 in the case that `to.timestamp < from.timestamp`.
+    /// Use `PagOptions::correct_skew` to make this less likely for remote edges.
     pub fn duration(&self) -> u64 {
         let dst_ts = self.destination.timestamp.as_nanos();
         let src_ts = self.source.timestamp.as_nanos();
@@ -146,8 +166,13 @@ impl Default for PagEdge {
             destination: Default::default(),
             edge_type: Waiting,
             operator_id: None,
+            operator_name: None,
             traverse: TraversalType::Block,
             length: None,
+            scope: None,
+            outer_scopes: Vec::new(),
+            scope_name: None,
+            channel_id: None,
         }
     }
 }
@@ -159,30 +184,123 @@ impl std::fmt::Debug for PagEdge {
         //        self.traverse, self.edge_type,
         //        self.operator_id)
 
-        write!(f, "{:?},{:?},{:?},{:?},{:?}",
+        write!(f, "{:?},{:?},{:?},{:?},{},{:?},{:?},{:?},{:?}",
                self.source, self.destination,
-               self.edge_type, self.operator_id, self.length)
+               self.edge_type, self.operator_id,
+               crate::csv_field(self.operator_name.as_ref().map(|x| x.as_str()).unwrap_or("")), self.length,
+               self.scope.map(|s| s.scope_id), self.scope.map(|s| s.round), self.channel_id)
     }
 }
 
-// @TODO currently, this creates a new pag per epoch, but never removes the old one.
-//       so state will continually grow and multiple pags exist side by side.
-// @TODO: add an optional checking operator that tests individual logrecord timelines for sanity
-// e.g. sched start -> sched end, no interleave, start & end always belong to scheduling,
-// sent/received always to remote messages, we don't see message types that we can't handle yet,
-// t1 is always > t0, same count of sent and received remote messages for every epoch
-// same results regardless of worker count, received events always have a remote worker
+/// Options for PAG construction
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PagOptions {
+    /// Panic on malformed events instead of skipping them
+    pub strict: bool,
+    /// Estimate clock offsets between source workers and correct
+    /// `LogRecord` timestamps with them
+    pub correct_skew: bool,
+}
+
+// @TODO: further sanity checks, not covered by `commands::validate` yet:
+// same results regardless of worker count,
 // matched remote events are (remote-count / 2), remote event count is always even
 /// Creates a PAG (a Collection of `PagEdge`s, grouped by epoch) from the provided `Replayer`s.
+/// All epochs are replayed; use `ingest::LogSource` to restrict them to a window.
 /// To be called from within a timely computation.
+/// Malformed events and clock offsets are logged.
 pub fn create_pag<S: Scope<Timestamp = Pair<u64, Duration>>, R: 'static + Read> (
     scope: &mut S,
     replayers: Vec<Replayer<S::Timestamp, R>>,
     index: usize,
     throttle: u64,
+    options: PagOptions,
+) -> Stream<S, (PagEdge, S::Timestamp, isize)> {
+    let lr_streams = create_lrs(scope, replayers, index, throttle, options.strict, EpochWindow::default());
+    create_pag_from_lrs(lr_streams, index, options)
+}
+
+/// Creates a PAG like `create_pag`, but from already constructed `LogRecord`s,
+/// e.g. ones ingested by `ingest::LogSource`.
+pub fn create_pag_from_lrs<S: Scope<Timestamp = Pair<u64, Duration>>> (
+    lr_streams: LrStreams<S>,
+    index: usize,
+    options: PagOptions,
 ) -> Stream<S, (PagEdge, S::Timestamp, isize)> {
-    create_lrs(scope, replayers, index, throttle)
-        .construct_pag(index)
+    let streams = create_pag_streams_from_lrs(lr_streams, index, options);
+
+    streams.anomalies
+        .inspect(|x| warn!("skipped malformed events: {:?}", x));
+
+    if let Some(offsets) = streams.clock_offsets {
+        offsets.inspect(|x| info!("clock offset: {:?}", x));
+    }
+
+    streams.edges
+}
+
+/// Creates a PAG like `create_pag`. Additionally reports the source computation's
+/// operators, remote events per epoch whose other side was never observed,
+/// the malformed events that were skipped, and estimated clock offsets.
+pub fn create_pag_streams<S: Scope<Timestamp = Pair<u64, Duration>>, R: 'static + Read> (
+    scope: &mut S,
+    replayers: Vec<Replayer<S::Timestamp, R>>,
+    index: usize,
+    throttle: u64,
+    options: PagOptions,
+) -> PagStreams<S> {
+    let lr_streams = create_lrs(scope, replayers, index, throttle, options.strict, EpochWindow::default());
+    create_pag_streams_from_lrs(lr_streams, index, options)
+}
+
+/// Creates a PAG like `create_pag_streams`, but from already constructed `LogRecord`s.
+pub fn create_pag_streams_from_lrs<S: Scope<Timestamp = Pair<u64, Duration>>> (
+    lr_streams: LrStreams<S>,
+    index: usize,
+    options: PagOptions,
+) -> PagStreams<S> {
+    let streams = lr_streams.lrs.construct_pag(index, options.strict, options.correct_skew);
+
+    PagStreams {
+        edges: streams.edges.name_operators(&lr_streams.operators),
+        operators: lr_streams.operators,
+        channels: lr_streams.channels,
+        unmatched: streams.unmatched,
+        anomalies: lr_streams.anomalies.concat(&streams.anomalies),
+        clock_offsets: streams.clock_offsets,
+    }
+}
+
+/// Outputs of PAG construction
+pub struct PagStreams<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// The PAG's edges
+    pub edges: Stream<S, (PagEdge, S::Timestamp, isize)>,
+    /// Operators of the source computation, one per worker
+    pub operators: Stream<S, OperatorInfo>,
+    /// Channels of the source computation, one per worker
+    pub channels: Stream<S, ChannelInfo>,
+    /// Remote events whose other side was never observed (at `epoch + 1`,
+    /// once their epoch has been completed)
+    pub unmatched: Stream<S, UnmatchedMessages>,
+    /// Malformed events that were skipped during PAG construction
+    pub anomalies: Stream<S, Anomaly>,
+    /// Estimated clock offsets between source workers (at `epoch + 1`),
+    /// if clock skew is corrected
+    pub clock_offsets: Option<Stream<S, ClockOffset>>,
+}
+
+/// Remote events of an epoch whose other side was never observed,
+/// e.g. a sent data message without a corresponding receive.
+#[derive(Abomonation, Clone, PartialEq, Eq, Hash, Debug, Serialize)]
+pub struct UnmatchedMessages {
+    /// Epoch the unmatched events belong to
+    pub epoch: u64,
+    /// `ControlMessage` or `DataMessage`
+    pub activity_type: ActivityType,
+    /// Number of sent events without a matching receive
+    pub sent: u64,
+    /// Number of received events without a matching send
+    pub received: u64,
 }
 
 /// Dump PAG to file
@@ -194,33 +312,162 @@ pub trait DumpPAG<S: Scope<Timestamp = Pair<u64, Duration>>> {
 impl<S: Scope<Timestamp = Pair<u64, Duration>>> DumpPAG<S> for Stream<S, (PagEdge, S::Timestamp, isize)> {
     fn dump_pag(&self, index: usize) -> Stream<S, (PagEdge, S::Timestamp, isize)> {
         if index == 0 {
-            println!("from_epoch,from_timestamp,from_workerid,from_seqno,to_epoch,to_timestamp,to_workerid,to_seqno,edge_type,edge_operatorid");
+            println!("from_epoch,from_timestamp,from_workerid,from_seqno,to_epoch,to_timestamp,to_workerid,to_seqno,edge_type,edge_operatorid,edge_operatorname,edge_length,edge_scopeid,edge_round,edge_channelid");
         }
 
         self.inspect(|(x, _, _)| println!("{:?}", x))
     }
 }
 
+/// Resolves operator ids of `PagEdge`s to operator names
+pub trait NameOperators<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Sets `PagEdge::operator_name` and `PagEdge::scope_name` from the source computation's `operators`.
+    /// Edges are held back until all operators up to their time are known.
+    fn name_operators(&self, operators: &Stream<S, OperatorInfo>) -> Stream<S, (PagEdge, S::Timestamp, isize)>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> NameOperators<S> for Stream<S, (PagEdge, S::Timestamp, isize)> {
+    fn name_operators(&self, operators: &Stream<S, OperatorInfo>) -> Stream<S, (PagEdge, S::Timestamp, isize)> {
+        // every ST2 peer might see edges of every source worker
+        let operators = operators.broadcast();
+
+        self.binary_frontier(&operators, Pipeline, Pipeline, "NameOperators", |_capability, _info| {
+            let mut names: HashMap<(Worker, OperatorId), String> = HashMap::new();
+            let mut stash: Vec<(Capability<S::Timestamp>, Vec<(PagEdge, S::Timestamp, isize)>)> = Vec::new();
+
+            let mut vector1 = Vec::new();
+            let mut vector2 = Vec::new();
+
+            move |input1, input2, output| {
+                input2.for_each(|_cap, data| {
+                    data.swap(&mut vector2);
+                    for operator in vector2.drain(..) {
+                        names.insert((operator.worker, operator.id), operator.name);
+                    }
+                });
+
+                input1.for_each(|cap, data| {
+                    data.swap(&mut vector1);
+                    stash.push((cap.delayed(cap.time()), vector1.drain(..).collect()));
+                });
+
+                // operators are created before they are scheduled
+                let frontier = input2.frontier();
+                let (ready, pending): (Vec<_>, Vec<_>) = stash.drain(..)
+                    .partition(|(cap, _)| !frontier.less_equal(cap.time()));
+                stash = pending;
+
+                for (cap, mut edges) in ready {
+                    for (edge, _t, _diff) in edges.iter_mut() {
+                        if let Some(id) = edge.operator_id {
+                            edge.operator_name = names.get(&(edge.source.worker_id, id)).cloned();
+                        }
+                        if let Some(scope) = edge.scope {
+                            edge.scope_name = names.get(&(edge.source.worker_id, scope.scope_id)).cloned();
+                        }
+                    }
+                    output.session(&cap).give_vec(&mut edges);
+                }
+            }
+        })
+    }
+}
+
+/// Collects the PAG of an epoch at a single ST2 peer
+pub trait CollectEpochs<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Gathers all `PagEdge`s of an epoch and emits them as a single
+    /// `(epoch, edges)` batch at `epoch + 1` once the epoch has been completed.
+    /// Epochs are spread across ST2 peers.
+    fn collect_epochs(&self) -> Stream<S, (u64, Vec<PagEdge>)>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> CollectEpochs<S> for Stream<S, (PagEdge, S::Timestamp, isize)> {
+    fn collect_epochs(&self) -> Stream<S, (u64, Vec<PagEdge>)> {
+        self
+            .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+            .map(|(edge, _t, _diff)| (edge.source.epoch, edge))
+            .aggregate::<_,Vec<PagEdge>,_,_,_>(
+                |_epoch, edge, acc| acc.push(edge),
+                |epoch, acc| (epoch, acc),
+                |epoch| *epoch)
+    }
+}
+
+/// Orders the nodes of an epoch's PAG topologically, only following edges that
+/// can be traversed (`TraversalType::Unbounded`).
+/// Returns the ordered nodes and each node's traversable successors.
+pub fn traversal_order(edges: &[PagEdge]) -> (Vec<PagNode>, HashMap<PagNode, Vec<PagNode>>) {
+    let mut in_degree: HashMap<PagNode, usize> = HashMap::new();
+    let mut successors: HashMap<PagNode, Vec<PagNode>> = HashMap::new();
+    for edge in edges.iter().filter(|e| e.traverse == TraversalType::Unbounded) {
+        in_degree.entry(edge.source).or_insert(0);
+        *in_degree.entry(edge.destination).or_insert(0) += 1;
+        successors.entry(edge.source).or_insert(Vec::new()).push(edge.destination);
+    }
+
+    let mut queue: VecDeque<PagNode> = in_degree.iter()
+        .filter(|(_, degree)| **degree == 0)
+        .map(|(node, _)| *node)
+        .collect();
+    let mut order = Vec::new();
+    while let Some(node) = queue.pop_front() {
+        for succ in successors.get(&node).into_iter().flatten() {
+            let degree = in_degree.get_mut(succ).expect("successor without in degree");
+            *degree -= 1;
+            if *degree == 0 {
+                queue.push_back(*succ);
+            }
+        }
+        order.push(node);
+    }
+
+    (order, successors)
+}
+
 /// Operator that converts a Stream of LogRecords to a PAG
 pub trait ConstructPAG<S: Scope<Timestamp = Pair<u64, Duration>>> {
     /// Builds a PAG from `LogRecord` by concatenating local edges, control edges
-    /// and data edges.
-    fn construct_pag(&self, index: usize) -> Stream<S, (PagEdge, S::Timestamp, isize)>;
-    /// Takes `LogRecord`s and connects local edges (per epoch, per worker)
-    fn make_local_edges(&self, index: usize) -> Stream<S, (PagEdge, S::Timestamp, isize)>;
-    /// Helper to create a `PagEdge` from two `LogRecord`s
-    fn build_local_edge(prev: &LogRecord, record: &LogRecord, next: &LogRecord) -> PagEdge;
-    /// Takes `LogRecord`s and connects remote edges (per epoch, across workers)
-    fn make_remote_edges(&self) -> Stream<S, (PagEdge, S::Timestamp, isize)>;
+    /// and data edges. Malformed `LogRecord`s are skipped, or panic if `strict`.
+    /// If `correct_skew`, clock offsets are estimated from the remote edges,
+    /// and used to correct the timestamps of all edges.
+    fn construct_pag(&self, index: usize, strict: bool, correct_skew: bool) -> PagStreams<S>;
+    /// Takes `LogRecord`s and connects local edges (per epoch, per worker).
+    /// Also returns the malformed `LogRecord`s that were skipped, or panics on them if `strict`.
+    fn make_local_edges(&self, index: usize, strict: bool) -> (Stream<S, (PagEdge, S::Timestamp, isize)>, Stream<S, Anomaly>);
+    /// Helper to create a `PagEdge` from two `LogRecord`s, given whether `prev`
+    /// happened within a schedule. Fails if they don't form a well-formatted edge.
+    fn build_local_edge(prev: &LogRecord, record: &LogRecord, next: &LogRecord, within_schedule: bool) -> Result<PagEdge, Anomaly>;
+    /// Takes `LogRecord`s and connects remote edges (per epoch, across workers).
+    /// Worker-local data messages (cf. `AdapterConfig::local_messages`) are connected, too.
+    /// Also returns the remote events per epoch that couldn't be matched, and the
+    /// malformed `LogRecord`s that were skipped, or panics on them if `strict`.
+    fn make_remote_edges(&self, index: usize, strict: bool) -> (Stream<S, (PagEdge, S::Timestamp, isize)>, Stream<S, UnmatchedMessages>, Stream<S, Anomaly>);
 }
 
 impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructPAG<S> for Stream<S, LogRecord> {
-    fn construct_pag(&self, index: usize) -> Stream<S, (PagEdge, S::Timestamp, isize)> {
-        self.make_local_edges(index)
-            .concat(&self.make_remote_edges())
+    fn construct_pag(&self, index: usize, strict: bool, correct_skew: bool) -> PagStreams<S> {
+        let (remote_edges, unmatched, remote_anomalies) = self.make_remote_edges(index, strict);
+
+        // remote messages are only matched once, for both the offsets and the PAG
+        let (lrs, remote_edges, clock_offsets) = if correct_skew {
+            let offsets = remote_edges.clock_offsets();
+            (self.correct_clock_skew(&offsets), remote_edges.correct_clock_skew(&offsets), Some(offsets))
+        } else {
+            (self.clone(), remote_edges, None)
+        };
+        let (local_edges, local_anomalies) = lrs.make_local_edges(index, strict);
+
+        PagStreams {
+            edges: local_edges.concat(&remote_edges),
+            operators: empty(&self.scope()),
+            channels: empty(&self.scope()),
+            unmatched,
+            anomalies: local_anomalies.concat(&remote_anomalies),
+            clock_offsets,
+        }
     }
 
-    fn make_local_edges(&self, index: usize) -> Stream<S, (PagEdge, S::Timestamp, isize)> {
+    fn make_local_edges(&self, index: usize, strict: bool) -> (Stream<S, (PagEdge, S::Timestamp, isize)>, Stream<S, Anomaly>) {
         // A differential join looks nicer and doesn't depend on order, but is
         // ~7x slower. Getting its semantics right is also tricky, since some `seq_no`s
         // are cut up due to `peel_ops`.
@@ -228,30 +475,56 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructPAG<S> for Stream<S, Lo
         let mut vector = Vec::new();
         let mut prev2_buffer: HashMap<usize, LogRecord> = HashMap::new();
         let mut prev_buffer: HashMap<usize, LogRecord> = HashMap::new();
+        // whether a schedule is open after the lr in prev2_buffer
+        let mut within_schedule: HashMap<usize, bool> = HashMap::new();
 
-        self.unary_frontier(Pipeline, "Local Edges", move |_, _| { move |input, output| {
+        let local_edges = self.unary_frontier(Pipeline, "Local Edges", move |_, _| { move |input, output| {
             input.for_each(|cap, data| {
                 data.swap(&mut vector);
                 for lr in vector.drain(..) {
                     let local_worker = lr.local_worker as usize;
 
+                    // skip lrs that happen before their predecessor, so that the buffers stay ordered.
+                    // Timestamps are only compared within an epoch, as clock skew
+                    // correction might shift epochs against each other.
+                    if let Some(prev_lr) = prev_buffer.get(&local_worker) {
+                        if lr.epoch < prev_lr.epoch || (lr.epoch == prev_lr.epoch && lr.timestamp < prev_lr.timestamp) {
+                            let anomaly = Anomaly::new(AnomalyReason::OutOfOrder, vec![prev_lr.clone(), lr]);
+                            if strict {
+                                panic!("w{}: {:?}", index, anomaly);
+                            }
+                            output.session(&cap).give(Err(anomaly));
+                            continue;
+                        }
+                    }
+
                     if let Some(prev_lr) = prev_buffer.remove(&local_worker) {
+                        let within = within_schedule.get(&local_worker).cloned().unwrap_or(false);
+
                         if let Some(prev2_lr) = prev2_buffer.remove(&local_worker) {
                             // we've seen two lrs from this local_worker before
 
-                            assert!(prev_lr.epoch >= prev2_lr.epoch);
-                            assert!(prev_lr.timestamp >= prev2_lr.timestamp);
-                            assert!(lr.epoch >= prev_lr.epoch, format!("w{}: {:?} should happen before {:?}", index, prev_lr.epoch, lr.epoch));
-                            assert!(lr.timestamp >= prev_lr.timestamp, format!("w{}: {:?} should happen before {:?}", index, prev_lr, lr));
-
                             // only join lrs within an epoch
                             if prev2_lr.epoch == prev_lr.epoch && prev_lr.epoch == lr.epoch  {
                                 // builds the edge between prev2_lr and prev_lr
-                                output.session(&cap).give((Self::build_local_edge(&prev2_lr, &prev_lr, &lr), cap.time().clone(), 1));
+                                match Self::build_local_edge(&prev2_lr, &prev_lr, &lr, within) {
+                                    Ok(edge) => output.session(&cap).give(Ok((edge, cap.time().clone(), 1))),
+                                    Err(anomaly) => {
+                                        if strict {
+                                            panic!("w{}: {:?}", index, anomaly);
+                                        }
+                                        output.session(&cap).give(Err(anomaly));
+                                    }
+                                }
                             }
                         }
 
                         // move prev_lr -> prev2_lr
+                        within_schedule.insert(local_worker, match (prev_lr.activity_type, prev_lr.event_type) {
+                            (Scheduling, Start) => true,
+                            (Scheduling, End) => false,
+                            _ => within,
+                        });
                         prev2_buffer.insert(local_worker, prev_lr);
                     }
 
@@ -261,30 +534,45 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructPAG<S> for Stream<S, Lo
             });
 
             trace!("made local edges");
-        }})
+        }});
+
+        (local_edges.flat_map(|x| x.ok()), local_edges.flat_map(|x| x.err()))
     }
 
-    fn build_local_edge(prev: &LogRecord, record: &LogRecord, next: &LogRecord) -> PagEdge {
+    fn build_local_edge(prev: &LogRecord, record: &LogRecord, next: &LogRecord, within_schedule: bool) -> Result<PagEdge, Anomaly> {
+        let anomaly = |reason| Err(Anomaly::new(reason, vec![prev.clone(), record.clone()]));
+
         // Rules for a well-formatted PAG
 
         // @TODO: In some cases, this assertion doesn't hold and a DataMessage is sent before the
         // operator it belongs to has started. In this case, we probably misreport the operator type
         // (Spinning instead of Processing) and its length (0 instead of the DataMessage's contents).
-        // I think that this has to do with differential arrangements, so it's worth thinking about
-        // them and this bug together.
+        // Arrangement maintenance (`Merging`) is logged as its own activity, so
+        // it can be ruled out as a cause.
         // No data messages outside a Schedules event
         // assert!((record.event_type != Start) || prev.activity_type != DataMessage, format!("{:?}, {:?}", prev, record));
 
-        assert!((prev.event_type != End) || record.activity_type != DataMessage);
+        if prev.event_type == End && !within_schedule && record.activity_type == DataMessage {
+            return anomaly(AnomalyReason::DataMessageOutsideSchedule);
+        }
 
         // No control messages within a Schedules event
-        assert!((record.event_type != End) || prev.activity_type != ControlMessage);
-        assert!((prev.event_type != Start) || record.activity_type != ControlMessage);
-        // A message with length != None is always either a SchedEnd or a remote data recv
-        assert!(record.length.is_none() || record.activity_type == DataMessage || record.event_type == End);
-        assert!(prev.length.is_none() || prev.activity_type == DataMessage || prev.event_type == End);
+        if (record.event_type == End && prev.activity_type == ControlMessage) ||
+            (prev.event_type == Start && record.activity_type == ControlMessage) {
+            return anomaly(AnomalyReason::ControlMessageWithinSchedule);
+        }
+        // A message with length != None is always either a SchedEnd, MergeEnd or a remote data recv
+        if !(record.length.is_none() || record.activity_type == DataMessage || record.event_type == End) ||
+            !(prev.length.is_none() || prev.activity_type == DataMessage || prev.event_type == End) {
+            return anomaly(AnomalyReason::UnexpectedLength);
+        }
         // local edges are local and provided in order
-        assert!(record.timestamp > prev.timestamp && record.local_worker == prev.local_worker);
+        if record.local_worker != prev.local_worker {
+            return anomaly(AnomalyReason::NotLocal);
+        }
+        if record.timestamp <= prev.timestamp {
+            return anomaly(AnomalyReason::OutOfOrder);
+        }
 
         let processing_or_spinning = if record.length.is_some() {
             Processing
@@ -301,13 +589,22 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructPAG<S> for Stream<S, Lo
 
             (Scheduling, Scheduling) if (p == Start && r == End) => processing_or_spinning,
             (Scheduling, Scheduling) if (p == End && r == Start) => Busy,
+            // arrangement maintenance usually happens within its operator's schedule,
+            // but trace handles might also merge in between schedules
+            (Merging, Merging) if (p == Start && r == End) => Merging,
+            (Merging, Merging) if (p == End && r == Start) && within_schedule => Processing,
+            (Merging, Merging) if (p == End && r == Start) => Busy,
+            (Scheduling, Merging) if (p == Start && r == Start) => Processing,
+            (Merging, Scheduling) if (p == End && r == End) => processing_or_spinning,
+            (Scheduling, Merging) if (p == End && r == Start) => Busy,
+            (Merging, Scheduling) if (p == End && r == Start) => Busy,
             (_, ControlMessage) if r == Received => Waiting,
             (_, ControlMessage) => Busy,
             (ControlMessage, _) => Busy,
             (DataMessage, _) => Processing,
             (_, DataMessage) => Processing,
 
-            _ => panic!("{:?}, {:?}", prev, record)
+            _ => return anomaly(AnomalyReason::UnknownEdge)
         };
 
         // waiting on data message
@@ -315,10 +612,12 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructPAG<S> for Stream<S, Lo
             edge_type = Waiting;
         }
 
-        let operator_id = if prev.event_type != End && record.event_type != Start {
-            prev.operator_id
-        } else {
+        // edges within a schedule belong to its operator, maintenance edges to their arrangement's
+        let operator_id = if (prev.activity_type == Scheduling && p == End) || (record.activity_type == Scheduling && r == Start) ||
+            (!within_schedule && edge_type != Merging) {
             None
+        } else {
+            prev.operator_id
         };
 
         let traverse = if edge_type == Waiting {
@@ -327,25 +626,52 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructPAG<S> for Stream<S, Lo
             TraversalType::Unbounded
         };
 
-        // only keep lengths for schedule edges
-        let length = if record.activity_type == Scheduling {
+        // only keep lengths for schedule and maintenance edges
+        let length = if record.activity_type == Scheduling || edge_type == Merging {
             record.length
         } else {
             None
         };
 
-        PagEdge {
+        // edges between two scope activations (e.g., between loop iterations) belong to neither,
+        // but to the activations both are nested in
+        let mut outer_scopes = prev.outer_scopes.iter().chain(prev.scope.iter())
+            .zip(record.outer_scopes.iter().chain(record.scope.iter()))
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| *a)
+            .collect::<Vec<_>>();
+        let scope = outer_scopes.pop();
+
+        Ok(PagEdge {
             source: PagNode::from(prev),
             destination: PagNode::from(record),
             edge_type,
             operator_id,
+            operator_name: None,
             traverse,
             length,
-        }
+            scope,
+            outer_scopes,
+            scope_name: None,
+            channel_id: None,
+        })
     }
 
-    fn make_remote_edges(&self) -> Stream<S, (PagEdge, S::Timestamp, isize)> {
-        let narrowed = self.filter(|x| x.activity_type == ControlMessage || x.activity_type == DataMessage);
+    fn make_remote_edges(&self, index: usize, strict: bool) -> (Stream<S, (PagEdge, S::Timestamp, isize)>, Stream<S, UnmatchedMessages>, Stream<S, Anomaly>) {
+        // remote events can only be matched by their correlator id
+        let narrowed = self
+            .filter(|x| x.activity_type == ControlMessage || x.activity_type == DataMessage)
+            .map(move |x| if x.correlator_id.is_some() {
+                Ok(x)
+            } else {
+                let anomaly = Anomaly::new(AnomalyReason::MissingCorrelator, vec![x]);
+                if strict {
+                    panic!("w{}: {:?}", index, anomaly);
+                }
+                Err(anomaly)
+            });
+        let uncorrelated = narrowed.flat_map(|x| x.err());
+        let narrowed = narrowed.flat_map(|x| x.ok());
 
         let sent = narrowed
             .filter(|x| x.event_type == Sent)
@@ -364,29 +690,79 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructPAG<S> for Stream<S, Lo
                 ((x.remote_worker, receiver, x.correlator_id, x.channel_id), x)
             });
 
-        sent.join_edges(&received)
-            .map(|(from, to, t)| {
-                assert!(to.local_worker != from.local_worker);
-                (PagEdge {
-                source: PagNode::from(&from),
-                destination: PagNode::from(&to),
-                edge_type: from.activity_type,
-                operator_id: None,
-                traverse: TraversalType::Unbounded,
-                length: from.length,
-                }, t, 1)})
+        let joined = sent.join_edges(&received);
+
+        let matched = joined
+            .flat_map(move |joined| match joined {
+                // only data messages might stay on a worker
+                Joined::Matched(from, to, _t) if to.local_worker == from.local_worker && from.activity_type != DataMessage => {
+                    let anomaly = Anomaly::new(AnomalyReason::LocalControlMessage, vec![from, to]);
+                    if strict {
+                        panic!("w{}: {:?}", index, anomaly);
+                    }
+                    Some(Err(anomaly))
+                }
+                Joined::Matched(from, to, t) => {
+                    Some(Ok((PagEdge {
+                        source: PagNode::from(&from),
+                        destination: PagNode::from(&to),
+                        edge_type: from.activity_type,
+                        operator_id: None,
+                        operator_name: None,
+                        traverse: TraversalType::Unbounded,
+                        length: from.length,
+                        scope: None,
+                        outer_scopes: Vec::new(),
+                        scope_name: None,
+                        channel_id: from.channel_id,
+                    }, t, 1)))
+                }
+                Joined::Unmatched(_) => None,
+            });
+
+        let edges = matched.flat_map(|x| x.ok());
+        let anomalies = matched.flat_map(|x| x.err()).concat(&uncorrelated);
+
+        // every ST2 peer only reports unmatched events for its part of the join
+        let unmatched = joined
+            .flat_map(|joined| match joined {
+                Joined::Matched(..) => None,
+                Joined::Unmatched(x) => Some(((x.epoch, x.activity_type), (x.sent, x.received))),
+            })
+            .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+            .aggregate::<_,(u64, u64),_,_,_>(
+                |_key, (sent, received), acc| {
+                    *acc = (acc.0 + sent, acc.1 + received);
+                },
+                |(epoch, activity_type), (sent, received)| UnmatchedMessages { epoch, activity_type, sent, received },
+                |(epoch, _activity_type)| *epoch);
+
+        (edges, unmatched, anomalies)
     }
 }
 
+/// Result of joining the sent and received sides of remote events
+#[derive(Clone, Debug)]
+enum Joined<T> {
+    /// A sent event and its matching received event
+    Matched(LogRecord, LogRecord, T),
+    /// Unmatched events of a completed epoch, as seen by a single ST2 peer
+    Unmatched(UnmatchedMessages),
+}
+
+/// Remote events of an epoch that are kept for the join,
+/// together with whether they have been matched yet
+type JoinState<D> = HashMap<u64, HashMap<D, Vec<(LogRecord, bool)>>>;
+
 trait JoinEdges<S: Scope<Timestamp = Pair<u64, Duration>>, D> where D: Data + Hash + Eq + Abomonation + Send + Sync {
-    fn join_edges(&self, other: &Stream<S, (D, LogRecord)>) -> Stream<S, (LogRecord, LogRecord, S::Timestamp)>;
+    fn join_edges(&self, other: &Stream<S, (D, LogRecord)>) -> Stream<S, Joined<S::Timestamp>>;
 }
 
 impl<S: Scope<Timestamp = Pair<u64, Duration>>, D> JoinEdges<S, D>
     for Stream<S, (D, LogRecord)>
 where D: Data + Hash + Eq + Abomonation + Send + Sync + std::fmt::Debug
 {
-    fn join_edges(&self, other: &Stream<S, (D, LogRecord)>) -> Stream<S, (LogRecord, LogRecord, S::Timestamp)> {
+    fn join_edges(&self, other: &Stream<S, (D, LogRecord)>) -> Stream<S, Joined<S::Timestamp>> {
         // exchange by epoch doesn't make sense for low epoch_in_flight counts
         // let exchange = Exchange::new(|(_, x): &(_, LogRecord)| x.epoch);
         // let exchange2 = Exchange::new(|(_, x): &(_, LogRecord)| x.epoch);
@@ -396,15 +772,17 @@ where D: Data + Hash + Eq + Abomonation + Send + Sync + std::fmt::Debug
         // let exchange2 = Exchange::new(|(_, x): &(_, LogRecord)| x.remote_worker.unwrap());
 
         // @TODO: exchange by correlator_id works, but is surprisingly slow
-        let exchange = Exchange::new(|(_, x): &(_, LogRecord)| x.correlator_id.expect("no corr id"));
-        let exchange2 = Exchange::new(|(_, x): &(_, LogRecord)| x.correlator_id.expect("no corr id"));
+        // (records without one are reported by `make_remote_edges` before the join)
+        let exchange = Exchange::new(|(_, x): &(_, LogRecord)| x.correlator_id.unwrap_or(0));
+        let exchange2 = Exchange::new(|(_, x): &(_, LogRecord)| x.correlator_id.unwrap_or(0));
 
-        // @TODO: in this join implementation, state continually grows.
-        // To fix this, check frontier and remove all state that is from older epochs
-        // (cross-epochs join shouldn't happen anyways)
-        self.binary(&other, exchange, exchange2, "HashJoin", |_capability, _info| {
-            let mut map1 = HashMap::new();
-            let mut map2 = HashMap::<_, Vec<LogRecord>>::new();
+        // State is kept per epoch. Cross-epoch joins shouldn't happen, so once the
+        // frontier has passed an epoch, its state is dropped and unmatched events are reported.
+        self.binary_frontier(&other, exchange, exchange2, "HashJoin", |_capability, _info| {
+            let mut map1: JoinState<D> = HashMap::new();
+            let mut map2: JoinState<D> = HashMap::new();
+            // one capability per epoch in state, used to report unmatched events
+            let mut caps: HashMap<u64, Capability<S::Timestamp>> = HashMap::new();
 
             let mut vector1 = Vec::new();
             let mut vector2 = Vec::new();
@@ -413,38 +791,388 @@ where D: Data + Hash + Eq + Abomonation + Send + Sync + std::fmt::Debug
                 // Drain first input, check second map, update first map.
                 input1.for_each(|cap, data| {
                     data.swap(&mut vector1);
+                    let epoch = cap.time().first;
+                    caps.entry(epoch).or_insert_with(|| cap.delayed(cap.time()));
+
                     let mut session = output.session(&cap);
                     for (key, val1) in vector1.drain(..) {
-                        if let Some(values) = map2.get(&key) {
-                            for val2 in values.iter() {
+                        let mut matched = false;
+                        if let Some(values) = map2.get_mut(&epoch).and_then(|m| m.get_mut(&key)) {
+                            for (val2, val2_matched) in values.iter_mut() {
                                 // assert!(val1.epoch == val2.epoch);
                                 if val1.epoch == val2.epoch {
-                                    session.give((val1.clone(), val2.clone(), cap.time().clone()));
+                                    session.give(Joined::Matched(val1.clone(), val2.clone(), cap.time().clone()));
+                                    *val2_matched = true;
+                                    matched = true;
                                 }
                             }
                         }
 
-                        map1.entry(key).or_insert(Vec::new()).push(val1);
+                        map1.entry(epoch).or_insert(HashMap::new())
+                            .entry(key).or_insert(Vec::new())
+                            .push((val1, matched));
                     }
                 });
 
                 input2.for_each(|cap, data| {
                     data.swap(&mut vector2);
+                    let epoch = cap.time().first;
+                    caps.entry(epoch).or_insert_with(|| cap.delayed(cap.time()));
+
                     let mut session = output.session(&cap);
                     for (key, val2) in vector2.drain(..) {
-                        if let Some(values) = map1.get(&key) {
-                            for val1 in values.iter() {
+                        let mut matched = false;
+                        if let Some(values) = map1.get_mut(&epoch).and_then(|m| m.get_mut(&key)) {
+                            for (val1, val1_matched) in values.iter_mut() {
                                 // assert!(val1.epoch == val2.epoch);
                                 if val1.epoch == val2.epoch {
-                                    session.give((val1.clone(), val2.clone(), cap.time().clone()));
+                                    session.give(Joined::Matched(val1.clone(), val2.clone(), cap.time().clone()));
+                                    *val1_matched = true;
+                                    matched = true;
                                 }
                             }
                         }
 
-                        map2.entry(key).or_insert(Vec::new()).push(val2);
+                        map2.entry(epoch).or_insert(HashMap::new())
+                            .entry(key).or_insert(Vec::new())
+                            .push((val2, matched));
                     }
                 });
+
+                // drop state of epochs that have been passed by both frontiers
+                let frontier1 = input1.frontier().frontier();
+                let frontier2 = input2.frontier().frontier();
+                let completed = caps.keys()
+                    .filter(|epoch| frontier1.iter().chain(frontier2.iter()).all(|t| t.first > **epoch))
+                    .cloned()
+                    .collect::<Vec<_>>();
+
+                for epoch in completed {
+                    let cap = caps.remove(&epoch).expect("epoch without capability");
+                    let unmatched_sent = count_unmatched(map1.remove(&epoch));
+                    let unmatched_received = count_unmatched(map2.remove(&epoch));
+
+                    let mut session = output.session(&cap);
+                    for activity_type in [ControlMessage, DataMessage].iter() {
+                        let sent = *unmatched_sent.get(activity_type).unwrap_or(&0);
+                        let received = *unmatched_received.get(activity_type).unwrap_or(&0);
+
+                        if sent > 0 || received > 0 {
+                            session.give(Joined::Unmatched(UnmatchedMessages {
+                                epoch,
+                                activity_type: *activity_type,
+                                sent,
+                                received,
+                            }));
+                        }
+                    }
+                }
             }
         })
     }
 }
+
+/// Counts the unmatched events per activity type in an epoch's join state
+fn count_unmatched<D>(state: Option<HashMap<D, Vec<(LogRecord, bool)>>>) -> HashMap<ActivityType, u64> {
+    let mut counts = HashMap::new();
+    for (record, _) in state.into_iter().flat_map(|m| m.into_iter()).flat_map(|(_, v)| v.into_iter()).filter(|(_, matched)| !matched) {
+        *counts.entry(record.activity_type).or_insert(0) += 1;
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::Receiver;
+    use timely::dataflow::operators::{Capture, Input, Probe, ToStream};
+    use timely::dataflow::operators::capture::event::Event;
+
+    fn time(epoch: u64) -> Pair<u64, Duration> {
+        Pair::new(epoch, Default::default())
+    }
+
+    /// A record of `local_worker` at `t` ns, with its `seq_no` set to `t`
+    fn record(epoch: u64, local_worker: Worker, t: u64, activity_type: ActivityType, event_type: EventType) -> LogRecord {
+        LogRecord {
+            seq_no: t, epoch, timestamp: Duration::from_nanos(t), local_worker, activity_type, event_type,
+            remote_worker: None, operator_id: None, channel_id: None, correlator_id: None, length: None, scope: None, outer_scopes: Vec::new(),
+        }
+    }
+
+    /// A remote event on channel 5
+    fn message(epoch: u64, local_worker: Worker, t: u64, activity_type: ActivityType, event_type: EventType,
+               remote_worker: Option<Worker>, correlator_id: u64) -> LogRecord {
+        LogRecord {
+            remote_worker,
+            channel_id: Some(5),
+            correlator_id: Some(correlator_id),
+            ..record(epoch, local_worker, t, activity_type, event_type)
+        }
+    }
+
+    fn collect<D>(captured: &Receiver<Event<Pair<u64, Duration>, D>>) -> Vec<D> {
+        captured.try_iter().flat_map(|event| match event {
+            Event::Messages(_time, data) => data,
+            Event::Progress(_) => Vec::new(),
+        }).collect()
+    }
+
+    /// Steps `worker` until `probe` has passed `epoch`, returning whether it did.
+    fn step_past<A: timely::communication::Allocate>(worker: &mut timely::worker::Worker<A>, probe: &timely::dataflow::ProbeHandle<Pair<u64, Duration>>, epoch: u64) -> bool {
+        for _ in 0 .. 100 {
+            if !probe.less_equal(&time(epoch)) {
+                return true;
+            }
+            worker.step();
+        }
+        !probe.less_equal(&time(epoch))
+    }
+
+    /// Matched events as `(epoch, correlator id)`, and unmatched events
+    fn split(joined: Vec<Joined<Pair<u64, Duration>>>) -> (Vec<(u64, Option<u64>)>, Vec<UnmatchedMessages>) {
+        let mut matched = Vec::new();
+        let mut unmatched = Vec::new();
+        for x in joined {
+            match x {
+                Joined::Matched(from, to, t) => {
+                    assert_eq!(from.correlator_id, to.correlator_id);
+                    matched.push((t.first, from.correlator_id));
+                }
+                Joined::Unmatched(x) => unmatched.push(x),
+            }
+        }
+        (matched, unmatched)
+    }
+
+    #[test]
+    fn join_releases_completed_epochs() {
+        let (epoch1, epoch2) = timely::execute_directly(|worker| {
+            let (mut sent, mut received, probe, captured) = worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                let (sent_input, sent) = scope.new_input::<(u64, LogRecord)>();
+                let (received_input, received) = scope.new_input::<(u64, LogRecord)>();
+                let joined = sent.join_edges(&received);
+                (sent_input, received_input, joined.probe(), joined.capture())
+            });
+
+            let send = |epoch, id| (id, message(epoch, 0, 10 * id, DataMessage, Sent, Some(1), id));
+            let receive = |epoch, id| (id, message(epoch, 1, 10 * id + 5, DataMessage, Received, Some(0), id));
+
+            sent.advance_to(time(1));
+            received.advance_to(time(1));
+            sent.send(send(1, 1));
+            received.send(receive(1, 1));
+            sent.send(send(1, 2));
+            received.send(receive(1, 3));
+
+            sent.advance_to(time(2));
+            received.advance_to(time(2));
+            // the same correlator id isn't matched across epochs
+            received.send(receive(2, 1));
+            // epoch 1's state holds back the frontier until it is dropped
+            assert!(step_past(worker, &probe, 1), "epoch 1 is still retained");
+            let epoch1 = split(collect(&captured));
+
+            sent.advance_to(time(3));
+            received.advance_to(time(3));
+            assert!(step_past(worker, &probe, 2), "epoch 2 is still retained");
+            let epoch2 = split(collect(&captured));
+
+            (epoch1, epoch2)
+        });
+
+        assert_eq!(epoch1, (vec![(1, Some(1))], vec![UnmatchedMessages { epoch: 1, activity_type: DataMessage, sent: 1, received: 1 }]));
+        assert_eq!(epoch2, (vec![], vec![UnmatchedMessages { epoch: 2, activity_type: DataMessage, sent: 0, received: 1 }]));
+    }
+
+    #[test]
+    fn report_unmatched_messages() {
+        let (edges, unmatched) = timely::execute_directly(|worker| {
+            worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                let lrs = vec![
+                    message(1, 0, 10, DataMessage, Sent, Some(1), 1),
+                    message(1, 1, 15, DataMessage, Received, Some(0), 1),
+                    message(1, 0, 20, DataMessage, Sent, Some(1), 2),
+                    // control messages are broadcast, so their receiver is unknown when sent
+                    message(1, 0, 30, ControlMessage, Sent, None, 3),
+                    message(1, 1, 35, ControlMessage, Received, Some(0), 3),
+                    message(1, 1, 45, ControlMessage, Received, Some(0), 4),
+                    message(2, 1, 55, DataMessage, Received, Some(0), 5),
+                ];
+                let lrs = lrs.to_stream(scope).delay(|lr, _t| Pair::new(lr.epoch, Default::default()));
+                let (edges, unmatched, _anomalies) = lrs.make_remote_edges(0, false);
+                (edges.capture(), unmatched.capture())
+            })
+        });
+
+        let mut edges = collect(&edges).into_iter()
+            .map(|(edge, _t, _diff)| (edge.edge_type, edge.source.seq_no, edge.destination.seq_no))
+            .collect::<Vec<_>>();
+        edges.sort();
+        let mut expected = vec![(DataMessage, 10, 15), (ControlMessage, 30, 35)];
+        expected.sort();
+        assert_eq!(edges, expected);
+
+        let mut unmatched = collect(&unmatched);
+        unmatched.sort_by_key(|x| (x.epoch, x.activity_type));
+        let mut expected = vec![
+            UnmatchedMessages { epoch: 1, activity_type: DataMessage, sent: 1, received: 0 },
+            UnmatchedMessages { epoch: 1, activity_type: ControlMessage, sent: 0, received: 1 },
+            UnmatchedMessages { epoch: 2, activity_type: DataMessage, sent: 0, received: 1 },
+        ];
+        expected.sort_by_key(|x| (x.epoch, x.activity_type));
+        assert_eq!(unmatched, expected);
+    }
+
+    type TestStream = Stream<timely::dataflow::scopes::Child<'static, timely::worker::Worker<timely::communication::allocator::Thread>, Pair<u64, Duration>>, LogRecord>;
+
+    /// The reason `build_local_edge` rejects the edge from `prev` to `lr`, if any
+    fn reject(prev: &LogRecord, lr: &LogRecord, within_schedule: bool) -> Option<AnomalyReason> {
+        let next = record(1, 0, 100, Scheduling, Start);
+        match <TestStream as ConstructPAG<_>>::build_local_edge(prev, lr, &next, within_schedule) {
+            Ok(_edge) => None,
+            Err(anomaly) => {
+                assert_eq!(anomaly.records, vec![prev.clone(), lr.clone()]);
+                Some(anomaly.reason)
+            }
+        }
+    }
+
+    #[test]
+    fn reject_malformed_local_edges() {
+        let start = |t| record(1, 0, t, Scheduling, Start);
+        let end = |t| record(1, 0, t, Scheduling, End);
+
+        assert_eq!(reject(&start(10), &end(20), false), None);
+        assert_eq!(reject(&end(10), &record(1, 0, 20, DataMessage, Sent), false), Some(AnomalyReason::DataMessageOutsideSchedule));
+        assert_eq!(reject(&end(10), &record(1, 0, 20, DataMessage, Sent), true), None);
+        assert_eq!(reject(&start(10), &record(1, 0, 20, ControlMessage, Sent), false), Some(AnomalyReason::ControlMessageWithinSchedule));
+        let merge = LogRecord { length: Some(3), ..record(1, 0, 20, Merging, Start) };
+        assert_eq!(reject(&start(10), &merge, false), Some(AnomalyReason::UnexpectedLength));
+        assert_eq!(reject(&start(10), &record(1, 1, 20, Scheduling, End), false), Some(AnomalyReason::NotLocal));
+        assert_eq!(reject(&start(20), &end(10), false), Some(AnomalyReason::OutOfOrder));
+        assert_eq!(reject(&start(10), &start(20), false), Some(AnomalyReason::UnknownEdge));
+    }
+
+    /// Local edges and anomalies of `lrs`, as `(type, from seq_no, to seq_no)`
+    fn local_edges(lrs: Vec<LogRecord>, strict: bool) -> (Vec<(ActivityType, u64, u64)>, Vec<Anomaly>) {
+        let (edges, anomalies) = timely::execute_directly(move |worker| {
+            worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                let lrs = lrs.to_stream(scope).delay(|lr, _t| Pair::new(lr.epoch, Default::default()));
+                let (edges, anomalies) = lrs.make_local_edges(0, strict);
+                (edges.capture(), anomalies.capture())
+            })
+        });
+
+        let edges = collect(&edges).into_iter()
+            .map(|(edge, _t, _diff)| (edge.edge_type, edge.source.seq_no, edge.destination.seq_no))
+            .collect();
+        (edges, collect(&anomalies))
+    }
+
+    fn out_of_order() -> Vec<LogRecord> {
+        vec![
+            record(1, 0, 10, Scheduling, Start),
+            record(1, 0, 20, Scheduling, End),
+            record(1, 0, 15, Scheduling, Start),
+            record(1, 0, 30, Scheduling, Start),
+            record(1, 0, 40, Scheduling, End),
+        ]
+    }
+
+    #[test]
+    fn skip_out_of_order_records() {
+        let (edges, anomalies) = local_edges(out_of_order(), false);
+
+        assert_eq!(edges, vec![(Spinning, 10, 20), (Busy, 20, 30)]);
+        assert_eq!(anomalies, vec![Anomaly::new(AnomalyReason::OutOfOrder, vec![
+            record(1, 0, 20, Scheduling, End),
+            record(1, 0, 15, Scheduling, Start),
+        ])]);
+    }
+
+    #[test]
+    #[should_panic(expected = "OutOfOrder")]
+    fn skip_out_of_order_records_strict() {
+        local_edges(out_of_order(), true);
+    }
+
+    #[test]
+    fn report_malformed_messages() {
+        let (edges, anomalies) = timely::execute_directly(|worker| {
+            worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                let uncorrelated = LogRecord { correlator_id: None, ..message(1, 0, 10, DataMessage, Sent, Some(1), 1) };
+                let lrs = vec![
+                    uncorrelated,
+                    // only data messages might stay on a worker
+                    message(1, 0, 20, DataMessage, Sent, Some(0), 2),
+                    message(1, 0, 25, DataMessage, Received, Some(0), 2),
+                    message(1, 0, 30, ControlMessage, Sent, None, 3),
+                    message(1, 0, 35, ControlMessage, Received, Some(0), 3),
+                ];
+                let lrs = lrs.to_stream(scope).delay(|lr, _t| Pair::new(lr.epoch, Default::default()));
+                let (edges, _unmatched, anomalies) = lrs.make_remote_edges(0, false);
+                (edges.capture(), anomalies.capture())
+            })
+        });
+
+        let edges = collect(&edges).into_iter()
+            .map(|(edge, _t, _diff)| (edge.edge_type, edge.source.seq_no, edge.destination.seq_no))
+            .collect::<Vec<_>>();
+        assert_eq!(edges, vec![(DataMessage, 20, 25)]);
+
+        let mut anomalies = collect(&anomalies).into_iter()
+            .map(|anomaly| (anomaly.reason, anomaly.records.iter().map(|lr| lr.seq_no).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        anomalies.sort_by_key(|(_reason, seq_nos)| seq_nos.clone());
+        assert_eq!(anomalies, vec![
+            (AnomalyReason::MissingCorrelator, vec![10]),
+            (AnomalyReason::LocalControlMessage, vec![30, 35]),
+        ]);
+    }
+
+    #[test]
+    fn name_operators_once_known() {
+        let (held_back, named) = timely::execute_directly(|worker| {
+            let (mut edges, mut operators, probe, captured) = worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                let (edges_input, edges) = scope.new_input::<(PagEdge, Pair<u64, Duration>, isize)>();
+                let (operators_input, operators) = scope.new_input::<OperatorInfo>();
+                let named = edges.name_operators(&operators);
+                (edges_input, operators_input, named.probe(), named.capture())
+            });
+
+            let edge = |worker_id| PagEdge {
+                source: PagNode { worker_id, epoch: 1, ..Default::default() },
+                operator_id: Some(3),
+                scope: Some(ScopeActivation { scope_id: 2, round: 0 }),
+                ..Default::default()
+            };
+            let operator = |id, name: &str| OperatorInfo { worker: 0, id, name: name.to_string(), addr: vec![0, 2, id as usize] };
+
+            edges.advance_to(time(1));
+            edges.send((edge(0), time(1), 1));
+            edges.send((edge(1), time(1), 1));
+            edges.advance_to(time(2));
+            // the edges are held back while operators of epoch 1 might still arrive
+            assert!(!step_past(worker, &probe, 1), "edges weren't held back");
+            let held_back = collect(&captured);
+
+            operators.send(operator(3, "Map"));
+            operators.send(operator(2, "Iterate"));
+            operators.advance_to(time(2));
+            assert!(step_past(worker, &probe, 1), "edges are still held back");
+
+            let named = collect(&captured).into_iter()
+                .map(|(edge, _t, _diff)| (edge.source.worker_id, edge.operator_name, edge.scope_name))
+                .collect::<Vec<_>>();
+            (held_back, named)
+        });
+
+        assert!(held_back.is_empty());
+        // operator ids are worker-local
+        assert_eq!(named, vec![
+            (0, Some("Map".to_string()), Some("Iterate".to_string())),
+            (1, None, None),
+        ]);
+    }
+}
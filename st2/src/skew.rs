@@ -0,0 +1,335 @@
+//! Clock skew estimation & correction
+//! Source workers might run on different machines with unsynchronized clocks.
+//! Matched remote messages are used to estimate the clock offsets between
+//! pairs of workers (NTP-style, using the minimum one-way delays per epoch).
+//! These are used to correct the timestamps of `LogRecord`s and remote edges
+//! during PAG construction.
+
+use std::collections::{HashMap, VecDeque, BTreeMap};
+use std::time::Duration;
+use std::convert::TryInto;
+
+use timely::dataflow::{Scope, Stream};
+use timely::dataflow::channels::pact::Pipeline;
+use timely::dataflow::operators::map::Map;
+use timely::dataflow::operators::filter::Filter;
+use timely::dataflow::operators::delay::Delay;
+use timely::dataflow::operators::broadcast::Broadcast;
+use timely::dataflow::operators::aggregation::aggregate::Aggregate;
+use timely::dataflow::operators::generic::operator::Operator;
+use timely::dataflow::operators::Capability;
+use timely::Data;
+
+use st2_logformat::{LogRecord, Worker};
+use st2_logformat::pair::Pair;
+
+use crate::pag::{ConstructPAG, PagEdge};
+
+use serde::Serialize;
+
+
+/// Estimated clock offset between two source workers in an epoch
+#[derive(Abomonation, Clone, PartialEq, Eq, Hash, Debug, Serialize)]
+pub struct ClockOffset {
+    /// Epoch the estimate is based on
+    pub epoch: u64,
+    /// Worker whose clock is used as reference
+    pub from: Worker,
+    /// Worker whose clock is compared to `from`'s
+    pub to: Worker,
+    /// Nanoseconds `to`'s clock is ahead of `from`'s clock (negative if it's behind)
+    pub offset: i64,
+    /// Sum of the minimum one-way delays in both directions in ns,
+    /// i.e. the round trip time the estimate is based on. The true offset
+    /// lies within `offset ± round_trip / 2`.
+    pub round_trip: i64,
+}
+
+/// Estimates and corrects clock skew between source workers
+pub trait ClockSkew<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Estimates the clock offset of every pair of workers that exchanged
+    /// remote messages in both directions in an epoch.
+    /// Offsets are emitted at `epoch + 1`. This matches remote messages on its own;
+    /// if they are matched anyway, use `EdgeClockSkew::clock_offsets` instead.
+    fn clock_offsets(&self) -> Stream<S, ClockOffset>;
+    /// Shifts `LogRecord` timestamps onto the clock of the lowest worker, using
+    /// the most recent `offsets` known for every pair of workers.
+    /// Records are held back until the offsets of their epoch are known.
+    fn correct_clock_skew(&self, offsets: &Stream<S, ClockOffset>) -> Stream<S, LogRecord>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> ClockSkew<S> for Stream<S, LogRecord> {
+    fn clock_offsets(&self) -> Stream<S, ClockOffset> {
+        // malformed records are already reported when constructing the PAG
+        self.make_remote_edges(self.scope().index(), false).0.clock_offsets()
+    }
+
+    fn correct_clock_skew(&self, offsets: &Stream<S, ClockOffset>) -> Stream<S, LogRecord> {
+        correct(self, offsets, "CorrectClockSkew", |record, worker_offsets| {
+            let offset = *worker_offsets.get(&record.local_worker).unwrap_or(&0);
+            record.timestamp = shift(record.timestamp, offset);
+        })
+    }
+}
+
+/// Estimates and corrects clock skew from the remote edges of a PAG
+pub trait EdgeClockSkew<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Estimates the clock offset of every pair of workers that exchanged
+    /// remote messages in both directions in an epoch, from the edges of these messages.
+    /// Offsets are emitted at `epoch + 1`.
+    fn clock_offsets(&self) -> Stream<S, ClockOffset>;
+    /// Shifts the timestamps of the edges' nodes onto the clock of the lowest worker,
+    /// like `ClockSkew::correct_clock_skew`.
+    fn correct_clock_skew(&self, offsets: &Stream<S, ClockOffset>) -> Stream<S, (PagEdge, S::Timestamp, isize)>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> EdgeClockSkew<S> for Stream<S, (PagEdge, S::Timestamp, isize)> {
+    fn clock_offsets(&self) -> Stream<S, ClockOffset> {
+        self
+            // worker-local data messages don't tell anything about clocks
+            .filter(|(edge, _t, _diff)| edge.source.worker_id != edge.destination.worker_id)
+            .map(|(edge, _t, _diff)| {
+                let from = edge.source.worker_id;
+                let to = edge.destination.worker_id;
+                let delay = nanos(edge.destination.timestamp) - nanos(edge.source.timestamp);
+
+                // one-way delays from the lower to the higher worker and back
+                if from < to {
+                    ((edge.source.epoch, from, to), (Some(delay), None))
+                } else {
+                    ((edge.source.epoch, to, from), (None, Some(delay)))
+                }
+            })
+            .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+            .aggregate::<_,(Option<i64>, Option<i64>),_,_,_>(
+                |_key, (there, back), acc| {
+                    acc.0 = min_delay(acc.0, there);
+                    acc.1 = min_delay(acc.1, back);
+                },
+                |key, acc| (key, acc),
+                |key| key.0)
+            .flat_map(|((epoch, from, to), (there, back))| {
+                // there = latency + offset, back = latency - offset
+                let (there, back) = (there?, back?);
+                Some(ClockOffset {
+                    epoch,
+                    from,
+                    to,
+                    offset: (there - back) / 2,
+                    round_trip: there + back,
+                })
+            })
+    }
+
+    fn correct_clock_skew(&self, offsets: &Stream<S, ClockOffset>) -> Stream<S, (PagEdge, S::Timestamp, isize)> {
+        correct(self, offsets, "CorrectEdgeClockSkew", |(edge, _t, _diff), worker_offsets| {
+            for node in &mut [&mut edge.source, &mut edge.destination] {
+                let offset = *worker_offsets.get(&node.worker_id).unwrap_or(&0);
+                node.timestamp = shift(node.timestamp, offset);
+            }
+        })
+    }
+}
+
+/// Applies `correct` with every worker's offset to the data in `stream`,
+/// holding it back until the offsets of its epoch are known.
+fn correct<S, D, F>(stream: &Stream<S, D>, offsets: &Stream<S, ClockOffset>, name: &str, correct: F) -> Stream<S, D>
+where
+    S: Scope<Timestamp = Pair<u64, Duration>>,
+    D: Data,
+    F: Fn(&mut D, &HashMap<Worker, i64>) + 'static,
+{
+    let offsets = offsets.broadcast();
+
+    stream.binary_frontier(&offsets, Pipeline, Pipeline, name, |_capability, _info| {
+        // data per epoch, batched by its capability
+        let mut stash: HashMap<u64, Vec<(Capability<S::Timestamp>, Vec<D>)>> = HashMap::new();
+        // offsets per epoch that haven't been applied yet
+        let mut pending: BTreeMap<u64, Vec<ClockOffset>> = BTreeMap::new();
+        // most recent offset per pair of workers
+        let mut pairs: HashMap<(Worker, Worker), i64> = HashMap::new();
+
+        let mut vector1 = Vec::new();
+        let mut vector2 = Vec::new();
+
+        move |input1, input2, output| {
+            input1.for_each(|cap, data| {
+                data.swap(&mut vector1);
+                stash.entry(cap.time().first).or_insert(Vec::new())
+                    .push((cap.delayed(cap.time()), vector1.drain(..).collect()));
+            });
+
+            input2.for_each(|_cap, data| {
+                data.swap(&mut vector2);
+                for offset in vector2.drain(..) {
+                    pending.entry(offset.epoch).or_insert(Vec::new()).push(offset);
+                }
+            });
+
+            // offsets of an epoch are complete once the frontier has passed `epoch + 1`
+            let frontier = input2.frontier().frontier();
+            let mut completed = stash.keys()
+                .filter(|epoch| frontier.iter().all(|t| *t > Pair::new(**epoch + 1, Default::default())))
+                .cloned()
+                .collect::<Vec<_>>();
+            completed.sort();
+
+            for epoch in completed {
+                let later = pending.split_off(&(epoch + 1));
+                for offset in std::mem::replace(&mut pending, later).into_iter().flat_map(|(_, x)| x) {
+                    pairs.insert((offset.from, offset.to), offset.offset);
+                }
+                let worker_offsets = worker_offsets(&pairs);
+
+                let mut batches = stash.remove(&epoch).expect("epoch without data");
+                batches.sort_by(|(a, _), (b, _)| a.time().cmp(b.time()));
+                for (cap, mut data) in batches {
+                    for datum in data.iter_mut() {
+                        correct(datum, &worker_offsets);
+                    }
+                    output.session(&cap).give_vec(&mut data);
+                }
+            }
+        }
+    })
+}
+
+/// Derives every worker's offset to the lowest worker it's (transitively) connected to.
+/// Workers without known offsets aren't corrected.
+fn worker_offsets(pairs: &HashMap<(Worker, Worker), i64>) -> HashMap<Worker, i64> {
+    let mut neighbors: BTreeMap<Worker, Vec<(Worker, i64)>> = BTreeMap::new();
+    for ((from, to), offset) in pairs.iter() {
+        neighbors.entry(*from).or_insert(Vec::new()).push((*to, *offset));
+        neighbors.entry(*to).or_insert(Vec::new()).push((*from, -*offset));
+    }
+
+    let mut offsets = HashMap::new();
+    for root in neighbors.keys() {
+        if offsets.contains_key(root) {
+            continue;
+        }
+
+        offsets.insert(*root, 0);
+        let mut queue = VecDeque::new();
+        queue.push_back(*root);
+        while let Some(worker) = queue.pop_front() {
+            let base = offsets[&worker];
+            for (neighbor, offset) in neighbors[&worker].iter() {
+                if !offsets.contains_key(neighbor) {
+                    offsets.insert(*neighbor, base + offset);
+                    queue.push_back(*neighbor);
+                }
+            }
+        }
+    }
+
+    offsets
+}
+
+/// Moves `timestamp` back by `offset` ns.
+fn shift(timestamp: Duration, offset: i64) -> Duration {
+    if offset >= 0 {
+        timestamp.checked_sub(Duration::from_nanos(offset as u64)).unwrap_or_default()
+    } else {
+        timestamp + Duration::from_nanos((-offset) as u64)
+    }
+}
+
+fn nanos(timestamp: Duration) -> i64 {
+    timestamp.as_nanos().try_into().expect("timestamp out of range")
+}
+
+fn min_delay(acc: Option<i64>, delay: Option<i64>) -> Option<i64> {
+    match (acc, delay) {
+        (Some(a), Some(d)) => Some(a.min(d)),
+        (a, d) => a.or(d),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use timely::dataflow::operators::{Capture, ToStream};
+    use timely::dataflow::operators::capture::event::Event;
+    use std::sync::mpsc::Receiver;
+    use st2_logformat::{ActivityType, EventType};
+
+    fn message(seq_no: u64, local_worker: Worker, event_type: EventType, remote_worker: Worker, correlator_id: u64, t: u64) -> LogRecord {
+        LogRecord {
+            seq_no, epoch: 1, timestamp: Duration::from_nanos(t), local_worker,
+            activity_type: ActivityType::DataMessage, event_type, remote_worker: Some(remote_worker),
+            operator_id: None, channel_id: Some(5), correlator_id: Some(correlator_id), length: Some(1), scope: None, outer_scopes: Vec::new(),
+        }
+    }
+
+    /// w1's clock is 1000ns ahead of w0's, messages take 100ns in both directions.
+    fn records() -> Vec<LogRecord> {
+        vec![
+            message(0, 0, EventType::Sent, 1, 1, 10_000),
+            message(0, 1, EventType::Received, 0, 1, 11_100),
+            message(1, 1, EventType::Sent, 0, 2, 21_000),
+            message(1, 0, EventType::Received, 1, 2, 20_100),
+        ]
+    }
+
+    fn collect<D>(captured: Receiver<Event<Pair<u64, Duration>, D>>) -> Vec<D> {
+        captured.try_iter().flat_map(|event| match event {
+            Event::Messages(_time, data) => data,
+            Event::Progress(_) => Vec::new(),
+        }).collect()
+    }
+
+    #[test]
+    fn two_workers_with_known_skew() {
+        let (offsets, corrected) = timely::execute_directly(|worker| {
+            worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                let lrs = records().to_stream(scope).delay(|lr, _t| Pair::new(lr.epoch, Default::default()));
+                let offsets = lrs.clock_offsets();
+                (offsets.capture(), lrs.correct_clock_skew(&offsets).capture())
+            })
+        });
+
+        assert_eq!(collect(offsets), vec![ClockOffset { epoch: 1, from: 0, to: 1, offset: 1000, round_trip: 200 }]);
+
+        let mut corrected = collect(corrected);
+        corrected.sort_by_key(|lr| (lr.local_worker, lr.seq_no));
+        let timestamps = corrected.iter().map(|lr| (lr.local_worker, nanos(lr.timestamp))).collect::<Vec<_>>();
+        assert_eq!(timestamps, vec![(0, 10_000), (0, 20_100), (1, 10_100), (1, 20_000)]);
+    }
+
+    #[test]
+    fn correct_remote_edges() {
+        let (offsets, edges) = timely::execute_directly(|worker| {
+            worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                let lrs = records().to_stream(scope).delay(|lr, _t| Pair::new(lr.epoch, Default::default()));
+                let streams = lrs.construct_pag(0, false, true);
+                (streams.clock_offsets.expect("skew not corrected").capture(), streams.edges.capture())
+            })
+        });
+
+        assert_eq!(collect(offsets), vec![ClockOffset { epoch: 1, from: 0, to: 1, offset: 1000, round_trip: 200 }]);
+
+        // local edges between the messages are corrected, too, but aren't of interest here
+        let mut edges = collect(edges).into_iter()
+            .filter(|(edge, _t, _diff)| edge.source.worker_id != edge.destination.worker_id)
+            .map(|(edge, _t, _diff)| ((edge.source.worker_id, nanos(edge.source.timestamp)), (edge.destination.worker_id, nanos(edge.destination.timestamp))))
+            .collect::<Vec<_>>();
+        edges.sort();
+        assert_eq!(edges, vec![((0, 10_000), (1, 10_100)), ((1, 20_000), (0, 20_100))]);
+    }
+
+    #[test]
+    fn transitive_worker_offsets() {
+        let pairs = vec![((0, 1), 1000), ((1, 2), -200)].into_iter().collect();
+        let offsets = worker_offsets(&pairs);
+        assert_eq!(offsets, vec![(0, 0), (1, 1000), (2, 800)].into_iter().collect());
+    }
+
+    #[test]
+    fn shift_timestamps() {
+        assert_eq!(shift(Duration::from_nanos(11_100), 1000), Duration::from_nanos(10_100));
+        assert_eq!(shift(Duration::from_nanos(100), -50), Duration::from_nanos(150));
+        // can't go before the Unix epoch
+        assert_eq!(shift(Duration::from_nanos(500), 1000), Duration::from_nanos(0));
+    }
+}